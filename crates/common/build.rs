@@ -1,4 +1,11 @@
 fn main() {
-    tonic_build::compile_protos("proto/mrklar.v1.proto")
+    tonic_build::configure()
+        // chunks are read into reused buffers and decoded without copies
+        .bytes([
+            ".mrklar.v1.UploadRequest.chunk",
+            ".mrklar.v1.UploadChunk.chunk",
+            ".mrklar.v1.DownloadResponse.chunk",
+        ])
+        .compile_protos(&["proto/mrklar.v1.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("Failed to compile protos {:?}", e));
-}
\ No newline at end of file
+}
@@ -1,5 +1,11 @@
-use std::{fmt, net::{IpAddr, Ipv4Addr, SocketAddr}};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
 
+use tonic::codec::CompressionEncoding;
 use url::Url;
 
 use crate::error::Error;
@@ -11,6 +17,96 @@ pub const DEFAULT_SERVER_URL_STR: &str = "http://127.0.0.1:10000";
 
 pub const DEFAULT_CHANNEL_SIZE: usize = 4;
 pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+/// Largest chunk size accepted by the client and the server
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// Room left in a grpc message for everything but the chunk bytes: the other
+/// message fields, the filename, the merkle proof, ...
+pub const MESSAGE_OVERHEAD: usize = 64 * 1024;
+/// Default largest grpc message sent or accepted by the client and the
+/// server: a chunk of `MAX_CHUNK_SIZE` plus the message overhead
+pub const MAX_MESSAGE_SIZE: usize = MAX_CHUNK_SIZE + MESSAGE_OVERHEAD;
+/// Largest number of entries returned by a single list request
+pub const MAX_LIST_LIMIT: u64 = 1000;
+/// Largest number of hashes looked up by a single audit request
+pub const MAX_AUDIT_HASHES: usize = 1000;
+/// Metadata entry holding the id of a request, returned by the server in
+/// every response. A client can set its own id in the request metadata.
+pub const REQUEST_ID_METADATA_KEY: &str = "x-request-id";
+/// Metadata entry holding the number of seconds after which a request
+/// rejected with `UNAVAILABLE` may be retried
+pub const RETRY_AFTER_METADATA_KEY: &str = "retry-after";
+/// Name of the health service reporting whether the server accepts uploads,
+/// `NOT_SERVING` on a replica or in maintenance mode
+pub const WRITE_HEALTH_SERVICE: &str = "mrklar.v1.FileApi.Write";
+
+/// Fails if `chunk_size` is zero or larger than `MAX_CHUNK_SIZE`
+pub fn validate_chunk_size(chunk_size: usize) -> Result<usize, Error> {
+    if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+        return Err(Error::InvalidChunkSize(chunk_size));
+    }
+    Ok(chunk_size)
+}
+
+/// Fails if a chunk of `chunk_size` bytes plus the message overhead does not
+/// fit in a grpc message of `max_message_size` bytes
+pub fn validate_message_size(chunk_size: usize, max_message_size: usize) -> Result<usize, Error> {
+    if chunk_size.saturating_add(MESSAGE_OVERHEAD) > max_message_size {
+        return Err(Error::MessageSizeTooSmall {
+            max_message_size,
+            chunk_size,
+        });
+    }
+    Ok(max_message_size)
+}
+
+/// Compression of the grpc messages sent by the client or the server. Both
+/// always accept the compressed messages, whatever their own setting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GrpcCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl GrpcCompression {
+    /// The encodings accepted by the client and the server
+    pub const ACCEPTED: [CompressionEncoding; 2] =
+        [CompressionEncoding::Gzip, CompressionEncoding::Zstd];
+
+    /// Returns the encoding of the sent messages, `None` if they are not
+    /// compressed
+    pub fn encoding(&self) -> Option<CompressionEncoding> {
+        match self {
+            GrpcCompression::None => None,
+            GrpcCompression::Gzip => Some(CompressionEncoding::Gzip),
+            GrpcCompression::Zstd => Some(CompressionEncoding::Zstd),
+        }
+    }
+}
+
+impl fmt::Display for GrpcCompression {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrpcCompression::None => write!(fmt, "none"),
+            GrpcCompression::Gzip => write!(fmt, "gzip"),
+            GrpcCompression::Zstd => write!(fmt, "zstd"),
+        }
+    }
+}
+
+impl FromStr for GrpcCompression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(GrpcCompression::None),
+            "gzip" => Ok(GrpcCompression::Gzip),
+            "zstd" => Ok(GrpcCompression::Zstd),
+            _ => Err(Error::InvalidGrpcCompression(s.to_string())),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct NetConfig {
@@ -18,6 +114,12 @@ pub struct NetConfig {
     pub host: IpAddr,
     pub chunk_size: usize,
     pub channel_size: usize,
+    /// Unix domain socket path, replaces the tcp `host` and `port` if set
+    pub uds_path: Option<PathBuf>,
+    /// Compression of the sent grpc messages
+    pub grpc_compression: GrpcCompression,
+    /// Largest grpc message sent or accepted, in bytes
+    pub max_message_size: usize,
 }
 
 impl Default for NetConfig {
@@ -27,6 +129,9 @@ impl Default for NetConfig {
             host: IpAddr::V4(Ipv4Addr::LOCALHOST),
             chunk_size: DEFAULT_CHUNK_SIZE,
             channel_size: DEFAULT_CHANNEL_SIZE,
+            uds_path: None,
+            grpc_compression: GrpcCompression::None,
+            max_message_size: MAX_MESSAGE_SIZE,
         }
     }
 }
@@ -36,7 +141,10 @@ impl fmt::Display for NetConfig {
         writeln!(fmt, "port={}", self.port)?;
         writeln!(fmt, "host={:?}", self.host)?;
         writeln!(fmt, "chunk_size={:?}", self.chunk_size)?;
-        write!(fmt, "channel_size={:?}", self.channel_size)?;
+        writeln!(fmt, "channel_size={:?}", self.channel_size)?;
+        writeln!(fmt, "uds_path={:?}", self.uds_path)?;
+        writeln!(fmt, "grpc_compression={}", self.grpc_compression)?;
+        write!(fmt, "max_message_size={:?}", self.max_message_size)?;
         Ok(())
     }
 }
@@ -56,6 +164,28 @@ impl NetConfig {
         self
     }
 
+    /// Uses the unix domain socket at `path` instead of tcp
+    #[must_use]
+    pub fn with_uds_path(mut self, path: Option<PathBuf>) -> Self {
+        self.uds_path = path;
+        self
+    }
+
+    /// Sets the compression of the sent grpc messages
+    #[must_use]
+    pub fn with_grpc_compression(mut self, compression: GrpcCompression) -> Self {
+        self.grpc_compression = compression;
+        self
+    }
+
+    /// Sets the largest grpc message sent or accepted, it must hold a chunk
+    /// plus `MESSAGE_OVERHEAD` bytes
+    #[must_use]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
     pub fn sock_addr(&self) -> SocketAddr {
         SocketAddr::new(self.host, self.port)
     }
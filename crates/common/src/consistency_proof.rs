@@ -0,0 +1,157 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::merkle_proof::MerkleProof;
+
+/// Number of levels above the leaves of a tree of `size` leaves: the leaves
+/// are the bottom of a perfect binary tree of at least 2 leaves, the missing
+/// nodes are null hashes
+pub fn tree_depth(size: u64) -> usize {
+    size.max(2).next_power_of_two().trailing_zeros() as usize
+}
+
+/// Proves that the tree of `new_size` leaves is an append-only extension of
+/// the tree of `old_size` leaves.
+///
+/// `hashes` starts with the hash of the last leaf of the old tree, followed
+/// by the sibling of its path to the root of the new tree at each level. The
+/// left siblings are complete subtrees, identical in both trees. The right
+/// siblings only hold leaves appended after the old tree, they are null
+/// hashes in the old tree. Folding the path with the null hashes gives the
+/// old root, folding it with the right siblings gives the new root.
+#[derive(Debug, Serialize, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct ConsistencyProof {
+    old_size: u64,
+    new_size: u64,
+    old_root: Vec<u8>,
+    new_root: Vec<u8>,
+    hashes: Vec<Vec<u8>>,
+}
+
+impl fmt::Display for ConsistencyProof {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(fmt, "Old size: {}", self.old_size)?;
+        writeln!(fmt, "Old merkle root: {}", hex::encode(&self.old_root))?;
+        writeln!(fmt, "New size: {}", self.new_size)?;
+        writeln!(fmt, "New merkle root: {}", hex::encode(&self.new_root))?;
+        write!(fmt, "Consistency proof (len={}):", self.hashes.len())?;
+        for hash in &self.hashes {
+            write!(fmt, "\n{}", hex::encode(hash))?;
+        }
+        Ok(())
+    }
+}
+
+impl ConsistencyProof {
+    pub fn from_raw_parts(
+        old_size: u64,
+        new_size: u64,
+        old_root: Vec<u8>,
+        new_root: Vec<u8>,
+        hashes: Vec<Vec<u8>>,
+    ) -> Self {
+        ConsistencyProof {
+            old_size,
+            new_size,
+            old_root,
+            new_root,
+            hashes,
+        }
+    }
+
+    pub fn old_size(&self) -> u64 {
+        self.old_size
+    }
+
+    pub fn new_size(&self) -> u64 {
+        self.new_size
+    }
+
+    pub fn old_root(&self) -> &Vec<u8> {
+        &self.old_root
+    }
+
+    pub fn new_root(&self) -> &Vec<u8> {
+        &self.new_root
+    }
+
+    pub fn hashes(&self) -> &[Vec<u8>] {
+        &self.hashes
+    }
+
+    pub fn verify(&self) -> bool {
+        self.verify_with_roots(&self.old_root, &self.new_root)
+    }
+
+    /// Verifies that the tree of root `new_root` extends the tree of root
+    /// `old_root`, rather than the roots embedded in the proof
+    pub fn verify_with_roots(&self, old_root: &[u8], new_root: &[u8]) -> bool {
+        if self.old_size == 0 || self.old_size > self.new_size {
+            return false;
+        }
+        let Some((last_leaf, siblings)) = self.hashes.split_first() else {
+            return false;
+        };
+        let old_depth = tree_depth(self.old_size);
+        if siblings.len() != tree_depth(self.new_size) {
+            return false;
+        }
+
+        // position of the last old leaf, then of its ancestors
+        let mut pos = self.old_size - 1;
+        let mut old_hash = last_leaf.clone();
+        let mut new_hash = last_leaf.clone();
+        for (level, sibling) in siblings.iter().enumerate() {
+            if pos % 2 == 1 {
+                old_hash = MerkleProof::sha256_pair(sibling, &old_hash);
+                new_hash = MerkleProof::sha256_pair(sibling, &new_hash);
+            } else {
+                if level < old_depth {
+                    old_hash = MerkleProof::sha256_pair(&old_hash, &MerkleProof::null_hash());
+                }
+                new_hash = MerkleProof::sha256_pair(&new_hash, sibling);
+            }
+            pos /= 2;
+        }
+
+        old_hash == old_root && new_hash == new_root
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tree_depth, ConsistencyProof};
+    use crate::merkle_proof::MerkleProof;
+
+    #[test]
+    fn test_tree_depth() {
+        assert_eq!(tree_depth(1), 1);
+        assert_eq!(tree_depth(2), 1);
+        assert_eq!(tree_depth(3), 2);
+        assert_eq!(tree_depth(4), 2);
+        assert_eq!(tree_depth(5), 3);
+        assert_eq!(tree_depth(1024), 10);
+        assert_eq!(tree_depth(1025), 11);
+    }
+
+    #[test]
+    fn test_verify() {
+        let null = MerkleProof::null_hash();
+        let (a, b, c) = (vec![1u8; 32], vec![2u8; 32], vec![3u8; 32]);
+        // 1 leaf: H(a, null), 3 leaves: H(H(a, b), H(c, null))
+        let old_root = MerkleProof::sha256_pair(&a, &null);
+        let cd = MerkleProof::sha256_pair(&c, &null);
+        let new_root = MerkleProof::sha256_pair(&MerkleProof::sha256_pair(&a, &b), &cd);
+
+        let hashes = vec![a.clone(), b.clone(), cd.clone()];
+        let proof =
+            ConsistencyProof::from_raw_parts(1, 3, old_root.clone(), new_root.clone(), hashes);
+        assert!(proof.verify());
+
+        // another old leaf
+        let hashes = vec![c.clone(), b, cd];
+        let forged = ConsistencyProof::from_raw_parts(1, 3, old_root, new_root, hashes);
+        assert!(!forged.verify());
+    }
+}
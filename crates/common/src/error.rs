@@ -6,4 +6,18 @@ pub enum Error {
     MerkleProofDecodeBin,
     #[error("Invalid Url")]
     BadUrl,
+    #[error("Invalid chunk size {0}, must be between 1 and {max}", max = crate::config::MAX_CHUNK_SIZE)]
+    InvalidChunkSize(usize),
+    #[error("Invalid grpc compression '{0}', expecting 'none', 'gzip' or 'zstd'")]
+    InvalidGrpcCompression(String),
+    #[error(
+        "Max grpc message size of {max_message_size} bytes cannot hold a chunk of {chunk_size} bytes plus {overhead} bytes of message overhead",
+        overhead = crate::config::MESSAGE_OVERHEAD
+    )]
+    MessageSizeTooSmall {
+        max_message_size: usize,
+        chunk_size: usize,
+    },
+    #[error("Invalid signed tree head of root {}: bad signature", hex::encode(.0))]
+    InvalidTreeHeadSignature(Vec<u8>),
 }
@@ -1,14 +1,19 @@
-pub mod error;
 pub mod config;
+pub mod consistency_proof;
+pub mod error;
 pub mod merkle_proof;
+pub mod tree_head;
 pub mod proto {
     tonic::include_proto!("mrklar.v1");
 }
 
+use consistency_proof::ConsistencyProof;
 use error::Error;
 use merkle_proof::MerkleProof;
+use prost::bytes::Bytes;
 use proto::{
-    download_response, upload_request, DownloadResponse, Entry, FileMetadata, ProofResponse, UploadRequest
+    download_response, upload_request, ConsistencyProofResponse, DownloadResponse, Entry,
+    FileMetadata, ProofResponse, UploadRequest,
 };
 
 // Helper
@@ -27,14 +32,14 @@ impl UploadRequest {
         }
     }
 
-    pub fn new_chunk(chunk: Vec<u8>) -> Self {
+    pub fn new_chunk(chunk: impl Into<Bytes>) -> Self {
         UploadRequest {
-            r#type: Some(upload_request::Type::Chunk(chunk)),
+            r#type: Some(upload_request::Type::Chunk(chunk.into())),
         }
     }
 
     // panics if not of type chunk
-    pub fn as_mut_chunk(&mut self) -> &mut Vec<u8> {
+    pub fn as_mut_chunk(&mut self) -> &mut Bytes {
         match self.r#type.as_mut().unwrap() {
             upload_request::Type::Chunk(c) => c,
             _ => panic!("Internal error"),
@@ -44,7 +49,12 @@ impl UploadRequest {
 
 // Helper
 impl DownloadResponse {
-    pub fn new_entry(filename: &str, merkle_proof: MerkleProof) -> Result<Self, Error> {
+    pub fn new_entry(
+        index: u64,
+        filename: &str,
+        size: u64,
+        merkle_proof: MerkleProof,
+    ) -> Result<Self, Error> {
         let merkle_proof_vec = merkle_proof.encode_bin()?;
 
         Ok(DownloadResponse {
@@ -53,13 +63,15 @@ impl DownloadResponse {
                     filename: filename.to_string(),
                 }),
                 merkle_proof: merkle_proof_vec,
+                size,
+                index,
             })),
         })
     }
 
-    pub fn new_chunk(chunk: Vec<u8>) -> Self {
+    pub fn new_chunk(chunk: impl Into<Bytes>) -> Self {
         DownloadResponse {
-            r#type: Some(download_response::Type::Chunk(chunk)),
+            r#type: Some(download_response::Type::Chunk(chunk.into())),
         }
     }
 }
@@ -69,7 +81,32 @@ impl ProofResponse {
     pub fn new_proof(merkle_proof: MerkleProof) -> Result<Self, Error> {
         let merkle_proof_vec = merkle_proof.encode_bin()?;
         Ok(ProofResponse {
-            merkle_proof: merkle_proof_vec
+            merkle_proof: merkle_proof_vec,
         })
     }
 }
+
+// Helper
+impl From<ConsistencyProof> for ConsistencyProofResponse {
+    fn from(proof: ConsistencyProof) -> Self {
+        ConsistencyProofResponse {
+            old_size: proof.old_size(),
+            new_size: proof.new_size(),
+            old_root: proof.old_root().clone(),
+            new_root: proof.new_root().clone(),
+            hashes: proof.hashes().to_vec(),
+        }
+    }
+}
+
+impl From<ConsistencyProofResponse> for ConsistencyProof {
+    fn from(response: ConsistencyProofResponse) -> Self {
+        ConsistencyProof::from_raw_parts(
+            response.old_size,
+            response.new_size,
+            response.old_root,
+            response.new_root,
+            response.hashes,
+        )
+    }
+}
@@ -40,7 +40,7 @@ impl fmt::Display for MerkleProof {
         writeln!(fmt, "Merkle root: {}", hex::encode(&self.root))?;
         writeln!(fmt, "Merkle proof (len={}):", self.hashes.len())?;
         if !self.hashes.is_empty() {
-            for i in 0..(self.hashes.len()-1) {
+            for i in 0..(self.hashes.len() - 1) {
                 writeln!(fmt, "{}", self.hashes[i])?;
             }
             write!(fmt, "{}", self.hashes.last().unwrap())?;
@@ -51,10 +51,7 @@ impl fmt::Display for MerkleProof {
 
 impl MerkleProof {
     pub fn from_raw_parts(root: Vec<u8>, hashes: Vec<MerkleProofHash>) -> Self {
-        MerkleProof { 
-            root,
-            hashes 
-        }
+        MerkleProof { root, hashes }
     }
 
     pub fn root(&self) -> &Vec<u8> {
@@ -66,7 +63,7 @@ impl MerkleProof {
     }
 
     pub fn encode_bin(&self) -> Result<Vec<u8>, Error> {
-        bincode::serialize(self).map_err(|_| Error::MerkleProofEncodeBin )
+        bincode::serialize(self).map_err(|_| Error::MerkleProofEncodeBin)
     }
 
     pub fn decode_bin(encoded: Vec<u8>) -> Result<Self, Error> {
@@ -84,41 +81,13 @@ impl MerkleProof {
         MerkleProof::sha256_pair(&hex::decode(left).unwrap(), &hex::decode(right).unwrap())
     }
 
-    // pub fn _verify(&self, input: &Vec<u8>, root: &Vec<u8>) -> bool {
-    //     if self.hashes.is_empty() {
-    //         return false;
-    //     }
-
-    //     let mut hasher = Sha256::new();
-
-    //     if self.hashes[0].left {
-    //         hasher.update(&self.hashes[0].hash);
-    //         hasher.update(input);
-    //     } else {
-    //         hasher.update(input);
-    //         hasher.update(&self.hashes[0].hash);
-    //     }
-
-    //     for i in 1..self.hashes.len() {
-    //         let h = hasher.finalize_reset();
-
-    //         if self.hashes[i].left {
-    //             hasher.update(&self.hashes[i].hash);
-    //             hasher.update(h);
-    //         } else {
-    //             hasher.update(h);
-    //             hasher.update(&self.hashes[i].hash);
-    //         }
-    //     }
-
-    //     let hash = hasher.finalize().to_vec(); 
-    //     let ok1 = hash == *root;
-    //     let ok2 = hash == self.root;
-    //     assert_eq!(ok1, ok2);
-        
-    //     ok1
-    // }
     pub fn verify(&self, input: &Vec<u8>) -> bool {
+        self.verify_with_root(input, &self.root)
+    }
+
+    /// Verifies that the path from `input` leads to `root` rather than to the
+    /// root embedded in the proof
+    pub fn verify_with_root(&self, input: &Vec<u8>, root: &Vec<u8>) -> bool {
         if self.hashes.is_empty() {
             return false;
         }
@@ -145,7 +114,7 @@ impl MerkleProof {
             }
         }
 
-        hasher.finalize().to_vec() == self.root
+        hasher.finalize().to_vec() == *root
     }
 }
 
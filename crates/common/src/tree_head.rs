@@ -0,0 +1,30 @@
+use ring::signature::{UnparsedPublicKey, ED25519};
+
+use crate::{error::Error, proto::SignedTreeHead};
+
+/// Prefix of the signed tree head messages, the server key signs nothing
+/// else that could be mistaken for a tree head
+const TREE_HEAD_PREFIX: &[u8] = b"mrklar-tree-head-v1";
+
+impl SignedTreeHead {
+    /// The message signed by the server for `merkle_root`, computed from
+    /// `tree_size` entries, at `timestamp_ms`
+    pub fn message(merkle_root: &[u8], tree_size: u64, timestamp_ms: u64) -> Vec<u8> {
+        let mut message = Vec::with_capacity(TREE_HEAD_PREFIX.len() + 16 + merkle_root.len());
+        message.extend_from_slice(TREE_HEAD_PREFIX);
+        message.extend_from_slice(&tree_size.to_be_bytes());
+        message.extend_from_slice(&timestamp_ms.to_be_bytes());
+        message.extend_from_slice(merkle_root);
+        message
+    }
+
+    /// Verifies the signature with the ed25519 `public_key` of the server,
+    /// fails with `Error::InvalidTreeHeadSignature` if any of the signed
+    /// fields has been altered or the tree head was signed by another key
+    pub fn verify(&self, public_key: &[u8]) -> Result<(), Error> {
+        let message = Self::message(&self.merkle_root, self.tree_size, self.timestamp_ms);
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(&message, &self.signature)
+            .map_err(|_| Error::InvalidTreeHeadSignature(self.merkle_root.clone()))
+    }
+}
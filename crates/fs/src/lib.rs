@@ -34,8 +34,12 @@ pub fn create_dir_if_needed(path: impl AsRef<Path>) -> Result<bool, io::Error> {
     if path.is_dir() {
         return Ok(false);
     }
-    std::fs::create_dir(path)?;
-    Ok(true)
+    match std::fs::create_dir(path) {
+        Ok(()) => Ok(true),
+        // created concurrently
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && path.is_dir() => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
 pub fn dir_exists(path: impl AsRef<Path>) -> bool {
@@ -43,6 +47,52 @@ pub fn dir_exists(path: impl AsRef<Path>) -> bool {
     path.is_dir()
 }
 
+/// Removes everything inside the directory at `path`, returns the number of
+/// removed files and their total size in bytes. Does nothing if the directory
+/// does not exist.
+pub fn clear_dir(path: impl AsRef<Path>) -> Result<(usize, u64), io::Error> {
+    let path = path.as_ref();
+    let mut removed = (0, 0);
+    if !path.is_dir() {
+        return Ok(removed);
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let (files, bytes) = clear_dir(entry.path())?;
+            std::fs::remove_dir(entry.path())?;
+            removed = (removed.0 + files, removed.1 + bytes);
+        } else {
+            std::fs::remove_file(entry.path())?;
+            removed = (removed.0 + 1, removed.1 + metadata.len());
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns the number of files inside the directory at `path`, including its
+/// subdirectories, and their total size in bytes. Zero if the directory does
+/// not exist.
+pub fn dir_size(path: impl AsRef<Path>) -> Result<(usize, u64), io::Error> {
+    let path = path.as_ref();
+    let mut size = (0, 0);
+    if !path.is_dir() {
+        return Ok(size);
+    }
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let (files, bytes) = dir_size(entry.path())?;
+            size = (size.0 + files, size.1 + bytes);
+        } else {
+            size = (size.0 + 1, size.1 + metadata.len());
+        }
+    }
+    Ok(size)
+}
+
 pub fn gen_tmp_filename() -> String {
     let y0 = rand::random::<u128>();
     let y1 = rand::random::<u128>();
@@ -0,0 +1,94 @@
+//! A synchronous wrapper around the async `MrklarApi`, for consumers
+//! without a tokio runtime of their own.
+//!
+//! Each call runs the async api to completion on a current-thread runtime
+//! owned by the wrapper and created on first use. Calling the blocking api
+//! from within an async runtime fails with `ApiError::InsideAsyncRuntime`.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use mrklar_common::config::NetConfig;
+use mrklar_common::merkle_proof::MerkleProof;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::error::ApiError;
+use crate::{ArchiveStatus, DownloadOutcome, UploadResult};
+
+pub struct MrklarApi {
+    api: crate::MrklarApi,
+    runtime: OnceLock<Runtime>,
+}
+
+impl MrklarApi {
+    /// Creates a new blocking api, the runtime and the connection to the
+    /// server are created lazily on first use. See `MrklarApi::new`.
+    pub fn new(config: NetConfig) -> Result<Self, ApiError> {
+        Ok(Self::from_async(crate::MrklarApi::new(config)?))
+    }
+
+    /// Wraps an already configured async api (TLS, auth token, timeout, ...)
+    pub fn from_async(api: crate::MrklarApi) -> Self {
+        MrklarApi {
+            api,
+            runtime: OnceLock::new(),
+        }
+    }
+
+    /// Gets the number of entries in the remote archive
+    pub fn count(&self) -> Result<u64, ApiError> {
+        self.block_on(self.api.count())
+    }
+
+    /// Gets the merkle root of the remote archive
+    pub fn root(&self) -> Result<Vec<u8>, ApiError> {
+        self.block_on(self.api.root())
+    }
+
+    /// See `MrklarApi::status`
+    pub fn status(&self) -> Result<ArchiveStatus, ApiError> {
+        self.block_on(self.api.status())
+    }
+
+    /// See `MrklarApi::upload`
+    pub fn upload(&self, path: &Path) -> UploadResult {
+        self.block_on(self.api.upload(path))
+    }
+
+    /// See `MrklarApi::download`
+    pub fn download(
+        &self,
+        index: u64,
+        output_dir: Option<PathBuf>,
+        output_filename: Option<String>,
+        force: bool,
+        expected_root: Option<Vec<u8>>,
+    ) -> Result<DownloadOutcome, ApiError> {
+        self.block_on(
+            self.api
+                .download(index, output_dir, output_filename, force, expected_root),
+        )
+    }
+
+    /// See `MrklarApi::proof`
+    pub fn proof(&self, index: u64) -> Result<MerkleProof, ApiError> {
+        self.block_on(self.api.proof(index))
+    }
+
+    fn block_on<T>(
+        &self,
+        fut: impl std::future::Future<Output = Result<T, ApiError>>,
+    ) -> Result<T, ApiError> {
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(ApiError::InsideAsyncRuntime);
+        }
+        let runtime = match self.runtime.get() {
+            Some(runtime) => runtime,
+            None => {
+                let runtime = Builder::new_current_thread().enable_all().build()?;
+                self.runtime.get_or_init(|| runtime)
+            }
+        };
+        runtime.block_on(fut)
+    }
+}
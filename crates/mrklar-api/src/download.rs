@@ -0,0 +1,186 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use mrklar_common::merkle_proof::MerkleProof;
+use mrklar_common::proto::{download_response, DownloadResponse};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::time::{Instant, Sleep};
+use tokio_stream::{Stream, StreamExt};
+use tonic::Streaming;
+
+use crate::error::ApiError;
+use crate::TransferStats;
+
+/// The body of a remote archive entry, returned by `MrklarApi::download_stream`.
+/// Yields the file chunks as they are received and hashes them on the fly,
+/// `finish` returns the merkle proof verification result once the whole
+/// content has been received.
+/// Dropping the stream cancels the download.
+#[derive(Debug)]
+pub struct DownloadStream {
+    filename: String,
+    proof: MerkleProof,
+    size: u64,
+    // the number of bytes requested, the stream fails if it ends earlier
+    len: u64,
+    inner: Streaming<DownloadResponse>,
+    hasher: Sha256,
+    stats: TransferStats,
+    start: Instant,
+    // the api timeout, enforced while streaming
+    deadline: Option<Pin<Box<Sleep>>>,
+    done: bool,
+}
+
+/// Summary of a completed `DownloadStream`
+#[derive(Debug, Clone)]
+pub struct DownloadSummary {
+    /// The entry filename in the remote archive
+    pub filename: String,
+    pub proof: MerkleProof,
+    /// The merkle proof verification status of the received content
+    pub verified: bool,
+    /// The sha256 of the received content
+    pub sha256: Vec<u8>,
+    /// The remote file size reported by the server
+    pub size: u64,
+    pub stats: TransferStats,
+}
+
+impl DownloadStream {
+    /// `hasher` holds the hash state of the bytes preceding the stream
+    /// content, when resuming a download. `len` is the number of requested
+    /// bytes.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        inner: Streaming<DownloadResponse>,
+        filename: String,
+        proof: MerkleProof,
+        size: u64,
+        len: u64,
+        hasher: Sha256,
+        start: Instant,
+        deadline: Option<Instant>,
+    ) -> Self {
+        DownloadStream {
+            filename,
+            proof,
+            size,
+            len,
+            inner,
+            hasher,
+            stats: TransferStats::default(),
+            start,
+            deadline: deadline.map(|d| Box::pin(tokio::time::sleep_until(d))),
+            done: false,
+        }
+    }
+
+    /// The entry filename in the remote archive
+    pub fn filename(&self) -> &str {
+        &self.filename
+    }
+
+    pub fn proof(&self) -> &MerkleProof {
+        &self.proof
+    }
+
+    /// The remote file size reported by the server
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Receives the remaining chunks, if any, and returns the download
+    /// summary. The verification status must be checked by the caller
+    /// whatever the strict mode.
+    pub async fn finish(mut self) -> Result<DownloadSummary, ApiError> {
+        while let Some(chunk) = self.next().await {
+            chunk?;
+        }
+        Ok(self.summary())
+    }
+
+    /// Writes the remaining chunks into `w` and returns the download summary
+    pub(crate) async fn copy_to(
+        mut self,
+        w: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<DownloadSummary, ApiError> {
+        while let Some(chunk) = self.next().await {
+            w.write_all(&chunk?).await?;
+        }
+        Ok(self.summary())
+    }
+
+    fn summary(self) -> DownloadSummary {
+        let sha256 = self.hasher.finalize().to_vec();
+        let mut stats = self.stats;
+        stats.elapsed = self.start.elapsed();
+        DownloadSummary {
+            verified: self.proof.verify(&sha256),
+            filename: self.filename,
+            proof: self.proof,
+            sha256,
+            size: self.size,
+            stats,
+        }
+    }
+
+    fn fail(&mut self, error: ApiError) -> Poll<Option<Result<Bytes, ApiError>>> {
+        self.done = true;
+        Poll::Ready(Some(Err(error)))
+    }
+}
+
+impl Stream for DownloadStream {
+    type Item = Result<Bytes, ApiError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if let Some(deadline) = &mut self.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                return self.fail(ApiError::DeadlineExceeded);
+            }
+        }
+
+        loop {
+            let response = match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // the server ended the stream without an error status
+                Poll::Ready(None) if self.stats.bytes < self.len => {
+                    let error = ApiError::DownloadTruncated {
+                        expected: self.len,
+                        received: self.stats.bytes,
+                    };
+                    return self.fail(error);
+                }
+                Poll::Ready(None) => {
+                    self.done = true;
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Some(Err(status))) => return self.fail(status.into()),
+                Poll::Ready(Some(Ok(response))) => response,
+            };
+
+            match response.r#type {
+                None => continue,
+                Some(download_response::Type::Chunk(chunk)) => {
+                    self.hasher.update(&chunk);
+                    self.stats.bytes += chunk.len() as u64;
+                    self.stats.chunks += 1;
+                    return Poll::Ready(Some(Ok(chunk)));
+                }
+                Some(download_response::Type::Entry(_)) => {
+                    return self.fail(ApiError::ProtocolViolation {
+                        expected: "file chunk",
+                        got: "file metadata",
+                    });
+                }
+            }
+        }
+    }
+}
@@ -1,21 +1,204 @@
-use mrklar_common::proto::UploadRequest;
+use std::time::Duration;
+
+use mrklar_common::config::RETRY_AFTER_METADATA_KEY;
+use mrklar_common::proto::{FileIndex, UploadRequest};
+use prost::Message;
+
+use crate::layer::LayerError;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ApiError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
-    Status(#[from] tonic::Status),
+    Status(Box<tonic::Status>),
     #[error(transparent)]
     Transport(#[from] tonic::transport::Error),
     #[error(transparent)]
     SendUploadRequest(#[from] tokio::sync::mpsc::error::SendError<UploadRequest>),
-    #[error("Unexpected error: {0}")]
-    Unexpected(String),
+    #[error("Protocol violation: expecting {expected}, got {got}")]
+    ProtocolViolation {
+        expected: &'static str,
+        got: &'static str,
+    },
+    /// The server rejected the upload while the local sending of the file
+    /// failed as well, `sender` is usually the cause of the rejection.
+    #[error("File upload: {status}, sender error: {sender}")]
+    UploadFailed {
+        status: Box<tonic::Status>,
+        sender: Box<ApiError>,
+    },
+    #[error("File upload: the server did not return the file index")]
+    MissingUploadIndex,
+    #[error("File upload: missing filename")]
+    MissingFilename,
+    #[error("The blocking api cannot be called from within an async runtime")]
+    InsideAsyncRuntime,
+    #[error("Background task failed: {0}")]
+    JoinFailed(#[from] tokio::task::JoinError),
     #[error("File upload: '{0}': File not found")]
     UploadFileNotFound(String),
     #[error("File download: '{0}': File already exists, use '--force' option to override any existing file.")]
     DownloadFileAlreadyExists(String),
+    #[error("File download: the stream ended after {received} of {expected} bytes")]
+    DownloadTruncated { expected: u64, received: u64 },
+    #[error("File index {0} does not exist")]
+    IndexNotFound(u64),
+    #[error("File named '{0}' does not exist")]
+    FileNameNotFound(String),
+    /// The upload session has expired, has been finished, or the server
+    /// restarted
+    #[error("Upload session '{0}' does not exist")]
+    UploadSessionNotFound(String),
+    #[error("Invalid server endpoint '{0}'")]
+    InvalidEndpoint(String),
+    #[error("Server not ready after {0:?}")]
+    ServerNotReady(std::time::Duration),
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+    #[error("Cancelled")]
+    Cancelled,
+    #[error("Merkle root mismatch: expected {}, got {}", hex::encode(.expected), hex::encode(.actual))]
+    RootMismatch { expected: Vec<u8>, actual: Vec<u8> },
+    #[error("File index {index} not verified against root {}, the downloaded file has been removed", hex::encode(.expected_root))]
+    VerificationFailed { index: u64, expected_root: Vec<u8> },
+    /// The tree heads are verified but the server did not sign the root,
+    /// see `MrklarApi::with_tree_head_key`
+    #[error("The server did not sign the merkle root")]
+    MissingTreeHead,
+    #[error("Merkle root {} does not match the signed tree head of root {} and size {tree_size}", hex::encode(.root), hex::encode(.signed_root))]
+    TreeHeadMismatch {
+        root: Vec<u8>,
+        signed_root: Vec<u8>,
+        tree_size: u64,
+    },
+    #[error("Upload of file index {index} not verified against root {}", hex::encode(.root))]
+    UploadNotVerified { index: u64, root: Vec<u8> },
+    #[error("Upload of file index {index}: sha256 mismatch, expected {}, got {}", hex::encode(.expected), hex::encode(.actual))]
+    UploadHashMismatch {
+        index: u64,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// The server rejects duplicate uploads and already has the content
+    /// at `0`
+    #[error("File already exists in the remote archive at index {0}")]
+    AlreadyExists(u64),
+    #[error("Unauthenticated: {0}")]
+    Unauthenticated(String),
+    #[error("Invalid request metadata entry '{0}'")]
+    InvalidMetadata(String),
+    #[error("Invalid mirror manifest: {0}")]
+    InvalidMirrorManifest(String),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
     #[error(transparent)]
     Common(#[from] mrklar_common::error::Error),
 }
+
+impl From<tonic::Status> for ApiError {
+    fn from(value: tonic::Status) -> Self {
+        match value.code() {
+            tonic::Code::DeadlineExceeded => ApiError::DeadlineExceeded,
+            // the tonic server timeout layer cancels expired requests
+            tonic::Code::Cancelled if value.message() == "Timeout expired" => {
+                ApiError::DeadlineExceeded
+            }
+            tonic::Code::Unauthenticated => ApiError::Unauthenticated(value.message().to_string()),
+            // the status details hold the index of the existing entry
+            tonic::Code::AlreadyExists => match FileIndex::decode(value.details()) {
+                Ok(index) => ApiError::AlreadyExists(index.index),
+                Err(_) => ApiError::Status(Box::new(value)),
+            },
+            _ => ApiError::Status(Box::new(value)),
+        }
+    }
+}
+
+impl ApiError {
+    /// Returns `true` if the server could not be reached or the connection
+    /// broke during the call
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            ApiError::Transport(_) => true,
+            ApiError::Status(s) | ApiError::UploadFailed { status: s, .. } => {
+                // a broken connection is reported with the `Unknown` code
+                s.code() == tonic::Code::Unavailable
+                    || std::error::Error::source(s).is_some_and(|e| {
+                        e.is::<tonic::transport::Error>()
+                            || e.downcast_ref::<LayerError>()
+                                .is_some_and(|e| e.inner().is::<tonic::transport::Error>())
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the status returned by the server, if any
+    pub fn status(&self) -> Option<&tonic::Status> {
+        match self {
+            ApiError::Status(s) | ApiError::UploadFailed { status: s, .. } => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Returns the delay after which the server invites the client to retry
+    /// a rejected call, sent along with the `UNAVAILABLE` status of the
+    /// uploads rejected in maintenance mode
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.status()?
+            .metadata()
+            .get(RETRY_AFTER_METADATA_KEY)?
+            .to_str()
+            .ok()?
+            .parse()
+            .ok()
+            .map(Duration::from_secs)
+    }
+
+    /// Merges the outcome of an upload call with the outcome of its sender
+    /// task. The server status always wins: a sender failing because the
+    /// server closed the stream is a mere consequence, any other sender
+    /// error is attached as context.
+    pub(crate) fn from_upload(status: tonic::Status, sender: Option<ApiError>) -> Self {
+        match sender {
+            None | Some(ApiError::SendUploadRequest(_)) => status.into(),
+            Some(sender) => ApiError::UploadFailed {
+                status: Box::new(status),
+                sender: Box::new(sender),
+            },
+        }
+    }
+
+    /// Maps a `NOT_FOUND` status returned by a call on `index` to `ApiError::IndexNotFound`
+    pub(crate) fn with_index(self, index: u64) -> Self {
+        match self {
+            ApiError::Status(s) if s.code() == tonic::Code::NotFound => {
+                ApiError::IndexNotFound(index)
+            }
+            e => e,
+        }
+    }
+
+    /// Maps a `NOT_FOUND` status returned by a call on `filename` to
+    /// `ApiError::FileNameNotFound`
+    pub(crate) fn with_filename(self, filename: &str) -> Self {
+        match self {
+            ApiError::Status(s) if s.code() == tonic::Code::NotFound => {
+                ApiError::FileNameNotFound(filename.to_string())
+            }
+            e => e,
+        }
+    }
+
+    /// Maps a `NOT_FOUND` status returned by a call on `session_id` to
+    /// `ApiError::UploadSessionNotFound`
+    pub(crate) fn with_session_id(self, session_id: &str) -> Self {
+        match self {
+            ApiError::Status(s) if s.code() == tonic::Code::NotFound => {
+                ApiError::UploadSessionNotFound(session_id.to_string())
+            }
+            e => e,
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, KeyAndValueRef, MetadataMap};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use crate::error::ApiError;
+
+/// Attaches a fixed set of metadata entries to every request
+#[derive(Debug, Default, Clone)]
+pub(crate) struct MetadataInterceptor {
+    metadata: Arc<MetadataMap>,
+}
+
+impl MetadataInterceptor {
+    /// Sets the `key` entry to `value`, replacing any previous value
+    pub(crate) fn insert(&mut self, key: &str, value: &str) -> Result<(), ApiError> {
+        let key = AsciiMetadataKey::from_bytes(key.as_bytes())
+            .map_err(|_| ApiError::InvalidMetadata(key.to_string()))?;
+        let value = AsciiMetadataValue::try_from(value)
+            .map_err(|_| ApiError::InvalidMetadata(key.to_string()))?;
+        Arc::make_mut(&mut self.metadata).insert(key, value);
+        Ok(())
+    }
+}
+
+impl Interceptor for MetadataInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        for entry in self.metadata.iter() {
+            // only ascii entries are ever inserted
+            if let KeyAndValueRef::Ascii(key, value) = entry {
+                request.metadata_mut().insert(key.clone(), value.clone());
+            }
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tonic::service::Interceptor;
+    use tonic::Request;
+
+    use super::MetadataInterceptor;
+
+    #[test]
+    fn test_metadata_interceptor() {
+        let mut interceptor = MetadataInterceptor::default();
+        interceptor.insert("authorization", "Bearer abc").unwrap();
+        interceptor.insert("x-tenant", "t1").unwrap();
+        interceptor.insert("x-tenant", "t2").unwrap();
+        assert!(interceptor.insert("bad key", "v").is_err());
+        assert!(interceptor.insert("x-key", "bad\nvalue").is_err());
+
+        let request = interceptor.call(Request::new(())).unwrap();
+        let metadata = request.metadata();
+        assert_eq!(metadata.get("authorization").unwrap(), "Bearer abc");
+        assert_eq!(metadata.get("x-tenant").unwrap(), "t2");
+        assert_eq!(metadata.len(), 2);
+    }
+}
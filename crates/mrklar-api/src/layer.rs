@@ -0,0 +1,101 @@
+use std::fmt;
+use std::sync::Arc;
+
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::service::interceptor;
+use tonic::transport::Channel;
+use tower::util::BoxCloneService;
+use tower::{Layer, Service, ServiceExt};
+
+use crate::interceptor::MetadataInterceptor;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// The type erased service every api call goes through
+pub type ClientService =
+    BoxCloneService<http::Request<BoxBody>, http::Response<BoxBody>, LayerError>;
+
+type BoxLayer = Arc<dyn Fn(ClientService) -> ClientService + Send + Sync>;
+
+/// The custom tower layers wrapping the api channel, in the order they
+/// have been added.
+#[derive(Default, Clone)]
+pub(crate) struct ClientLayers {
+    layers: Vec<BoxLayer>,
+}
+
+impl fmt::Debug for ClientLayers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientLayers")
+            .field("len", &self.layers.len())
+            .finish()
+    }
+}
+
+/// Error returned by the services wrapping the api channel: the channel
+/// error or any error returned by a custom layer.
+#[derive(Debug)]
+pub struct LayerError(BoxError);
+
+impl LayerError {
+    pub fn inner(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+impl fmt::Display for LayerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+// transparent: the channel error source chain is kept as is
+impl std::error::Error for LayerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl ClientLayers {
+    pub(crate) fn push<L>(&mut self, layer: L)
+    where
+        L: Layer<ClientService> + Send + Sync + 'static,
+        L::Service: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>>
+            + Clone
+            + Send
+            + 'static,
+        <L::Service as Service<http::Request<BoxBody>>>::Future: Send + 'static,
+        <L::Service as Service<http::Request<BoxBody>>>::Error: Into<BoxError>,
+    {
+        self.layers
+            .push(Arc::new(move |service| boxed(layer.layer(service))));
+    }
+
+    /// Wraps `channel` in the custom layers, the first added being the
+    /// outermost one, then in the `metadata` interceptor, so that the custom
+    /// layers see the metadata attached by the api (auth token, ...).
+    pub(crate) fn apply(&self, channel: Channel, metadata: MetadataInterceptor) -> ClientService {
+        let service = self
+            .layers
+            .iter()
+            .rev()
+            .fold(boxed(channel), |service, layer| layer(service));
+        boxed(interceptor(metadata).layer(service))
+    }
+}
+
+fn boxed<S>(service: S) -> ClientService
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    BoxCloneService::new(service.map_err(|e| {
+        // errors of the inner layers are already wrapped
+        match e.into().downcast::<LayerError>() {
+            Ok(e) => *e,
+            Err(e) => LayerError(e),
+        }
+    }))
+}
@@ -1,225 +1,1592 @@
-use std::path::PathBuf;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use bytes::{Bytes, BytesMut};
+use mrklar_common::config::{
+    validate_chunk_size, validate_message_size, GrpcCompression, NetConfig, MAX_CHUNK_SIZE,
+    MAX_LIST_LIMIT, MESSAGE_OVERHEAD,
+};
+use mrklar_common::consistency_proof::ConsistencyProof;
 use mrklar_common::merkle_proof::MerkleProof;
-use mrklar_common::proto::{download_response, Empty, FileIndex, UploadRequest};
-use mrklar_common::{config::NetConfig, proto::file_api_client::FileApiClient};
-use mrklar_fs::{absolute_path, file_name_as_string, sha256};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::mpsc;
+use mrklar_common::proto::file_api_client::FileApiClient;
+use mrklar_common::proto::{
+    audit_result, download_response, AuditRequest, ConsistencyProofRequest, DownloadRequest,
+    DownloadResponse, Empty, Entry, EntryInfo, FileIndex, FileMetadata, FileName, FileSha256,
+    FinishUploadRequest, ListRequest, RootResponse, ServerInfo, SignedTreeHead, StartUploadRequest,
+    StatsResponse, UploadChunk, UploadRequest, UploadResponse, UploadSessionId, WatchEvent,
+};
+use mrklar_fs::{absolute_path, file_name_as_string, gen_tmp_filename};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
-use tonic::transport::Channel;
-use tonic::Request;
+use tokio_stream::{Stream, StreamExt};
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::service::Interceptor;
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Streaming};
 use url::Url;
 
+pub use tokio_util::sync::CancellationToken;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod download;
 pub mod error;
+mod interceptor;
+pub mod layer;
+pub mod mirror;
+pub mod retry;
+pub mod tls;
+use download::{DownloadStream, DownloadSummary};
 use error::ApiError;
+use interceptor::MetadataInterceptor;
+use layer::{BoxError, ClientLayers, ClientService};
+use mirror::{
+    verify_files, DownloadAllEntry, DownloadAllReport, MirrorManifest, VerifyReport, VerifyResult,
+    VerifyStatus,
+};
+use retry::{is_transient, RetryPolicy};
+use tls::ClientTls;
 
-pub struct MrklarApi {
+/// Number of entries requested per page by `MrklarApi::list_all`
+const LIST_PAGE_SIZE: u64 = MAX_LIST_LIMIT;
+
+/// Bounds of the delay between two polls of `MrklarApi::wait_until_ready`
+const READY_POLL_MIN_DELAY: Duration = Duration::from_millis(10);
+const READY_POLL_MAX_DELAY: Duration = Duration::from_millis(200);
+
+/// A page of the remote archive entries
+#[derive(Debug, Clone)]
+pub struct ListPage {
+    pub entries: Vec<EntryInfo>,
+    /// total number of entries in the remote archive
+    pub total: u64,
+}
+
+/// Statistics of a single upload or download
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TransferStats {
+    /// number of file bytes sent or received
+    pub bytes: u64,
+    /// number of file chunks sent or received
+    pub chunks: u64,
+    /// wall time of the whole call
+    pub elapsed: Duration,
+}
+
+/// Outcome of a successful upload
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadOutcome {
+    /// The index of the uploaded file in the remote archive
+    pub index: u64,
+    /// The new remote merkle root
+    pub root: Vec<u8>,
+    pub stats: TransferStats,
+    /// The sha256 of the uploaded content
+    #[serde(default)]
+    pub sha256: Vec<u8>,
+    /// `true` if the remote archive already had the content, nothing has
+    /// been transferred (see `MrklarApi::upload_dedup`)
+    #[serde(default)]
+    pub deduplicated: bool,
+    /// `root` signed by the server, `None` if the server has no signing key
+    #[serde(skip)]
+    pub tree_head: Option<SignedTreeHead>,
+    /// The merkle proof of the uploaded file, verified against `root`.
+    /// `None` for a deduplicated upload or if the server is too old to
+    /// return it.
+    #[serde(default)]
+    pub proof: Option<MerkleProof>,
+}
+
+/// Outcome of a successful download
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadOutcome {
+    /// The absolute path of the downloaded file
+    pub path: PathBuf,
+    /// The entry filename in the remote archive
+    pub filename: String,
+    pub proof: MerkleProof,
+    /// The merkle proof verification status of the downloaded file
+    pub verified: bool,
+    /// The sha256 of the downloaded file, computed while streaming
+    pub sha256: Vec<u8>,
+    /// The remote file size reported by the server
+    pub size: u64,
+    /// Bytes actually received, only the missing bytes for a resumed download
+    pub stats: TransferStats,
+}
+
+/// The number of entries of the remote archive and its merkle root,
+/// captured at the same time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveStatus {
+    pub count: u64,
+    pub root: Vec<u8>,
+}
+
+/// An audited sha256 and, if the remote archive has that content, the index
+/// of its first entry along with its merkle proof
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub sha256: Vec<u8>,
+    pub found: Option<(u64, MerkleProof)>,
+}
+
+/// Outcome of `MrklarApi::audit`, one entry per audited sha256. All the
+/// proofs lead to `status.root`.
+#[derive(Debug, Clone)]
+pub struct AuditReport {
+    pub entries: Vec<AuditEntry>,
+    pub status: ArchiveStatus,
+}
+
+impl DownloadOutcome {
+    fn new(path: PathBuf, summary: DownloadSummary, verified: bool, start: Instant) -> Self {
+        let mut stats = summary.stats;
+        stats.elapsed = start.elapsed();
+        DownloadOutcome {
+            path,
+            filename: summary.filename,
+            proof: summary.proof,
+            verified,
+            sha256: summary.sha256,
+            size: summary.size,
+            stats,
+        }
+    }
+}
+
+/// Result of a single upload
+pub type UploadResult = Result<UploadOutcome, ApiError>;
+
+/// Result of the verification of a local file against the remote archive
+#[derive(Debug, Clone)]
+pub struct VerifyOutcome {
+    pub verified: bool,
+    /// The merkle root the file has been verified against
+    pub root: Vec<u8>,
+    /// The local file sha256
+    pub sha256: Vec<u8>,
+}
+
+type Client = FileApiClient<ClientService>;
+
+/// The server connection settings and the channel created on first use
+#[derive(Debug, Default)]
+struct Connection {
     config: NetConfig,
+    // the server url, derived from `config` or given explicitly,
+    // `None` for a channel provided by the caller
+    endpoint: Option<Url>,
+    tls: Option<ClientTls>,
+    // dropped after a connection failure, then created again by the next call
+    channel: Mutex<Option<Channel>>,
+    // the channel has been provided by the caller and cannot be created again
+    injected: bool,
+    // the largest chunk accepted by the server, fetched on the first upload
+    max_chunk_size: tokio::sync::OnceCell<usize>,
+}
+
+impl Connection {
+    /// Returns new connection settings, built from the current ones,
+    /// with a channel not created yet.
+    fn reset(&self, f: impl FnOnce(&mut Connection)) -> Arc<Connection> {
+        let mut conn = Connection {
+            config: self.config.clone(),
+            endpoint: self.endpoint.clone(),
+            tls: self.tls.clone(),
+            ..Default::default()
+        };
+        f(&mut conn);
+        Arc::new(conn)
+    }
+}
+
+/// The api client. Cloning is cheap: clones share the connection settings
+/// and the underlying HTTP/2 connection, multiplexing their calls over it.
+/// A single api can therefore be cloned into many concurrent tasks.
+///
+/// The connection is established on first use by any of the clones.
+/// Changing the connection settings (`with_tls`) starts a new connection
+/// which is not shared with the previous clones.
+///
+/// A call failing because the connection is broken (server restarted,
+/// network failure) drops the channel, the next call reconnects. Read-only
+/// calls reconnect and run again once before reporting the error, uploads
+/// and downloads are never replayed.
+#[derive(Debug, Clone)]
+pub struct MrklarApi {
+    conn: Arc<Connection>,
+    // overrides the `config` chunk size, also requested from the server when downloading
+    chunk_size: Option<usize>,
+    // overrides the `config` channel size, number of upload chunks queued ahead of the transport
+    upload_window: Option<usize>,
+    // attached to every request
+    metadata: MetadataInterceptor,
+    // custom layers wrapping the channel
+    layers: ClientLayers,
+    // maximum duration of a single api call
+    timeout: Option<Duration>,
+    // applied to the idempotent read-only calls
+    retry: Option<RetryPolicy>,
+    // downloads failing the merkle proof verification are removed
+    strict: bool,
+    // uploads send the sha256 computed while streaming after the last chunk
+    upload_sha256: bool,
+    // compression of the sent messages, the compressed responses are always accepted
+    grpc_compression: GrpcCompression,
+    // largest grpc message sent or accepted
+    max_message_size: usize,
+    // ed25519 public key of the server verifying the signed tree heads
+    tree_head_key: Option<Vec<u8>>,
 }
 
 impl MrklarApi {
-    pub fn new(config: NetConfig) -> Self {
-        MrklarApi { config }
+    /// Creates a new api, the connection to the server is established
+    /// lazily on first use. Fails with `ApiError::InvalidEndpoint` if no
+    /// server url can be derived from `config`, or if the `config` chunk
+    /// size does not fit in its max message size.
+    pub fn new(config: NetConfig) -> Result<Self, ApiError> {
+        let url = config
+            .url()
+            .map_err(|_| ApiError::InvalidEndpoint(config.sock_addr().to_string()))?;
+        validate_message_size(config.chunk_size, config.max_message_size)?;
+        Ok(MrklarApi::from_connection(Connection {
+            config,
+            endpoint: Some(url),
+            ..Default::default()
+        }))
+    }
+
+    fn from_connection(conn: Connection) -> Self {
+        MrklarApi {
+            grpc_compression: conn.config.grpc_compression,
+            max_message_size: conn.config.max_message_size,
+            conn: Arc::new(conn),
+            chunk_size: None,
+            upload_window: None,
+            metadata: MetadataInterceptor::default(),
+            layers: ClientLayers::default(),
+            timeout: None,
+            retry: None,
+            strict: false,
+            upload_sha256: true,
+            tree_head_key: None,
+        }
+    }
+
+    /// Sets the maximum duration of every api call. The deadline is sent to
+    /// the server with each request and also enforced locally, including
+    /// while streaming. An expired call fails with `ApiError::DeadlineExceeded`.
+    #[must_use]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Creates a new api sending its calls over `channel`, a transport built
+    /// by the caller (custom connector, in-memory transport, ...). The api
+    /// does not dial the server itself, `config` only provides the chunk and
+    /// channel sizes. Changing the connection settings afterwards (`with_tls`)
+    /// drops `channel`.
+    pub fn with_channel(channel: Channel, config: NetConfig) -> Self {
+        MrklarApi::from_connection(Connection {
+            config,
+            channel: Mutex::new(Some(channel)),
+            injected: true,
+            ..Default::default()
+        })
+    }
+
+    /// Creates a new api and connects to the server endpoint specified
+    /// in `config`. Will fail if the server is unreachable.
+    pub async fn connect(config: NetConfig) -> Result<Self, ApiError> {
+        let api = MrklarApi::new(config)?;
+        let endpoint = api.endpoint()?;
+        let channel = match &api.conn.config.uds_path {
+            Some(path) => {
+                endpoint
+                    .connect_with_connector(uds_connector(path.clone()))
+                    .await?
+            }
+            None => endpoint.connect().await?,
+        };
+        *api.conn.channel.lock().unwrap() = Some(channel);
+        Ok(api)
+    }
+
+    /// Creates a new api reaching the server at `url`, the connection is
+    /// established lazily on first use. An `https` url enables TLS with the
+    /// default settings, see `with_tls` to customize them.
+    pub fn from_url(url: &str) -> Result<Self, ApiError> {
+        let invalid = || ApiError::InvalidEndpoint(url.to_string());
+        let parsed = Url::parse(url).map_err(|_| invalid())?;
+        let tls = match parsed.scheme() {
+            "http" => None,
+            "https" => Some(ClientTls::default()),
+            _ => return Err(invalid()),
+        };
+        Ok(MrklarApi::from_connection(Connection {
+            endpoint: Some(parsed),
+            tls,
+            ..Default::default()
+        }))
+    }
+
+    /// Compresses the messages sent to the server with `compression`, the
+    /// server always accepts them. The compressed server messages are always
+    /// accepted, whatever `compression`.
+    #[must_use]
+    pub fn with_grpc_compression(mut self, compression: GrpcCompression) -> Self {
+        self.grpc_compression = compression;
+        self
     }
 
-    fn url(&self) -> Url {
-        self.config.url().unwrap()
+    /// Sets the largest grpc message sent or accepted, instead of the
+    /// `config` one. Fails if the api chunk size plus `MESSAGE_OVERHEAD`
+    /// bytes does not fit in `max_message_size`. The upload chunks are
+    /// shrunk to fit in it, whatever the server max chunk size.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Result<Self, ApiError> {
+        let chunk_size = self.chunk_size.unwrap_or(self.conn.config.chunk_size);
+        self.max_message_size = validate_message_size(chunk_size, max_message_size)?;
+        Ok(self)
     }
 
-    /// Attempt to create a new `FileApiClient` by connecting to a server endpoint.
-    /// specified in the `config` field.
-    /// Will fail if the connection is refused or the server is not running.
-    async fn connect(&self) -> Result<FileApiClient<Channel>, tonic::transport::Error> {
-        let url = self.url();
-        FileApiClient::connect(url.to_string()).await
+    /// Connects to the server over TLS using the `https` scheme
+    #[must_use]
+    pub fn with_tls(mut self, tls: ClientTls) -> Self {
+        self.conn = self.conn.reset(|conn| conn.tls = Some(tls));
+        self
+    }
+
+    /// Returns the server url, its scheme is `https` if TLS is enabled.
+    /// Without any url, as for a channel provided by the caller, the url is
+    /// derived from `config`.
+    fn url(&self) -> Result<Url, ApiError> {
+        let mut url = match &self.conn.endpoint {
+            Some(url) => url.clone(),
+            None => {
+                let config = &self.conn.config;
+                config
+                    .url()
+                    .map_err(|_| ApiError::InvalidEndpoint(config.sock_addr().to_string()))?
+            }
+        };
+        if self.conn.tls.is_some() {
+            // http and https are both special schemes, cannot fail
+            let _ = url.set_scheme("https");
+        }
+        Ok(url)
+    }
+
+    /// Returns a clone of the api, sharing the same channel, sending
+    /// uploads in chunks of `chunk_size` bytes and requesting downloads
+    /// in chunks of `chunk_size` bytes from the server. Fails if
+    /// `chunk_size` is zero, larger than `MAX_CHUNK_SIZE` or does not fit
+    /// in the api max message size.
+    ///
+    /// ```ignore
+    /// api.with_chunk_size(64 * 1024)?.upload(&small_file).await?;
+    /// api.with_chunk_size(4 * 1024 * 1024)?.upload(&large_file).await?;
+    /// ```
+    pub fn with_chunk_size(&self, chunk_size: usize) -> Result<Self, ApiError> {
+        let mut api = self.clone();
+        api.chunk_size = Some(validate_chunk_size(chunk_size)?);
+        validate_message_size(chunk_size, api.max_message_size)?;
+        Ok(api)
+    }
+
+    /// Returns a clone of the api, sharing the same channel, queuing at
+    /// most `window` upload chunks ahead of the transport instead of the
+    /// `config` channel size, at the cost of `window * chunk_size` bytes of
+    /// memory per upload. The data in flight is bounded by the HTTP/2 flow
+    /// control windows, adapted to the link latency by the server, a deeper
+    /// window only keeps the transport fed when reading the source is bursty.
+    /// A zero `window` is treated as 1.
+    ///
+    /// ```ignore
+    /// api.with_upload_window(16).upload(&large_file).await?;
+    /// ```
+    pub fn with_upload_window(&self, window: usize) -> Self {
+        let mut api = self.clone();
+        api.upload_window = Some(window.max(1));
+        api
+    }
+
+    /// Attaches the `authorization: Bearer <token>` metadata entry to every request
+    pub fn with_auth_token(self, token: String) -> Result<Self, ApiError> {
+        self.with_metadata("authorization", &format!("Bearer {}", token))
+    }
+
+    /// Attaches the `key: value` metadata entry to every request, replacing
+    /// any previous value of `key`. Fails if `key` or `value` is not a valid
+    /// ascii metadata entry.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Result<Self, ApiError> {
+        self.metadata.insert(key, value)?;
+        Ok(self)
+    }
+
+    /// Wraps every call in the tower `layer` (tracing, logging, ...).
+    /// Layers see the requests in the order they have been added, after the
+    /// metadata entries (`with_auth_token`, `with_metadata`) have been
+    /// attached. The layers are shared by the clones of the api.
+    #[must_use]
+    pub fn with_layer<L>(mut self, layer: L) -> Self
+    where
+        L: tower::Layer<ClientService> + Send + Sync + 'static,
+        L::Service: tower::Service<http::Request<BoxBody>, Response = http::Response<BoxBody>>
+            + Clone
+            + Send
+            + 'static,
+        <L::Service as tower::Service<http::Request<BoxBody>>>::Future: Send + 'static,
+        <L::Service as tower::Service<http::Request<BoxBody>>>::Error: Into<BoxError>,
+    {
+        self.layers.push(layer);
+        self
+    }
+
+    /// Runs `interceptor` on every request, see `with_layer`. A request
+    /// rejected by the interceptor fails with the returned status.
+    #[must_use]
+    pub fn with_interceptor<F>(self, interceptor: F) -> Self
+    where
+        F: Interceptor + Clone + Send + Sync + 'static,
+    {
+        self.with_layer(tonic::service::interceptor(interceptor))
+    }
+
+    /// Returns the endpoint of the server url, configured with the TLS settings
+    fn endpoint(&self) -> Result<Endpoint, ApiError> {
+        // flow control windows adapted to the link latency
+        let mut endpoint =
+            Endpoint::from_shared(self.url()?.to_string())?.http2_adaptive_window(true);
+        if let Some(tls) = &self.conn.tls {
+            endpoint = endpoint.tls_config(tls.client_tls_config())?;
+        }
+        Ok(endpoint)
+    }
+
+    /// Returns a `FileApiClient` sharing the api channel. The channel is
+    /// created on the first call, the connection to the server url, or to the
+    /// `config` unix domain socket if set, is established by the first request.
+    async fn client(&self) -> Result<Client, ApiError> {
+        let channel = {
+            let mut channel = self.conn.channel.lock().unwrap();
+            match &*channel {
+                Some(channel) => channel.clone(),
+                None => {
+                    let endpoint = self.endpoint()?;
+                    let new_channel = match &self.conn.config.uds_path {
+                        Some(path) => {
+                            endpoint.connect_with_connector_lazy(uds_connector(path.clone()))
+                        }
+                        None => endpoint.connect_lazy(),
+                    };
+                    channel.insert(new_channel).clone()
+                }
+            }
+        };
+        let mut client = FileApiClient::new(self.layers.apply(channel, self.metadata.clone()))
+            .max_decoding_message_size(self.max_message_size)
+            .max_encoding_message_size(self.max_message_size);
+        for encoding in GrpcCompression::ACCEPTED {
+            client = client.accept_compressed(encoding);
+        }
+        if let Some(encoding) = self.grpc_compression.encoding() {
+            client = client.send_compressed(encoding);
+        }
+        Ok(client)
+    }
+
+    /// Drops the channel if `error` is a connection failure, the next call
+    /// reconnects. Returns `true` if the channel has been dropped.
+    fn reset_on_connection_error(&self, error: &ApiError) -> bool {
+        if self.conn.injected || !error.is_connection_error() {
+            return false;
+        }
+        *self.conn.channel.lock().unwrap() = None;
+        true
+    }
+
+    /// Runs `call` once more on a new connection if it fails because the
+    /// connection is broken
+    async fn reconnecting<T, F, Fut>(&self, call: &F) -> Result<T, ApiError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        match call().await {
+            Err(e) if self.reset_on_connection_error(&e) => call().await,
+            res => res,
+        }
+    }
+
+    /// In strict mode, a downloaded file failing the merkle proof verification
+    /// is removed and the download fails with `ApiError::VerificationFailed`
+    /// instead of returning an outcome with `verified` unset.
+    #[must_use]
+    pub fn with_strict_verification(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Fails with `ApiError::VerificationFailed` in strict mode if the file
+    /// at `index` has not been verified
+    fn check_verified(
+        &self,
+        verified: bool,
+        index: u64,
+        proof: &MerkleProof,
+        expected_root: Option<Vec<u8>>,
+    ) -> Result<(), ApiError> {
+        if verified || !self.strict {
+            return Ok(());
+        }
+        Err(ApiError::VerificationFailed {
+            index,
+            expected_root: expected_root.unwrap_or_else(|| proof.root().clone()),
+        })
+    }
+
+    /// Disabling the upload sha256 lets the server compute the content
+    /// sha256 from the received chunks instead of verifying the one sent
+    /// after the last chunk. The sha256 returned by the server is checked
+    /// against the local one in both cases, a mismatch fails the upload with
+    /// `ApiError::UploadHashMismatch` but the file has then been stored.
+    /// Enabled by default.
+    #[must_use]
+    pub fn with_upload_sha256(mut self, send: bool) -> Self {
+        self.upload_sha256 = send;
+        self
+    }
+
+    /// Verifies the signed tree heads returned along with the merkle roots
+    /// (`root`, `status`, `tree_head` and the uploads) with the ed25519
+    /// `public_key` of the server. These calls then fail with
+    /// `ApiError::MissingTreeHead` if the server did not sign the root, or
+    /// with an error if the signature is invalid or does not match the root.
+    /// A failed upload verification does not undo the upload.
+    #[must_use]
+    pub fn with_tree_head_key(mut self, public_key: Option<Vec<u8>>) -> Self {
+        self.tree_head_key = public_key;
+        self
+    }
+
+    /// Retries the idempotent read-only calls (`count`, `root`, `status`, `proof`,
+    /// `consistency_proof`, `audit`, `metadata`, `list`, `stats`) failing with a transient error according to `policy`.
+    /// Uploads and downloads are never retried.
+    #[must_use]
+    pub fn with_retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Runs `call` until it succeeds, fails with a non transient error or the
+    /// retry policy attempts are exhausted. Returns the last error.
+    async fn retried<T, F, Fut>(&self, call: F) -> Result<T, ApiError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, ApiError>>,
+    {
+        let Some(policy) = &self.retry else {
+            return self.reconnecting(&call).await;
+        };
+        let mut attempt = 1;
+        loop {
+            match self.reconnecting(&call).await {
+                Err(e) if attempt < policy.max_attempts && is_transient(&e) => {
+                    tokio::time::sleep(policy.delay(attempt)).await;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Returns the deadline of a call starting now
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|t| Instant::now() + t)
+    }
+
+    /// Wraps `message` into a request carrying the api timeout
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(timeout) = self.timeout {
+            request.set_timeout(timeout);
+        }
+        request
+    }
+
+    /// Polls the server until it answers a `count` call, waiting a short and
+    /// growing delay between attempts. Any answer, even an error status, means
+    /// the server is up. Fails with `ApiError::ServerNotReady` if the server
+    /// is still unreachable after `timeout`.
+    pub async fn wait_until_ready(&self, timeout: Duration) -> Result<(), ApiError> {
+        let deadline = Instant::now() + timeout;
+        let mut delay = READY_POLL_MIN_DELAY;
+        loop {
+            match timed(Some(deadline), self.count()).await {
+                Err(ApiError::DeadlineExceeded) => break,
+                Err(e) if e.is_connection_error() => {}
+                _ => return Ok(()),
+            }
+            if Instant::now() + delay >= deadline {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(READY_POLL_MAX_DELAY);
+        }
+        Err(ApiError::ServerNotReady(timeout))
     }
 
     /// Gets the number of entries in the remote archive
-    pub async fn count(&self) -> eyre::Result<u64> {
-        let mut client = self.connect().await?;
-        let result = client.count(Request::new(Empty {})).await?.into_inner();
-        Ok(result.value)
+    pub async fn count(&self) -> Result<u64, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client.count(self.request(Empty {})).await?.into_inner();
+                Ok(result.value)
+            })
+        })
+        .await
     }
 
     /// Gets the merkle root of the remote archive
-    pub async fn root(&self) -> eyre::Result<Vec<u8>> {
-        let mut client = self.connect().await?;
-        let result = client.root(Request::new(Empty {})).await?.into_inner();
-        Ok(result.merkle_root)
+    pub async fn root(&self) -> Result<Vec<u8>, ApiError> {
+        Ok(self.root_response().await?.merkle_root)
+    }
+
+    /// Gets the number of entries and the merkle root of the remote archive
+    /// in a single call, the root is always the root of exactly `count` entries
+    pub async fn status(&self) -> Result<ArchiveStatus, ApiError> {
+        let result = self.root_response().await?;
+        Ok(ArchiveStatus {
+            count: result.count,
+            root: result.merkle_root,
+        })
+    }
+
+    /// Gets the merkle root of the remote archive signed by the server, fails
+    /// with `ApiError::MissingTreeHead` if the server has no signing key.
+    /// The signature is only verified with a key, see `with_tree_head_key`.
+    pub async fn tree_head(&self) -> Result<SignedTreeHead, ApiError> {
+        self.root_response()
+            .await?
+            .signed_tree_head
+            .ok_or(ApiError::MissingTreeHead)
+    }
+
+    /// Gets the merkle root, entry count and signed tree head of the remote
+    /// archive, the tree head is verified if a key is set
+    async fn root_response(&self) -> Result<RootResponse, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client.root(self.request(Empty {})).await?.into_inner();
+                check_tree_head(
+                    self.tree_head_key.as_deref(),
+                    result.signed_tree_head.as_ref(),
+                    &result.merkle_root,
+                    |tree_size| tree_size == result.count,
+                )?;
+                Ok(result)
+            })
+        })
+        .await
+    }
+
+    /// Gets the metadata (filename, sha256) and download statistics of the
+    /// entry at `index`. Fails with `ApiError::IndexNotFound` if `index` is out of bounds.
+    pub async fn metadata(&self, index: u64) -> Result<EntryInfo, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client
+                    .metadata(self.request(FileIndex { index }))
+                    .await
+                    .map_err(|e| ApiError::from(e).with_index(index))?
+                    .into_inner();
+                Ok(result)
+            })
+        })
+        .await
+    }
+
+    /// Returns the index of the first remote entry with content `sha256`,
+    /// `None` if the remote archive does not have that content. The server
+    /// rejects a `sha256` which is not 32 bytes long.
+    pub async fn find(&self, sha256: &[u8]) -> Result<Option<u64>, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client
+                    .find(self.request(FileSha256 {
+                        sha256: sha256.to_vec(),
+                    }))
+                    .await;
+                match result {
+                    Ok(response) => Ok(Some(response.into_inner().index)),
+                    Err(s) if s.code() == tonic::Code::NotFound => Ok(None),
+                    Err(s) => Err(s.into()),
+                }
+            })
+        })
+        .await
+    }
+
+    /// Looks up each of `sha256s` in the remote archive in a single call, gets
+    /// the index and merkle proof of those present, all computed from the same
+    /// merkle root. The server rejects more than `MAX_AUDIT_HASHES` hashes or
+    /// a sha256 which is not 32 bytes long.
+    pub async fn audit(&self, sha256s: &[Vec<u8>]) -> Result<AuditReport, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client
+                    .audit(self.request(AuditRequest {
+                        sha256: sha256s.to_vec(),
+                    }))
+                    .await?
+                    .into_inner();
+                if result.results.len() != sha256s.len() {
+                    return Err(ApiError::ProtocolViolation {
+                        expected: "one audit result per sha256",
+                        got: "another number of audit results",
+                    });
+                }
+
+                let mut entries = Vec::with_capacity(sha256s.len());
+                for (sha256, r) in sha256s.iter().zip(result.results) {
+                    let found = match r.result {
+                        Some(audit_result::Result::Found(proof)) => {
+                            Some((proof.index, MerkleProof::decode_bin(proof.merkle_proof)?))
+                        }
+                        Some(audit_result::Result::NotFound(_)) => None,
+                        None => {
+                            return Err(ApiError::ProtocolViolation {
+                                expected: "audit result",
+                                got: "empty message",
+                            })
+                        }
+                    };
+                    entries.push(AuditEntry {
+                        sha256: sha256.clone(),
+                        found,
+                    });
+                }
+                Ok(AuditReport {
+                    entries,
+                    status: ArchiveStatus {
+                        count: result.count,
+                        root: result.merkle_root,
+                    },
+                })
+            })
+        })
+        .await
+    }
+
+    /// Gets at most `limit` remote archive entries starting at `offset`, with
+    /// their metadata and download statistics, along with the total number of
+    /// entries. An out of range `offset` returns an empty page. The server
+    /// returns at most `MAX_LIST_LIMIT` entries, whatever `limit`.
+    pub async fn list(&self, offset: u64, limit: u64) -> Result<ListPage, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client
+                    .list(self.request(ListRequest { offset, limit }))
+                    .await?
+                    .into_inner();
+                Ok(ListPage {
+                    entries: result.entries,
+                    total: result.total,
+                })
+            })
+        })
+        .await
+    }
+
+    /// Gets all the remote archive entries, page by page
+    pub async fn list_all(&self) -> Result<Vec<EntryInfo>, ApiError> {
+        let mut entries: Vec<EntryInfo> = vec![];
+        loop {
+            let page = self.list(entries.len() as u64, LIST_PAGE_SIZE).await?;
+            if page.entries.is_empty() {
+                break;
+            }
+            entries.extend(page.entries);
+            if entries.len() as u64 >= page.total {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Gets the remote archive statistics
+    pub async fn stats(&self) -> Result<StatsResponse, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client.stats(self.request(Empty {})).await?.into_inner();
+                Ok(result)
+            })
+        })
+        .await
+    }
+
+    /// Subscribes to the entries added to the remote archive from now on.
+    /// The returned stream yields an event per entry, in index order, and
+    /// fails with a `RESOURCE_EXHAUSTED` status if the events are not
+    /// consumed fast enough. The api timeout only applies to the subscription.
+    pub async fn subscribe(
+        &self,
+    ) -> Result<impl Stream<Item = Result<WatchEvent, ApiError>> + Send + Unpin, ApiError> {
+        let stream = self
+            .retried(|| {
+                timed(self.deadline(), async {
+                    let mut client = self.client().await?;
+                    let stream = client.watch(self.request(Empty {})).await?.into_inner();
+                    Ok(stream)
+                })
+            })
+            .await?;
+        Ok(stream.map(|event| event.map_err(ApiError::from)))
+    }
+
+    /// Gets the server limits
+    pub async fn server_info(&self) -> Result<ServerInfo, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client.info(self.request(Empty {})).await?.into_inner();
+                Ok(result)
+            })
+        })
+        .await
+    }
+
+    /// Returns the size of the upload chunks: the api chunk size, lowered to
+    /// the largest chunk accepted by the server. The server limit is fetched
+    /// once per connection, a server not advertising it is assumed to accept
+    /// any chunk up to `MAX_CHUNK_SIZE`.
+    async fn upload_chunk_size(&self) -> Result<usize, ApiError> {
+        let chunk_size = self.chunk_size.unwrap_or(self.conn.config.chunk_size);
+        let max_chunk_size = self
+            .conn
+            .max_chunk_size
+            .get_or_try_init(|| async {
+                match self.server_info().await {
+                    Ok(info) => Ok(info.max_chunk_size as usize),
+                    Err(ApiError::Status(s)) if s.code() == tonic::Code::Unimplemented => {
+                        Ok(MAX_CHUNK_SIZE)
+                    }
+                    Err(e) => Err(e),
+                }
+            })
+            .await?;
+        let max_message_chunk = self.max_message_size.saturating_sub(MESSAGE_OVERHEAD);
+        Ok(chunk_size
+            .min(*max_chunk_size)
+            .min(max_message_chunk)
+            .max(1))
+    }
+
+    /// The chunk size requested in downloads, 0 meaning the server default.
+    /// The default is not requested if it may not fit in the api max
+    /// message size.
+    fn download_chunk_size(&self) -> usize {
+        match self.chunk_size {
+            Some(chunk_size) => chunk_size,
+            None if self.max_message_size < MAX_CHUNK_SIZE + MESSAGE_OVERHEAD => {
+                self.max_message_size.saturating_sub(MESSAGE_OVERHEAD)
+            }
+            None => 0,
+        }
     }
 
     /// Downloads the file at `index` form the remote archive.
     /// Will fail if `index` is out of bounds.
+    /// The file is written to a temporary file next to the output path, then
+    /// renamed once the whole content has been received.
+    ///
+    /// If `expected_root` is set, the file is verified against that pinned
+    /// root instead of the root embedded in the merkle proof sent by the
+    /// server. The download fails with `ApiError::RootMismatch`, before any
+    /// file is written, if the server proof leads to a different root.
     pub async fn download(
         &self,
         index: u64,
         output_dir: Option<PathBuf>,
         output_filename: Option<String>,
         force: bool,
-    ) -> Result<(PathBuf, MerkleProof, bool), ApiError> {
-        let mut client = self.connect().await?;
+        expected_root: Option<Vec<u8>>,
+    ) -> Result<DownloadOutcome, ApiError> {
+        let start = Instant::now();
+        let stream = self
+            .open_download(index, 0, 0, Sha256::new(), self.deadline())
+            .await?;
+        let (path, summary, verified) = self
+            .save_download(
+                stream,
+                index,
+                output_dir,
+                output_filename,
+                force,
+                expected_root,
+            )
+            .await?;
+        Ok(DownloadOutcome::new(path, summary, verified, start))
+    }
 
-        let mut stream = client
-            .download(Request::new(FileIndex { index }))
-            .await?
-            .into_inner();
+    /// Same as `download`, for the most recently uploaded entry named
+    /// `filename`. Fails with `ApiError::FileNameNotFound` if the remote
+    /// archive has no entry with that filename.
+    pub async fn download_by_name(
+        &self,
+        filename: &str,
+        output_dir: Option<PathBuf>,
+        output_filename: Option<String>,
+        force: bool,
+        expected_root: Option<Vec<u8>>,
+    ) -> Result<DownloadOutcome, ApiError> {
+        let start = Instant::now();
+        let deadline = self.deadline();
+        let (stream, entry) = timed(deadline, async {
+            let mut client = self.client().await?;
+            let stream = client
+                .download_by_name(self.request(FileName {
+                    filename: filename.to_string(),
+                }))
+                .await
+                .map_err(|e| ApiError::from(e).with_filename(filename))
+                .inspect_err(|e| {
+                    self.reset_on_connection_error(e);
+                })?
+                .into_inner();
+            read_download_entry(stream).await
+        })
+        .await?;
 
-        let mut merkle_proof: MerkleProof = MerkleProof::default();
-        let mut filename: String = String::default();
+        let index = entry.index;
+        let merkle_proof = MerkleProof::decode_bin(entry.merkle_proof)?;
+        let stream = DownloadStream::new(
+            stream,
+            entry.metadata.unwrap_or_default().filename,
+            merkle_proof,
+            entry.size,
+            entry.size,
+            Sha256::new(),
+            start,
+            deadline,
+        );
+        let (path, summary, verified) = self
+            .save_download(
+                stream,
+                index,
+                output_dir,
+                output_filename,
+                force,
+                expected_root,
+            )
+            .await?;
+        Ok(DownloadOutcome::new(path, summary, verified, start))
+    }
 
-        let output_path = match output_dir {
-            Some(p) => p,
-            None => PathBuf::new(),
-        };
+    /// Writes the content of `stream` to the output path, returns the path,
+    /// the download summary and the verification result
+    async fn save_download(
+        &self,
+        stream: DownloadStream,
+        index: u64,
+        output_dir: Option<PathBuf>,
+        output_filename: Option<String>,
+        force: bool,
+        expected_root: Option<Vec<u8>>,
+    ) -> Result<(PathBuf, DownloadSummary, bool), ApiError> {
+        check_expected_root(stream.proof(), expected_root.as_ref())?;
 
-        // 1- Download metadata
-        while let Some(response) = stream.message().await? {
-            if response.r#type.is_none() {
-                continue;
-            }
-            match response.r#type.unwrap() {
-                download_response::Type::Entry(entry) => {
-                    filename = entry.metadata.unwrap_or_default().filename;
-                    merkle_proof = MerkleProof::decode_bin(entry.merkle_proof)?;
-                    break;
-                }
-                _ => {
-                    return Err(ApiError::Unexpected(
-                        "Invalid message type, expecting file metadata.".to_string(),
-                    ));
-                }
-            }
+        let path = output_file_path(output_dir, output_filename, stream.filename(), index)?;
+        if path.is_file() && !force {
+            let p = path.to_str().unwrap_or_default().to_string();
+            return Err(ApiError::DownloadFileAlreadyExists(p));
         }
 
-        let of = output_filename.unwrap_or_default();
-        let path = if !of.is_empty() {
-            output_path.join(of)
-        } else if !filename.is_empty() {
-            output_path.join(&filename)
-        } else {
-            return Err(ApiError::Unexpected("Missing out filename.".to_string()));
+        // Stream into a temporary file located in the output directory, so that
+        // the final rename is atomic and an interrupted download never leaves
+        // a truncated file under the requested name.
+        let tmp_path = path.with_file_name(format!(
+            ".{}.{}.part",
+            file_name_as_string(&path),
+            gen_tmp_filename()
+        ));
+
+        // removes the temporary file on error or if this future is dropped,
+        // declared first to be dropped after the file is closed
+        let partial_file = PartialFile(Some(&tmp_path));
+        let mut tokio_file = tokio::fs::File::create(&tmp_path).await?;
+
+        let summary = stream.copy_to(&mut tokio_file).await?;
+        tokio_file.sync_all().await?;
+        drop(tokio_file);
+
+        let verified = match &expected_root {
+            Some(root) => summary.proof.verify_with_root(&summary.sha256, root),
+            None => summary.verified,
         };
+        self.check_verified(verified, index, &summary.proof, expected_root)?;
+
+        rename_download(&tmp_path, &path, force).await?;
+        partial_file.keep();
+
+        Ok((path, summary, verified))
+    }
 
-        let path = absolute_path(&path)?;
+    /// Same as `download`, fails with `ApiError::Cancelled` as soon as `cancel`
+    /// is triggered. The partially written output file is removed.
+    pub async fn download_with_cancel(
+        &self,
+        index: u64,
+        output_dir: Option<PathBuf>,
+        output_filename: Option<String>,
+        force: bool,
+        expected_root: Option<Vec<u8>>,
+        cancel: CancellationToken,
+    ) -> Result<DownloadOutcome, ApiError> {
+        cancellable(
+            &cancel,
+            self.download(index, output_dir, output_filename, force, expected_root),
+        )
+        .await
+    }
+
+    /// Same as `download`, but the content is streamed into a `.<name>.part`
+    /// file located next to the output path which is kept if the download is
+    /// interrupted. If that file already exists, the download resumes from its
+    /// current length: the existing bytes are hashed again and only the
+    /// remaining bytes are requested. The complete file is verified against
+    /// the merkle proof before being renamed to the output path.
+    pub async fn download_resumable(
+        &self,
+        index: u64,
+        output_dir: Option<PathBuf>,
+        output_filename: Option<String>,
+        force: bool,
+        expected_root: Option<Vec<u8>>,
+    ) -> Result<DownloadOutcome, ApiError> {
+        let start = Instant::now();
+        // the part file name must be known before the download starts
+        let filename = match &output_filename {
+            Some(of) if !of.is_empty() => of.clone(),
+            _ => self.metadata(index).await?.filename,
+        };
+        let path = output_file_path(output_dir, output_filename, &filename, index)?;
         if path.is_file() && !force {
             let p = path.to_str().unwrap_or_default().to_string();
             return Err(ApiError::DownloadFileAlreadyExists(p));
         }
+        let part_path = path.with_file_name(format!(".{}.part", file_name_as_string(&path)));
 
-        let mut tokio_file = tokio::fs::File::create(&path).await?;
-
-        let mut succeeded = true;
-        while let Some(response) = stream.message().await? {
-            if response.r#type.is_none() {
-                continue;
+        let deadline = self.deadline();
+        let (mut part_file, hasher, offset) = open_part_file(&part_path).await?;
+        let stream = match self.open_download(index, offset, 0, hasher, deadline).await {
+            // the part file is longer than the remote file, start over
+            Err(ApiError::Status(s)) if s.code() == tonic::Code::OutOfRange => {
+                part_file.set_len(0).await?;
+                self.open_download(index, 0, 0, Sha256::new(), deadline)
+                    .await?
             }
+            res => res?,
+        };
+        check_expected_root(stream.proof(), expected_root.as_ref())?;
 
-            match response.r#type.unwrap() {
-                download_response::Type::Chunk(c) => tokio_file.write_all(&c).await?,
-                _ => {
-                    succeeded = false;
-                    break;
-                }
-            }
-        }
+        let summary = stream.copy_to(&mut part_file).await?;
+        part_file.sync_all().await?;
+        drop(part_file);
 
-        if !succeeded {
-            // close file
-            drop(tokio_file);
-            // remove file (no need to handle the error)
-            let _res = tokio::fs::remove_file(&path).await;
-            return Err(ApiError::Unexpected(
-                "Invalid message type, expecting file chunk.".to_string(),
-            ));
-        } else {
-            tokio_file.sync_all().await?;
+        let verified = match &expected_root {
+            Some(root) => summary.proof.verify_with_root(&summary.sha256, root),
+            None => summary.verified,
+        };
+        if let Err(e) = self.check_verified(verified, index, &summary.proof, expected_root) {
+            // the part file cannot be resumed
+            tokio::fs::remove_file(&part_path).await?;
+            return Err(e);
         }
 
-        let file_sha256 = sha256(&path)?;
-        let verified = merkle_proof.verify(&file_sha256);
+        rename_download(&part_path, &path, force).await?;
 
-        // // Verify if merkle root has been provided
-        // let verified = if root.is_some() {
-        //     let root_v = root.unwrap();
-        //     // let root_v = match hex::decode(root.unwrap()) {
-        //     //     Ok(v) => v,
-        //     //     Err(_) => {
-        //     //         return Err(ApiError::Unexpected(
-        //     //             "Invalid merkle root hash.".to_string(),
-        //     //         ))
-        //     //     }
-        //     // };
-        //     let file_sha256 = sha256(&path)?;
-        //     let ok = merkle_proof.verify(&file_sha256, &root_v);
-        //     Some(ok)
-        // } else {
-        //     None
-        // };
+        Ok(DownloadOutcome::new(path, summary, verified, start))
+    }
 
-        Ok((path, merkle_proof, verified))
+    /// Downloads the file at `index` form the remote archive into `w`.
+    /// The content is hashed as it is written, nothing is stored on disk.
+    /// Returns the filename, the merkle proof, the number of bytes written
+    /// and the verification result, which must be checked by the caller
+    /// whatever the strict mode.
+    /// Will fail if `index` is out of bounds.
+    pub async fn download_to_writer(
+        &self,
+        index: u64,
+        mut w: impl AsyncWrite + Unpin,
+    ) -> Result<(String, MerkleProof, u64, bool), ApiError> {
+        let summary = self.download_stream(index).await?.copy_to(&mut w).await?;
+        w.flush().await?;
+
+        Ok((
+            summary.filename,
+            summary.proof,
+            summary.stats.bytes,
+            summary.verified,
+        ))
     }
 
-    /// Compute the merkle proof of file at `index` form the remote archive.
+    /// Starts downloading the file at `index` form the remote archive and
+    /// returns the stream of the file chunks, positioned after the entry
+    /// metadata (filename, merkle proof). The content is hashed as it is
+    /// streamed, see `DownloadStream::finish`. The api timeout applies to the
+    /// whole download. Dropping the stream cancels the download.
     /// Will fail if `index` is out of bounds.
-    pub async fn proof(&self, index: u64) -> Result<MerkleProof, ApiError> {
-        let mut client = self.connect().await?;
+    pub async fn download_stream(&self, index: u64) -> Result<DownloadStream, ApiError> {
+        self.open_download(index, 0, 0, Sha256::new(), self.deadline())
+            .await
+    }
 
-        let mut stream = client
-            .proof(Request::new(FileIndex { index }))
+    /// Downloads `length` bytes of the file at `index` starting at byte
+    /// `offset`, 0 meaning up to the end of the file, and writes them into
+    /// `w`. Returns the filename, the merkle proof of the whole file and the
+    /// number of bytes written. The range content cannot be checked against
+    /// the merkle proof. Will fail with `ApiError::Status(OUT_OF_RANGE)` if
+    /// the range goes beyond the end of the file.
+    pub async fn download_range(
+        &self,
+        index: u64,
+        offset: u64,
+        length: u64,
+        mut w: impl AsyncWrite + Unpin,
+    ) -> Result<(String, MerkleProof, u64), ApiError> {
+        let summary = self
+            .open_download(index, offset, length, Sha256::new(), self.deadline())
             .await?
+            .copy_to(&mut w)
+            .await?;
+        w.flush().await?;
+
+        Ok((summary.filename, summary.proof, summary.stats.bytes))
+    }
+
+    /// Starts downloading `length` bytes of the file at `index` from byte
+    /// `offset`, reads the file metadata and returns the stream positioned on
+    /// the first file chunk. `hasher` holds the hash state of the bytes
+    /// preceding `offset`.
+    async fn open_download(
+        &self,
+        index: u64,
+        offset: u64,
+        length: u64,
+        hasher: Sha256,
+        deadline: Option<Instant>,
+    ) -> Result<DownloadStream, ApiError> {
+        let start = Instant::now();
+        let (stream, filename, merkle_proof, size) =
+            timed(deadline, self.download_entry(index, offset, length, false)).await?;
+        let len = match length {
+            0 => size.saturating_sub(offset),
+            _ => length,
+        };
+        Ok(DownloadStream::new(
+            stream,
+            filename,
+            merkle_proof,
+            size,
+            len,
+            hasher,
+            start,
+            deadline,
+        ))
+    }
+
+    /// Sends the download request and reads the file metadata, returns the
+    /// response stream, the filename, the merkle proof and the file size.
+    /// With `metadata_only`, the server ends the stream after the metadata.
+    async fn download_entry(
+        &self,
+        index: u64,
+        offset: u64,
+        length: u64,
+        metadata_only: bool,
+    ) -> Result<(Streaming<DownloadResponse>, String, MerkleProof, u64), ApiError> {
+        let mut client = self.client().await?;
+
+        let stream = client
+            .download(self.request(DownloadRequest {
+                index,
+                offset,
+                chunk_size: self.download_chunk_size() as u64,
+                length,
+                metadata_only,
+            }))
+            .await
+            .map_err(|e| ApiError::from(e).with_index(index))
+            .inspect_err(|e| {
+                self.reset_on_connection_error(e);
+            })?
             .into_inner();
 
-        let mut encoded_proof: Vec<u8> = vec![];
-        while let Some(proof_response) = stream.message().await? {
-            let mut p = proof_response.merkle_proof;
-            encoded_proof.append(&mut p);
+        // 1- Download metadata
+        let (stream, entry) = read_download_entry(stream).await?;
+        let filename = entry.metadata.unwrap_or_default().filename;
+        let merkle_proof = MerkleProof::decode_bin(entry.merkle_proof)?;
+        Ok((stream, filename, merkle_proof, entry.size))
+    }
+
+    /// Gets the filename and the merkle proof of the entry at `index`
+    /// without transferring the file: the server only sends the file
+    /// metadata. Will fail if `index` is out of bounds.
+    pub async fn entry(&self, index: u64) -> Result<(String, MerkleProof), ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let (stream, filename, merkle_proof, _) =
+                    self.download_entry(index, 0, 0, true).await?;
+                // older servers ignore `metadata_only`, the download is
+                // cancelled and the chunks already received are discarded
+                drop(stream);
+                Ok((filename, merkle_proof))
+            })
+        })
+        .await
+    }
+
+    /// Compute the merkle proof of file at `index` form the remote archive.
+    /// Will fail if `index` is out of bounds.
+    pub async fn proof(&self, index: u64) -> Result<MerkleProof, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+
+                let mut stream = client
+                    .proof(self.request(FileIndex { index }))
+                    .await
+                    .map_err(|e| ApiError::from(e).with_index(index))?
+                    .into_inner();
+
+                let mut encoded_proof: Vec<u8> = vec![];
+                while let Some(proof_response) = stream.message().await? {
+                    let mut p = proof_response.merkle_proof;
+                    encoded_proof.append(&mut p);
+                }
+
+                let m = MerkleProof::decode_bin(encoded_proof)?;
+                Ok(m)
+            })
+        })
+        .await
+    }
+
+    /// Computes the proof that the remote archive of `new_size` entries is an
+    /// append-only extension of the archive of `old_size` entries. Will fail
+    /// if `old_size` is 0, greater than `new_size`, or if `new_size` is greater
+    /// than the current entry count.
+    pub async fn consistency_proof(
+        &self,
+        old_size: u64,
+        new_size: u64,
+    ) -> Result<ConsistencyProof, ApiError> {
+        self.retried(|| {
+            timed(self.deadline(), async {
+                let mut client = self.client().await?;
+                let result = client
+                    .consistency_proof(self.request(ConsistencyProofRequest { old_size, new_size }))
+                    .await?
+                    .into_inner();
+                Ok(ConsistencyProof::from(result))
+            })
+        })
+        .await
+    }
+
+    /// Same as `proof`, also checks that the proof root is the current root
+    /// of the remote archive. Since an upload may land between the two calls,
+    /// the proof is fetched once more if the roots differ. Fails with
+    /// `ApiError::RootMismatch` if they still differ.
+    pub async fn proof_checked(&self, index: u64) -> Result<MerkleProof, ApiError> {
+        let proof = self.proof(index).await?;
+        let root = self.root().await?;
+        if proof.root() == &root {
+            return Ok(proof);
+        }
+
+        let proof = self.proof(index).await?;
+        if proof.root() != &root {
+            return Err(ApiError::RootMismatch {
+                expected: root,
+                actual: proof.root().clone(),
+            });
+        }
+        Ok(proof)
+    }
+
+    /// Verifies that the local file at `path` is exactly the file stored at
+    /// `index` in the remote archive, without downloading it: the local file
+    /// is hashed and checked against the merkle proof of `index` and the
+    /// proof root. A mismatch is not an error, it is reported as `verified: false`.
+    pub async fn verify(
+        &self,
+        index: u64,
+        path: impl AsRef<Path>,
+    ) -> Result<VerifyOutcome, ApiError> {
+        self.verify_with(index, path.as_ref(), false).await
+    }
+
+    /// Same as `verify`, also requires the proof root to be the current
+    /// remote merkle root, freshly fetched.
+    pub async fn verify_current(
+        &self,
+        index: u64,
+        path: impl AsRef<Path>,
+    ) -> Result<VerifyOutcome, ApiError> {
+        self.verify_with(index, path.as_ref(), true).await
+    }
+
+    async fn verify_with(
+        &self,
+        index: u64,
+        path: &Path,
+        check_current_root: bool,
+    ) -> Result<VerifyOutcome, ApiError> {
+        let path = path.to_path_buf();
+        let sha256 = tokio::task::spawn_blocking(move || mrklar_fs::sha256(path)).await??;
+
+        let proof = self.proof(index).await?;
+        let mut verified = proof.verify(&sha256);
+        let mut root = proof.root().clone();
+
+        if check_current_root {
+            let current_root = self.root().await?;
+            verified = verified && current_root == root;
+            root = current_root;
         }
 
-        let m = MerkleProof::decode_bin(encoded_proof)?;
-        Ok(m)
+        Ok(VerifyOutcome {
+            verified,
+            root,
+            sha256,
+        })
     }
 
     /// Upload file specified by `path` to remote archive.
-    /// Returns the file index and the new remote merkle root
-    pub async fn upload(&self, path: &PathBuf) -> Result<(u64, Vec<u8>), ApiError> {
-        let (tx, rx) = mpsc::channel::<UploadRequest>(self.config.channel_size);
+    /// Returns the file index, the new remote merkle root and the merkle
+    /// proof of the file
+    pub async fn upload(&self, path: &Path) -> UploadResult {
+        let (filename, tokio_file, len) = open_upload_file(path).await?;
+        self.upload_reader(&filename, tokio_file, Some(len)).await
+    }
 
-        if !path.is_file() {
-            return Err(ApiError::UploadFileNotFound(
-                path.to_str().unwrap_or_default().to_string(),
-            ));
+    /// Same as `upload`, then checks that the server actually inserted the
+    /// file: the merkle proof of the returned index must lead from the local
+    /// file sha256 to the returned root. Fails with `ApiError::UploadNotVerified`
+    /// otherwise, the returned root must then not be trusted.
+    /// The proof is requested separately from the servers too old to return
+    /// it along with the upload, an upload by another client in between
+    /// changes the remote root and is reported as not verified as well.
+    pub async fn upload_verified(&self, path: &Path) -> UploadResult {
+        let (filename, tokio_file, len) = open_upload_file(path).await?;
+        let (outcome, sha256) = timed(
+            self.deadline(),
+            self.upload_reader_inner(&filename, tokio_file, Some(len), None),
+        )
+        .await?;
+
+        // already verified if returned along with the upload
+        if outcome.proof.is_some() {
+            return Ok(outcome);
+        }
+        let proof = self.proof(outcome.index).await?;
+        if !proof.verify_with_root(&sha256, &outcome.root) {
+            return Err(ApiError::UploadNotVerified {
+                index: outcome.index,
+                root: outcome.root,
+            });
         }
+        Ok(outcome)
+    }
 
-        let filename = file_name_as_string(path);
-        if filename.is_empty() {
-            return Err(ApiError::Unexpected("Empty filename".to_string()));
+    /// Same as `upload`, for callers already knowing the file sha256 (from a
+    /// manifest, ...): the digest is sent before the content, which is not
+    /// hashed locally. The server still verifies it and rejects the upload
+    /// on mismatch.
+    pub async fn upload_with_known_sha256(&self, path: &Path, sha256: Vec<u8>) -> UploadResult {
+        let (filename, tokio_file, len) = open_upload_file(path).await?;
+        timed(
+            self.deadline(),
+            self.upload_reader_inner(&filename, tokio_file, Some(len), Some(sha256)),
+        )
+        .await
+        .map(|(outcome, _)| outcome)
+    }
+
+    /// Same as `upload`, unless the remote archive already has an entry with
+    /// the same content: the index of that entry and the current merkle root
+    /// are then returned, flagged as `deduplicated`, and nothing is
+    /// transferred. The file is hashed locally before asking the server.
+    pub async fn upload_dedup(&self, path: &Path) -> UploadResult {
+        let start = Instant::now();
+        let (filename, tokio_file, len) = open_upload_file(path).await?;
+        let hashed_path = path.to_path_buf();
+        let sha256 = tokio::task::spawn_blocking(move || mrklar_fs::sha256(hashed_path)).await??;
+
+        if let Some(index) = self.find(&sha256).await? {
+            let result = self.root_response().await?;
+            return Ok(UploadOutcome {
+                index,
+                root: result.merkle_root,
+                stats: TransferStats {
+                    elapsed: start.elapsed(),
+                    ..Default::default()
+                },
+                sha256,
+                deduplicated: true,
+                tree_head: result.signed_tree_head,
+                proof: None,
+            });
+        }
+
+        timed(
+            self.deadline(),
+            self.upload_reader_inner(&filename, tokio_file, Some(len), Some(sha256)),
+        )
+        .await
+        .map(|(outcome, _)| outcome)
+    }
+
+    /// Same as `upload`, fails with `ApiError::Cancelled` as soon as `cancel`
+    /// is triggered. The upload call is then cancelled, the server discards
+    /// the partially received file.
+    pub async fn upload_with_cancel(&self, path: &Path, cancel: CancellationToken) -> UploadResult {
+        cancellable(&cancel, self.upload(path)).await
+    }
+
+    /// Uploads the files specified by `paths` to the remote archive, at most
+    /// `concurrency` uploads are in flight at the same time, all sharing the
+    /// api channel. A failed upload does not stop the others.
+    /// Returns the result of each upload in `paths` order and the final
+    /// remote merkle root.
+    pub async fn upload_many(
+        &self,
+        paths: Vec<PathBuf>,
+        concurrency: usize,
+    ) -> Result<(Vec<UploadResult>, Vec<u8>), ApiError> {
+        // establish the channel once before sharing it
+        self.client().await?;
+
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut tasks = tokio::task::JoinSet::new();
+        let num_paths = paths.len();
+
+        for (i, path) in paths.into_iter().enumerate() {
+            let api = self.clone();
+            let semaphore = semaphore.clone();
+            tasks.spawn(async move {
+                // the semaphore is never closed
+                let _permit = semaphore.acquire_owned().await;
+                (i, api.upload(&path).await)
+            });
         }
 
-        let chunk_size = self.config.chunk_size;
-        let file_sha256 = sha256(path)?;
-        let file_path = path.clone();
+        let mut results: Vec<Option<UploadResult>> = (0..num_paths).map(|_| None).collect();
+        while let Some(res) = tasks.join_next().await {
+            let (i, result) = res?;
+            results[i] = Some(result);
+        }
 
-        let mut client = self.connect().await?;
-        //let receiver_stream = ReceiverStream::new(rx);
+        let root = self.root().await?;
+        let results = results
+            .into_iter()
+            .map(|r| r.expect("every upload task completed"))
+            .collect();
+        Ok((results, root))
+    }
 
-        let task_handle = tokio::spawn(async move {
+    /// Upload the in-memory `data` to remote archive under the name `name`.
+    /// Returns the file index and the new remote merkle root
+    pub async fn upload_bytes(&self, name: &str, data: Bytes) -> UploadResult {
+        let len = data.len() as u64;
+        self.upload_reader(name, std::io::Cursor::new(data), Some(len))
+            .await
+    }
+
+    /// Upload everything read from `reader` to remote archive under the name `name`.
+    /// The sha256 is computed while streaming and sent after the last chunk.
+    /// `len_hint`, if known, is only used to size the read buffers.
+    /// Returns the file index and the new remote merkle root
+    pub async fn upload_reader(
+        &self,
+        name: &str,
+        reader: impl AsyncRead + Unpin,
+        len_hint: Option<u64>,
+    ) -> UploadResult {
+        timed(
+            self.deadline(),
+            self.upload_reader_inner(name, reader, len_hint, None),
+        )
+        .await
+        .map(|(outcome, _)| outcome)
+    }
+
+    /// Returns the upload outcome and the sha256 of the uploaded content.
+    /// A `known_sha256` is sent before the first chunk, the content is
+    /// then not hashed locally. Fails with `ApiError::UploadHashMismatch`
+    /// if the server reports another sha256.
+    async fn upload_reader_inner(
+        &self,
+        name: &str,
+        mut reader: impl AsyncRead + Unpin,
+        len_hint: Option<u64>,
+        known_sha256: Option<Vec<u8>>,
+    ) -> Result<(UploadOutcome, Vec<u8>), ApiError> {
+        if name.is_empty() {
+            return Err(ApiError::MissingFilename);
+        }
+
+        let window = self.upload_window.unwrap_or(self.conn.config.channel_size);
+        let (tx, rx) = mpsc::channel::<UploadRequest>(window.max(1));
+        let chunk_size = self.upload_chunk_size().await?;
+        let chunk_capacity = len_hint.map_or(chunk_size, |l| l.min(chunk_size as u64) as usize);
+
+        let start = Instant::now();
+        let mut client = self.client().await?;
+        let send_sha256 = self.upload_sha256;
+
+        let send = async move {
+            let mut stats = TransferStats::default();
             // 1- Send file metadata (filename)
-            let request = UploadRequest::new_metadata(&filename);
+            let request = UploadRequest::new_metadata(name);
             tx.send(request).await?;
 
-            // 2- Send file sha256
-            let request = UploadRequest::new_sha256(file_sha256);
-            tx.send(request).await?;
+            if let Some(sha256) = &known_sha256 {
+                let request = UploadRequest::new_sha256(sha256.clone());
+                tx.send(request).await?;
+            }
 
-            let tokio_file = tokio::fs::File::open(file_path).await?;
-            let mut handle = tokio_file.take(chunk_size as u64);
+            let mut hasher = Sha256::new();
+            let mut handle = (&mut reader).take(chunk_size as u64);
+            // the memory of a sent chunk is reused once the chunk has been encoded
+            let mut buf = BytesMut::new();
 
             loop {
-                let mut chunk = Vec::with_capacity(chunk_size);
+                buf.reserve(chunk_capacity);
 
-                // read a chunk from the file
-                let n = handle.read_to_end(&mut chunk).await?;
+                // read a chunk
+                while handle.read_buf(&mut buf).await? != 0 {}
+                let chunk = buf.split().freeze();
+                let n = chunk.len();
 
                 // reset the take limit before the next chunk
                 handle.set_limit(chunk_size as u64);
@@ -229,40 +1596,738 @@ impl MrklarApi {
                     break;
                 }
 
-                // Send the file chunk to the receiver
+                if known_sha256.is_none() {
+                    hasher.update(&chunk);
+                }
+                stats.bytes += n as u64;
+                stats.chunks += 1;
+
+                // Send the chunk to the receiver
                 let request = UploadRequest::new_chunk(chunk);
                 tx.send(request).await?;
+            }
 
-                // reached the end
-                if n < chunk_size {
-                    break;
-                }
+            // 2- Send the sha256 computed while streaming
+            if let Some(sha256) = known_sha256 {
+                return Ok((sha256, stats));
+            }
+            let sha256 = hasher.finalize().to_vec();
+            if send_sha256 {
+                let request = UploadRequest::new_sha256(sha256.clone());
+                tx.send(request).await?;
             }
 
-            Ok::<(), ApiError>(())
-        });
+            Ok::<_, ApiError>((sha256, stats))
+        };
 
         let receiver_stream = ReceiverStream::new(rx);
-        let response = client.upload(receiver_stream).await?;
+        let upload = client.upload(self.request(receiver_stream));
+        tokio::pin!(upload, send);
+        let mut response = None;
+        let result = loop {
+            tokio::select! {
+                result = &mut send => break result,
+                r = &mut upload, if response.is_none() => response = Some(r),
+            }
+        };
+        let (response, result) = match (response, result) {
+            (Some(response), result) => (response, result),
+            // the sha256 being optional, closing the stream would have the
+            // content sent so far stored: the upload is cancelled instead
+            // by dropping the call
+            (None, Err(e)) if !matches!(e, ApiError::SendUploadRequest(_)) => return Err(e),
+            (None, result) => (upload.await, result),
+        };
+        // never replayed, the file may have been stored
+        let (response, (sha256, mut stats)) = match (response, result) {
+            (Ok(response), Ok(result)) => (response, result),
+            (Ok(_), Err(e)) => return Err(e),
+            (Err(status), result) => {
+                let e = ApiError::from_upload(status, result.err());
+                self.reset_on_connection_error(&e);
+                return Err(e);
+            }
+        };
+
+        stats.elapsed = start.elapsed();
+        let outcome = upload_outcome(
+            response.into_inner(),
+            &sha256,
+            stats,
+            self.tree_head_key.as_deref(),
+        )?;
+        Ok((outcome, sha256))
+    }
+
+    /// Same as `upload`, through an upload session: the chunks carry their
+    /// offset in the file, a transfer interrupted by a connection error is
+    /// resumed from the offset acknowledged by the server, within the api
+    /// retry policy. The file is hashed locally first, the server checks
+    /// the sha256 before adding the file.
+    pub async fn upload_resumable(&self, path: &Path) -> UploadResult {
+        let start = Instant::now();
+        let (filename, _, _) = open_upload_file(path).await?;
+        let hashed_path = path.to_path_buf();
+        let sha256 = tokio::task::spawn_blocking(move || mrklar_fs::sha256(hashed_path)).await??;
+
+        let session_id = self.start_upload_session(&filename).await?;
+        let (_, mut stats) = self.resume_upload_session_inner(&session_id, path).await?;
+        let mut outcome = self
+            .finish_upload_session(&session_id, Some(sha256))
+            .await?;
+
+        stats.elapsed = start.elapsed();
+        outcome.stats = stats;
+        Ok(outcome)
+    }
 
-        let result = match task_handle.await {
-            Ok(result) => result,
-            Err(_) => return Err(ApiError::Unexpected("Failed to upload file".to_string())),
+    /// Opens an upload session for a file named `filename` in the remote
+    /// archive, returns the session id. The content is sent with
+    /// `resume_upload_session`, the file is added to the archive by
+    /// `finish_upload_session`. A session without any activity for the
+    /// server ttl expires.
+    pub async fn start_upload_session(&self, filename: &str) -> Result<String, ApiError> {
+        if filename.is_empty() {
+            return Err(ApiError::MissingFilename);
+        }
+        timed(self.deadline(), async {
+            let mut client = self.client().await?;
+            let session = client
+                .start_upload(self.request(StartUploadRequest {
+                    metadata: Some(FileMetadata {
+                        filename: filename.to_string(),
+                    }),
+                }))
+                .await?
+                .into_inner();
+            Ok(session.session_id)
+        })
+        .await
+    }
+
+    /// Returns the number of bytes received by the upload session
+    pub async fn upload_session_offset(&self, session_id: &str) -> Result<u64, ApiError> {
+        self.retried(|| timed(self.deadline(), self.get_upload_session_offset(session_id)))
+            .await
+    }
+
+    async fn get_upload_session_offset(&self, session_id: &str) -> Result<u64, ApiError> {
+        let mut client = self.client().await?;
+        let session = client
+            .get_upload_session(self.request(UploadSessionId {
+                session_id: session_id.to_string(),
+            }))
+            .await
+            .map_err(|e| ApiError::from(e).with_session_id(session_id))?
+            .into_inner();
+        Ok(session.offset)
+    }
+
+    /// Sends the content of the file at `path` the upload session has not
+    /// received yet, starting from the offset acknowledged by the server.
+    /// The chunks carrying their offset, an attempt failing with a
+    /// connection error is retried with the api retry policy.
+    /// Returns the new session offset.
+    pub async fn resume_upload_session(
+        &self,
+        session_id: &str,
+        path: &Path,
+    ) -> Result<u64, ApiError> {
+        self.resume_upload_session_inner(session_id, path)
+            .await
+            .map(|(offset, _)| offset)
+    }
+
+    /// Returns the new session offset and the statistics of all the attempts
+    async fn resume_upload_session_inner(
+        &self,
+        session_id: &str,
+        path: &Path,
+    ) -> Result<(u64, TransferStats), ApiError> {
+        let start = Instant::now();
+        let stats = Mutex::new(TransferStats::default());
+        let offset = self
+            .retried(|| {
+                timed(
+                    self.deadline(),
+                    self.send_session_chunks(session_id, path, &stats),
+                )
+            })
+            .await?;
+
+        let mut stats = stats.into_inner().unwrap();
+        stats.elapsed = start.elapsed();
+        Ok((offset, stats))
+    }
+
+    /// A single attempt of `resume_upload_session`, the sent bytes are added
+    /// to `stats`
+    async fn send_session_chunks(
+        &self,
+        session_id: &str,
+        path: &Path,
+        stats: &Mutex<TransferStats>,
+    ) -> Result<u64, ApiError> {
+        let offset = self.get_upload_session_offset(session_id).await?;
+        let mut tokio_file = tokio::fs::File::open(path).await?;
+        tokio_file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let window = self.upload_window.unwrap_or(self.conn.config.channel_size);
+        let (tx, rx) = mpsc::channel::<UploadChunk>(window.max(1));
+        let chunk_size = self.upload_chunk_size().await?;
+        let mut client = self.client().await?;
+
+        let send = async move {
+            let mut offset = offset;
+            let mut handle = tokio_file.take(chunk_size as u64);
+            let mut buf = BytesMut::new();
+
+            loop {
+                buf.reserve(chunk_size);
+
+                // read a chunk
+                while handle.read_buf(&mut buf).await? != 0 {}
+                let chunk = buf.split().freeze();
+                let n = chunk.len() as u64;
+
+                // reset the take limit before the next chunk
+                handle.set_limit(chunk_size as u64);
+
+                // nothing left
+                if n == 0 {
+                    break;
+                }
+
+                let request = UploadChunk {
+                    session_id: session_id.to_string(),
+                    offset,
+                    chunk,
+                };
+                // the call has ended, its status tells why
+                if tx.send(request).await.is_err() {
+                    break;
+                }
+                offset += n;
+
+                let mut stats = stats.lock().unwrap();
+                stats.bytes += n;
+                stats.chunks += 1;
+            }
+            Ok::<_, ApiError>(())
         };
-        if result.is_err() {
-            return Err(ApiError::Unexpected("Failed to upload file".to_string()));
+
+        // the chunks written before an error are kept by the server
+        let upload = client.upload_chunks(self.request(ReceiverStream::new(rx)));
+        let (response, sent) = tokio::join!(upload, send);
+        let session = response
+            .map_err(|e| ApiError::from(e).with_session_id(session_id))?
+            .into_inner();
+        sent?;
+        Ok(session.offset)
+    }
+
+    /// Closes the upload session and adds the received file to the remote
+    /// archive. The server rejects the upload if `sha256` does not match
+    /// the received content. Returns the file index and the new remote
+    /// merkle root, the stats are left empty.
+    pub async fn finish_upload_session(
+        &self,
+        session_id: &str,
+        sha256: Option<Vec<u8>>,
+    ) -> UploadResult {
+        let sha256 = sha256.unwrap_or_default();
+        timed(self.deadline(), async {
+            let mut client = self.client().await?;
+            let response = client
+                .finish_upload(self.request(FinishUploadRequest {
+                    session_id: session_id.to_string(),
+                    sha256: sha256.clone(),
+                }))
+                .await
+                .map_err(|e| ApiError::from(e).with_session_id(session_id))?
+                .into_inner();
+            // the sha256 computed by the server is reported if none was sent
+            let sha256 = match sha256.is_empty() {
+                true => response.sha256.clone(),
+                false => sha256,
+            };
+            upload_outcome(
+                response,
+                &sha256,
+                TransferStats::default(),
+                self.tree_head_key.as_deref(),
+            )
+        })
+        .await
+    }
+
+    /// Downloads every entry of the remote archive into `out_dir`, at most
+    /// `concurrency` downloads are in flight at the same time. Each file is
+    /// named after its entry filename, colliding names are resolved
+    /// deterministically (see `mirror::mirror_file_names`), existing files
+    /// are overwritten. The mirror manifest is written in `out_dir`.
+    /// A failed download does not stop the others, the returned report lists
+    /// the path and the verification status or the error of every entry.
+    pub async fn download_all(
+        &self,
+        out_dir: &Path,
+        concurrency: usize,
+    ) -> Result<DownloadAllReport, ApiError> {
+        let ArchiveStatus { count, root } = self.status().await?;
+        // entries uploaded since are not covered by the root
+        let mut infos = self.list_all().await?;
+        infos.retain(|info| info.index < count);
+        let manifest = MirrorManifest::new(&root, count, &infos);
+
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut tasks = tokio::task::JoinSet::new();
+
+        for (i, entry) in manifest.entries.iter().enumerate() {
+            let api = self.clone();
+            let semaphore = semaphore.clone();
+            let index = entry.index;
+            let out_dir = out_dir.to_path_buf();
+            let filename = entry.path.clone();
+            tasks.spawn(async move {
+                // the semaphore is never closed
+                let _permit = semaphore.acquire_owned().await;
+                let result = api
+                    .download(index, Some(out_dir), Some(filename), true, None)
+                    .await
+                    .map(|outcome| outcome.verified);
+                (i, result)
+            });
+        }
+
+        let mut results: Vec<Option<Result<bool, ApiError>>> =
+            manifest.entries.iter().map(|_| None).collect();
+        while let Some(res) = tasks.join_next().await {
+            let (i, result) = res?;
+            results[i] = Some(result);
         }
+        let entries = manifest
+            .entries
+            .iter()
+            .zip(results)
+            .map(|(e, result)| DownloadAllEntry {
+                index: e.index,
+                path: out_dir.join(&e.path),
+                result: result.expect("every download task completed"),
+            })
+            .collect();
 
-        let ur = response.into_inner();
-        let file_index = match ur.index {
-            Some(fi) => fi.index,
+        manifest.save(out_dir)?;
+        Ok(DownloadAllReport { root, entries })
+    }
+
+    /// Verifies the local mirror located in `dir` against the remote archive.
+    /// Each entry of the mirror manifest is hashed and checked using its
+    /// merkle proof against the current remote root, after checking through
+    /// a consistency proof that the remote archive extends the tree of root
+    /// `root`, or of the manifest root if `root` is `None`. If it does not,
+    /// every file is checked against `root` and fails to verify.
+    /// Without any manifest, the whole remote archive is verified against
+    /// the default mirror naming scheme. The merkle proofs are fetched with
+    /// at most `concurrency` requests in flight.
+    pub async fn verify_mirror(
+        &self,
+        dir: &Path,
+        root: Option<Vec<u8>>,
+        concurrency: usize,
+    ) -> Result<VerifyReport, ApiError> {
+        let current = self.status().await?;
+        let manifest = match MirrorManifest::load(dir)? {
+            Some(m) => m,
             None => {
-                return Err(ApiError::Unexpected(
-                    "Failed to upload file, (did not receive file index).".to_string(),
-                ))
+                let mut infos = self.list_all().await?;
+                infos.retain(|info| info.index < current.count);
+                MirrorManifest::new(&current.root, current.count, &infos)
             }
         };
+        let root = match root {
+            Some(root) => root,
+            None => hex::decode(&manifest.root)
+                .map_err(|e| ApiError::InvalidMirrorManifest(e.to_string()))?,
+        };
+
+        let size = manifest.tree_size();
+        let consistent = if root == current.root {
+            true
+        } else if size == 0 || size > current.count {
+            false
+        } else {
+            self.consistency_proof(size, current.count)
+                .await?
+                .verify_with_roots(&root, &current.root)
+        };
+
+        let entries: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|e| (e.index, dir.join(&e.path)))
+            .collect();
+        let proof_root = if consistent {
+            current.root
+        } else {
+            root.clone()
+        };
+        let results = self
+            .verify_entries(proof_root, &entries, concurrency)
+            .await?;
+
+        Ok(VerifyReport { root, results })
+    }
+
+    /// Verifies local copies of remote archive entries, given as
+    /// `(index, path)` pairs, against the current remote root fetched once.
+    /// The merkle proofs are fetched with at most `concurrency` requests in
+    /// flight, all sharing the api channel, and the local files are hashed
+    /// using all the available cores. A failed entry, local file or proof
+    /// request, does not stop the others, the report lists the status of every
+    /// entry in `entries` order.
+    pub async fn verify_archive(
+        &self,
+        entries: &[(u64, PathBuf)],
+        concurrency: usize,
+    ) -> Result<VerifyReport, ApiError> {
+        let root = self.root().await?;
+        let results = self
+            .verify_entries(root.clone(), entries, concurrency)
+            .await?;
+        Ok(VerifyReport { root, results })
+    }
+
+    /// Verifies `entries` against `root`, see `verify_archive`
+    async fn verify_entries(
+        &self,
+        root: Vec<u8>,
+        entries: &[(u64, PathBuf)],
+        concurrency: usize,
+    ) -> Result<Vec<VerifyResult>, ApiError> {
+        let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+        let mut tasks = tokio::task::JoinSet::new();
+        for (i, (index, _)) in entries.iter().enumerate() {
+            let api = self.clone();
+            let semaphore = semaphore.clone();
+            let index = *index;
+            tasks.spawn(async move {
+                // the semaphore is never closed
+                let _permit = semaphore.acquire_owned().await;
+                (i, api.proof(index).await)
+            });
+        }
+
+        let mut proofs: Vec<Result<MerkleProof, VerifyStatus>> = entries
+            .iter()
+            .map(|_| Err(VerifyStatus::ProofError))
+            .collect();
+        while let Some(res) = tasks.join_next().await {
+            let (i, proof) = res?;
+            proofs[i] = proof.map_err(|e| match e {
+                ApiError::IndexNotFound(_) => VerifyStatus::IndexNotFound,
+                _ => VerifyStatus::ProofError,
+            });
+        }
+
+        let files: Vec<_> = entries
+            .iter()
+            .zip(&proofs)
+            .filter_map(|((index, path), proof)| {
+                Some((*index, path.clone(), proof.as_ref().ok()?.clone()))
+            })
+            .collect();
+        let mut verified = tokio::task::spawn_blocking(move || verify_files(&root, &files))
+            .await?
+            .into_iter();
+
+        let results = entries
+            .iter()
+            .zip(&proofs)
+            .map(|((index, path), proof)| match proof {
+                Ok(_) => verified.next().expect("one result per verified file"),
+                Err(status) => VerifyResult {
+                    index: *index,
+                    path: path.clone(),
+                    status: *status,
+                },
+            })
+            .collect();
+
+        Ok(results)
+    }
+}
+
+/// Returns a connector to the unix domain socket at `path`, the endpoint
+/// url is then only used to build the request uris.
+fn uds_connector(
+    path: PathBuf,
+) -> impl tower::Service<
+    tonic::transport::Uri,
+    Response = hyper_util::rt::TokioIo<UdsStream>,
+    Error = std::io::Error,
+    Future = impl Future<Output = std::io::Result<hyper_util::rt::TokioIo<UdsStream>>> + Send,
+> + Clone
+       + Send
+       + 'static {
+    tower::service_fn(move |_: tonic::transport::Uri| {
+        let path = path.clone();
+        async move {
+            let stream = connect_uds_stream(&path).await?;
+            Ok(hyper_util::rt::TokioIo::new(stream))
+        }
+    })
+}
+
+#[cfg(unix)]
+type UdsStream = tokio::net::UnixStream;
+
+#[cfg(unix)]
+async fn connect_uds_stream(path: &Path) -> std::io::Result<UdsStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+#[cfg(not(unix))]
+type UdsStream = tokio::net::TcpStream;
+
+#[cfg(not(unix))]
+async fn connect_uds_stream(_path: &Path) -> std::io::Result<UdsStream> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Runs `fut` to completion, fails with `ApiError::DeadlineExceeded`
+/// if `deadline` is reached first
+async fn timed<T>(
+    deadline: Option<Instant>,
+    fut: impl Future<Output = Result<T, ApiError>>,
+) -> Result<T, ApiError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| ApiError::DeadlineExceeded)?,
+        None => fut.await,
+    }
+}
+
+/// Reads the entry message starting a download response stream, returns the
+/// stream positioned on the first file chunk
+async fn read_download_entry(
+    mut stream: Streaming<DownloadResponse>,
+) -> Result<(Streaming<DownloadResponse>, Entry), ApiError> {
+    while let Some(response) = stream.message().await? {
+        match response.r#type {
+            None => continue,
+            Some(download_response::Type::Entry(entry)) => return Ok((stream, entry)),
+            Some(download_response::Type::Chunk(_)) => {
+                return Err(ApiError::ProtocolViolation {
+                    expected: "file metadata",
+                    got: "file chunk",
+                });
+            }
+        }
+    }
+
+    Err(ApiError::ProtocolViolation {
+        expected: "file metadata",
+        got: "end of stream",
+    })
+}
+
+/// Fails with `ApiError::RootMismatch` if `expected_root` is set and differs
+/// from the root of `proof`
+fn check_expected_root(
+    proof: &MerkleProof,
+    expected_root: Option<&Vec<u8>>,
+) -> Result<(), ApiError> {
+    match expected_root {
+        Some(expected) if proof.root() != expected => Err(ApiError::RootMismatch {
+            expected: expected.clone(),
+            actual: proof.root().clone(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the absolute path of a downloaded file: `output_filename` if set,
+/// the sanitized server provided `filename` otherwise, in `output_dir`.
+fn output_file_path(
+    output_dir: Option<PathBuf>,
+    output_filename: Option<String>,
+    filename: &str,
+    index: u64,
+) -> Result<PathBuf, ApiError> {
+    let output_dir = output_dir.unwrap_or_default();
+    // never trust the server provided filename
+    let path = match output_filename {
+        Some(of) if !of.is_empty() => output_dir.join(of),
+        _ => output_dir.join(safe_filename(filename, index)),
+    };
+    Ok(absolute_path(&path)?)
+}
+
+/// Moves a completely downloaded file to its final location, `force`
+/// allows overwriting an existing file
+async fn rename_download(from: &Path, to: &Path, force: bool) -> Result<(), ApiError> {
+    // the output file may have been created while downloading
+    if to.is_file() && !force {
+        let p = to.to_str().unwrap_or_default().to_string();
+        return Err(ApiError::DownloadFileAlreadyExists(p));
+    }
+    tokio::fs::rename(from, to).await?;
+    Ok(())
+}
 
-        Ok((file_index, ur.merkle_root))
+/// Opens (or creates) the part file of a resumable download for appending.
+/// Returns the file, the hash state of its current content and its length.
+async fn open_part_file(path: &Path) -> Result<(tokio::fs::File, Sha256, u64), ApiError> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .append(true)
+        .open(path)
+        .await?;
+
+    let mut hasher = Sha256::new();
+    let mut len = 0u64;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        len += n as u64;
+    }
+    Ok((file, hasher, len))
+}
+
+/// Returns the outcome of a completed upload of content `sha256`, fails
+/// with `ApiError::UploadHashMismatch` if the server reports another sha256,
+/// or with `ApiError::UploadNotVerified` if the returned proof does not lead
+/// to the returned root. The signed tree head is verified with
+/// `tree_head_key` if set.
+fn upload_outcome(
+    response: UploadResponse,
+    sha256: &[u8],
+    stats: TransferStats,
+    tree_head_key: Option<&[u8]>,
+) -> UploadResult {
+    let file_index = response.index.ok_or(ApiError::MissingUploadIndex)?.index;
+    check_tree_head(
+        tree_head_key,
+        response.signed_tree_head.as_ref(),
+        &response.merkle_root,
+        |tree_size| file_index < tree_size,
+    )?;
+    // not reported by older servers
+    if !response.sha256.is_empty() && response.sha256 != sha256 {
+        return Err(ApiError::UploadHashMismatch {
+            index: file_index,
+            expected: sha256.to_vec(),
+            actual: response.sha256,
+        });
+    }
+    // not returned by older servers
+    let proof = if response.merkle_proof.is_empty() {
+        None
+    } else {
+        let proof = MerkleProof::decode_bin(response.merkle_proof)?;
+        if !proof.verify_with_root(&sha256.to_vec(), &response.merkle_root) {
+            return Err(ApiError::UploadNotVerified {
+                index: file_index,
+                root: response.merkle_root,
+            });
+        }
+        Some(proof)
+    };
+
+    Ok(UploadOutcome {
+        index: file_index,
+        root: response.merkle_root,
+        stats,
+        sha256: sha256.to_vec(),
+        deduplicated: false,
+        tree_head: response.signed_tree_head,
+        proof,
+    })
+}
+
+/// Verifies `tree_head` with the server `public_key`, if set. The signed
+/// root must be `root` and `size_matches` must accept the signed tree size.
+fn check_tree_head(
+    public_key: Option<&[u8]>,
+    tree_head: Option<&SignedTreeHead>,
+    root: &[u8],
+    size_matches: impl FnOnce(u64) -> bool,
+) -> Result<(), ApiError> {
+    let Some(public_key) = public_key else {
+        return Ok(());
+    };
+    let tree_head = tree_head.ok_or(ApiError::MissingTreeHead)?;
+    tree_head.verify(public_key)?;
+    if tree_head.merkle_root != root || !size_matches(tree_head.tree_size) {
+        return Err(ApiError::TreeHeadMismatch {
+            root: root.to_vec(),
+            signed_root: tree_head.merkle_root.clone(),
+            tree_size: tree_head.tree_size,
+        });
+    }
+    Ok(())
+}
+
+/// Opens the file to upload, returns its name, the opened file and its length
+async fn open_upload_file(path: &Path) -> Result<(String, tokio::fs::File, u64), ApiError> {
+    if !path.is_file() {
+        return Err(ApiError::UploadFileNotFound(
+            path.to_str().unwrap_or_default().to_string(),
+        ));
+    }
+
+    let filename = file_name_as_string(path);
+    let tokio_file = tokio::fs::File::open(path).await?;
+    let len = tokio_file.metadata().await?.len();
+    Ok((filename, tokio_file, len))
+}
+
+/// Returns the last path component of the server provided `filename`, or
+/// `index` if the result is empty, `.`, `..` or contains a NUL byte, so that
+/// the local file is always created inside the requested directory.
+pub(crate) fn safe_filename(filename: &str, index: u64) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or_default();
+    if name.is_empty() || name == "." || name == ".." || name.contains('\0') {
+        index.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+/// Runs `fut` until it completes or `cancel` is triggered. On cancellation
+/// `fut` is dropped, which stops any in-flight stream.
+async fn cancellable<T>(
+    cancel: &CancellationToken,
+    fut: impl Future<Output = Result<T, ApiError>>,
+) -> Result<T, ApiError> {
+    tokio::select! {
+        biased;
+        _ = cancel.cancelled() => Err(ApiError::Cancelled),
+        res = fut => res,
+    }
+}
+
+/// Removes the file at the wrapped path when dropped, unless `keep` is called
+struct PartialFile<'a>(Option<&'a Path>);
+
+impl PartialFile<'_> {
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for PartialFile<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.0 {
+            // no need to handle the error
+            let _ = std::fs::remove_file(path);
+        }
     }
 }
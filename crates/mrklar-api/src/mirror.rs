@@ -0,0 +1,241 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use mrklar_common::{merkle_proof::MerkleProof, proto::EntryInfo};
+use mrklar_fs::sha256;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::ApiError, safe_filename};
+
+/// Name of the metadata file written at the root of a mirror directory
+pub const MIRROR_MANIFEST_FILENAME: &str = "mrklar-mirror.json";
+
+/// Describes a local mirror of the remote archive: the merkle root at the
+/// time of the mirroring and, for each entry, the path of its local copy.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MirrorManifest {
+    /// hex encoded merkle root
+    pub root: String,
+    /// Number of archive entries of the tree of root `root`, 0 if the
+    /// manifest was written without it
+    #[serde(default)]
+    pub size: u64,
+    pub entries: Vec<MirrorEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorEntry {
+    pub index: u64,
+    /// path relative to the mirror directory
+    pub path: String,
+}
+
+impl MirrorManifest {
+    /// Builds a manifest from the remote entry list of the tree of `size`
+    /// entries and root `root`.
+    /// See `mirror_file_names` for the naming scheme.
+    pub fn new(root: &[u8], size: u64, infos: &[EntryInfo]) -> Self {
+        let entries = infos
+            .iter()
+            .zip(mirror_file_names(infos))
+            .map(|(info, path)| MirrorEntry {
+                index: info.index,
+                path,
+            })
+            .collect();
+        MirrorManifest {
+            root: hex::encode(root),
+            size,
+            entries,
+        }
+    }
+
+    /// Loads the manifest of the mirror located in `dir`, returns `None`
+    /// if the directory does not contain any manifest.
+    pub fn load(dir: &Path) -> Result<Option<Self>, ApiError> {
+        let path = dir.join(MIRROR_MANIFEST_FILENAME);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let file = std::fs::File::open(path)?;
+        let manifest = serde_json::from_reader(std::io::BufReader::new(file))?;
+        Ok(Some(manifest))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), ApiError> {
+        let file = std::fs::File::create(dir.join(MIRROR_MANIFEST_FILENAME))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    /// Returns the number of archive entries of the tree of root `root`,
+    /// deduced from the entry indices if the manifest does not record it
+    pub fn tree_size(&self) -> u64 {
+        if self.size > 0 {
+            return self.size;
+        }
+        self.entries.iter().map(|e| e.index + 1).max().unwrap_or(0)
+    }
+
+    pub fn path_of(&self, index: u64) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|e| e.index == index)
+            .map(|e| e.path.as_str())
+    }
+}
+
+/// Returns the local file name of each entry: the sanitized entry filename
+/// (see `safe_filename`), or `<index>_<filename>` if an entry with a lower
+/// index already uses that name.
+pub fn mirror_file_names(infos: &[EntryInfo]) -> Vec<String> {
+    let mut used = HashSet::new();
+    infos
+        .iter()
+        .map(|info| {
+            let filename = safe_filename(&info.filename, info.index);
+            if used.insert(filename.clone()) {
+                filename
+            } else {
+                let name = format!("{}_{}", info.index, filename);
+                used.insert(name.clone());
+                name
+            }
+        })
+        .collect()
+}
+
+/// Outcome of the download of a single entry by `MrklarApi::download_all`
+#[derive(Debug)]
+pub struct DownloadAllEntry {
+    pub index: u64,
+    pub path: PathBuf,
+    /// The merkle proof verification status, or the download error
+    pub result: Result<bool, ApiError>,
+}
+
+#[derive(Debug)]
+pub struct DownloadAllReport {
+    /// The remote merkle root when the download started
+    pub root: Vec<u8>,
+    pub entries: Vec<DownloadAllEntry>,
+}
+
+impl DownloadAllReport {
+    /// Returns `true` if every entry has been downloaded and verified
+    pub fn is_ok(&self) -> bool {
+        self.entries.iter().all(|e| matches!(e.result, Ok(true)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Ok,
+    /// The local file does not exist
+    Missing,
+    /// The local file hash does not match the merkle proof
+    Mismatch,
+    /// The remote archive has no entry at that index
+    IndexNotFound,
+    /// The local file could not be read
+    IoError,
+    /// The merkle proof could not be fetched from the remote archive
+    ProofError,
+}
+
+impl fmt::Display for VerifyStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyStatus::Ok => write!(fmt, "OK"),
+            VerifyStatus::Missing => write!(fmt, "MISSING"),
+            VerifyStatus::Mismatch => write!(fmt, "MISMATCH"),
+            VerifyStatus::IndexNotFound => write!(fmt, "INDEX_NOT_FOUND"),
+            VerifyStatus::IoError => write!(fmt, "IO_ERROR"),
+            VerifyStatus::ProofError => write!(fmt, "PROOF_ERROR"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyResult {
+    pub index: u64,
+    pub path: PathBuf,
+    pub status: VerifyStatus,
+}
+
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    /// The merkle root the mirror has been verified against
+    pub root: Vec<u8>,
+    pub results: Vec<VerifyResult>,
+}
+
+impl VerifyReport {
+    pub fn count(&self, status: VerifyStatus) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.results.iter().all(|r| r.status == VerifyStatus::Ok)
+    }
+}
+
+/// Hashes every local file using all the available cores and verifies
+/// each hash against the corresponding proof and `root`.
+pub(crate) fn verify_files(
+    root: &[u8],
+    files: &[(u64, PathBuf, MerkleProof)],
+) -> Vec<VerifyResult> {
+    let num_threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(files.len().max(1));
+    let next = AtomicUsize::new(0);
+
+    let mut results: Vec<(usize, VerifyResult)> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..num_threads)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((index, path, proof)) = files.get(i) else {
+                            break;
+                        };
+                        let status = if !path.is_file() {
+                            VerifyStatus::Missing
+                        } else {
+                            match sha256(path) {
+                                Ok(hash) if proof.root() == root && proof.verify(&hash) => {
+                                    VerifyStatus::Ok
+                                }
+                                Ok(_) => VerifyStatus::Mismatch,
+                                Err(_) => VerifyStatus::IoError,
+                            }
+                        };
+                        results.push((
+                            i,
+                            VerifyResult {
+                                index: *index,
+                                path: path.clone(),
+                                status,
+                            },
+                        ));
+                    }
+                    results
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().unwrap())
+            .collect()
+    });
+
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, r)| r).collect()
+}
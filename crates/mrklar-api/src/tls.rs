@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use tonic::transport::{Certificate, ClientTlsConfig, Identity};
+
+use crate::error::ApiError;
+
+/// TLS settings of the connection to the server. When set on a `MrklarApi`,
+/// the server is reached using the `https` scheme.
+///
+/// Without a custom CA certificate, the server certificate is verified
+/// against the webpki root certificates.
+#[derive(Debug, Default, Clone)]
+pub struct ClientTls {
+    // PEM encoded CA certificate
+    ca_cert: Option<Vec<u8>>,
+    // overrides the server name used for SNI and certificate verification
+    domain_name: Option<String>,
+    // PEM encoded client certificate and private key
+    identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ClientTls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verifies the server certificate against the PEM encoded `ca_cert`
+    /// instead of the webpki root certificates
+    #[must_use]
+    pub fn with_ca_cert(mut self, ca_cert: impl Into<Vec<u8>>) -> Self {
+        self.ca_cert = Some(ca_cert.into());
+        self
+    }
+
+    /// Sets the domain name sent with SNI and expected in the server
+    /// certificate, defaults to the server url host
+    #[must_use]
+    pub fn with_domain_name(mut self, domain_name: impl Into<String>) -> Self {
+        self.domain_name = Some(domain_name.into());
+        self
+    }
+
+    /// Sets the PEM encoded client certificate and private key presented
+    /// to servers requiring client authentication
+    #[must_use]
+    pub fn with_identity(mut self, cert: impl Into<Vec<u8>>, key: impl Into<Vec<u8>>) -> Self {
+        self.identity = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Reads the PEM encoded CA certificate from `path`
+    pub fn with_ca_cert_file(self, path: &Path) -> Result<Self, ApiError> {
+        Ok(self.with_ca_cert(std::fs::read(path)?))
+    }
+
+    /// Reads the PEM encoded client certificate and private key from
+    /// `cert_path` and `key_path`
+    pub fn with_identity_files(self, cert_path: &Path, key_path: &Path) -> Result<Self, ApiError> {
+        Ok(self.with_identity(std::fs::read(cert_path)?, std::fs::read(key_path)?))
+    }
+
+    pub(crate) fn client_tls_config(&self) -> ClientTlsConfig {
+        let mut config = ClientTlsConfig::new();
+        config = match &self.ca_cert {
+            Some(pem) => config.ca_certificate(Certificate::from_pem(pem)),
+            None => config.with_webpki_roots(),
+        };
+        if let Some(domain_name) = &self.domain_name {
+            config = config.domain_name(domain_name);
+        }
+        if let Some((cert, key)) = &self.identity {
+            config = config.identity(Identity::from_pem(cert, key));
+        }
+        config
+    }
+}
@@ -1,8 +1,16 @@
-use std::{net::IpAddr, path::{Path, PathBuf}, str::FromStr};
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, UNIX_EPOCH},
+};
 
-use clap::{Parser, Subcommand};
-use mrklar_common::config::{NetConfig, DEFAULT_SERVER_HOST_STR, DEFAULT_SERVER_PORT_STR};
-use mrklar_api::MrklarApi;
+use clap::{Parser, Subcommand, ValueEnum};
+use mrklar_api::{error::ApiError, mirror::VerifyStatus, tls::ClientTls, MrklarApi};
+use mrklar_common::config::{
+    GrpcCompression, NetConfig, DEFAULT_SERVER_HOST_STR, DEFAULT_SERVER_PORT_STR, MAX_MESSAGE_SIZE,
+};
+use mrklar_common::proto::EntryInfo;
 
 #[derive(Parser)]
 #[command(name = "mrklar-cli", version = env!("CARGO_PKG_VERSION"), next_display_order = None)]
@@ -18,11 +26,11 @@ pub struct Cli {
 pub struct NetCmd {
     /// Port number to listen on.
     #[arg(
-        long, 
-        short, 
-        value_name = "NUM", 
+        long,
+        short,
+        value_name = "NUM",
         env = "MRKLAR_PORT",
-        default_value = DEFAULT_SERVER_PORT_STR, 
+        default_value = DEFAULT_SERVER_PORT_STR,
     )]
     pub port: u16,
 
@@ -34,6 +42,78 @@ pub struct NetCmd {
         default_value = DEFAULT_SERVER_HOST_STR
     )]
     pub host: IpAddr,
+
+    /// The server url (http or https), overrides '--host' and '--port'.
+    #[arg(long, value_name = "URL", env = "MRKLAR_URL")]
+    pub url: Option<String>,
+
+    /// Connect to the server unix domain socket, overrides '--host' and '--port'.
+    #[arg(long, value_name = "PATH", env = "MRKLAR_UDS", conflicts_with = "url")]
+    pub uds: Option<PathBuf>,
+
+    /// Compression of the grpc messages sent to the server: none, gzip or zstd.
+    #[arg(
+        long,
+        value_name = "COMPRESSION",
+        env = "MRKLAR_GRPC_COMPRESSION",
+        default_value_t = GrpcCompression::None,
+    )]
+    pub grpc_compression: GrpcCompression,
+
+    /// Largest grpc message sent or accepted, in bytes. Must hold a chunk
+    /// plus 64 KiB of message overhead.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "MRKLAR_MAX_MESSAGE_SIZE",
+        default_value_t = MAX_MESSAGE_SIZE,
+    )]
+    pub max_message_size: usize,
+
+    /// Connect to the server over TLS.
+    #[arg(long, env = "MRKLAR_TLS")]
+    pub tls: bool,
+
+    /// PEM encoded CA certificate used to verify the server certificate,
+    /// implies '--tls'.
+    #[arg(long, value_name = "PATH", env = "MRKLAR_TLS_CA_CERT")]
+    pub tls_ca_cert: Option<PathBuf>,
+
+    /// Domain name expected in the server certificate, implies '--tls'.
+    #[arg(long, value_name = "NAME", env = "MRKLAR_TLS_DOMAIN")]
+    pub tls_domain: Option<String>,
+
+    /// PEM encoded client certificate, implies '--tls'.
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "MRKLAR_TLS_CLIENT_CERT",
+        requires = "tls_client_key"
+    )]
+    pub tls_client_cert: Option<PathBuf>,
+
+    /// PEM encoded client private key, implies '--tls'.
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "MRKLAR_TLS_CLIENT_KEY",
+        requires = "tls_client_cert"
+    )]
+    pub tls_client_key: Option<PathBuf>,
+
+    /// Bearer token sent with every request.
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "MRKLAR_AUTH_TOKEN",
+        hide_env_values = true
+    )]
+    pub auth_token: Option<String>,
+
+    /// Hex encoded ed25519 public key of the server, the merkle roots
+    /// returned by the server must be signed with its private key.
+    #[arg(long, value_name = "HEX", env = "MRKLAR_TREE_HEAD_KEY")]
+    pub tree_head_key: Option<String>,
 }
 
 impl NetCmd {
@@ -41,6 +121,53 @@ impl NetCmd {
         NetConfig::default()
             .with_port(self.port)
             .with_host(self.host)
+            .with_uds_path(self.uds)
+            .with_grpc_compression(self.grpc_compression)
+            .with_max_message_size(self.max_message_size)
+    }
+
+    /// Returns the TLS settings if any TLS option is specified
+    fn tls(&self) -> eyre::Result<Option<ClientTls>> {
+        if !self.tls
+            && self.tls_ca_cert.is_none()
+            && self.tls_domain.is_none()
+            && self.tls_client_cert.is_none()
+        {
+            return Ok(None);
+        }
+        let mut tls = ClientTls::new();
+        if let Some(path) = &self.tls_ca_cert {
+            tls = tls.with_ca_cert_file(path)?;
+        }
+        if let Some(domain) = &self.tls_domain {
+            tls = tls.with_domain_name(domain);
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_client_cert, &self.tls_client_key) {
+            tls = tls.with_identity_files(cert, key)?;
+        }
+        Ok(Some(tls))
+    }
+
+    pub fn into_api(self) -> eyre::Result<MrklarApi> {
+        let tls = self.tls()?;
+        let auth_token = self.auth_token.clone();
+        let tree_head_key = self.tree_head_key.as_deref().map(hex::decode).transpose()?;
+        let grpc_compression = self.grpc_compression;
+        let max_message_size = self.max_message_size;
+        let mut api = match &self.url {
+            Some(url) => MrklarApi::from_url(url)?,
+            None => MrklarApi::new(self.into_net_config())?,
+        }
+        .with_grpc_compression(grpc_compression)
+        .with_max_message_size(max_message_size)?
+        .with_tree_head_key(tree_head_key);
+        if let Some(tls) = tls {
+            api = api.with_tls(tls);
+        }
+        if let Some(token) = auth_token {
+            api = api.with_auth_token(token)?;
+        }
+        Ok(api)
     }
 }
 
@@ -56,56 +183,165 @@ pub enum CliSubcommand {
     /// Download file at specified index from the remote archive
     #[command(name = "download")]
     Download(DownloadCmd),
-    /// Print file proof 
+    /// Print file proof
     #[command(name = "proof")]
     Proof(ProofCmd),
+    /// Print the metadata, size and download statistics of the file at specified index
+    #[command(name = "metadata")]
+    Metadata(MetadataCmd),
+    /// Print the index of the first file with the specified sha256
+    #[command(name = "find")]
+    Find(FindCmd),
+    /// List the archive entries with their size and download statistics
+    #[command(name = "list")]
+    List(ListCmd),
+    /// Print the archive statistics
+    Stats,
+    /// Verify a local mirror of the archive (see `download --all`) against the remote merkle root
+    #[command(name = "verify-archive")]
+    VerifyArchive(VerifyArchiveCmd),
 }
 
 #[derive(Parser)]
 pub struct UploadCmd {
-    path: String
+    path: String,
+
+    /// Check that the server proof of the uploaded file leads to the returned merkle root
+    #[arg(long)]
+    verify: bool,
+
+    /// Skip the upload if the remote archive already has the file content,
+    /// the index of the existing entry is then returned
+    #[arg(long, conflicts_with = "verify")]
+    dedup: bool,
 }
 
 #[derive(Parser)]
 pub struct DownloadCmd {
     /// File index to download
-    #[arg(value_name = "INDEX")]
-    index: u64,
+    #[arg(value_name = "INDEX", required_unless_present_any = ["all", "name"])]
+    index: Option<u64>,
 
-    // /// Perform file verification using the remote archive merkle root
-    // #[arg(
-    //     long, 
-    //     value_name = "PROOF", 
-    // )]
-    // pub verify: Option<String>,
-
-    /// Directory where the downloaded file should be saved
+    /// Download the most recently uploaded file with this exact filename
+    /// instead of a file index
     #[arg(
-        long, 
-        value_name = "DIR", 
+        long,
+        value_name = "FILENAME",
+        conflicts_with_all = ["index", "all"],
     )]
+    pub name: Option<String>,
+
+    /// Download every file of the archive and write a mirror manifest in the output directory,
+    /// existing files are overwritten
+    #[arg(long, conflicts_with_all = ["index", "out_filename"])]
+    pub all: bool,
+
+    /// Maximum number of parallel downloads when using '--all'
+    #[arg(long, value_name = "NUM", default_value = "4", requires = "all")]
+    pub concurrency: usize,
+
+    /// Hex encoded merkle root the file must be verified against,
+    /// instead of the root embedded in the proof sent by the server
+    #[arg(long, value_name = "ROOT", conflicts_with = "all")]
+    pub expected_root: Option<String>,
+
+    /// Keep the downloaded file even if its merkle proof verification fails
+    #[arg(long)]
+    pub no_strict: bool,
+
+    /// Directory where the downloaded file should be saved
+    #[arg(long, value_name = "DIR")]
     pub out_dir: Option<PathBuf>,
 
     /// Specify the filename of downloaded file
-    #[arg(
-        long, 
-        value_name = "NAME", 
-    )]
+    #[arg(long, value_name = "NAME")]
     pub out_filename: Option<String>,
 
     /// Override any existing file
-    #[arg(
-        long, 
-        short,
-    )]
+    #[arg(long, short)]
     pub force: bool,
 }
 
 #[derive(Parser)]
 pub struct ProofCmd {
-    /// File index 
+    /// File index
+    #[arg(value_name = "INDEX")]
+    index: u64,
+}
+
+#[derive(Parser)]
+pub struct VerifyArchiveCmd {
+    /// Directory containing the local mirror
+    #[arg(long, value_name = "DIR")]
+    pub dir: PathBuf,
+
+    /// Hex encoded merkle root of the mirror, checked against the current
+    /// remote root, defaults to the root recorded in the mirror manifest
+    #[arg(long, value_name = "HEX")]
+    pub root: Option<String>,
+
+    /// Maximum number of merkle proofs fetched in parallel
+    #[arg(long, value_name = "NUM", default_value = "4")]
+    pub concurrency: usize,
+}
+
+#[derive(Parser)]
+pub struct MetadataCmd {
+    /// File index
     #[arg(value_name = "INDEX")]
-    index: u64
+    index: u64,
+}
+
+#[derive(Parser)]
+pub struct FindCmd {
+    /// Hex encoded sha256 of the file content
+    #[arg(value_name = "SHA256")]
+    sha256: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum SortBy {
+    /// Ascending file index
+    #[default]
+    Index,
+    /// Most downloaded first
+    Downloads,
+    /// Most recently downloaded first
+    LastAccess,
+}
+
+#[derive(Parser)]
+pub struct ListCmd {
+    /// Sort order of the listed entries
+    #[arg(long, value_name = "ORDER", default_value = "index")]
+    pub sort_by: SortBy,
+}
+
+fn format_last_access(last_download_ms: u64) -> String {
+    if last_download_ms == 0 {
+        return "never".to_string();
+    }
+    let t = UNIX_EPOCH + Duration::from_millis(last_download_ms);
+    humantime::format_rfc3339_seconds(t).to_string()
+}
+
+fn format_uploaded_at(uploaded_at: u64) -> String {
+    if uploaded_at == 0 {
+        return "unknown".to_string();
+    }
+    let t = UNIX_EPOCH + Duration::from_secs(uploaded_at);
+    humantime::format_rfc3339_seconds(t).to_string()
+}
+
+fn print_entry_info(info: &EntryInfo) {
+    println!(
+        "{} {} {} {} {}",
+        info.index,
+        info.size,
+        info.download_count,
+        format_last_access(info.last_download_ms),
+        info.filename
+    );
 }
 
 async fn run_count_cmd(api: MrklarApi) -> eyre::Result<()> {
@@ -114,27 +350,142 @@ async fn run_count_cmd(api: MrklarApi) -> eyre::Result<()> {
     Ok(())
 }
 
-async fn run_root_cmd(api: MrklarApi) -> eyre::Result<()> {
-    let result = api.root().await?;
-    let root_hex = hex::encode(result);
-    println!("{}", root_hex);
+async fn run_root_cmd(api: MrklarApi, verify: bool) -> eyre::Result<()> {
+    let tree_head = match api.tree_head().await {
+        Ok(tree_head) => tree_head,
+        // the server does not sign its merkle roots
+        Err(ApiError::MissingTreeHead) if !verify => {
+            let result = api.root().await?;
+            println!("{}", hex::encode(result));
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    println!("{}", hex::encode(&tree_head.merkle_root));
+    let t = UNIX_EPOCH + Duration::from_millis(tree_head.timestamp_ms);
+    eprintln!(
+        "signed tree head: size {}, signed at {}, signature {} ({})",
+        tree_head.tree_size,
+        humantime::format_rfc3339_millis(t),
+        hex::encode(&tree_head.signature),
+        if verify {
+            "verified"
+        } else {
+            "not verified, see '--tree-head-key'"
+        },
+    );
     Ok(())
 }
 
-async fn run_upload_cmd(api: MrklarApi, path: &Path) -> eyre::Result<()> {
+async fn run_upload_cmd(
+    api: MrklarApi,
+    path: &Path,
+    verify: bool,
+    dedup: bool,
+) -> eyre::Result<()> {
     let path_buf = path.to_path_buf();
-    let result = api.upload(&path_buf).await?;
-    let file_index = result.0;
-    let root_hex = hex::encode(result.1);
-    println!("{} {}", file_index, root_hex);
+    let outcome = if verify {
+        api.upload_verified(&path_buf).await?
+    } else if dedup {
+        api.upload_dedup(&path_buf).await?
+    } else {
+        api.upload(&path_buf).await?
+    };
+    if outcome.deduplicated {
+        eprintln!("already in the remote archive, nothing uploaded");
+    }
+    println!("{} {}", outcome.index, hex::encode(outcome.root));
     Ok(())
 }
 
-async fn run_download_cmd(api: MrklarApi, index: u64, out_dir: Option<PathBuf>, out_filename: Option<String>, force: bool) -> eyre::Result<()> {
-    let result = api.download(index, out_dir, out_filename, force).await?;
-    println!("path: {}", result.0.display());
-    println!("{}", result.1);
-    println!("verification: {}", if result.2 { "OK" } else { "FAILED" } );
+/// The remote file to download, by index or by filename
+enum DownloadTarget {
+    Index(u64),
+    Name(String),
+}
+
+async fn run_download_cmd(
+    api: MrklarApi,
+    target: DownloadTarget,
+    out_dir: Option<PathBuf>,
+    out_filename: Option<String>,
+    force: bool,
+    expected_root: Option<String>,
+) -> eyre::Result<()> {
+    let expected_root = expected_root.map(hex::decode).transpose()?;
+    let outcome = match target {
+        DownloadTarget::Index(index) => {
+            api.download(index, out_dir, out_filename, force, expected_root)
+                .await?
+        }
+        DownloadTarget::Name(name) => {
+            api.download_by_name(&name, out_dir, out_filename, force, expected_root)
+                .await?
+        }
+    };
+    println!("path: {}", outcome.path.display());
+    println!("sha256: {}", hex::encode(&outcome.sha256));
+    println!(
+        "received: {}/{} bytes, {} chunks in {:.3}s",
+        outcome.stats.bytes,
+        outcome.size,
+        outcome.stats.chunks,
+        outcome.stats.elapsed.as_secs_f64()
+    );
+    println!("{}", outcome.proof);
+    println!(
+        "verification: {}",
+        if outcome.verified { "OK" } else { "FAILED" }
+    );
+    Ok(())
+}
+
+async fn run_download_all_cmd(
+    api: MrklarApi,
+    out_dir: Option<PathBuf>,
+    concurrency: usize,
+) -> eyre::Result<()> {
+    let out_dir = out_dir.unwrap_or_default();
+    let report = api.download_all(&out_dir, concurrency).await?;
+    for entry in &report.entries {
+        match &entry.result {
+            Ok(true) => println!("OK {} {}", entry.index, entry.path.display()),
+            Ok(false) => println!("FAILED {} {}", entry.index, entry.path.display()),
+            Err(e) => println!("ERROR {} {}: {}", entry.index, entry.path.display(), e),
+        }
+    }
+    println!("root: {}", hex::encode(&report.root));
+    if !report.is_ok() {
+        eyre::bail!("some files could not be downloaded or verified");
+    }
+    Ok(())
+}
+
+async fn run_verify_archive_cmd(
+    api: MrklarApi,
+    dir: PathBuf,
+    root: Option<String>,
+    concurrency: usize,
+) -> eyre::Result<()> {
+    let root = root.map(hex::decode).transpose()?;
+    let report = api.verify_mirror(&dir, root, concurrency).await?;
+    for r in &report.results {
+        println!("{} {} {}", r.status, r.index, r.path.display());
+    }
+    println!("root: {}", hex::encode(&report.root));
+    println!(
+        "total: {}, ok: {}, missing: {}, mismatch: {}, index not found: {}, io error: {}, proof error: {}",
+        report.results.len(),
+        report.count(VerifyStatus::Ok),
+        report.count(VerifyStatus::Missing),
+        report.count(VerifyStatus::Mismatch),
+        report.count(VerifyStatus::IndexNotFound),
+        report.count(VerifyStatus::IoError),
+        report.count(VerifyStatus::ProofError)
+    );
+    if !report.is_ok() {
+        eyre::bail!("archive verification failed");
+    }
     Ok(())
 }
 
@@ -144,28 +495,99 @@ async fn run_proof_cmd(api: MrklarApi, index: u64) -> eyre::Result<()> {
     Ok(())
 }
 
+async fn run_metadata_cmd(api: MrklarApi, index: u64) -> eyre::Result<()> {
+    let info = api.metadata(index).await?;
+    println!("index: {}", info.index);
+    println!("filename: {}", info.filename);
+    println!("sha256: {}", hex::encode(&info.sha256));
+    println!("size: {}", info.size);
+    println!("uploaded: {}", format_uploaded_at(info.uploaded_at));
+    println!("downloads: {}", info.download_count);
+    println!("last access: {}", format_last_access(info.last_download_ms));
+    Ok(())
+}
+
+async fn run_list_cmd(api: MrklarApi, sort_by: SortBy) -> eyre::Result<()> {
+    let mut entries = api.list_all().await?;
+    match sort_by {
+        SortBy::Index => entries.sort_by_key(|e| e.index),
+        SortBy::Downloads => entries.sort_by_key(|e| std::cmp::Reverse(e.download_count)),
+        SortBy::LastAccess => entries.sort_by_key(|e| std::cmp::Reverse(e.last_download_ms)),
+    }
+    for info in &entries {
+        print_entry_info(info);
+    }
+    Ok(())
+}
+
+async fn run_find_cmd(api: MrklarApi, sha256: &str) -> eyre::Result<()> {
+    match api.find(&hex::decode(sha256)?).await? {
+        Some(index) => println!("{}", index),
+        None => eyre::bail!("no file with sha256 {}", sha256),
+    }
+    Ok(())
+}
+
+async fn run_stats_cmd(api: MrklarApi) -> eyre::Result<()> {
+    let stats = api.stats().await?;
+    println!("count: {}", stats.count);
+    println!("downloads: {}", stats.total_downloads);
+    println!("stored bytes: {}", stats.total_bytes);
+    println!("tree depth: {}", stats.tree_depth);
+    println!("db file bytes: {}", stats.db_file_bytes);
+    println!("tmp files: {}", stats.tmp_files);
+    println!("tmp bytes: {}", stats.tmp_bytes);
+    println!("maintenance: {}", stats.maintenance);
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     let cli = Cli::parse();
-    let config = cli.net.into_net_config();
-    let api = MrklarApi::new(config);
+    let verify_tree_head = cli.net.tree_head_key.is_some();
+    let api = cli.net.into_api()?;
     match cli.cmd {
-        CliSubcommand::Count => {
-            run_count_cmd(api).await?
-        },
-        CliSubcommand::Root => {
-            run_root_cmd(api).await?
-        },
+        CliSubcommand::Count => run_count_cmd(api).await?,
+        CliSubcommand::Root => run_root_cmd(api, verify_tree_head).await?,
         CliSubcommand::Upload(upload_cmd) => {
             let p = PathBuf::from_str(&upload_cmd.path)?;
-            run_upload_cmd(api, &p).await?
-        },
+            run_upload_cmd(api, &p, upload_cmd.verify, upload_cmd.dedup).await?
+        }
         CliSubcommand::Download(download_cmd) => {
-            run_download_cmd(api, download_cmd.index, download_cmd.out_dir, download_cmd.out_filename, download_cmd.force).await?
-        },
-        CliSubcommand::Proof(proof_cmd) => {
-            run_proof_cmd(api, proof_cmd.index).await?
-        },
+            let api = api.with_strict_verification(!download_cmd.no_strict);
+            let target = match (download_cmd.index, download_cmd.name) {
+                _ if download_cmd.all => None,
+                (Some(index), _) => Some(DownloadTarget::Index(index)),
+                (None, Some(name)) => Some(DownloadTarget::Name(name)),
+                (None, None) => None,
+            };
+            match target {
+                Some(target) => {
+                    run_download_cmd(
+                        api,
+                        target,
+                        download_cmd.out_dir,
+                        download_cmd.out_filename,
+                        download_cmd.force,
+                        download_cmd.expected_root,
+                    )
+                    .await?
+                }
+                None => {
+                    run_download_all_cmd(api, download_cmd.out_dir, download_cmd.concurrency)
+                        .await?
+                }
+            }
+        }
+        CliSubcommand::Proof(proof_cmd) => run_proof_cmd(api, proof_cmd.index).await?,
+        CliSubcommand::Metadata(metadata_cmd) => run_metadata_cmd(api, metadata_cmd.index).await?,
+        CliSubcommand::Find(find_cmd) => run_find_cmd(api, &find_cmd.sha256).await?,
+        CliSubcommand::List(list_cmd) => run_list_cmd(api, list_cmd.sort_by).await?,
+        CliSubcommand::Stats => run_stats_cmd(api).await?,
+        CliSubcommand::VerifyArchive(verify_cmd) => {
+            run_verify_archive_cmd(api, verify_cmd.dir, verify_cmd.root, verify_cmd.concurrency)
+                .await?
+        }
     };
 
     Ok(())
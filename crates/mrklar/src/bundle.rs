@@ -0,0 +1,337 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ServerConfig, error::ServerError, layout::StorageLayout, lock::DbLock, mem_db::MemDb,
+    stored_file,
+};
+
+// A bundle is a zstd compressed tar archive holding, in this order:
+// - `manifest.json`: the entry count, the merkle root and the sha256 of
+//   each entry
+// - `db.bin`: the db file, including the entries of the db journal
+// - `files/...`: the stored files, at their path inside the files db
+//   directory
+const MANIFEST_NAME: &str = "manifest.json";
+const DB_FILE_NAME: &str = "db.bin";
+const FILES_DIR_NAME: &str = "files";
+
+// - version 1: initial version
+const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleManifest {
+    pub version: u32,
+    pub layout: StorageLayout,
+    pub count: u64,
+    /// The hex encoded merkle root, empty if the archive is empty
+    pub root: String,
+    pub entries: Vec<BundleEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub filename: String,
+    /// The hex encoded sha256 of the uploaded content
+    pub sha256: String,
+    /// The size of the uploaded content
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct BundleReport {
+    pub layout: StorageLayout,
+    pub entries: usize,
+    /// The number of stored files, fewer than the entries with the content
+    /// addressed layout
+    pub files: usize,
+    pub root: Option<Vec<u8>>,
+}
+
+/// Writes the whole archive into the bundle at `out`: the db file, every
+/// stored file and a manifest listing the entries. The bundle is written
+/// next to `out` then renamed, a failed export leaves no partial bundle.
+///
+/// The server must be stopped, the db lock is held exclusively during the
+/// whole operation.
+pub fn export_bundle(config: &ServerConfig, out: &Path) -> Result<BundleReport, ServerError> {
+    let config = config.validate()?;
+    let _lock = DbLock::exclusive(&config)?;
+
+    let db = MemDb::try_load(&config)?;
+    export_db(&config, &db, out)
+}
+
+/// Same as `export_bundle` with an already loaded `db`. The db file content,
+/// the entries and the merkle root are read under the db read lock, the
+/// entries added afterwards are not part of the bundle, and the stored
+/// files of the bundled entries are never modified by the uploads.
+pub(crate) fn export_db(
+    config: &ServerConfig,
+    db: &MemDb,
+    out: &Path,
+) -> Result<BundleReport, ServerError> {
+    let snapshot = db.snapshot()?;
+    let files_db_dir = config.files_db_dir();
+
+    let manifest = BundleManifest {
+        version: BUNDLE_VERSION,
+        layout: snapshot.layout,
+        count: snapshot.entries.len() as u64,
+        root: snapshot
+            .merkle_root
+            .as_ref()
+            .map(hex::encode)
+            .unwrap_or_default(),
+        entries: snapshot
+            .entries
+            .iter()
+            .map(|entry| BundleEntry {
+                filename: entry.filename().to_string(),
+                sha256: hex::encode(entry.sha256()),
+                size: entry.size(),
+            })
+            .collect(),
+    };
+    let manifest =
+        serde_json::to_vec_pretty(&manifest).map_err(|e| ServerError::Unexpected(e.to_string()))?;
+
+    let mut tmp_out = out.as_os_str().to_owned();
+    tmp_out.push(".tmp");
+    let tmp_out = PathBuf::from(tmp_out);
+
+    let res = (|| -> Result<usize, ServerError> {
+        let encoder = zstd::Encoder::new(BufWriter::new(File::create(&tmp_out)?), 0)?;
+        let mut builder = tar::Builder::new(encoder);
+        append_bytes(&mut builder, MANIFEST_NAME, &manifest)?;
+        append_bytes(&mut builder, DB_FILE_NAME, &snapshot.bytes)?;
+
+        // a stored file shared by several entries is only bundled once
+        let mut stored_paths = HashSet::new();
+        for (index, entry) in snapshot.entries.iter().enumerate() {
+            let path = snapshot
+                .layout
+                .file_path_at(index, entry.sha256(), &files_db_dir);
+            if !stored_paths.insert(path.clone()) {
+                continue;
+            }
+            if !path.is_file() {
+                return Err(ServerError::StoredFileNotFound(index));
+            }
+            let relative = path.strip_prefix(&files_db_dir).unwrap_or(&path);
+            builder.append_path_with_name(&path, Path::new(FILES_DIR_NAME).join(relative))?;
+        }
+
+        let writer = builder.into_inner()?.finish()?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(&tmp_out, out)?;
+        Ok(stored_paths.len())
+    })();
+    let files = res.inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_out);
+    })?;
+
+    Ok(BundleReport {
+        layout: snapshot.layout,
+        entries: snapshot.entries.len(),
+        files,
+        root: snapshot.merkle_root,
+    })
+}
+
+/// Restores the bundle at `bundle` into the db and files directories, then
+/// verifies it: every stored file is hashed, the merkle root recomputed from
+/// these hashes must be the root of the manifest and of the restored db.
+/// On failure, the restored files are removed.
+///
+/// Refuses to overwrite a non-empty db file, db journal or files db
+/// directory. The server must be stopped, the db lock is held exclusively
+/// during the whole operation.
+pub fn import_bundle(config: &ServerConfig, bundle: &Path) -> Result<BundleReport, ServerError> {
+    let config = config.validate()?;
+    let _lock = DbLock::exclusive(&config)?;
+
+    for db_file in [config.db_file(), config.db_journal_file()] {
+        let len = fs::metadata(&db_file).map(|m| m.len()).unwrap_or(0);
+        if len > 0 {
+            return Err(ServerError::DbFileNotEmpty(db_file.display().to_string()));
+        }
+    }
+    let files_db_dir = config.files_db_dir();
+    if fs::read_dir(&files_db_dir).is_ok_and(|mut dir| dir.next().is_some()) {
+        return Err(ServerError::FilesDbDirNotEmpty(
+            files_db_dir.display().to_string(),
+        ));
+    }
+
+    let res = unpack(&config, bundle).and_then(|manifest| verify(&config, bundle, &manifest));
+    if res.is_err() {
+        let _ = fs::remove_file(config.db_file());
+        let _ = fs::remove_dir_all(&files_db_dir);
+    }
+    res
+}
+
+/// Extracts the db file and the stored files of the bundle, returns its
+/// manifest
+fn unpack(config: &ServerConfig, bundle: &Path) -> Result<BundleManifest, ServerError> {
+    let invalid = |message: String| ServerError::InvalidBundle {
+        path: bundle.display().to_string(),
+        message,
+    };
+    let files_db_dir = config.files_db_dir();
+
+    let decoder = zstd::Decoder::new(File::open(bundle)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut manifest = None;
+    let mut has_db_file = false;
+    for entry in archive.entries().map_err(|e| invalid(e.to_string()))? {
+        let mut entry = entry.map_err(|e| invalid(e.to_string()))?;
+        let path = entry
+            .path()
+            .map_err(|e| invalid(e.to_string()))?
+            .into_owned();
+        if !entry.header().entry_type().is_file() {
+            return Err(invalid(format!("'{}' is not a file", path.display())));
+        }
+
+        if path == Path::new(MANIFEST_NAME) {
+            manifest = Some(
+                serde_json::from_reader::<_, BundleManifest>(&mut entry)
+                    .map_err(|e| invalid(format!("invalid manifest: {}", e)))?,
+            );
+        } else if path == Path::new(DB_FILE_NAME) {
+            entry
+                .unpack(config.db_file())
+                .map_err(|e| invalid(e.to_string()))?;
+            has_db_file = true;
+        } else if let Ok(relative) = path.strip_prefix(FILES_DIR_NAME) {
+            // the stored files never leave the files db directory
+            if !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(invalid(format!("invalid file path '{}'", path.display())));
+            }
+            let dest = files_db_dir.join(relative);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            entry.unpack(&dest).map_err(|e| invalid(e.to_string()))?;
+        } else {
+            return Err(invalid(format!("unexpected entry '{}'", path.display())));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid(format!("missing {}", MANIFEST_NAME)))?;
+    if manifest.version > BUNDLE_VERSION {
+        return Err(invalid(format!(
+            "unsupported bundle version {}",
+            manifest.version
+        )));
+    }
+    if !has_db_file {
+        return Err(invalid(format!("missing {}", DB_FILE_NAME)));
+    }
+    Ok(manifest)
+}
+
+/// Checks the restored db and stored files against the bundle manifest
+fn verify(
+    config: &ServerConfig,
+    bundle: &Path,
+    manifest: &BundleManifest,
+) -> Result<BundleReport, ServerError> {
+    let invalid = |message: String| ServerError::InvalidBundle {
+        path: bundle.display().to_string(),
+        message,
+    };
+
+    let db =
+        MemDb::try_load(config).map_err(|e| invalid(format!("invalid {}: {}", DB_FILE_NAME, e)))?;
+    let entries = db.num_entries();
+    if manifest.count != entries as u64 || manifest.entries.len() != entries {
+        return Err(invalid(format!(
+            "the manifest lists {} entries, the db has {}",
+            manifest.count, entries
+        )));
+    }
+
+    // a stored file shared by several entries is only hashed once
+    let files_db_dir = config.files_db_dir();
+    let mut hashes = HashMap::new();
+    let mut leaves = Vec::with_capacity(entries);
+    for (index, expected) in manifest.entries.iter().enumerate() {
+        let path = db.file_path_at(index, &files_db_dir);
+        let compressed = db.is_compressed_at(index)?;
+        let sha256 = match hashes.get(&path) {
+            Some(sha256) => Vec::clone(sha256),
+            None => {
+                let sha256 = stored_file::sha256(&path, compressed)
+                    .map_err(|e| invalid(format!("stored file of entry {}: {}", index, e)))?;
+                hashes.insert(path, sha256.clone());
+                sha256
+            }
+        };
+        if hex::encode(&sha256) != expected.sha256 {
+            return Err(invalid(format!(
+                "stored file of entry {} does not match the manifest sha256",
+                index
+            )));
+        }
+        leaves.push((sha256, expected.size, compressed));
+    }
+
+    let (root, db_root) = if entries == 0 {
+        (None, None)
+    } else {
+        let recomputed = MemDb::from_leaves(db.layout(), leaves)?;
+        (Some(recomputed.merkle_root()?), Some(db.merkle_root()?))
+    };
+    let hex_root = root.as_ref().map(hex::encode).unwrap_or_default();
+    if hex_root != manifest.root {
+        return Err(invalid(format!(
+            "recomputed merkle root '{}' does not match the manifest root '{}'",
+            hex_root, manifest.root
+        )));
+    }
+    if db_root != root {
+        return Err(invalid(format!(
+            "the {} merkle root does not match the stored files",
+            DB_FILE_NAME
+        )));
+    }
+
+    Ok(BundleReport {
+        layout: db.layout(),
+        entries,
+        files: hashes.len(),
+        root,
+    })
+}
+
+fn append_bytes<W: Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    bytes: &[u8],
+) -> io::Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, name, bytes)
+}
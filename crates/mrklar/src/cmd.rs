@@ -1,77 +1,780 @@
-use crate::config::ServerConfig;
-use clap::Parser;
-use mrklar_common::config::{DEFAULT_SERVER_HOST_STR, DEFAULT_SERVER_PORT_STR};
-use std::{net::IpAddr, path::PathBuf};
+use crate::config::{
+    DuplicatePolicy, FileMode, LogRotation, PersistencePolicy, DEFAULT_LOG_FILE,
+    DEFAULT_MAX_FILENAME_LEN, DEFAULT_REPLICATION_INTERVAL, DEFAULT_SHUTDOWN_GRACE_PERIOD,
+    DEFAULT_STREAM_IDLE_TIMEOUT, DEFAULT_UPLOAD_SESSION_TTL,
+};
+use crate::{
+    bundle::{export_bundle, import_bundle, BundleReport},
+    config::ServerConfig,
+    config_file::ServerConfigFile,
+    error::ServerError,
+    fsck::{fsck, FsckRepair},
+    layout::StorageLayout,
+    migrate::migrate_layout,
+    rebuild::rebuild,
+};
+use clap::{parser::ValueSource, ArgMatches, Parser};
+use mrklar_common::config::{
+    GrpcCompression, DEFAULT_SERVER_HOST_STR, DEFAULT_SERVER_PORT_STR, MAX_CHUNK_SIZE,
+    MAX_MESSAGE_SIZE,
+};
+use std::{fmt, net::IpAddr, path::PathBuf, time::Duration};
+
+/// Default seconds between two writes of the new entries with
+/// '--persistence interval'
+pub const DEFAULT_PERSISTENCE_INTERVAL: u64 = 1;
+
+/// The '--persistence' modes, see `PersistencePolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PersistenceMode {
+    /// Each entry is synced to disk before the upload completes
+    Always,
+    /// The new entries are written every '--persistence-interval' seconds
+    Interval,
+    /// The new entries are only written on shutdown
+    OnShutdown,
+}
+
+impl PersistenceMode {
+    pub(crate) fn into_policy(self, interval_secs: u64) -> PersistencePolicy {
+        match self {
+            PersistenceMode::Always => PersistencePolicy::Always,
+            PersistenceMode::Interval => {
+                PersistencePolicy::Interval(Duration::from_secs(interval_secs.max(1)))
+            }
+            PersistenceMode::OnShutdown => PersistencePolicy::OnShutdown,
+        }
+    }
+}
+
+impl fmt::Display for PersistenceMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistenceMode::Always => write!(fmt, "always"),
+            PersistenceMode::Interval => write!(fmt, "interval"),
+            PersistenceMode::OnShutdown => write!(fmt, "on-shutdown"),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Parser)]
 pub struct ServerCmd {
-    /// Port number to listen on.
+    /// TOML file the server settings are read from, the flags and
+    /// environment variables explicitly set take precedence over it.
+    #[arg(long, value_name = "PATH", env = "MRKLAR_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit.
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Port number to listen on, 0 to listen on any free port.
     #[arg(
-        long, 
-        short, 
-        value_name = "NUM", 
+        long,
+        short,
+        value_name = "NUM",
         env = "MRKLAR_PORT",
-        default_value = DEFAULT_SERVER_PORT_STR, 
+        default_value = DEFAULT_SERVER_PORT_STR,
     )]
     pub port: u16,
 
-    /// The hosts the server will listen on.
+    /// The hosts the server will listen on, on the same port. Can be given
+    /// multiple times or as a comma separated list.
     #[arg(
         long,
         value_name = "IP_ADDR",
         env = "MRKLAR_IP_ADDR",
+        value_delimiter = ',',
         default_value = DEFAULT_SERVER_HOST_STR
     )]
-    pub host: IpAddr,
+    pub host: Vec<IpAddr>,
+
+    /// Listen on a unix domain socket instead of '--host' and '--port',
+    /// unless '--uds-keep-tcp' is set.
+    #[arg(long, value_name = "PATH", env = "MRKLAR_UDS")]
+    pub uds: Option<PathBuf>,
+
+    /// Octal permissions of the '--uds' socket file, such as 660.
+    /// The umask applies if not set.
+    #[arg(long, value_name = "MODE", env = "MRKLAR_UDS_MODE")]
+    pub uds_mode: Option<FileMode>,
+
+    /// Also listen on '--host' and '--port' when '--uds' is set.
+    #[arg(long, env = "MRKLAR_UDS_KEEP_TCP")]
+    pub uds_keep_tcp: bool,
+
+    /// Compression of the grpc messages sent to the clients accepting it:
+    /// none, gzip or zstd. The compressed client messages are always accepted.
+    #[arg(
+        long,
+        value_name = "COMPRESSION",
+        env = "MRKLAR_GRPC_COMPRESSION",
+        default_value_t = GrpcCompression::None,
+    )]
+    pub grpc_compression: GrpcCompression,
+
+    /// Largest grpc message sent or accepted, in bytes. Must hold a chunk of
+    /// '--max-chunk-size' bytes plus 64 KiB of message overhead.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "MRKLAR_MAX_MESSAGE_SIZE",
+        default_value_t = MAX_MESSAGE_SIZE,
+    )]
+    pub max_message_size: usize,
 
     /// Server db directory.
     #[arg(
-        long, 
+        long,
         value_name = "DB_DIR",
         env = "MRKLAR_DB_DIR",
+        required_unless_present = "config"
     )]
-    pub db_dir: PathBuf,
+    pub db_dir: Option<PathBuf>,
 
     /// Server files db directory.
     #[arg(
-        long, 
+        long,
         value_name = "FILES_DIR",
         env = "MRKLAR_FILES_DIR",
+        required_unless_present = "config"
     )]
-    pub files_dir: PathBuf,
+    pub files_dir: Option<PathBuf>,
+
+    /// Create the db and files directories, with their parents, if they do
+    /// not exist. Otherwise the server fails to start without them.
+    #[arg(long, env = "MRKLAR_CREATE_DIRS")]
+    pub create_dirs: bool,
 
     /// Enable/disable server trace [default:true].
-    #[arg(
-        long,
-        env = "MRKLAR_TRACING",
-    )]
+    #[arg(long, env = "MRKLAR_TRACING")]
     pub tracing: bool,
 
     /// Server log level.
     #[arg(
-        long, 
-        value_parser = ["error", "warn", "info", "debug", "trace"], 
-        default_value = "info", 
+        long,
+        value_parser = ["error", "warn", "info", "debug", "trace"],
+        default_value = "info",
         value_name = "LEVEL",
         env = "MRKLAR_TRACING_LEVEL",
     )]
     pub tracing_level: String,
+
+    /// Also write the server traces to rolling log files in this directory.
+    #[arg(long, value_name = "DIR", env = "MRKLAR_LOG_DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Name of the log file in '--log-dir', suffixed with the date when rotated.
+    #[arg(
+        long,
+        value_name = "NAME",
+        env = "MRKLAR_LOG_FILE",
+        default_value = DEFAULT_LOG_FILE,
+    )]
+    pub log_file: String,
+
+    /// How often a new log file is started.
+    #[arg(
+        long,
+        value_name = "ROTATION",
+        env = "MRKLAR_LOG_ROTATION",
+        default_value_t = LogRotation::Daily,
+    )]
+    pub log_rotation: LogRotation,
+
+    /// Maximum number of log files kept in '--log-dir', the oldest ones are removed.
+    #[arg(long, value_name = "NUM", env = "MRKLAR_LOG_MAX_FILES")]
+    pub log_max_files: Option<usize>,
+
+    /// Do not write the server traces to stdout, only to the '--log-dir' files.
+    #[arg(long, env = "MRKLAR_NO_LOG_STDOUT", requires = "log_dir")]
+    pub no_log_stdout: bool,
+
+    /// Maximum number of bytes per second sent by a single download stream.
+    #[arg(
+        long,
+        value_name = "BYTES_PER_SEC",
+        env = "MRKLAR_MAX_DOWNLOAD_BYTES_PER_SEC_PER_STREAM"
+    )]
+    pub max_download_bytes_per_sec_per_stream: Option<u64>,
+
+    /// Maximum number of bytes per second sent by all the download streams.
+    #[arg(
+        long,
+        value_name = "BYTES_PER_SEC",
+        env = "MRKLAR_MAX_DOWNLOAD_BYTES_PER_SEC"
+    )]
+    pub max_download_bytes_per_sec: Option<u64>,
+
+    /// Seconds given to the in-flight uploads and downloads to complete on
+    /// shutdown, the transfers still running afterwards are aborted.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "MRKLAR_SHUTDOWN_GRACE_PERIOD",
+        default_value_t = DEFAULT_SHUTDOWN_GRACE_PERIOD.as_secs(),
+    )]
+    pub shutdown_grace_period: u64,
+
+    /// Maximum number of uploads running concurrently, the excess uploads
+    /// are rejected unless '--queue-uploads' is set.
+    #[arg(long, value_name = "NUM", env = "MRKLAR_MAX_CONCURRENT_UPLOADS")]
+    pub max_concurrent_uploads: Option<usize>,
+
+    /// Queue the uploads exceeding '--max-concurrent-uploads' instead of rejecting them.
+    #[arg(long, env = "MRKLAR_QUEUE_UPLOADS")]
+    pub queue_uploads: bool,
+
+    /// Seconds after which an upload without any incoming message, or a
+    /// download not consumed by the client, is aborted.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "MRKLAR_STREAM_IDLE_TIMEOUT",
+        default_value_t = DEFAULT_STREAM_IDLE_TIMEOUT.as_secs(),
+    )]
+    pub stream_idle_timeout: u64,
+
+    /// Seconds after which an upload session without any activity expires.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "MRKLAR_UPLOAD_SESSION_TTL",
+        default_value_t = DEFAULT_UPLOAD_SESSION_TTL.as_secs(),
+    )]
+    pub upload_session_ttl: u64,
+
+    /// Largest chunk accepted in uploads and streamed in downloads, in
+    /// bytes. Advertised to the clients, the larger upload chunks are rejected.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "MRKLAR_MAX_CHUNK_SIZE",
+        default_value_t = MAX_CHUNK_SIZE,
+    )]
+    pub max_chunk_size: usize,
+
+    /// Longest filename accepted in uploads, in bytes.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "MRKLAR_MAX_FILENAME_LEN",
+        default_value_t = DEFAULT_MAX_FILENAME_LEN,
+    )]
+    pub max_filename_len: usize,
+
+    /// What to do with an uploaded file having the same sha256 as an existing entry.
+    #[arg(
+        long,
+        value_name = "POLICY",
+        env = "MRKLAR_DUPLICATE_POLICY",
+        default_value_t = DuplicatePolicy::Allow,
+    )]
+    pub duplicate_policy: DuplicatePolicy,
+
+    /// When the entries added by the uploads are written to disk.
+    #[arg(
+        long,
+        value_name = "MODE",
+        env = "MRKLAR_PERSISTENCE",
+        default_value_t = PersistenceMode::Always,
+    )]
+    pub persistence: PersistenceMode,
+
+    /// Seconds between two writes of the new entries with '--persistence interval'.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "MRKLAR_PERSISTENCE_INTERVAL",
+        default_value_t = DEFAULT_PERSISTENCE_INTERVAL,
+    )]
+    pub persistence_interval: u64,
+
+    /// How the stored files are organized, an existing archive is migrated
+    /// to this layout at startup.
+    #[arg(
+        long,
+        value_name = "LAYOUT",
+        env = "MRKLAR_STORAGE_LAYOUT",
+        default_value_t = StorageLayout::Sharded,
+    )]
+    pub storage_layout: StorageLayout,
+
+    /// Hash the stored files while they are downloaded and abort the
+    /// downloads of the corrupted ones.
+    #[arg(long, env = "MRKLAR_VERIFY_ON_DOWNLOAD")]
+    pub verify_on_download: bool,
+
+    /// Compress the uploaded files with zstd at this level before storing
+    /// them, the downloads are decompressed on the fly.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        env = "MRKLAR_COMPRESSION_LEVEL",
+        value_parser = clap::value_parser!(i32).range(1..=22),
+    )]
+    pub compression_level: Option<i32>,
+
+    /// PEM encoded server certificate chain, enables TLS. Requires '--tls-key'.
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "MRKLAR_TLS_CERT",
+        requires = "tls_key"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM encoded private key of the '--tls-cert' certificate.
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "MRKLAR_TLS_KEY",
+        requires = "tls_cert"
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM encoded CA certificates verifying the client certificates, enables
+    /// client authentication: the clients without a valid certificate are
+    /// rejected. Requires '--tls-cert'.
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "MRKLAR_TLS_CLIENT_CA",
+        requires = "tls_cert"
+    )]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// PEM encoded PKCS#8 ed25519 key signing the merkle roots returned to
+    /// the clients. A new key is generated if the file does not exist.
+    /// Requires the `always` persistence policy.
+    #[arg(long, value_name = "PATH", env = "MRKLAR_SIGNING_KEY")]
+    pub signing_key: Option<PathBuf>,
+
+    /// Url of a primary server to mirror. The server becomes a read-only
+    /// replica: it pulls the new entries of the primary, verifies them and
+    /// appends them to its own archive. The uploads are rejected.
+    #[arg(long, value_name = "URL", env = "MRKLAR_REPLICATE_FROM")]
+    pub replicate_from: Option<String>,
+
+    /// Seconds between two pulls of the new entries of the primary.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "MRKLAR_REPLICATION_INTERVAL",
+        default_value_t = DEFAULT_REPLICATION_INTERVAL.as_secs(),
+    )]
+    pub replication_interval: u64,
 }
 
 impl ServerCmd {
     pub fn into_server_config(self) -> ServerConfig {
         ServerConfig::default()
             .with_port(self.port)
-            .with_host(self.host)
-            .with_db_dir(self.db_dir)
-            .with_files_dir(self.files_dir)
+            .with_hosts(self.host)
+            .with_uds_path(self.uds)
+            .with_uds_mode(self.uds_mode)
+            .with_uds_keep_tcp(self.uds_keep_tcp)
+            .with_grpc_compression(self.grpc_compression)
+            .with_max_message_size(self.max_message_size)
+            .with_db_dir(self.db_dir.unwrap_or_default())
+            .with_files_dir(self.files_dir.unwrap_or_default())
+            .with_create_missing_dirs(self.create_dirs)
             .with_tracing(self.tracing)
             .with_tracing_level(&self.tracing_level)
+            .with_log_dir(self.log_dir)
+            .with_log_file(&self.log_file)
+            .with_log_rotation(self.log_rotation)
+            .with_log_max_files(self.log_max_files)
+            .with_log_stdout(!self.no_log_stdout)
+            .with_max_download_bytes_per_sec_per_stream(self.max_download_bytes_per_sec_per_stream)
+            .with_max_download_bytes_per_sec(self.max_download_bytes_per_sec)
+            .with_shutdown_grace_period(Duration::from_secs(self.shutdown_grace_period))
+            .with_max_concurrent_uploads(self.max_concurrent_uploads)
+            .with_queue_uploads(self.queue_uploads)
+            .with_stream_idle_timeout(Duration::from_secs(self.stream_idle_timeout))
+            .with_upload_session_ttl(Duration::from_secs(self.upload_session_ttl))
+            .with_max_chunk_size(self.max_chunk_size)
+            .with_max_filename_len(self.max_filename_len)
+            .with_duplicate_policy(self.duplicate_policy)
+            .with_persistence(self.persistence.into_policy(self.persistence_interval))
+            .with_storage_layout(self.storage_layout)
+            .with_verify_on_download(self.verify_on_download)
+            .with_compression_level(self.compression_level)
+            .with_tls_cert(self.tls_cert)
+            .with_tls_key(self.tls_key)
+            .with_tls_client_ca(self.tls_client_ca)
+            .with_signing_key(self.signing_key)
+            .with_replicate_from(self.replicate_from)
+            .with_replication_interval(Duration::from_secs(self.replication_interval))
+    }
+
+    /// Returns the server config of the '--config' file overridden by the
+    /// flags and environment variables explicitly set in `matches`, the
+    /// arguments `self` has been parsed from. The flag defaults apply to the
+    /// settings set by neither. Fails with `ServerError::MissingConfigKey` if
+    /// the db or files directory is not set.
+    pub fn into_merged_server_config(
+        self,
+        matches: &ArgMatches,
+    ) -> Result<ServerConfig, ServerError> {
+        let Some(path) = self.config.clone() else {
+            return Ok(self.into_server_config());
+        };
+        let (file, unknown_keys) = ServerConfigFile::load(&path)?;
+        for key in unknown_keys {
+            eprintln!(
+                "Warning: ignoring unknown key '{}' in config file '{}'",
+                key,
+                path.display()
+            );
+        }
+
+        let explicit = |id: &str| {
+            matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        let secs = Duration::from_secs;
+        let mut config = file.apply(self.clone().into_server_config());
+        if explicit("port") {
+            config = config.with_port(self.port);
+        }
+        if explicit("host") {
+            config = config.with_hosts(self.host.clone());
+        }
+        if explicit("uds") {
+            config = config.with_uds_path(self.uds);
+        }
+        if explicit("uds_mode") {
+            config = config.with_uds_mode(self.uds_mode);
+        }
+        if explicit("uds_keep_tcp") {
+            config = config.with_uds_keep_tcp(self.uds_keep_tcp);
+        }
+        if explicit("grpc_compression") {
+            config = config.with_grpc_compression(self.grpc_compression);
+        }
+        if explicit("max_message_size") {
+            config = config.with_max_message_size(self.max_message_size);
+        }
+        if let Some(dir) = self.db_dir {
+            config = config.with_db_dir(dir);
+        }
+        if let Some(dir) = self.files_dir {
+            config = config.with_files_dir(dir);
+        }
+        if explicit("create_dirs") {
+            config = config.with_create_missing_dirs(self.create_dirs);
+        }
+        if explicit("tracing") {
+            config = config.with_tracing(self.tracing);
+        }
+        if explicit("tracing_level") {
+            config = config.with_tracing_level(&self.tracing_level);
+        }
+        if explicit("log_dir") {
+            config = config.with_log_dir(self.log_dir);
+        }
+        if explicit("log_file") {
+            config = config.with_log_file(&self.log_file);
+        }
+        if explicit("log_rotation") {
+            config = config.with_log_rotation(self.log_rotation);
+        }
+        if explicit("log_max_files") {
+            config = config.with_log_max_files(self.log_max_files);
+        }
+        if explicit("no_log_stdout") {
+            config = config.with_log_stdout(!self.no_log_stdout);
+        }
+        if explicit("max_download_bytes_per_sec_per_stream") {
+            config = config.with_max_download_bytes_per_sec_per_stream(
+                self.max_download_bytes_per_sec_per_stream,
+            );
+        }
+        if explicit("max_download_bytes_per_sec") {
+            config = config.with_max_download_bytes_per_sec(self.max_download_bytes_per_sec);
+        }
+        if explicit("shutdown_grace_period") {
+            config = config.with_shutdown_grace_period(secs(self.shutdown_grace_period));
+        }
+        if explicit("max_concurrent_uploads") {
+            config = config.with_max_concurrent_uploads(self.max_concurrent_uploads);
+        }
+        if explicit("queue_uploads") {
+            config = config.with_queue_uploads(self.queue_uploads);
+        }
+        if explicit("stream_idle_timeout") {
+            config = config.with_stream_idle_timeout(secs(self.stream_idle_timeout));
+        }
+        if explicit("upload_session_ttl") {
+            config = config.with_upload_session_ttl(secs(self.upload_session_ttl));
+        }
+        if explicit("max_chunk_size") {
+            config = config.with_max_chunk_size(self.max_chunk_size);
+        }
+        if explicit("max_filename_len") {
+            config = config.with_max_filename_len(self.max_filename_len);
+        }
+        if explicit("duplicate_policy") {
+            config = config.with_duplicate_policy(self.duplicate_policy);
+        }
+        // the interval of the file applies to an explicit '--persistence interval'
+        let interval = match config.persistence() {
+            PersistencePolicy::Interval(interval) if !explicit("persistence_interval") => {
+                interval.as_secs()
+            }
+            _ => self.persistence_interval,
+        };
+        if explicit("persistence") {
+            config = config.with_persistence(self.persistence.into_policy(interval));
+        } else if let PersistencePolicy::Interval(_) = config.persistence() {
+            config = config.with_persistence(PersistenceMode::Interval.into_policy(interval));
+        }
+        if explicit("storage_layout") {
+            config = config.with_storage_layout(self.storage_layout);
+        }
+        if explicit("verify_on_download") {
+            config = config.with_verify_on_download(self.verify_on_download);
+        }
+        if explicit("compression_level") {
+            config = config.with_compression_level(self.compression_level);
+        }
+        if explicit("tls_cert") {
+            config = config.with_tls_cert(self.tls_cert);
+        }
+        if explicit("tls_key") {
+            config = config.with_tls_key(self.tls_key);
+        }
+        if explicit("tls_client_ca") {
+            config = config.with_tls_client_ca(self.tls_client_ca);
+        }
+        if explicit("signing_key") {
+            config = config.with_signing_key(self.signing_key);
+        }
+        if explicit("replicate_from") {
+            config = config.with_replicate_from(self.replicate_from);
+        }
+        if explicit("replication_interval") {
+            config = config.with_replication_interval(secs(self.replication_interval));
+        }
+
+        for (key, dir) in [
+            ("db_dir", config.db_dir()),
+            ("files_dir", config.files_dir()),
+        ] {
+            if dir.as_os_str().is_empty() {
+                return Err(ServerError::MissingConfigKey(key.to_string()));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Starts the server, or prints its config with '--print-config'.
+    /// `matches` are the arguments `self` has been parsed from.
+    pub async fn run(self, matches: &ArgMatches) -> eyre::Result<()> {
+        let print_config = self.print_config;
+        let config = self.into_merged_server_config(matches)?;
+        if print_config {
+            print!("{}", ServerConfigFile::from(&config).to_toml()?);
+            return Ok(());
+        }
+        crate::run_until_shutdown(crate::start(config).await?).await
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct MigrateLayoutCmd {
+    /// Server db directory.
+    #[arg(long, value_name = "DB_DIR", env = "MRKLAR_DB_DIR")]
+    pub db_dir: PathBuf,
+
+    /// Server files db directory.
+    #[arg(long, value_name = "FILES_DIR", env = "MRKLAR_FILES_DIR")]
+    pub files_dir: PathBuf,
+
+    /// The target storage layout.
+    #[arg(long, value_name = "LAYOUT")]
+    pub to: StorageLayout,
+
+    /// Verify every stored file instead of a sample.
+    #[arg(long)]
+    pub verify: bool,
+}
+
+impl MigrateLayoutCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let config = ServerConfig::default()
+            .with_tracing(false)
+            .with_db_dir(self.db_dir)
+            .with_files_dir(self.files_dir);
+
+        let report = migrate_layout(&config, self.to, self.verify)?;
+        println!(
+            "layout: {} -> {}, entries: {}, moved: {}, verified: {}",
+            report.from, report.to, report.entries, report.moved, report.verified
+        );
+        Ok(())
     }
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct FsckCmd {
+    /// Server db directory.
+    #[arg(long, value_name = "DB_DIR", env = "MRKLAR_DB_DIR")]
+    pub db_dir: PathBuf,
+
+    /// Server files db directory.
+    #[arg(long, value_name = "FILES_DIR", env = "MRKLAR_FILES_DIR")]
+    pub files_dir: PathBuf,
+
+    /// Move the corrupted and extra files into the files 'quarantine' directory.
+    #[arg(long, value_name = "MODE")]
+    pub repair: Option<FsckRepair>,
+}
+
+impl FsckCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let config = ServerConfig::default()
+            .with_tracing(false)
+            .with_db_dir(self.db_dir)
+            .with_files_dir(self.files_dir);
 
-    pub async fn run(self) -> eyre::Result<()> {
-        let config = self.into_server_config();
-        crate::try_spawn(config).await?;
+        let report = fsck(&config, self.repair)?;
+        for index in &report.corrupted {
+            println!("CORRUPTED {}", index);
+        }
+        for index in &report.missing {
+            println!("MISSING {}", index);
+        }
+        for path in &report.extra {
+            println!("EXTRA {}", path.display());
+        }
+        println!(
+            "entries: {}, corrupted: {}, missing: {}, extra: {}",
+            report.entries,
+            report.corrupted.len(),
+            report.missing.len(),
+            report.extra.len()
+        );
+        if let Some(dir) = &report.quarantine_dir {
+            println!("quarantine: {}", dir.display());
+        }
+        if report.has_total_bytes_drift() {
+            println!(
+                "WARNING the db records {} stored bytes, the stored files hold {} bytes",
+                report.total_bytes, report.stored_bytes
+            );
+        }
+        if !report.is_ok() {
+            eyre::bail!("the archive failed the integrity check");
+        }
         Ok(())
     }
 }
+
+#[derive(Clone, Debug, Parser)]
+pub struct RebuildCmd {
+    /// Server db directory.
+    #[arg(long, value_name = "DB_DIR", env = "MRKLAR_DB_DIR")]
+    pub db_dir: PathBuf,
+
+    /// Server files db directory.
+    #[arg(long, value_name = "FILES_DIR", env = "MRKLAR_FILES_DIR")]
+    pub files_dir: PathBuf,
+
+    /// Overwrite the existing db file.
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl RebuildCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let config = ServerConfig::default()
+            .with_tracing(false)
+            .with_db_dir(self.db_dir)
+            .with_files_dir(self.files_dir);
+
+        let report = rebuild(&config, self.force)?;
+        println!(
+            "layout: {}, entries: {}, root: {}",
+            report.layout,
+            report.entries,
+            report.root.map(hex::encode).unwrap_or_default()
+        );
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ExportCmd {
+    /// Server db directory.
+    #[arg(long, value_name = "DB_DIR", env = "MRKLAR_DB_DIR")]
+    pub db_dir: PathBuf,
+
+    /// Server files db directory.
+    #[arg(long, value_name = "FILES_DIR", env = "MRKLAR_FILES_DIR")]
+    pub files_dir: PathBuf,
+
+    /// The bundle file to write, a zstd compressed tar archive.
+    #[arg(long, value_name = "BUNDLE")]
+    pub out: PathBuf,
+}
+
+impl ExportCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let config = ServerConfig::default()
+            .with_tracing(false)
+            .with_db_dir(self.db_dir)
+            .with_files_dir(self.files_dir);
+
+        let report = export_bundle(&config, &self.out)?;
+        print_bundle_report(&report);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+pub struct ImportCmd {
+    /// Server db directory.
+    #[arg(long, value_name = "DB_DIR", env = "MRKLAR_DB_DIR")]
+    pub db_dir: PathBuf,
+
+    /// Server files db directory.
+    #[arg(long, value_name = "FILES_DIR", env = "MRKLAR_FILES_DIR")]
+    pub files_dir: PathBuf,
+
+    /// Create the db and files directories, with their parents, if they do
+    /// not exist.
+    #[arg(long, env = "MRKLAR_CREATE_DIRS")]
+    pub create_dirs: bool,
+
+    /// The bundle file written by 'mrklar export'.
+    #[arg(value_name = "BUNDLE")]
+    pub bundle: PathBuf,
+}
+
+impl ImportCmd {
+    pub fn run(self) -> eyre::Result<()> {
+        let config = ServerConfig::default()
+            .with_tracing(false)
+            .with_db_dir(self.db_dir)
+            .with_files_dir(self.files_dir)
+            .with_create_missing_dirs(self.create_dirs);
+
+        let report = import_bundle(&config, &self.bundle)?;
+        print_bundle_report(&report);
+        Ok(())
+    }
+}
+
+fn print_bundle_report(report: &BundleReport) {
+    println!(
+        "layout: {}, entries: {}, files: {}, root: {}",
+        report.layout,
+        report.entries,
+        report.files,
+        report.root.as_ref().map(hex::encode).unwrap_or_default()
+    );
+}
@@ -1,27 +1,255 @@
-use mrklar_common::config::NetConfig;
+use mrklar_common::config::{
+    validate_chunk_size, validate_message_size, GrpcCompression, NetConfig, MAX_CHUNK_SIZE,
+};
 use mrklar_fs::{absolute_path, create_dir_if_needed, get_test_db_dir, get_test_files_dir};
 use std::{
-    fmt, net::{IpAddr, SocketAddr}, path::PathBuf, str::FromStr
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
+use crate::{
+    error::ServerError,
+    layout::StorageLayout,
+    storage::{DB_PREFIX, TMP_PREFIX},
 };
 
-use crate::error::ServerError;
+/// Time given to the in-flight uploads and downloads to complete on shutdown
+pub const DEFAULT_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Time after which a transfer stream without any activity is aborted
+pub const DEFAULT_STREAM_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time after which an upload session without any activity expires
+pub const DEFAULT_UPLOAD_SESSION_TTL: Duration = Duration::from_secs(3600);
+
+/// Time between two pulls of the new entries of the primary by a replica
+pub const DEFAULT_REPLICATION_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest filename accepted in uploads, in bytes
+pub const DEFAULT_MAX_FILENAME_LEN: usize = 255;
+
+/// What the server does with an uploaded file having the same sha256 as
+/// an existing entry
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DuplicatePolicy {
+    /// A new entry is added
+    #[default]
+    Allow,
+    /// The upload is rejected with `ALREADY_EXISTS`, the status details
+    /// hold the `FileIndex` of the existing entry
+    Reject,
+    /// The index of the existing entry and the current merkle root are
+    /// returned, no entry is added
+    Dedup,
+}
+
+/// When the entries added by the uploads are written to disk. An upload
+/// completes once its entry is in the in-memory merkle tree, with a policy
+/// other than `Always` the entries added since the last write are lost if
+/// the server crashes. Nothing is lost on a clean shutdown.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PersistencePolicy {
+    /// Each entry is written and synced to disk before the upload completes
+    #[default]
+    Always,
+    /// The new entries are written in a single batch at this interval
+    Interval(Duration),
+    /// The new entries are only written on shutdown
+    OnShutdown,
+}
+
+impl fmt::Display for PersistencePolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistencePolicy::Always => write!(fmt, "always"),
+            PersistencePolicy::Interval(interval) => write!(fmt, "interval({:?})", interval),
+            PersistencePolicy::OnShutdown => write!(fmt, "on-shutdown"),
+        }
+    }
+}
+
+/// How often the server log file is rotated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    #[default]
+    Daily,
+    /// A single log file is written
+    Never,
+}
+
+impl From<LogRotation> for tracing_appender::rolling::Rotation {
+    fn from(value: LogRotation) -> Self {
+        match value {
+            LogRotation::Minutely => Self::MINUTELY,
+            LogRotation::Hourly => Self::HOURLY,
+            LogRotation::Daily => Self::DAILY,
+            LogRotation::Never => Self::NEVER,
+        }
+    }
+}
+
+impl fmt::Display for LogRotation {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogRotation::Minutely => write!(fmt, "minutely"),
+            LogRotation::Hourly => write!(fmt, "hourly"),
+            LogRotation::Daily => write!(fmt, "daily"),
+            LogRotation::Never => write!(fmt, "never"),
+        }
+    }
+}
+
+/// Default name of the server log file, suffixed with the date when rotated
+pub const DEFAULT_LOG_FILE: &str = "mrklar.log";
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DuplicatePolicy::Allow => write!(fmt, "allow"),
+            DuplicatePolicy::Reject => write!(fmt, "reject"),
+            DuplicatePolicy::Dedup => write!(fmt, "dedup"),
+        }
+    }
+}
+
+/// Unix permissions of a file, parsed from and displayed in octal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileMode(pub u32);
+
+impl fmt::Display for FileMode {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{:04o}", self.0)
+    }
+}
+
+impl FromStr for FileMode {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if !digits.starts_with('+') && mode <= 0o7777 => Ok(FileMode(mode)),
+            _ => Err(ServerError::InvalidFileMode(s.to_string())),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
     pub net: NetConfig,
+    // all the addresses listened on, the `net` host only if empty
+    hosts: Vec<IpAddr>,
+    // permissions of the unix domain socket file, the umask applies if unset
+    uds_mode: Option<FileMode>,
+    // also listen on the tcp hosts when listening on a unix domain socket
+    uds_keep_tcp: bool,
     db_dir: PathBuf,
     files_dir: PathBuf,
+    // create the missing db and files directories, with their parents, in
+    // `validate`
+    create_missing_dirs: bool,
     tracing: bool,
     tracing_level: tracing::Level,
+    // the traces are also written to rolling log files in `log_dir`
+    log_dir: Option<PathBuf>,
+    log_file: String,
+    log_rotation: LogRotation,
+    log_max_files: Option<usize>,
+    log_stdout: bool,
+    max_download_bytes_per_sec_per_stream: Option<u64>,
+    max_download_bytes_per_sec: Option<u64>,
+    shutdown_grace_period: Duration,
+    max_concurrent_uploads: Option<usize>,
+    queue_uploads: bool,
+    stream_idle_timeout: Duration,
+    upload_session_ttl: Duration,
+    // largest chunk accepted in uploads and streamed in downloads, advertised
+    // to the clients
+    max_chunk_size: usize,
+    // longest filename accepted in uploads, in bytes
+    max_filename_len: usize,
+    duplicate_policy: DuplicatePolicy,
+    persistence: PersistencePolicy,
+    // the layout of the new archives, the existing ones are migrated to it
+    // at startup
+    storage_layout: StorageLayout,
+    // hash the stored files while they are downloaded
+    verify_on_download: bool,
+    // zstd level of the stored files compression, not compressed if unset
+    compression_level: Option<i32>,
+    // PEM encoded certificate chain and private key files
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    // PEM encoded CA certificates the client certificates must chain to
+    tls_client_ca: Option<PathBuf>,
+    // PEM encoded PKCS#8 ed25519 key signing the merkle roots, generated if
+    // the file does not exist
+    signing_key: Option<PathBuf>,
+    // url of the primary server mirrored by this server, the uploads are
+    // rejected if set
+    replicate_from: Option<String>,
+    replication_interval: Duration,
 }
 
 impl fmt::Display for ServerConfig {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(fmt, "{}", self.net)?;
+        writeln!(fmt, "hosts={:?}", self.hosts())?;
+        writeln!(fmt, "uds_mode={:?}", self.uds_mode.map(|m| m.to_string()))?;
+        writeln!(fmt, "uds_keep_tcp={:?}", self.uds_keep_tcp)?;
         writeln!(fmt, "db_dir={:?}", self.db_dir)?;
         writeln!(fmt, "files_dir={:?}", self.files_dir)?;
+        writeln!(fmt, "create_missing_dirs={:?}", self.create_missing_dirs)?;
         writeln!(fmt, "tracing={:?}", self.tracing)?;
-        write!(fmt, "tracing_level={:?}", self.tracing_level)?;
+        writeln!(fmt, "tracing_level={:?}", self.tracing_level)?;
+        writeln!(fmt, "log_dir={:?}", self.log_dir)?;
+        writeln!(fmt, "log_file={:?}", self.log_file)?;
+        writeln!(fmt, "log_rotation={}", self.log_rotation)?;
+        writeln!(fmt, "log_max_files={:?}", self.log_max_files)?;
+        writeln!(fmt, "log_stdout={:?}", self.log_stdout)?;
+        writeln!(
+            fmt,
+            "max_download_bytes_per_sec_per_stream={:?}",
+            self.max_download_bytes_per_sec_per_stream
+        )?;
+        writeln!(
+            fmt,
+            "max_download_bytes_per_sec={:?}",
+            self.max_download_bytes_per_sec
+        )?;
+        writeln!(
+            fmt,
+            "shutdown_grace_period={:?}",
+            self.shutdown_grace_period
+        )?;
+        writeln!(
+            fmt,
+            "max_concurrent_uploads={:?}",
+            self.max_concurrent_uploads
+        )?;
+        writeln!(fmt, "queue_uploads={:?}", self.queue_uploads)?;
+        writeln!(fmt, "stream_idle_timeout={:?}", self.stream_idle_timeout)?;
+        writeln!(fmt, "upload_session_ttl={:?}", self.upload_session_ttl)?;
+        writeln!(fmt, "max_chunk_size={:?}", self.max_chunk_size)?;
+        writeln!(fmt, "max_filename_len={:?}", self.max_filename_len)?;
+        writeln!(fmt, "duplicate_policy={}", self.duplicate_policy)?;
+        writeln!(fmt, "persistence={}", self.persistence)?;
+        writeln!(fmt, "storage_layout={}", self.storage_layout)?;
+        writeln!(fmt, "verify_on_download={:?}", self.verify_on_download)?;
+        writeln!(fmt, "compression_level={:?}", self.compression_level)?;
+        writeln!(fmt, "tls_cert={:?}", self.tls_cert)?;
+        writeln!(fmt, "tls_key={:?}", self.tls_key)?;
+        writeln!(fmt, "tls_client_ca={:?}", self.tls_client_ca)?;
+        writeln!(fmt, "signing_key={:?}", self.signing_key)?;
+        writeln!(fmt, "replicate_from={:?}", self.replicate_from)?;
+        write!(fmt, "replication_interval={:?}", self.replication_interval)?;
         Ok(())
     }
 }
@@ -38,6 +266,50 @@ impl ServerConfig {
     #[must_use]
     pub fn with_host(mut self, host: IpAddr) -> Self {
         self.net.host = host;
+        self.hosts.clear();
+        self
+    }
+
+    /// Listens on every address of `hosts`, on the same port. The first one
+    /// is also the `net` host. An empty list keeps the current host.
+    #[must_use]
+    pub fn with_hosts(mut self, hosts: Vec<IpAddr>) -> Self {
+        if let Some(host) = hosts.first() {
+            self.net.host = *host;
+            self.hosts = hosts;
+        }
+        self
+    }
+
+    /// Listens on the unix domain socket at `path` instead of tcp, see
+    /// `with_uds_keep_tcp`
+    #[must_use]
+    pub fn with_uds_path(mut self, path: Option<PathBuf>) -> Self {
+        self.net.uds_path = path;
+        self
+    }
+
+    /// Sets the permissions of the unix domain socket file, access to the
+    /// server is then controlled by the filesystem
+    #[must_use]
+    pub fn with_uds_mode(mut self, mode: Option<FileMode>) -> Self {
+        self.uds_mode = mode;
+        self
+    }
+
+    /// Also listens on the tcp hosts and port when a unix domain socket is
+    /// set. By default, the unix domain socket replaces tcp.
+    #[must_use]
+    pub fn with_uds_keep_tcp(mut self, keep_tcp: bool) -> Self {
+        self.uds_keep_tcp = keep_tcp;
+        self
+    }
+
+    /// Sets the compression of the grpc messages sent to the clients
+    /// accepting it. The compressed client messages are always accepted.
+    #[must_use]
+    pub fn with_grpc_compression(mut self, compression: GrpcCompression) -> Self {
+        self.net.grpc_compression = compression;
         self
     }
 
@@ -53,6 +325,15 @@ impl ServerConfig {
         self
     }
 
+    /// Creates the db and files directories, with their parents, if they do
+    /// not exist when the config is validated. By default, missing
+    /// directories fail the validation.
+    #[must_use]
+    pub fn with_create_missing_dirs(mut self, create: bool) -> Self {
+        self.create_missing_dirs = create;
+        self
+    }
+
     pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
         self.net.chunk_size = chunk_size;
         self
@@ -73,14 +354,219 @@ impl ServerConfig {
         self
     }
 
+    /// Writes the traces to rolling log files in `log_dir`, in addition to stdout
+    /// unless disabled with `with_log_stdout`
+    pub fn with_log_dir(mut self, log_dir: Option<PathBuf>) -> Self {
+        self.log_dir = log_dir;
+        self
+    }
+
+    /// Sets the name of the log file, suffixed with the date when rotated
+    pub fn with_log_file(mut self, log_file: &str) -> Self {
+        self.log_file = log_file.to_string();
+        self
+    }
+
+    pub fn with_log_rotation(mut self, rotation: LogRotation) -> Self {
+        self.log_rotation = rotation;
+        self
+    }
+
+    /// Sets the maximum number of rotated log files kept in the log directory,
+    /// the oldest ones are removed
+    pub fn with_log_max_files(mut self, max_files: Option<usize>) -> Self {
+        self.log_max_files = max_files.filter(|n| *n > 0);
+        self
+    }
+
+    /// Enables or disables the traces written to stdout when a log directory is set
+    pub fn with_log_stdout(mut self, log_stdout: bool) -> Self {
+        self.log_stdout = log_stdout;
+        self
+    }
+
+    /// Sets the maximum number of bytes per second sent by a single download stream
+    pub fn with_max_download_bytes_per_sec_per_stream(mut self, limit: Option<u64>) -> Self {
+        self.max_download_bytes_per_sec_per_stream = limit.filter(|l| *l > 0);
+        self
+    }
+
+    /// Sets the maximum number of bytes per second sent by all the download streams
+    pub fn with_max_download_bytes_per_sec(mut self, limit: Option<u64>) -> Self {
+        self.max_download_bytes_per_sec = limit.filter(|l| *l > 0);
+        self
+    }
+
+    /// Sets the time given to the in-flight uploads and downloads to complete
+    /// on shutdown, the transfers still running afterwards are aborted.
+    pub fn with_shutdown_grace_period(mut self, grace_period: Duration) -> Self {
+        self.shutdown_grace_period = grace_period;
+        self
+    }
+
+    /// Sets the maximum number of uploads running concurrently
+    pub fn with_max_concurrent_uploads(mut self, limit: Option<usize>) -> Self {
+        self.max_concurrent_uploads = limit.filter(|l| *l > 0);
+        self
+    }
+
+    /// Queues the uploads exceeding `max_concurrent_uploads` until a running
+    /// upload completes, instead of rejecting them
+    pub fn with_queue_uploads(mut self, queue: bool) -> Self {
+        self.queue_uploads = queue;
+        self
+    }
+
+    /// Sets the time after which an upload stream without any incoming
+    /// message, or a download stream not consumed by the client, is aborted
+    pub fn with_stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_idle_timeout = timeout;
+        self
+    }
+
+    /// Sets the time after which an upload session without any activity
+    /// expires, its partially uploaded file is then removed
+    pub fn with_upload_session_ttl(mut self, ttl: Duration) -> Self {
+        self.upload_session_ttl = ttl;
+        self
+    }
+
+    /// Sets the largest chunk accepted in uploads, the larger ones are
+    /// rejected with `INVALID_ARGUMENT`. Downloads are streamed in chunks of
+    /// at most this size. The limit is advertised to the clients, which
+    /// adapt their chunk size to it.
+    pub fn with_max_chunk_size(mut self, max_chunk_size: usize) -> Self {
+        self.max_chunk_size = max_chunk_size;
+        self
+    }
+
+    /// Sets the longest filename accepted in uploads, in bytes. The longer
+    /// ones are rejected with `INVALID_ARGUMENT`, see `validate_filename`.
+    #[must_use]
+    pub fn with_max_filename_len(mut self, max_filename_len: usize) -> Self {
+        self.max_filename_len = max_filename_len;
+        self
+    }
+
+    /// Sets the largest grpc message sent or accepted, it must hold a chunk
+    /// of the max chunk size plus the message overhead
+    #[must_use]
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.net.max_message_size = max_message_size;
+        self
+    }
+
+    /// Sets what the server does with an uploaded file having the same
+    /// sha256 as an existing entry
+    pub fn with_duplicate_policy(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicate_policy = policy;
+        self
+    }
+
+    /// Sets how the stored files are organized, see `StorageLayout`
+    pub fn with_storage_layout(mut self, layout: StorageLayout) -> Self {
+        self.storage_layout = layout;
+        self
+    }
+
+    /// Hashes each stored file while it is downloaded, a download of a file
+    /// not matching its entry sha256 ends with a `DATA_LOSS` error. The
+    /// bytes preceding the offset of a resumed download are read twice.
+    pub fn with_verify_on_download(mut self, verify: bool) -> Self {
+        self.verify_on_download = verify;
+        self
+    }
+
+    /// Compresses the uploaded files with zstd at `level` before storing
+    /// them, the files not getting smaller are stored as is. The downloads
+    /// are decompressed on the fly, the entries sha256 and sizes are the ones
+    /// of the uploaded content. `None` disables the compression of the new
+    /// uploads, the already compressed files are still served.
+    pub fn with_compression_level(mut self, level: Option<i32>) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Sets when the entries added by the uploads are written to disk
+    pub fn with_persistence(mut self, persistence: PersistencePolicy) -> Self {
+        self.persistence = persistence;
+        self
+    }
+
+    /// Sets the PEM encoded certificate chain file presented to the clients,
+    /// the server only accepts TLS connections if set. Requires `with_tls_key`.
+    #[must_use]
+    pub fn with_tls_cert(mut self, path: Option<PathBuf>) -> Self {
+        self.tls_cert = path;
+        self
+    }
+
+    /// Sets the PEM encoded private key file of the `with_tls_cert` certificate
+    #[must_use]
+    pub fn with_tls_key(mut self, path: Option<PathBuf>) -> Self {
+        self.tls_key = path;
+        self
+    }
+
+    /// Sets the PEM encoded CA certificates file used to verify the client
+    /// certificates, the clients without a valid certificate are rejected
+    /// if set. Requires TLS, see `with_tls_cert`.
+    #[must_use]
+    pub fn with_tls_client_ca(mut self, path: Option<PathBuf>) -> Self {
+        self.tls_client_ca = path;
+        self
+    }
+
+    /// Sets the PEM encoded PKCS#8 ed25519 key file the merkle roots
+    /// returned to the clients are signed with. A new key is generated at
+    /// `path` on startup if the file does not exist. Requires the `Always`
+    /// persistence policy, a signed root must never cover lost entries.
+    #[must_use]
+    pub fn with_signing_key(mut self, path: Option<PathBuf>) -> Self {
+        self.signing_key = path;
+        self
+    }
+
+    /// Makes the server a read-only replica of the primary server at `url`:
+    /// the new entries of the primary are pulled, verified and appended to
+    /// the local archive, the uploads are rejected.
+    #[must_use]
+    pub fn with_replicate_from(mut self, url: Option<String>) -> Self {
+        self.replicate_from = url;
+        self
+    }
+
+    /// Sets the time between two pulls of the new entries of the primary
+    #[must_use]
+    pub fn with_replication_interval(mut self, interval: Duration) -> Self {
+        self.replication_interval = interval;
+        self
+    }
+
     pub fn chunk_size(&self) -> usize {
         self.net.chunk_size
     }
 
+    pub fn max_chunk_size(&self) -> usize {
+        self.max_chunk_size
+    }
+
+    pub fn max_filename_len(&self) -> usize {
+        self.max_filename_len
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.net.max_message_size
+    }
+
     pub fn channel_size(&self) -> usize {
         self.net.channel_size
     }
 
+    pub fn create_missing_dirs(&self) -> bool {
+        self.create_missing_dirs
+    }
+
     pub fn tracing(&self) -> bool {
         self.tracing
     }
@@ -89,35 +575,251 @@ impl ServerConfig {
         self.tracing_level
     }
 
+    pub fn log_dir(&self) -> Option<&PathBuf> {
+        self.log_dir.as_ref()
+    }
+
+    pub fn log_file(&self) -> &str {
+        &self.log_file
+    }
+
+    pub fn log_rotation(&self) -> LogRotation {
+        self.log_rotation
+    }
+
+    pub fn log_max_files(&self) -> Option<usize> {
+        self.log_max_files
+    }
+
+    pub fn log_stdout(&self) -> bool {
+        self.log_stdout
+    }
+
+    pub fn max_download_bytes_per_sec_per_stream(&self) -> Option<u64> {
+        self.max_download_bytes_per_sec_per_stream
+    }
+
+    pub fn max_download_bytes_per_sec(&self) -> Option<u64> {
+        self.max_download_bytes_per_sec
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        self.shutdown_grace_period
+    }
+
+    pub fn max_concurrent_uploads(&self) -> Option<usize> {
+        self.max_concurrent_uploads
+    }
+
+    pub fn queue_uploads(&self) -> bool {
+        self.queue_uploads
+    }
+
+    pub fn stream_idle_timeout(&self) -> Duration {
+        self.stream_idle_timeout
+    }
+
+    pub fn upload_session_ttl(&self) -> Duration {
+        self.upload_session_ttl
+    }
+
+    pub fn duplicate_policy(&self) -> DuplicatePolicy {
+        self.duplicate_policy
+    }
+
+    pub fn persistence(&self) -> PersistencePolicy {
+        self.persistence
+    }
+
+    pub fn storage_layout(&self) -> StorageLayout {
+        self.storage_layout
+    }
+
+    pub fn verify_on_download(&self) -> bool {
+        self.verify_on_download
+    }
+
+    pub fn compression_level(&self) -> Option<i32> {
+        self.compression_level
+    }
+
+    pub fn tls_cert(&self) -> Option<&PathBuf> {
+        self.tls_cert.as_ref()
+    }
+
+    pub fn tls_key(&self) -> Option<&PathBuf> {
+        self.tls_key.as_ref()
+    }
+
+    pub fn tls_client_ca(&self) -> Option<&PathBuf> {
+        self.tls_client_ca.as_ref()
+    }
+
+    pub fn signing_key(&self) -> Option<&PathBuf> {
+        self.signing_key.as_ref()
+    }
+
+    pub fn replicate_from(&self) -> Option<&str> {
+        self.replicate_from.as_deref()
+    }
+
+    pub fn is_replica(&self) -> bool {
+        self.replicate_from.is_some()
+    }
+
+    pub fn replication_interval(&self) -> Duration {
+        self.replication_interval
+    }
+
+    /// Reads the TLS certificate, key and client CA files, returns `None`
+    /// if TLS is not enabled
+    pub fn server_tls_config(&self) -> Result<Option<ServerTlsConfig>, ServerError> {
+        let (cert_path, key_path) = match (&self.tls_cert, &self.tls_key) {
+            (None, None) if self.tls_client_ca.is_some() => {
+                return Err(ServerError::TlsConfig(
+                    "client authentication requires TLS".to_string(),
+                ))
+            }
+            (None, None) => return Ok(None),
+            (Some(cert), Some(key)) => (cert, key),
+            (Some(_), None) => return Err(ServerError::TlsConfig("missing TLS key".to_string())),
+            (None, Some(_)) => {
+                return Err(ServerError::TlsConfig(
+                    "missing TLS certificate".to_string(),
+                ))
+            }
+        };
+        let read = |path: &PathBuf| {
+            std::fs::read(path).map_err(|e| {
+                ServerError::TlsConfig(format!("unable to read '{}': {}", path.display(), e))
+            })
+        };
+        let identity = Identity::from_pem(read(cert_path)?, read(key_path)?);
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(ca_path) = &self.tls_client_ca {
+            tls = tls.client_ca_root(Certificate::from_pem(read(ca_path)?));
+        }
+        Ok(Some(tls))
+    }
+
     pub fn files_db_dir(&self) -> PathBuf {
-        self.files_dir.join("db")
+        self.files_dir.join(DB_PREFIX)
     }
 
     pub fn files_tmp_dir(&self) -> PathBuf {
-        self.files_dir.join("tmp")
+        self.files_dir.join(TMP_PREFIX)
+    }
+
+    /// The directory the stored files failing `fsck` are moved into
+    pub fn files_quarantine_dir(&self) -> PathBuf {
+        self.files_dir.join("quarantine")
     }
 
     pub fn db_dir(&self) -> &PathBuf {
         &self.db_dir
     }
 
+    pub fn files_dir(&self) -> &PathBuf {
+        &self.files_dir
+    }
+
     pub fn db_file(&self) -> PathBuf {
         self.db_dir.join("db.bin")
     }
 
+    pub fn db_tmp_file(&self) -> PathBuf {
+        self.db_dir.join("db.bin.tmp")
+    }
+
+    /// The entries added since the last db file snapshot
+    pub fn db_journal_file(&self) -> PathBuf {
+        self.db_dir.join("db.journal")
+    }
+
+    /// The previous generation of the db file, kept by each save
+    pub fn db_bak_file(&self) -> PathBuf {
+        self.db_dir.join("db.bin.bak")
+    }
+
+    pub fn db_lock_file(&self) -> PathBuf {
+        self.db_dir.join("db.lock")
+    }
+
+    pub fn uds_path(&self) -> Option<&PathBuf> {
+        self.net.uds_path.as_ref()
+    }
+
+    pub fn uds_mode(&self) -> Option<FileMode> {
+        self.uds_mode
+    }
+
+    pub fn uds_keep_tcp(&self) -> bool {
+        self.uds_keep_tcp
+    }
+
+    /// Whether the server listens on its tcp hosts and port
+    pub fn listens_on_tcp(&self) -> bool {
+        self.net.uds_path.is_none() || self.uds_keep_tcp
+    }
+
     pub fn sock_addr(&self) -> SocketAddr {
         self.net.sock_addr()
     }
+
+    /// The addresses the server listens on
+    pub fn hosts(&self) -> Vec<IpAddr> {
+        match self.hosts.is_empty() {
+            true => vec![self.net.host],
+            false => self.hosts.clone(),
+        }
+    }
+
+    /// The tcp addresses the server listens on, one per host
+    pub fn sock_addrs(&self) -> Vec<SocketAddr> {
+        self.hosts()
+            .into_iter()
+            .map(|host| SocketAddr::new(host, self.net.port))
+            .collect()
+    }
 }
 
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             net: NetConfig::default(),
+            hosts: vec![],
+            uds_mode: None,
+            uds_keep_tcp: false,
             db_dir: PathBuf::default(),
             files_dir: PathBuf::default(),
+            create_missing_dirs: false,
             tracing: true,
             tracing_level: tracing::Level::INFO,
+            log_dir: None,
+            log_file: DEFAULT_LOG_FILE.to_string(),
+            log_rotation: LogRotation::default(),
+            log_max_files: None,
+            log_stdout: true,
+            max_download_bytes_per_sec_per_stream: None,
+            max_download_bytes_per_sec: None,
+            shutdown_grace_period: DEFAULT_SHUTDOWN_GRACE_PERIOD,
+            max_concurrent_uploads: None,
+            queue_uploads: false,
+            stream_idle_timeout: DEFAULT_STREAM_IDLE_TIMEOUT,
+            upload_session_ttl: DEFAULT_UPLOAD_SESSION_TTL,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            max_filename_len: DEFAULT_MAX_FILENAME_LEN,
+            duplicate_policy: DuplicatePolicy::default(),
+            persistence: PersistencePolicy::default(),
+            storage_layout: StorageLayout::Sharded,
+            verify_on_download: false,
+            compression_level: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            signing_key: None,
+            replicate_from: None,
+            replication_interval: DEFAULT_REPLICATION_INTERVAL,
         }
     }
 }
@@ -134,6 +836,14 @@ impl ServerConfig {
         config.db_dir = absolute_path(&self.db_dir)?;
         config.files_dir = absolute_path(&self.files_dir)?;
 
+        if config.create_missing_dirs {
+            for dir in [&config.db_dir, &config.files_dir] {
+                std::fs::create_dir_all(dir).map_err(|e| {
+                    ServerError::CreateDir(dir.display().to_string(), e.to_string())
+                })?;
+            }
+        }
+
         if !config.db_dir.is_dir() {
             return Err(ServerError::DbDirDoesNotExist(String::from(
                 self.db_dir.to_str().unwrap_or(""),
@@ -144,6 +854,23 @@ impl ServerConfig {
                 self.files_dir.to_str().unwrap_or(""),
             )));
         }
+        validate_chunk_size(config.max_chunk_size)?;
+        validate_chunk_size(config.chunk_size())?;
+        if config.chunk_size() > config.max_chunk_size {
+            return Err(ServerError::ChunkTooLarge {
+                size: config.chunk_size(),
+                max: config.max_chunk_size,
+            });
+        }
+        validate_message_size(config.max_chunk_size, config.max_message_size())?;
+        if let Some(level) = config.compression_level {
+            if !zstd::compression_level_range().contains(&level) {
+                return Err(ServerError::InvalidCompressionLevel(level));
+            }
+        }
+        if config.signing_key.is_some() && config.persistence != PersistencePolicy::Always {
+            return Err(ServerError::SigningKeyPersistence(config.persistence));
+        }
 
         Ok(config)
     }
@@ -0,0 +1,511 @@
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use mrklar_common::config::GrpcCompression;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cmd::{PersistenceMode, DEFAULT_PERSISTENCE_INTERVAL},
+    config::{DuplicatePolicy, FileMode, LogRotation, PersistencePolicy, ServerConfig},
+    error::ServerError,
+    layout::StorageLayout,
+};
+
+/// The server settings of a TOML config file. Every key is optional, the
+/// keys are named after the `ServerConfig` settings, the durations are in
+/// seconds and the enums take the values of the matching command line flags.
+/// The relative paths are relative to the directory of the config file.
+///
+/// ```toml
+/// port = 10000
+/// db_dir = "/var/lib/mrklar/db"
+/// files_dir = "/var/lib/mrklar/files"
+/// storage_layout = "sharded"
+/// persistence = "interval"
+/// persistence_interval = 5
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfigFile {
+    pub port: Option<u16>,
+    pub host: Option<IpAddr>,
+    // all the addresses listened on, replaces `host`
+    pub hosts: Option<Vec<IpAddr>>,
+    pub uds_path: Option<PathBuf>,
+    #[serde(default, with = "from_str")]
+    pub uds_mode: Option<FileMode>,
+    pub uds_keep_tcp: Option<bool>,
+    #[serde(default, with = "from_str")]
+    pub grpc_compression: Option<GrpcCompression>,
+    pub max_message_size: Option<usize>,
+    pub chunk_size: Option<usize>,
+    pub channel_size: Option<usize>,
+    pub db_dir: Option<PathBuf>,
+    pub files_dir: Option<PathBuf>,
+    pub create_dirs: Option<bool>,
+    pub tracing: Option<bool>,
+    #[serde(default, with = "from_str")]
+    pub tracing_level: Option<tracing::Level>,
+    pub log_dir: Option<PathBuf>,
+    pub log_file: Option<String>,
+    #[serde(default, with = "value_enum")]
+    pub log_rotation: Option<LogRotation>,
+    pub log_max_files: Option<usize>,
+    pub log_stdout: Option<bool>,
+    pub max_download_bytes_per_sec_per_stream: Option<u64>,
+    pub max_download_bytes_per_sec: Option<u64>,
+    pub shutdown_grace_period: Option<u64>,
+    pub max_concurrent_uploads: Option<usize>,
+    pub queue_uploads: Option<bool>,
+    pub stream_idle_timeout: Option<u64>,
+    pub upload_session_ttl: Option<u64>,
+    pub max_chunk_size: Option<usize>,
+    pub max_filename_len: Option<usize>,
+    #[serde(default, with = "value_enum")]
+    pub duplicate_policy: Option<DuplicatePolicy>,
+    #[serde(default, with = "value_enum")]
+    pub persistence: Option<PersistenceMode>,
+    pub persistence_interval: Option<u64>,
+    #[serde(default, with = "value_enum")]
+    pub storage_layout: Option<StorageLayout>,
+    pub verify_on_download: Option<bool>,
+    pub compression_level: Option<i32>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub signing_key: Option<PathBuf>,
+    pub replicate_from: Option<String>,
+    pub replication_interval: Option<u64>,
+}
+
+impl ServerConfigFile {
+    /// Reads the config file at `path`, returns the settings and the keys
+    /// not matching any setting, which are ignored
+    pub fn load(path: &Path) -> Result<(Self, Vec<String>), ServerError> {
+        let invalid = |message: String| ServerError::ConfigFile {
+            path: path.display().to_string(),
+            message,
+        };
+        let content = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        let (mut file, unknown_keys) = Self::parse(&content).map_err(invalid)?;
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for p in [
+            &mut file.uds_path,
+            &mut file.db_dir,
+            &mut file.files_dir,
+            &mut file.log_dir,
+            &mut file.tls_cert,
+            &mut file.tls_key,
+            &mut file.tls_client_ca,
+            &mut file.signing_key,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if p.is_relative() {
+                *p = dir.join(&*p);
+            }
+        }
+        Ok((file, unknown_keys))
+    }
+
+    /// Parses the TOML `content`, returns the settings and the unknown keys
+    pub fn parse(content: &str) -> Result<(Self, Vec<String>), String> {
+        let mut unknown_keys = vec![];
+        let file = serde_ignored::deserialize(toml::Deserializer::new(content), |key| {
+            unknown_keys.push(key.to_string())
+        })
+        .map_err(|e| e.to_string())?;
+        Ok((file, unknown_keys))
+    }
+
+    /// Returns the TOML representation of the settings
+    pub fn to_toml(&self) -> Result<String, ServerError> {
+        toml::to_string(self).map_err(|e| ServerError::Unexpected(e.to_string()))
+    }
+
+    /// Returns `config` with the settings of the file
+    pub fn apply(&self, mut config: ServerConfig) -> ServerConfig {
+        let secs = Duration::from_secs;
+        if let Some(port) = self.port {
+            config = config.with_port(port);
+        }
+        if let Some(host) = self.host {
+            config = config.with_host(host);
+        }
+        if let Some(hosts) = &self.hosts {
+            config = config.with_hosts(hosts.clone());
+        }
+        if let Some(path) = &self.uds_path {
+            config = config.with_uds_path(Some(path.clone()));
+        }
+        if let Some(mode) = self.uds_mode {
+            config = config.with_uds_mode(Some(mode));
+        }
+        if let Some(keep_tcp) = self.uds_keep_tcp {
+            config = config.with_uds_keep_tcp(keep_tcp);
+        }
+        if let Some(compression) = self.grpc_compression {
+            config = config.with_grpc_compression(compression);
+        }
+        if let Some(size) = self.max_message_size {
+            config = config.with_max_message_size(size);
+        }
+        if let Some(size) = self.chunk_size {
+            config = config.with_chunk_size(size);
+        }
+        if let Some(size) = self.channel_size {
+            config = config.with_channel_size(size);
+        }
+        if let Some(dir) = &self.db_dir {
+            config = config.with_db_dir(dir.clone());
+        }
+        if let Some(dir) = &self.files_dir {
+            config = config.with_files_dir(dir.clone());
+        }
+        if let Some(create_dirs) = self.create_dirs {
+            config = config.with_create_missing_dirs(create_dirs);
+        }
+        if let Some(tracing) = self.tracing {
+            config = config.with_tracing(tracing);
+        }
+        if let Some(level) = self.tracing_level {
+            config = config.with_tracing_level(level.as_str());
+        }
+        if let Some(dir) = &self.log_dir {
+            config = config.with_log_dir(Some(dir.clone()));
+        }
+        if let Some(file) = &self.log_file {
+            config = config.with_log_file(file);
+        }
+        if let Some(rotation) = self.log_rotation {
+            config = config.with_log_rotation(rotation);
+        }
+        if let Some(max_files) = self.log_max_files {
+            config = config.with_log_max_files(Some(max_files));
+        }
+        if let Some(log_stdout) = self.log_stdout {
+            config = config.with_log_stdout(log_stdout);
+        }
+        if let Some(limit) = self.max_download_bytes_per_sec_per_stream {
+            config = config.with_max_download_bytes_per_sec_per_stream(Some(limit));
+        }
+        if let Some(limit) = self.max_download_bytes_per_sec {
+            config = config.with_max_download_bytes_per_sec(Some(limit));
+        }
+        if let Some(grace_period) = self.shutdown_grace_period {
+            config = config.with_shutdown_grace_period(secs(grace_period));
+        }
+        if let Some(limit) = self.max_concurrent_uploads {
+            config = config.with_max_concurrent_uploads(Some(limit));
+        }
+        if let Some(queue) = self.queue_uploads {
+            config = config.with_queue_uploads(queue);
+        }
+        if let Some(timeout) = self.stream_idle_timeout {
+            config = config.with_stream_idle_timeout(secs(timeout));
+        }
+        if let Some(ttl) = self.upload_session_ttl {
+            config = config.with_upload_session_ttl(secs(ttl));
+        }
+        if let Some(size) = self.max_chunk_size {
+            config = config.with_max_chunk_size(size);
+        }
+        if let Some(len) = self.max_filename_len {
+            config = config.with_max_filename_len(len);
+        }
+        if let Some(policy) = self.duplicate_policy {
+            config = config.with_duplicate_policy(policy);
+        }
+        if let Some(mode) = self.persistence {
+            let interval = self
+                .persistence_interval
+                .unwrap_or(DEFAULT_PERSISTENCE_INTERVAL);
+            config = config.with_persistence(mode.into_policy(interval));
+        }
+        if let Some(layout) = self.storage_layout {
+            config = config.with_storage_layout(layout);
+        }
+        if let Some(verify) = self.verify_on_download {
+            config = config.with_verify_on_download(verify);
+        }
+        if let Some(level) = self.compression_level {
+            config = config.with_compression_level(Some(level));
+        }
+        if let Some(path) = &self.tls_cert {
+            config = config.with_tls_cert(Some(path.clone()));
+        }
+        if let Some(path) = &self.tls_key {
+            config = config.with_tls_key(Some(path.clone()));
+        }
+        if let Some(path) = &self.tls_client_ca {
+            config = config.with_tls_client_ca(Some(path.clone()));
+        }
+        if let Some(path) = &self.signing_key {
+            config = config.with_signing_key(Some(path.clone()));
+        }
+        if let Some(url) = &self.replicate_from {
+            config = config.with_replicate_from(Some(url.clone()));
+        }
+        if let Some(interval) = self.replication_interval {
+            config = config.with_replication_interval(secs(interval));
+        }
+        config
+    }
+}
+
+impl From<&ServerConfig> for ServerConfigFile {
+    fn from(config: &ServerConfig) -> Self {
+        let (persistence, persistence_interval) = match config.persistence() {
+            PersistencePolicy::Always => (PersistenceMode::Always, None),
+            PersistencePolicy::Interval(interval) => {
+                (PersistenceMode::Interval, Some(interval.as_secs()))
+            }
+            PersistencePolicy::OnShutdown => (PersistenceMode::OnShutdown, None),
+        };
+        ServerConfigFile {
+            port: Some(config.net.port),
+            host: Some(config.net.host),
+            hosts: Some(config.hosts()).filter(|hosts| hosts.len() > 1),
+            uds_path: config.uds_path().cloned(),
+            uds_mode: config.uds_mode(),
+            uds_keep_tcp: Some(config.uds_keep_tcp()),
+            grpc_compression: Some(config.net.grpc_compression),
+            max_message_size: Some(config.max_message_size()),
+            chunk_size: Some(config.chunk_size()),
+            channel_size: Some(config.channel_size()),
+            db_dir: Some(config.db_dir().clone()),
+            files_dir: Some(config.files_dir().clone()),
+            create_dirs: Some(config.create_missing_dirs()),
+            tracing: Some(config.tracing()),
+            tracing_level: Some(config.tracing_level()),
+            log_dir: config.log_dir().cloned(),
+            log_file: Some(config.log_file().to_string()),
+            log_rotation: Some(config.log_rotation()),
+            log_max_files: config.log_max_files(),
+            log_stdout: Some(config.log_stdout()),
+            max_download_bytes_per_sec_per_stream: config.max_download_bytes_per_sec_per_stream(),
+            max_download_bytes_per_sec: config.max_download_bytes_per_sec(),
+            shutdown_grace_period: Some(config.shutdown_grace_period().as_secs()),
+            max_concurrent_uploads: config.max_concurrent_uploads(),
+            queue_uploads: Some(config.queue_uploads()),
+            stream_idle_timeout: Some(config.stream_idle_timeout().as_secs()),
+            upload_session_ttl: Some(config.upload_session_ttl().as_secs()),
+            max_chunk_size: Some(config.max_chunk_size()),
+            max_filename_len: Some(config.max_filename_len()),
+            duplicate_policy: Some(config.duplicate_policy()),
+            persistence: Some(persistence),
+            persistence_interval,
+            storage_layout: Some(config.storage_layout()),
+            verify_on_download: Some(config.verify_on_download()),
+            compression_level: config.compression_level(),
+            tls_cert: config.tls_cert().cloned(),
+            tls_key: config.tls_key().cloned(),
+            tls_client_ca: config.tls_client_ca().cloned(),
+            signing_key: config.signing_key().cloned(),
+            replicate_from: config.replicate_from().map(str::to_string),
+            replication_interval: Some(config.replication_interval().as_secs()),
+        }
+    }
+}
+
+// the enums given as the values of their command line flag
+mod value_enum {
+    use clap::ValueEnum;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: ValueEnum, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value.as_ref().and_then(|v| v.to_possible_value()) {
+            Some(v) => serializer.serialize_str(v.get_name()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T: ValueEnum, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<T>, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        T::from_str(&value, false).map(Some).map_err(|_| {
+            let expected: Vec<_> = T::value_variants()
+                .iter()
+                .filter_map(|v| v.to_possible_value())
+                .map(|v| format!("'{}'", v.get_name()))
+                .collect();
+            D::Error::custom(format!(
+                "invalid value '{}', expecting {}",
+                value,
+                expected.join(", ")
+            ))
+        })
+    }
+}
+
+// the values parsed from and displayed as strings
+mod from_str {
+    use std::{fmt::Display, str::FromStr};
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<T: Display, S: Serializer>(
+        value: &Option<T>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(v) => serializer.serialize_str(&v.to_string().to_lowercase()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: FromStr,
+        T::Err: Display,
+        D: Deserializer<'de>,
+    {
+        let value = String::deserialize(deserializer)?;
+        T::from_str(&value)
+            .map(Some)
+            .map_err(|e| D::Error::custom(format!("invalid value '{}': {}", value, e)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use clap::{CommandFactory, FromArgMatches};
+    use tempfile::tempdir;
+
+    use super::ServerConfigFile;
+    use crate::{
+        cmd::ServerCmd, error::ServerError, layout::StorageLayout, DuplicatePolicy, FileMode,
+        PersistencePolicy, ServerConfig,
+    };
+
+    fn merged(args: &[&str]) -> Result<ServerConfig, ServerError> {
+        let matches = ServerCmd::command()
+            .try_get_matches_from([&["mrklar"], args].concat())
+            .unwrap();
+        ServerCmd::from_arg_matches(&matches)
+            .unwrap()
+            .into_merged_server_config(&matches)
+    }
+
+    #[test]
+    fn test_parse() {
+        let (file, unknown_keys) = ServerConfigFile::parse(
+            r#"
+            port = 1234
+            storage_layout = "content-addressed"
+            grpc_compression = "zstd"
+            tracing_level = "debug"
+            uds_mode = "0660"
+            colour = "blue"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.port, Some(1234));
+        assert_eq!(file.storage_layout, Some(StorageLayout::ContentAddressed));
+        assert_eq!(file.tracing_level, Some(tracing::Level::DEBUG));
+        assert_eq!(file.uds_mode, Some(FileMode(0o660)));
+        assert_eq!(unknown_keys, vec!["colour".to_string()]);
+
+        let e = ServerConfigFile::parse("duplicate_policy = \"never\"").unwrap_err();
+        assert!(e.contains("duplicate_policy"), "{e}");
+        assert!(e.contains("'allow', 'reject', 'dedup'"), "{e}");
+        assert!(ServerConfigFile::parse("port = \"http\"").is_err());
+        let e = ServerConfigFile::parse("uds_mode = \"0680\"").unwrap_err();
+        assert!(e.contains("Invalid file mode '0680'"), "{e}");
+
+        // the printed config reads back to the same config
+        let config = ServerConfig::default()
+            .with_db_dir("/db".into())
+            .with_files_dir("/files".into())
+            .with_persistence(PersistencePolicy::Interval(Duration::from_secs(7)))
+            .with_duplicate_policy(DuplicatePolicy::Dedup)
+            .with_compression_level(Some(3))
+            .with_uds_mode(Some(FileMode(0o600)));
+        let printed = ServerConfigFile::from(&config);
+        let (read, unknown_keys) = ServerConfigFile::parse(&printed.to_toml().unwrap()).unwrap();
+        assert!(unknown_keys.is_empty());
+        assert_eq!(read, printed);
+        assert_eq!(
+            read.apply(ServerConfig::default()).to_string(),
+            config.to_string()
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            r#"
+            port = 1234
+            db_dir = "db"
+            files_dir = "/srv/files"
+            tracing = true
+            persistence = "interval"
+            persistence_interval = 10
+            "#,
+        )
+        .unwrap();
+        let config_arg = path.to_str().unwrap();
+
+        let config = merged(&["--config", config_arg]).unwrap();
+        assert_eq!(config.sock_addr().port(), 1234);
+        // relative to the config file
+        assert_eq!(config.db_dir(), &dir.path().join("db"));
+        assert_eq!(config.files_dir(), &PathBuf::from("/srv/files"));
+        assert!(config.tracing());
+        assert_eq!(
+            config.persistence(),
+            PersistencePolicy::Interval(Duration::from_secs(10))
+        );
+        // not in the file, the flag default
+        assert_eq!(config.storage_layout(), StorageLayout::Sharded);
+
+        // the explicit flags take precedence
+        let config = merged(&[
+            "--config",
+            config_arg,
+            "--port",
+            "4321",
+            "--db-dir",
+            "/srv/db",
+            "--persistence-interval",
+            "3",
+        ])
+        .unwrap();
+        assert_eq!(config.sock_addr().port(), 4321);
+        assert_eq!(config.db_dir(), &PathBuf::from("/srv/db"));
+        assert_eq!(
+            config.persistence(),
+            PersistencePolicy::Interval(Duration::from_secs(3))
+        );
+        assert_eq!(
+            merged(&["--config", config_arg, "--persistence", "always"])
+                .unwrap()
+                .persistence(),
+            PersistencePolicy::Always
+        );
+
+        std::fs::write(&path, "db_dir = \"db\"").unwrap();
+        match merged(&["--config", config_arg]) {
+            Err(ServerError::MissingConfigKey(key)) => assert_eq!(key, "files_dir"),
+            r => panic!("unexpected result {r:?}"),
+        }
+        assert!(matches!(
+            merged(&["--config", "/does/not/exist.toml"]),
+            Err(ServerError::ConfigFile { .. })
+        ));
+    }
+}
@@ -1,16 +1,25 @@
-use mrklar_common::proto::{DownloadResponse, ProofResponse};
-use tonic::Status;
+use mrklar_common::config::RETRY_AFTER_METADATA_KEY;
+use mrklar_common::proto::{DownloadResponse, FileIndex, ProofResponse};
+use mrklar_tree::error::MerkleTreeError;
+use prost::Message;
+
+use crate::config::PersistencePolicy;
+use crate::filename::FilenameError;
+use tokio::sync::mpsc::error::SendError;
+use tonic::{metadata::MetadataValue, Code, Status};
 
 #[derive(Debug, thiserror::Error)]
 pub enum ServerError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
-    Status(#[from] tonic::Status),
+    Status(Box<tonic::Status>),
     #[error("Server db directory '{0}' does not exist")]
     DbDirDoesNotExist(String),
     #[error("Server files directory '{0}' does not exist")]
     FilesDirDoesNotExist(String),
+    #[error("Unable to create the directory '{0}': {1}")]
+    CreateDir(String, String),
     #[error("Unexpected error: {0}")]
     Unexpected(String),
     #[error("Undefined message type")]
@@ -21,50 +30,190 @@ pub enum ServerError {
     EmptyMessage,
     #[error("Upload failed, invalid hash value")]
     UploadInvalidHash,
-    #[error("Upload failed, invalid filename")]
-    UploadInvalidFilename,
+    #[error("Invalid filename, {0}")]
+    InvalidFilename(#[from] FilenameError),
+    #[error("Upload session '{0}' does not exist")]
+    UploadSessionDoesNotExist(String),
+    #[error("Upload chunk offset {offset} does not match the session offset {expected}")]
+    UploadSessionInvalidOffset { offset: u64, expected: u64 },
+    #[error("Chunk of {size} bytes exceeds the maximum chunk size of {max} bytes")]
+    ChunkTooLarge { size: usize, max: usize },
     #[error("File index {0} does not exist")]
     FileIndexDoesNotExist(usize),
+    #[error("File named '{0}' does not exist")]
+    FileNameDoesNotExist(String),
+    #[error("No file with sha256 {0}")]
+    Sha256DoesNotExist(String),
+    #[error("Invalid sha256 length: {0} bytes, expecting 32")]
+    InvalidSha256Length(usize),
+    #[error("Too many hashes to audit: {count}, expecting at most {max}")]
+    TooManyAuditHashes { count: usize, max: usize },
+    #[error("File already exists at index {0}")]
+    DuplicateEntry(usize),
+    #[error("Download offset {offset} is beyond the end of the file ({len} bytes)")]
+    DownloadInvalidOffset { offset: u64, len: u64 },
+    #[error("Download range of {length} bytes at offset {offset} is beyond the end of the file ({len} bytes)")]
+    DownloadInvalidRange { offset: u64, length: u64, len: u64 },
     #[error(transparent)]
     MerkleTree(#[from] mrklar_tree::error::MerkleTreeError),
     #[error("Memory DB save failed.")]
     DbSave,
     #[error("Memory DB load failed.")]
     DbLoad,
+    #[error("Server db is locked by another process (lock file '{0}')")]
+    DbLocked(String),
+    #[error("Server db file '{0}' is not empty")]
+    DbFileNotEmpty(String),
+    #[error("Server files db directory '{0}' is not empty")]
+    FilesDbDirNotEmpty(String),
+    #[error("Invalid bundle '{path}': {message}")]
+    InvalidBundle { path: String, message: String },
+    #[error("Unable to rebuild the db of the {0} storage layout, the stored files are not named by their index")]
+    RebuildUnsupportedLayout(crate::layout::StorageLayout),
+    #[error("Stored file at index {0} not found")]
+    StoredFileNotFound(usize),
+    #[error("Stored file at index {0} does not match its merkle tree leaf")]
+    StoredFileCorrupted(usize),
     // receiver dropped
     #[error(transparent)]
-    SendDownloadResponse(
-        #[from] tokio::sync::mpsc::error::SendError<Result<DownloadResponse, Status>>,
-    ),
+    SendDownloadResponse(Box<SendError<Result<DownloadResponse, Status>>>),
     // receiver dropped
     #[error(transparent)]
-    SendProofResponse(
-        #[from] tokio::sync::mpsc::error::SendError<Result<ProofResponse, Status>>,
-    ),
+    SendProofResponse(Box<SendError<Result<ProofResponse, Status>>>),
+    #[error("Server is shutting down")]
+    ShuttingDown,
+    #[error("Server is in maintenance mode, retry in {} seconds", .0.as_secs())]
+    Maintenance(std::time::Duration),
+    #[error("Blocking task failed: {0}")]
+    BlockingTask(#[from] tokio::task::JoinError),
+    #[error("Too many concurrent uploads, retry later")]
+    TooManyUploads,
+    #[error("Invalid TLS config: {0}")]
+    TlsConfig(String),
+    #[error("Invalid zstd compression level {0}")]
+    InvalidCompressionLevel(i32),
+    #[error("Unable to write the server log into '{0}': {1}")]
+    LogDir(String, String),
+    #[error("Stream idle for more than {0:?}, aborted")]
+    StreamIdle(std::time::Duration),
+    #[error("Invalid config file '{path}': {message}")]
+    ConfigFile { path: String, message: String },
+    #[error("Invalid signing key '{path}': {message}")]
+    SigningKey { path: String, message: String },
+    /// A signed root could cover entries lost on a crash
+    #[error("A signing key requires the 'always' persistence policy, got '{0}'")]
+    SigningKeyPersistence(PersistencePolicy),
+    #[error("Missing required setting '{0}', set it in the config file or on the command line")]
+    MissingConfigKey(String),
+    #[error("Invalid file mode '{0}', expecting octal permissions such as 660")]
+    InvalidFileMode(String),
+    #[error("Watch stream lagged behind by {0} events")]
+    WatchLagged(u64),
+    #[error("The server is a read-only replica, uploads are not accepted")]
+    ReadOnlyReplica,
+    #[error("Replication failed: {0}")]
+    Replication(#[from] mrklar_api::error::ApiError),
+    #[error("Replica diverged from the primary: {0}")]
+    ReplicaDiverged(String),
     #[error(transparent)]
     Common(#[from] mrklar_common::error::Error),
 }
 
+impl From<tonic::Status> for ServerError {
+    fn from(value: tonic::Status) -> Self {
+        ServerError::Status(Box::new(value))
+    }
+}
+
+impl From<SendError<Result<DownloadResponse, Status>>> for ServerError {
+    fn from(value: SendError<Result<DownloadResponse, Status>>) -> Self {
+        ServerError::SendDownloadResponse(Box::new(value))
+    }
+}
+
+impl From<SendError<Result<ProofResponse, Status>>> for ServerError {
+    fn from(value: SendError<Result<ProofResponse, Status>>) -> Self {
+        ServerError::SendProofResponse(Box::new(value))
+    }
+}
+
 impl From<ServerError> for Status {
     fn from(value: ServerError) -> Self {
         match value {
-            ServerError::Io(e) => Status::from_error(Box::new(e)),
-            ServerError::Status(s) => s,
+            // the io errors are server side failures, the client stream
+            // errors are forwarded as `ServerError::Status`
+            ServerError::Io(_) => Status::internal(value.to_string()),
+            ServerError::Status(s) => *s,
             ServerError::DbDirDoesNotExist(m) => Status::not_found(m),
             ServerError::FilesDirDoesNotExist(m) => Status::not_found(m),
+            ServerError::CreateDir(..) => Status::internal(value.to_string()),
             ServerError::Unexpected(m) => Status::internal(m),
-            ServerError::UndefinedMessageType => Status::internal(value.to_string()),
-            ServerError::UnknownMessageType => Status::internal(value.to_string()),
-            ServerError::EmptyMessage => Status::internal(value.to_string()),
+            ServerError::UndefinedMessageType => Status::invalid_argument(value.to_string()),
+            ServerError::UnknownMessageType => Status::invalid_argument(value.to_string()),
+            ServerError::EmptyMessage => Status::invalid_argument(value.to_string()),
             ServerError::UploadInvalidHash => Status::invalid_argument(value.to_string()),
-            ServerError::UploadInvalidFilename => Status::invalid_argument(value.to_string()),
+            ServerError::InvalidFilename(_) => Status::invalid_argument(value.to_string()),
+            ServerError::UploadSessionDoesNotExist(_) => Status::not_found(value.to_string()),
+            ServerError::UploadSessionInvalidOffset { .. } => {
+                Status::failed_precondition(value.to_string())
+            }
+            ServerError::ChunkTooLarge { .. } => Status::invalid_argument(value.to_string()),
             ServerError::FileIndexDoesNotExist(_) => Status::not_found(value.to_string()),
+            ServerError::FileNameDoesNotExist(_) => Status::not_found(value.to_string()),
+            ServerError::Sha256DoesNotExist(_) => Status::not_found(value.to_string()),
+            ServerError::InvalidSha256Length(_) => Status::invalid_argument(value.to_string()),
+            ServerError::TooManyAuditHashes { .. } => Status::invalid_argument(value.to_string()),
+            ServerError::DuplicateEntry(index) => {
+                let details = FileIndex {
+                    index: index as u64,
+                }
+                .encode_to_vec();
+                Status::with_details(Code::AlreadyExists, value.to_string(), details.into())
+            }
+            ServerError::DownloadInvalidOffset { .. } => Status::out_of_range(value.to_string()),
+            ServerError::DownloadInvalidRange { .. } => Status::out_of_range(value.to_string()),
+            ServerError::MerkleTree(e @ MerkleTreeError::InvalidTreeSizes { .. }) => {
+                Status::invalid_argument(e.to_string())
+            }
             ServerError::MerkleTree(e) => Status::internal(e.to_string()),
             ServerError::SendDownloadResponse(e) => Status::internal(e.to_string()),
             ServerError::SendProofResponse(e) => Status::internal(e.to_string()),
             ServerError::Common(e) => Status::internal(e.to_string()),
             ServerError::DbSave => Status::internal(value.to_string()),
             ServerError::DbLoad => Status::internal(value.to_string()),
+            ServerError::DbLocked(_) => Status::unavailable(value.to_string()),
+            ServerError::DbFileNotEmpty(_) => Status::failed_precondition(value.to_string()),
+            ServerError::FilesDbDirNotEmpty(_) => Status::failed_precondition(value.to_string()),
+            ServerError::InvalidBundle { .. } => Status::invalid_argument(value.to_string()),
+            ServerError::RebuildUnsupportedLayout(_) => {
+                Status::failed_precondition(value.to_string())
+            }
+            ServerError::StoredFileNotFound(_) => Status::data_loss(value.to_string()),
+            ServerError::StoredFileCorrupted(_) => Status::data_loss(value.to_string()),
+            ServerError::ShuttingDown => Status::unavailable(value.to_string()),
+            ServerError::Maintenance(retry_after) => {
+                let mut status = Status::unavailable(value.to_string());
+                status.metadata_mut().insert(
+                    RETRY_AFTER_METADATA_KEY,
+                    MetadataValue::from(retry_after.as_secs()),
+                );
+                status
+            }
+            ServerError::BlockingTask(_) => Status::internal(value.to_string()),
+            ServerError::TooManyUploads => Status::resource_exhausted(value.to_string()),
+            ServerError::TlsConfig(_) => Status::internal(value.to_string()),
+            ServerError::InvalidCompressionLevel(_) => Status::internal(value.to_string()),
+            ServerError::LogDir(..) => Status::internal(value.to_string()),
+            ServerError::ConfigFile { .. } => Status::internal(value.to_string()),
+            ServerError::SigningKey { .. } => Status::internal(value.to_string()),
+            ServerError::SigningKeyPersistence(_) => Status::internal(value.to_string()),
+            ServerError::MissingConfigKey(_) => Status::internal(value.to_string()),
+            ServerError::InvalidFileMode(_) => Status::internal(value.to_string()),
+            ServerError::StreamIdle(_) => Status::deadline_exceeded(value.to_string()),
+            ServerError::WatchLagged(_) => Status::resource_exhausted(value.to_string()),
+            ServerError::ReadOnlyReplica => Status::failed_precondition(value.to_string()),
+            ServerError::Replication(_) => Status::internal(value.to_string()),
+            ServerError::ReplicaDiverged(_) => Status::internal(value.to_string()),
         }
     }
 }
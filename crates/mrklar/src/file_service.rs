@@ -1,18 +1,37 @@
 use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::{error::ServerError, mem_db::MemDb, node::Node};
+use crate::{
+    error::ServerError,
+    filename::validate_filename,
+    mem_db::{AddedEntry, AddedFile},
+    node::{spawn_blocking, Node},
+    storage::{self, TmpObject},
+    stored_file,
+    upload_session::PendingUpload,
+};
+use mrklar_common::config::{MAX_AUDIT_HASHES, MAX_LIST_LIMIT};
+use mrklar_common::consistency_proof::tree_depth;
 use mrklar_common::proto::{
-    file_api_server::FileApi, upload_request, DownloadResponse, Empty, FileIndex, FileMetadata,
-    ProofResponse, RootResponse, UploadRequest, UploadResponse, U64,
+    audit_result, file_api_server::FileApi, upload_request, AuditProof, AuditRequest,
+    AuditResponse, AuditResult, ConsistencyProofRequest, ConsistencyProofResponse, DownloadRequest,
+    DownloadResponse, Empty, EntryInfo, FileIndex, FileMetadata, FileName, FileSha256,
+    FinishUploadRequest, ListRequest, ListResponse, ProofResponse, RootResponse, ServerInfo,
+    StartUploadRequest, StatsResponse, UploadChunk, UploadRequest, UploadResponse, UploadSession,
+    UploadSessionId, WatchEvent, U64,
 };
 use mrklar_fs::gen_tmp_filename;
 use sha2::{Digest, Sha256};
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::{SendError, SendTimeoutError};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::StreamExt;
 use tonic::{Request, Response, Status, Streaming};
+use tracing::Instrument;
 
 #[derive(Debug)]
 pub struct FileService {
@@ -23,6 +42,216 @@ impl FileService {
     pub fn new(node: Node) -> Self {
         FileService { node }
     }
+
+    /// Streams the entry of the file at `file_index` only, without opening
+    /// the stored file
+    async fn stream_entry(
+        &self,
+        file_index: u64,
+    ) -> Result<Response<ReceiverStream<Result<DownloadResponse, Status>>>, Status> {
+        tracing::info!(message = "download entry", %file_index);
+
+        self.node.check_file_index(file_index)?;
+        let (entry, merkle_proof) = self
+            .node
+            .db()
+            .compute_proof_and_entry(file_index as usize)?;
+        let response =
+            DownloadResponse::new_entry(file_index, entry.filename(), entry.size(), merkle_proof)
+                .map_err(ServerError::from)?;
+
+        // the stream ends once the entry is received, as `tx` is dropped
+        let (tx, rx) = mpsc::channel::<Result<DownloadResponse, Status>>(1);
+        tx.send(Ok(response)).await.map_err(ServerError::from)?;
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    /// Streams the entry of the file at `file_index`, then `length` bytes of
+    /// its content starting at `offset`, 0 meaning up to the end of the file,
+    /// in chunks of `chunk_size` bytes, 0 meaning the server default. The
+    /// chunks are at most the config max chunk size.
+    async fn stream_file(
+        &self,
+        file_index: u64,
+        offset: u64,
+        length: u64,
+        chunk_size: u64,
+    ) -> Result<Response<ReceiverStream<Result<DownloadResponse, Status>>>, Status> {
+        let (tx, rx) =
+            mpsc::channel::<Result<DownloadResponse, Status>>(self.node.config().channel_size());
+
+        let node = self.node.clone();
+        let idle_timeout = node.config().stream_idle_timeout();
+        let verify = node.config().verify_on_download();
+
+        let chunk_size = match chunk_size {
+            0 => node.config().chunk_size(),
+            n => (n as usize).min(node.config().max_chunk_size()),
+        };
+        let key = node.db().file_key_at(file_index as usize);
+        let storage = node.storage().clone();
+
+        tracing::info!(message = "download", %file_index, %offset, %length);
+
+        node.check_not_shutting_down()?;
+
+        // report a bad index or offset before opening the stream
+        node.check_file_index(file_index)?;
+        let entry = node.db().entry_at(file_index as usize)?;
+        let compressed = entry.compressed();
+        let stored_size = {
+            let (storage, key) = (storage.clone(), key.clone());
+            spawn_blocking(move || Ok(storage.size(&key)?))
+                .await
+                .map_err(|e| stored_file_error(e, file_index))?
+        };
+        // the size of the uploaded content, not of the compressed file
+        let len = if compressed {
+            entry.size()
+        } else {
+            stored_size
+        };
+        if offset > len {
+            return Err(ServerError::DownloadInvalidOffset { offset, len }.into());
+        }
+        let end = match length {
+            0 => len,
+            _ => match offset.checked_add(length) {
+                Some(end) if end <= len => end,
+                _ => {
+                    return Err(ServerError::DownloadInvalidRange {
+                        offset,
+                        length,
+                        len,
+                    }
+                    .into())
+                }
+            },
+        };
+
+        // the download could not complete within the shutdown grace period
+        let abort_tx = tx.clone();
+        let on_abort = async move {
+            let _ = abort_tx.send(Err(ServerError::ShuttingDown.into())).await;
+            Ok(())
+        };
+
+        // the transfer span is a child of the request span, the fields
+        // are recorded once the stream is over
+        let span = tracing::info_span!(
+            "download",
+            %file_index,
+            bytes_sent = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        );
+        let download = async move {
+            let start = Instant::now();
+            let mut bytes_sent = 0u64;
+            let res = async {
+                // Retreive request file from the db
+                let (mem_db_entry, merkle_proof) =
+                    node.db().compute_proof_and_entry(file_index as usize)?;
+
+                // 1- Send file metadata (filename, size)
+                let response = DownloadResponse::new_entry(
+                    file_index,
+                    mem_db_entry.filename(),
+                    len,
+                    merkle_proof,
+                )?;
+                // will fail if rx dropped
+                send_within(&tx, Ok(response), idle_timeout).await?;
+
+                // the skipped bytes of a resumed download are hashed first
+                let mut hasher = if verify {
+                    let hasher = Sha256::new();
+                    let hasher =
+                        stored_file::hash_range(&storage, &key, compressed, hasher, 0, offset)
+                            .await?;
+                    Some(hasher)
+                } else {
+                    None
+                };
+
+                let throttle = node.download_throttle();
+                let chunk_size = throttle.chunk_size(chunk_size);
+                let mut remaining = end - offset;
+                let reader = stored_file::open_range(&storage, &key, compressed, offset, remaining)
+                    .await
+                    .map_err(|e| stored_file_error(e, file_index))?;
+                let mut handle = reader.take(remaining.min(chunk_size as u64));
+
+                while remaining > 0 {
+                    let mut chunk = Vec::with_capacity(chunk_size);
+
+                    // read a chunk from the file
+                    let n = handle.read_to_end(&mut chunk).await?;
+
+                    // nothing left
+                    if n == 0 {
+                        break;
+                    }
+
+                    // reset the take limit before the next chunk
+                    remaining -= n as u64;
+                    handle.set_limit(remaining.min(chunk_size as u64));
+
+                    // Wait for the bandwidth budget, stop as soon as the receiver is gone
+                    let delay = throttle.reserve(n);
+                    if !delay.is_zero() {
+                        tokio::select! {
+                            _ = tokio::time::sleep(delay) => {}
+                            _ = tx.closed() => return Ok(()),
+                        }
+                    }
+
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&chunk);
+                    }
+
+                    // Send the file chunk to the receiver
+                    let response = DownloadResponse::new_chunk(chunk);
+                    // will fail if rx dropped
+                    send_within(&tx, Ok(response), idle_timeout).await?;
+                    bytes_sent += n as u64;
+                }
+
+                // the stored file is shorter than expected, or failed to decompress
+                if remaining > 0 {
+                    tracing::error!(message = "Stored file corrupted", %file_index);
+                    return Err(ServerError::StoredFileCorrupted(file_index as usize));
+                }
+
+                // the bytes following the requested range are hashed last
+                if let Some(h) = hasher.take() {
+                    let h = stored_file::hash_range(&storage, &key, compressed, h, end, len - end)
+                        .await?;
+                    hasher = Some(h);
+                }
+
+                if let Some(hasher) = hasher {
+                    if hasher.finalize().as_slice() != mem_db_entry.sha256() {
+                        tracing::error!(message = "Stored file corrupted", %file_index);
+                        return Err(ServerError::StoredFileCorrupted(file_index as usize));
+                    }
+                }
+
+                // the file has been sent up to its end
+                if end == len {
+                    node.db().record_download(file_index as usize)?;
+                }
+
+                Ok::<(), ServerError>(())
+            }
+            .await;
+            record_transfer("bytes_sent", bytes_sent, start);
+            send_task_error(&tx, res, idle_timeout).await
+        }
+        .instrument(span);
+        self.node.spawn_transfer(download, on_abort);
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
 }
 
 #[tonic::async_trait]
@@ -35,14 +264,19 @@ impl FileApi for FileService {
         }))
     }
 
-    /// Returns the merkle root of the archive
+    /// Returns the merkle root of the archive along with the number
+    /// of entries it was computed from
     async fn root(&self, _: Request<Empty>) -> Result<Response<RootResponse>, Status> {
-        let merkle_root = self
+        let (count, merkle_root) = self
             .node
             .db()
-            .merkle_root()
+            .count_and_root()
             .map_err(ServerError::MerkleTree)?;
-        Ok(Response::new(RootResponse { merkle_root }))
+        Ok(Response::new(RootResponse {
+            signed_tree_head: self.node.sign_tree_head(&merkle_root, count),
+            merkle_root,
+            count: count as u64,
+        }))
     }
 
     /// Uploads a file, upon successful completion, saves the file
@@ -52,96 +286,118 @@ impl FileApi for FileService {
         &self,
         request: Request<Streaming<UploadRequest>>,
     ) -> Result<Response<UploadResponse>, Status> {
+        let span = tracing::info_span!(
+            "upload",
+            client_cert = tracing::field::Empty,
+            bytes_received = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        );
+        if let Some(subject) = peer_cert_subject(&request) {
+            span.record("client_cert", subject);
+        }
         let mut request_stream = request.into_inner();
 
-        // create db directories if needed
-        let res = self.node.config().create_dirs();
-        if let Err(e) = res {
-            return Err(Status::internal(e.to_string()));
-        }
+        self.node.check_not_shutting_down()?;
+        self.node.check_not_replica()?;
+        self.node.check_not_in_maintenance()?;
+
+        // released when the upload task completes
+        let upload_slot = self.node.acquire_upload_slot().await?;
 
-        let tmp_dir = self.node.config().files_tmp_dir();
-        let tmp_filename = gen_tmp_filename();
-        let tmp_path = tmp_dir.join(tmp_filename);
         let node = self.node.clone();
+        let idle_timeout = node.config().stream_idle_timeout();
+        let max_chunk_size = node.config().max_chunk_size();
+        let max_filename_len = node.config().max_filename_len();
+
+        // the upload could not complete within the shutdown grace period,
+        // the tmp file is removed when the task is dropped
+        let on_abort = async move { Err(ServerError::ShuttingDown) };
+
+        let upload = async move {
+            let _upload_slot = upload_slot;
 
-        let task_handle = tokio::spawn(async move {
             // 1- read file metadata
-            let mut next = request_stream.next().await;
+            let next = next_within(&mut request_stream, idle_timeout).await?;
             let file_metadata = upload_request_file_metadata(next)?;
             let filename = &file_metadata.filename;
+            validate_filename(filename, max_filename_len)?;
 
-            if filename.is_empty() {
-                return Err(ServerError::UploadInvalidFilename);
-            }
-
-            // 2- read file sha256
-            next = request_stream.next().await;
-            let file_sha256 = upload_request_file_sha256(next)?;
-            let file_hash = file_sha256.clone();
-
-            // Trace
-            if node.config().tracing() {
-                let sha256 = hex::encode(&file_sha256);
-                tracing::info!(message = "upload", filename, sha256);
-            }
-
-            // 3- save file into a tmp file
-            let mut tokio_file = tokio::fs::File::create(&tmp_path).await?;
+            // 2- save file into a tmp object, deleted when `tmp_object` is
+            // dropped: on failure, or if the task is cancelled
+            let tmp_object = TmpObject::new(
+                node.storage().clone(),
+                storage::tmp_key(&gen_tmp_filename()),
+            );
+            let mut writer = node.storage().put_stream(tmp_object.as_ref(), 0).await?;
 
-            // 4- Upload bytes chunk by chunk and compute hash
-            let res: Result<(), ServerError> = async move {
+            // 3- Upload bytes chunk by chunk and compute hash.
+            // The optional file sha256 is sent either before the first
+            // chunk or, when computed while streaming, after the last one.
+            let start = Instant::now();
+            let mut bytes_received = 0u64;
+            let res: Result<Vec<u8>, ServerError> = async {
                 let mut hasher = Sha256::new();
+                let mut file_hash: Option<Vec<u8>> = None;
+                let mut received_chunks = false;
+                let mut trailing_hash = false;
 
                 loop {
-                    let next = request_stream.next().await;
+                    let next = next_within(&mut request_stream, idle_timeout).await?;
                     if next.is_none() {
                         break;
                     }
 
-                    let chunk = upload_request_chunk(next)?;
-                    hasher.update(&chunk);
-
-                    tokio_file.write_all(&chunk).await?;
+                    match get_upload_request_type(next)? {
+                        upload_request::Type::Sha256(h) if file_hash.is_none() => {
+                            trailing_hash = received_chunks;
+                            file_hash = Some(h);
+                        }
+                        upload_request::Type::Chunk(chunk) if !trailing_hash => {
+                            check_chunk_size(&chunk, max_chunk_size)?;
+                            received_chunks = true;
+                            hasher.update(&chunk);
+                            writer.write_all(&chunk).await?;
+                            bytes_received += chunk.len() as u64;
+                        }
+                        _ => return Err(ServerError::UnknownMessageType),
+                    }
                 }
 
-                tokio_file.sync_all().await?;
+                // the tmp object is synced once committed
+                writer.shutdown().await?;
 
-                // Compare hash
+                // Compare hash, if sent by the client
                 let hash = hasher.finalize().to_vec();
-                if hash != file_hash {
+                if file_hash.is_some_and(|h| h != hash) {
                     tracing::error!(message = "upload sha256 mismatched.");
                     return Err(ServerError::UploadInvalidHash);
                 }
 
-                Ok(())
+                Ok(hash)
             }
             .await;
+            record_transfer("bytes_received", bytes_received, start);
+
+            let file_sha256 = res?;
 
-            // if task failed, remove temporary file
-            // TODO: use tempfile crate instead.
-            if let Err(e) = res {
-                let _ = tokio::fs::remove_file(tmp_path).await;
-                return Err(e);
+            // Trace
+            if node.config().tracing() {
+                let sha256 = hex::encode(&file_sha256);
+                tracing::info!(message = "upload", filename, sha256);
             }
 
-            // add_file() will do the following:
-            // - move the temporary file 'tmp_path' into the db if succeeded
-            // - delete the temporary file 'tmp_path' if failed internaly
-            let (file_index, merkle_root) = node
-                .db()
-                .add_file(
-                    node.config(),
-                    &file_metadata.filename,
-                    file_sha256,
-                    &tmp_path,
-                )
-                .map_err(|_| {
-                    ServerError::Unexpected("Unable to add file to merkle tree".to_string())
-                })?;
-
-            Ok::<(usize, Vec<u8>), ServerError>((file_index, merkle_root))
-        });
+            let added = add_uploaded_file(
+                &node,
+                &file_metadata.filename,
+                file_sha256.clone(),
+                tmp_object,
+            )
+            .await?;
+
+            Ok::<(AddedFile, Vec<u8>), ServerError>((added, file_sha256))
+        }
+        .instrument(span);
+        let task_handle = self.node.spawn_transfer(upload, on_abort);
 
         // Wait for the upload task to complete
         // retreive the task output result
@@ -152,15 +408,208 @@ impl FileApi for FileService {
         };
 
         match result {
-            // upload succeded, return the file index and the new merkle root
-            Ok((file_index, merkle_root)) => Ok(Response::new(UploadResponse {
-                index: Some(FileIndex {
-                    index: file_index as u64,
-                }),
-                merkle_root,
-            })),
+            // upload succeded, return the file index, the new merkle root,
+            // the file sha256 and its merkle proof
+            Ok((added, sha256)) => Ok(Response::new(upload_response(&self.node, added, sha256)?)),
             // upload failed, forward the error to the client
-            Err(e) => Err(Status::internal(e.to_string())),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens a resumable upload session, returns its id
+    async fn start_upload(
+        &self,
+        request: Request<StartUploadRequest>,
+    ) -> Result<Response<UploadSession>, Status> {
+        self.node.check_not_shutting_down()?;
+        self.node.check_not_replica()?;
+        self.node.check_not_in_maintenance()?;
+
+        let filename = request.into_inner().metadata.unwrap_or_default().filename;
+        validate_filename(&filename, self.node.config().max_filename_len())
+            .map_err(ServerError::from)?;
+
+        // the session id is unguessable, it also names the session tmp object
+        let session_id = gen_tmp_filename();
+        let tmp_key = storage::tmp_key(&session_id);
+        self.node
+            .storage()
+            .put_stream(&tmp_key, 0)
+            .await
+            .map_err(ServerError::from)?
+            .shutdown()
+            .await
+            .map_err(ServerError::from)?;
+
+        tracing::info!(message = "upload session started", %session_id, filename);
+        self.node
+            .upload_sessions()
+            .insert(session_id.clone(), PendingUpload::new(filename, tmp_key));
+
+        Ok(Response::new(UploadSession {
+            session_id,
+            offset: 0,
+        }))
+    }
+
+    /// Appends the streamed chunks to the session designated by the first
+    /// chunk. The chunks received before an error or a disconnection are
+    /// kept. Returns the new session offset.
+    async fn upload_chunks(
+        &self,
+        request: Request<Streaming<UploadChunk>>,
+    ) -> Result<Response<UploadSession>, Status> {
+        let mut request_stream = request.into_inner();
+
+        self.node.check_not_shutting_down()?;
+        self.node.check_not_replica()?;
+        self.node.check_not_in_maintenance()?;
+
+        // released when the upload task completes
+        let upload_slot = self.node.acquire_upload_slot().await?;
+
+        let node = self.node.clone();
+        let idle_timeout = node.config().stream_idle_timeout();
+        let max_chunk_size = node.config().max_chunk_size();
+
+        // the session file is cleared on the next startup
+        let on_abort = async { Err(ServerError::ShuttingDown) };
+
+        let span = tracing::info_span!(
+            "upload_chunks",
+            bytes_received = tracing::field::Empty,
+            elapsed_ms = tracing::field::Empty,
+        );
+        let upload = async move {
+            let _upload_slot = upload_slot;
+            let start = Instant::now();
+            let mut bytes_received = 0u64;
+            let res = async {
+                let mut next = next_within(&mut request_stream, idle_timeout)
+                    .await?
+                    .transpose()?;
+                let session_id = match &next {
+                    Some(chunk) => chunk.session_id.clone(),
+                    None => return Err(ServerError::EmptyMessage),
+                };
+                let mut upload = node.upload_sessions().lock(&session_id).await?;
+
+                // drops the bytes written past the offset by a failed stream
+                let mut writer = node
+                    .storage()
+                    .put_stream(&upload.tmp_key, upload.offset)
+                    .await?;
+
+                while let Some(chunk) = next {
+                    if chunk.offset != upload.offset {
+                        return Err(ServerError::UploadSessionInvalidOffset {
+                            offset: chunk.offset,
+                            expected: upload.offset,
+                        });
+                    }
+                    check_chunk_size(&chunk.chunk, max_chunk_size)?;
+
+                    // the offset only moves once the chunk is written
+                    writer.write_all(&chunk.chunk).await?;
+                    writer.flush().await?;
+                    upload.hasher.update(&chunk.chunk);
+                    upload.offset += chunk.chunk.len() as u64;
+                    bytes_received += chunk.chunk.len() as u64;
+                    upload.touch();
+
+                    next = next_within(&mut request_stream, idle_timeout)
+                        .await?
+                        .transpose()?;
+                }
+
+                writer.shutdown().await?;
+
+                Ok::<UploadSession, ServerError>(UploadSession {
+                    session_id,
+                    offset: upload.offset,
+                })
+            }
+            .await;
+            record_transfer("bytes_received", bytes_received, start);
+            res
+        }
+        .instrument(span);
+        let task_handle = self.node.spawn_transfer(upload, on_abort);
+
+        match task_handle.await {
+            Ok(result) => Ok(Response::new(result?)),
+            // Internal error, the JoinHandle 'task_handle' has failed to execute to completion
+            Err(_) => Err(Status::internal("Failed to upload file chunks")),
+        }
+    }
+
+    /// Returns the number of bytes received by an upload session, waits for
+    /// the chunk stream writing to the session, if any, to complete
+    async fn get_upload_session(
+        &self,
+        request: Request<UploadSessionId>,
+    ) -> Result<Response<UploadSession>, Status> {
+        let session_id = request.into_inner().session_id;
+        let upload = self.node.upload_sessions().lock(&session_id).await?;
+        Ok(Response::new(UploadSession {
+            session_id,
+            offset: upload.offset,
+        }))
+    }
+
+    /// Closes an upload session, checks the optional sha256 then adds the
+    /// received file to the db. Returns the file index and the merkle root.
+    async fn finish_upload(
+        &self,
+        request: Request<FinishUploadRequest>,
+    ) -> Result<Response<UploadResponse>, Status> {
+        let FinishUploadRequest { session_id, sha256 } = request.into_inner();
+
+        self.node.check_not_shutting_down()?;
+        // the session is kept, it can be finished once the maintenance is over
+        self.node.check_not_in_maintenance()?;
+
+        let mut upload = self.node.upload_sessions().lock(&session_id).await?;
+
+        let file_sha256 = upload.hasher.clone().finalize().to_vec();
+        if !sha256.is_empty() && sha256 != file_sha256 {
+            tracing::error!(message = "upload sha256 mismatched.", %session_id);
+            self.node.upload_sessions().close(&session_id, &mut upload);
+            let storage = self.node.storage().clone();
+            let tmp_key = upload.tmp_key.clone();
+            let _ = spawn_blocking(move || Ok(storage.delete(&tmp_key)?)).await;
+            return Err(ServerError::UploadInvalidHash.into());
+        }
+
+        if self.node.config().tracing() {
+            let sha256 = hex::encode(&file_sha256);
+            tracing::info!(message = "upload", filename = upload.filename, sha256, %session_id);
+        }
+
+        // the session and its tmp object are kept on failure, the client can
+        // finish it again. The shutdown waits for the file to be added.
+        let node = self.node.clone();
+        let add = async move {
+            let added = add_uploaded_file(
+                &node,
+                &upload.filename,
+                file_sha256.clone(),
+                upload.tmp_key.clone(),
+            )
+            .await?;
+            node.upload_sessions().close(&session_id, &mut upload);
+            Ok::<(AddedFile, Vec<u8>), ServerError>((added, file_sha256))
+        };
+        let on_abort = async { Err(ServerError::ShuttingDown) };
+        let task_handle = self.node.spawn_transfer(add, on_abort);
+
+        match task_handle.await {
+            Ok(result) => {
+                let (added, sha256) = result?;
+                Ok(Response::new(upload_response(&self.node, added, sha256)?))
+            }
+            // Internal error, the JoinHandle 'task_handle' has failed to execute to completion
+            Err(_) => Err(Status::internal("Failed to finish the upload")),
         }
     }
 
@@ -176,87 +625,279 @@ impl FileApi for FileService {
 
         let node = self.node.clone();
         let file_index = request.get_ref().index;
+        let idle_timeout = node.config().stream_idle_timeout();
 
         tracing::info!(message = "proof", %file_index);
 
+        // report a bad index before opening the stream
+        self.node.check_file_index(file_index)?;
+
         tokio::spawn(async move {
-            let (_, merkle_proof) =
-                node.db().compute_proof_and_entry(file_index as usize)?;
+            let res = async {
+                let (_, merkle_proof) = node.db().compute_proof_and_entry(file_index as usize)?;
 
-            let response = ProofResponse::new_proof(merkle_proof)?;
-            // will fail if rx dropped
-            tx.send(Ok(response)).await?;
+                let response = ProofResponse::new_proof(merkle_proof)?;
+                // will fail if rx dropped
+                send_within(&tx, Ok(response), idle_timeout).await?;
 
-            Ok::<(), ServerError>(())
+                Ok::<(), ServerError>(())
+            }
+            .await;
+            send_task_error(&tx, res, idle_timeout).await
         });
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 
+    /// Returns the proof that the archive of `new_size` entries extends the
+    /// archive of `old_size` entries
+    async fn consistency_proof(
+        &self,
+        request: Request<ConsistencyProofRequest>,
+    ) -> Result<Response<ConsistencyProofResponse>, Status> {
+        let ConsistencyProofRequest { old_size, new_size } = *request.get_ref();
+        tracing::info!(message = "consistency proof", old_size, new_size);
+        let proof = self
+            .node
+            .db()
+            .consistency_proof(
+                usize::try_from(old_size).unwrap_or(usize::MAX),
+                usize::try_from(new_size).unwrap_or(usize::MAX),
+            )
+            .map_err(ServerError::MerkleTree)?;
+        Ok(Response::new(proof.into()))
+    }
+
     type DownloadStream = ReceiverStream<Result<DownloadResponse, Status>>;
 
     /// Downloads the file at the given index, returns its corresponding
-    /// filename as well as its merkle proof.
+    /// filename as well as its merkle proof, followed by the file content
+    /// starting at the requested offset.
     async fn download(
         &self,
-        request: tonic::Request<FileIndex>,
+        request: tonic::Request<DownloadRequest>,
     ) -> std::result::Result<Response<Self::DownloadStream>, Status> {
-        let (tx, rx) =
-            mpsc::channel::<Result<DownloadResponse, Status>>(self.node.config().channel_size());
+        let DownloadRequest {
+            index,
+            offset,
+            chunk_size,
+            length,
+            metadata_only,
+        } = *request.get_ref();
+        if metadata_only {
+            return self.stream_entry(index).await;
+        }
+        self.stream_file(index, offset, length, chunk_size).await
+    }
 
-        let node = self.node.clone();
+    type DownloadByNameStream = ReceiverStream<Result<DownloadResponse, Status>>;
 
+    /// Same as `download` from offset 0, for the most recently uploaded
+    /// entry with the requested filename. The filename must follow the same
+    /// rules as the uploaded ones, see `validate_filename`.
+    async fn download_by_name(
+        &self,
+        request: tonic::Request<FileName>,
+    ) -> std::result::Result<Response<Self::DownloadByNameStream>, Status> {
+        let filename = &request.get_ref().filename;
+        validate_filename(filename, self.node.config().max_filename_len())
+            .map_err(ServerError::from)?;
+        let file_index = self
+            .node
+            .db()
+            .last_index_of_filename(filename)
+            .ok_or_else(|| ServerError::FileNameDoesNotExist(filename.clone()))?;
+        self.stream_file(file_index as u64, 0, 0, 0).await
+    }
+
+    /// Returns the metadata and download statistics of the file at the given index
+    async fn metadata(&self, request: Request<FileIndex>) -> Result<Response<EntryInfo>, Status> {
         let file_index = request.get_ref().index;
-        let path = MemDb::file_path_at(file_index as usize, &node.config().files_db_dir());
+        let info = self.node.db().entry_info_at(file_index as usize)?;
+        Ok(Response::new(info))
+    }
 
-        tracing::info!(message = "download", %file_index);
+    /// Returns the index of the first entry with the requested sha256
+    async fn find(&self, request: Request<FileSha256>) -> Result<Response<FileIndex>, Status> {
+        let sha256 = &request.get_ref().sha256;
+        if sha256.len() != 32 {
+            return Err(ServerError::InvalidSha256Length(sha256.len()).into());
+        }
+        let index = self
+            .node
+            .db()
+            .index_of_sha256(sha256)
+            .ok_or_else(|| ServerError::Sha256DoesNotExist(hex::encode(sha256)))?;
+        Ok(Response::new(FileIndex {
+            index: index as u64,
+        }))
+    }
 
-        tokio::spawn(async move {
-            // Retreive request file from the db
-            let (mem_db_entry, merkle_proof) =
-                node.db().compute_proof_and_entry(file_index as usize)?;
+    /// Returns the index and merkle proof of each requested sha256 present in
+    /// the archive, all computed from the same merkle root
+    async fn audit(
+        &self,
+        request: Request<AuditRequest>,
+    ) -> Result<Response<AuditResponse>, Status> {
+        let sha256s = &request.get_ref().sha256;
+        if sha256s.len() > MAX_AUDIT_HASHES {
+            return Err(ServerError::TooManyAuditHashes {
+                count: sha256s.len(),
+                max: MAX_AUDIT_HASHES,
+            }
+            .into());
+        }
+        if let Some(sha256) = sha256s.iter().find(|sha256| sha256.len() != 32) {
+            return Err(ServerError::InvalidSha256Length(sha256.len()).into());
+        }
+        tracing::info!(message = "audit", hashes = sha256s.len());
+
+        let snapshot = self
+            .node
+            .db()
+            .audit(sha256s)
+            .map_err(ServerError::MerkleTree)?;
+        let results = snapshot
+            .proofs
+            .into_iter()
+            .map(|proof| {
+                let result = match proof {
+                    Some((index, merkle_proof)) => audit_result::Result::Found(AuditProof {
+                        index: index as u64,
+                        merkle_proof: merkle_proof.encode_bin().map_err(ServerError::from)?,
+                    }),
+                    None => audit_result::Result::NotFound(Empty {}),
+                };
+                Ok(AuditResult {
+                    result: Some(result),
+                })
+            })
+            .collect::<Result<_, ServerError>>()?;
+        Ok(Response::new(AuditResponse {
+            results,
+            merkle_root: snapshot.merkle_root,
+            count: snapshot.count as u64,
+        }))
+    }
+
+    /// Returns a page of at most `MAX_LIST_LIMIT` archive entries metadata and
+    /// download statistics along with the total number of entries
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        let ListRequest { offset, limit } = *request.get_ref();
+        let limit = if limit == 0 {
+            MAX_LIST_LIMIT
+        } else {
+            limit.min(MAX_LIST_LIMIT)
+        };
+        let (entries, total) = self.node.db().entry_infos(
+            usize::try_from(offset).unwrap_or(usize::MAX),
+            usize::try_from(limit).unwrap_or(usize::MAX),
+        )?;
+        Ok(Response::new(ListResponse {
+            entries,
+            total: total as u64,
+        }))
+    }
 
-            // 1- Send file metadata (filename)
-            let response = DownloadResponse::new_entry(mem_db_entry.filename(), merkle_proof)?;
-            // will fail if rx dropped
-            tx.send(Ok(response)).await?;
+    /// Returns archive wide statistics
+    async fn stats(&self, _: Request<Empty>) -> Result<Response<StatsResponse>, Status> {
+        let (count, total_bytes, total_downloads) = self.node.db().totals();
+        let count = count as u64;
 
-            let chunk_size = node.config().chunk_size();
-            let tokio_file = tokio::fs::File::open(path).await?;
-            let mut handle = tokio_file.take(chunk_size as u64);
+        let config = self.node.config().clone();
+        let (db_file_bytes, (tmp_files, tmp_bytes)) = spawn_blocking(move || {
+            let db_file_bytes = std::fs::metadata(config.db_file())
+                .map(|m| m.len())
+                .unwrap_or(0);
+            Ok((db_file_bytes, mrklar_fs::dir_size(config.files_tmp_dir())?))
+        })
+        .await?;
 
-            loop {
-                let mut chunk = Vec::with_capacity(chunk_size);
+        Ok(Response::new(StatsResponse {
+            count,
+            total_downloads,
+            total_bytes,
+            tree_depth: if count == 0 {
+                0
+            } else {
+                tree_depth(count) as u64
+            },
+            db_file_bytes,
+            tmp_files: tmp_files as u64,
+            tmp_bytes,
+            maintenance: self.node.maintenance().is_on(),
+        }))
+    }
 
-                // read a chunk from the file
-                let n = handle.read_to_end(&mut chunk).await?;
+    /// Returns the server limits
+    async fn info(&self, _: Request<Empty>) -> Result<Response<ServerInfo>, Status> {
+        Ok(Response::new(ServerInfo {
+            max_chunk_size: self.node.config().max_chunk_size() as u64,
+        }))
+    }
 
-                // reset the take limit before the next chunk
-                handle.set_limit(chunk_size as u64);
+    type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
 
-                // nothing left
-                if n == 0 {
-                    break;
-                }
+    /// Streams the entries added from now on, until the client disconnects,
+    /// lags behind or the server shuts down
+    async fn watch(&self, _: Request<Empty>) -> Result<Response<Self::WatchStream>, Status> {
+        self.node.check_not_shutting_down()?;
 
-                // Send the file chunk to the receiver
-                let response = DownloadResponse::new_chunk(chunk);
-                // will fail if rx dropped
-                tx.send(Ok(response)).await?;
+        let (tx, rx) = mpsc::channel(self.node.config().channel_size());
+        // subscribed before returning, no entry added afterwards is missed
+        let added_entries = self.node.db().subscribe();
+        let node = self.node.clone();
+        let idle_timeout = node.config().stream_idle_timeout();
 
-                // reached the end
-                if n < chunk_size {
-                    break;
+        tracing::info!(message = "watch");
+
+        tokio::spawn(
+            async move {
+                let to_event = |entry: AddedEntry| WatchEvent {
+                    index: entry.index as u64,
+                    signed_tree_head: node.sign_tree_head(&entry.merkle_root, entry.index + 1),
+                    filename: entry.filename,
+                    sha256: entry.sha256,
+                    merkle_root: entry.merkle_root,
+                };
+                let res = tokio::select! {
+                    res = forward_added_entries(added_entries, &tx, to_event) => res,
+                    _ = tx.closed() => Ok(()),
+                    _ = node.shutdown_requested() => Err(ServerError::ShuttingDown),
+                };
+                if let Err(e) = &res {
+                    tracing::info!(message = "watch stream ended", %e);
                 }
+                send_task_error(&tx, res, idle_timeout).await
             }
-
-            Ok::<(), ServerError>(())
-        });
+            .in_current_span(),
+        );
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
 }
 
+/// Sends an event built by `to_event` for each entry received from
+/// `added_entries`, until the client disconnects. Fails with
+/// `ServerError::WatchLagged` once the client lags too far behind.
+async fn forward_added_entries(
+    mut added_entries: broadcast::Receiver<AddedEntry>,
+    tx: &mpsc::Sender<Result<WatchEvent, Status>>,
+    to_event: impl Fn(AddedEntry) -> WatchEvent,
+) -> Result<(), ServerError> {
+    loop {
+        let entry = match added_entries.recv().await {
+            Ok(entry) => entry,
+            Err(RecvError::Lagged(missed)) => return Err(ServerError::WatchLagged(missed)),
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if tx.send(Ok(to_event(entry))).await.is_err() {
+            // the client is gone
+            return Ok(());
+        }
+    }
+}
+
 fn get_upload_request_type(
     o: Option<Result<UploadRequest, Status>>,
 ) -> Result<upload_request::Type, ServerError> {
@@ -264,10 +905,7 @@ fn get_upload_request_type(
         return Err(ServerError::EmptyMessage);
     }
 
-    let ur = match o.unwrap() {
-        Ok(ur) => ur,
-        Err(e) => return Err(ServerError::Status(e)),
-    };
+    let ur = o.unwrap()?;
 
     if ur.r#type.is_none() {
         return Err(ServerError::UndefinedMessageType);
@@ -276,6 +914,17 @@ fn get_upload_request_type(
     Ok(ur.r#type.unwrap())
 }
 
+/// Fails with `ServerError::ChunkTooLarge` if `chunk` exceeds `max_chunk_size`
+fn check_chunk_size(chunk: &[u8], max_chunk_size: usize) -> Result<(), ServerError> {
+    if chunk.len() > max_chunk_size {
+        return Err(ServerError::ChunkTooLarge {
+            size: chunk.len(),
+            max: max_chunk_size,
+        });
+    }
+    Ok(())
+}
+
 fn upload_request_file_metadata(
     o: Option<Result<UploadRequest, Status>>,
 ) -> Result<FileMetadata, ServerError> {
@@ -286,22 +935,118 @@ fn upload_request_file_metadata(
     Ok(file_metadata)
 }
 
-fn upload_request_file_sha256(
-    o: Option<Result<UploadRequest, Status>>,
-) -> Result<Vec<u8>, ServerError> {
-    let file_sha256 = match get_upload_request_type(o)? {
-        upload_request::Type::Sha256(h) => h,
-        _ => return Err(ServerError::UnknownMessageType),
-    };
-    Ok(file_sha256)
+/// Adds an uploaded file to the db. `Node::add_file` commits the tmp object
+/// to the db on success, and deletes it if it fails internally or if the
+/// file is a duplicate not allowed by the config.
+async fn add_uploaded_file(
+    node: &Node,
+    filename: &str,
+    sha256: Vec<u8>,
+    tmp_key: impl AsRef<Path> + Send + 'static,
+) -> Result<AddedFile, ServerError> {
+    node.add_file(filename, sha256, tmp_key)
+        .await
+        .map_err(|e| match e {
+            ServerError::DuplicateEntry(_) => e,
+            _ => ServerError::Unexpected(format!("Unable to add file to the db: {e}")),
+        })
 }
 
-fn upload_request_chunk(o: Option<Result<UploadRequest, Status>>) -> Result<Vec<u8>, ServerError> {
-    let chunk = match get_upload_request_type(o)? {
-        upload_request::Type::Chunk(chunk) => chunk,
-        _ => return Err(ServerError::UnknownMessageType),
+/// The response to a completed upload of content `sha256`
+fn upload_response(
+    node: &Node,
+    added: AddedFile,
+    sha256: Vec<u8>,
+) -> Result<UploadResponse, ServerError> {
+    Ok(UploadResponse {
+        index: Some(FileIndex {
+            index: added.index as u64,
+        }),
+        signed_tree_head: node.sign_tree_head(&added.merkle_root, added.tree_size),
+        merkle_root: added.merkle_root,
+        sha256,
+        merkle_proof: added.proof.encode_bin()?,
+    })
+}
+
+/// Receives the next upload message, fails with `ServerError::StreamIdle`
+/// if the client does not send anything within `timeout`
+async fn next_within<T>(
+    request_stream: &mut Streaming<T>,
+    timeout: Duration,
+) -> Result<Option<Result<T, Status>>, ServerError> {
+    tokio::time::timeout(timeout, request_stream.next())
+        .await
+        .map_err(|_| ServerError::StreamIdle(timeout))
+}
+
+/// Sends a response message, fails with `ServerError::StreamIdle` if the
+/// client does not consume the stream within `timeout`
+async fn send_within<T>(
+    tx: &mpsc::Sender<T>,
+    value: T,
+    timeout: Duration,
+) -> Result<(), ServerError>
+where
+    ServerError: From<SendError<T>>,
+{
+    match tx.send_timeout(value, timeout).await {
+        Ok(()) => Ok(()),
+        Err(SendTimeoutError::Timeout(_)) => Err(ServerError::StreamIdle(timeout)),
+        Err(SendTimeoutError::Closed(value)) => Err(SendError(value).into()),
+    }
+}
+
+/// Records the `bytes` streamed since `start` in the `field` and
+/// `elapsed_ms` fields of the current transfer span, whether the stream
+/// completed or failed
+fn record_transfer(field: &str, bytes: u64, start: Instant) {
+    let span = tracing::Span::current();
+    span.record(field, bytes);
+    span.record("elapsed_ms", start.elapsed().as_millis() as u64);
+    tracing::info!(message = "stream finished");
+}
+
+/// Sends the error of a failed download or proof task to the client, as the
+/// status ending the stream. Nothing is sent if the client is gone or does
+/// not consume the stream.
+async fn send_task_error<T>(
+    tx: &mpsc::Sender<Result<T, Status>>,
+    res: Result<(), ServerError>,
+    timeout: Duration,
+) -> Result<(), ServerError> {
+    let e = match res {
+        Ok(()) => return Ok(()),
+        Err(
+            e @ (ServerError::SendDownloadResponse(_)
+            | ServerError::SendProofResponse(_)
+            | ServerError::StreamIdle(_)),
+        ) => return Err(e),
+        Err(e) => e,
     };
-    Ok(chunk)
+    let status = Status::from(e);
+    let _ = tx.send_timeout(Err(status.clone()), timeout).await;
+    Err(status.into())
+}
+
+/// Maps the failure to open the stored file of the entry at `file_index`
+/// to `ServerError::StoredFileNotFound` if the file does not exist
+fn stored_file_error(e: ServerError, file_index: u64) -> ServerError {
+    match e {
+        ServerError::Io(e) if e.kind() == io::ErrorKind::NotFound => {
+            tracing::error!(message = "Stored file not found", %file_index);
+            ServerError::StoredFileNotFound(file_index as usize)
+        }
+        e => e,
+    }
+}
+
+/// Returns the subject of the certificate presented by the client, if the
+/// server requires client authentication
+fn peer_cert_subject<T>(request: &Request<T>) -> Option<String> {
+    let certs = request.peer_certs()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(certs.first()?).ok()?;
+    Some(cert.subject().to_string())
 }
 
 /// A test function to force a real io error
@@ -317,3 +1062,55 @@ fn test_throw_io_error() -> Result<(), io::Error> {
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::{broadcast, mpsc};
+
+    use super::{forward_added_entries, AddedEntry, ServerError, WatchEvent};
+
+    fn added_entry(index: usize) -> AddedEntry {
+        AddedEntry {
+            index,
+            filename: format!("{index}.txt"),
+            sha256: vec![index as u8; 32],
+            merkle_root: vec![0; 32],
+        }
+    }
+
+    fn to_event(entry: AddedEntry) -> WatchEvent {
+        WatchEvent {
+            index: entry.index as u64,
+            filename: entry.filename,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forward_added_entries() {
+        let (tx, mut rx) = mpsc::channel(8);
+
+        let (added, added_entries) = broadcast::channel(4);
+        for index in 0..3 {
+            added.send(added_entry(index)).unwrap();
+        }
+        drop(added);
+        forward_added_entries(added_entries, &tx, to_event)
+            .await
+            .unwrap();
+        for index in 0..3 {
+            let event = rx.recv().await.unwrap().unwrap();
+            assert_eq!(event.index, index);
+            assert_eq!(event.filename, format!("{index}.txt"));
+        }
+
+        // the oldest entries are dropped for a lagging subscriber
+        let (added, added_entries) = broadcast::channel(2);
+        for index in 0..5 {
+            added.send(added_entry(index)).unwrap();
+        }
+        let res = forward_added_entries(added_entries, &tx, to_event).await;
+        assert!(matches!(res, Err(ServerError::WatchLagged(3))), "{res:?}");
+        assert!(rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,93 @@
+/// The rule broken by a filename rejected by the server
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum FilenameError {
+    #[error("the filename is empty")]
+    Empty,
+    #[error("the filename is {len} bytes long, the maximum is {max} bytes")]
+    TooLong { len: usize, max: usize },
+    #[error("the filename contains a path separator")]
+    PathSeparator,
+    #[error("the filename is a '.' or '..' path component")]
+    DotComponent,
+    #[error("the filename contains a control character")]
+    ControlCharacter,
+}
+
+/// Checks the filenames sent by the clients: the uploaded ones, and the ones
+/// looked up by `DownloadByName`. A valid filename is a single path
+/// component of at most `max_len` bytes, without control characters. It can
+/// be written to disk and logged as is.
+pub fn validate_filename(filename: &str, max_len: usize) -> Result<(), FilenameError> {
+    if filename.is_empty() {
+        return Err(FilenameError::Empty);
+    }
+    if filename.len() > max_len {
+        return Err(FilenameError::TooLong {
+            len: filename.len(),
+            max: max_len,
+        });
+    }
+    if filename.contains(['/', '\\']) {
+        return Err(FilenameError::PathSeparator);
+    }
+    if filename == "." || filename == ".." {
+        return Err(FilenameError::DotComponent);
+    }
+    if filename.chars().any(char::is_control) {
+        return Err(FilenameError::ControlCharacter);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{validate_filename, FilenameError};
+    use crate::config::DEFAULT_MAX_FILENAME_LEN;
+
+    #[test]
+    fn test_validate_filename() {
+        let max = DEFAULT_MAX_FILENAME_LEN;
+        for name in [
+            "file.txt",
+            "..hidden",
+            "a..b",
+            "...",
+            "été 2024.pdf",
+            "ファイル",
+        ] {
+            assert_eq!(validate_filename(name, max), Ok(()), "{name}");
+        }
+        assert_eq!(validate_filename(&"a".repeat(max), max), Ok(()));
+
+        let rejected = [
+            ("", FilenameError::Empty),
+            ("dir/file", FilenameError::PathSeparator),
+            ("/etc/passwd", FilenameError::PathSeparator),
+            ("dir/", FilenameError::PathSeparator),
+            ("..\\evil.bat", FilenameError::PathSeparator),
+            ("../evil", FilenameError::PathSeparator),
+            (".", FilenameError::DotComponent),
+            ("..", FilenameError::DotComponent),
+            ("nul\0byte", FilenameError::ControlCharacter),
+            ("new\nline", FilenameError::ControlCharacter),
+            ("carriage\rreturn", FilenameError::ControlCharacter),
+            ("tab\t", FilenameError::ControlCharacter),
+            ("escape\x1b[31m", FilenameError::ControlCharacter),
+            ("del\x7f", FilenameError::ControlCharacter),
+            ("c1\u{85}", FilenameError::ControlCharacter),
+        ];
+        for (name, expected) in rejected {
+            assert_eq!(validate_filename(name, max), Err(expected), "{name:?}");
+        }
+
+        // the length is in bytes, not characters
+        let name = "é".repeat(max / 2 + 1);
+        assert_eq!(
+            validate_filename(&name, max),
+            Err(FilenameError::TooLong {
+                len: name.len(),
+                max
+            })
+        );
+    }
+}
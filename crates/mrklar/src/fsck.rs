@@ -0,0 +1,152 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::ServerConfig, error::ServerError, lock::DbLock, mem_db::MemDb, stored_file};
+
+/// What `fsck` does with the stored files failing the check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsckRepair {
+    /// The corrupted and extra files are moved into a new directory of the
+    /// files quarantine directory
+    Quarantine,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FsckReport {
+    pub entries: usize,
+    /// The indices of the stored files not matching their merkle tree leaf
+    pub corrupted: Vec<usize>,
+    /// The indices without any stored file
+    pub missing: Vec<usize>,
+    /// The files of the files db directory not belonging to any entry
+    pub extra: Vec<PathBuf>,
+    /// The directory the corrupted and extra files have been moved into
+    pub quarantine_dir: Option<PathBuf>,
+    /// The total size of the stored files recorded by the db
+    pub total_bytes: u64,
+    /// The total size of the stored files recomputed from the files db
+    /// directory, before the repair
+    pub stored_bytes: u64,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupted.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+    }
+
+    /// The total size recorded by the db does not match the stored files,
+    /// it is recomputed by the next storage layout migration or db rebuild
+    pub fn has_total_bytes_drift(&self) -> bool {
+        self.total_bytes != self.stored_bytes
+    }
+}
+
+/// Hashes every stored file and compares the hash with the sha256 of its
+/// entry, then looks for the files of the files db directory not
+/// belonging to any entry. With `FsckRepair::Quarantine`, the corrupted and
+/// extra files are moved aside, the corrupted indices are then reported as
+/// missing by the next check.
+///
+/// The server must be stopped, the db lock is held exclusively during the
+/// whole operation.
+pub fn fsck(config: &ServerConfig, repair: Option<FsckRepair>) -> Result<FsckReport, ServerError> {
+    let config = config.validate()?;
+    let _lock = DbLock::exclusive(&config)?;
+
+    let db = MemDb::try_load(&config)?;
+    let files_db_dir = config.files_db_dir();
+    let mut report = FsckReport {
+        entries: db.num_entries(),
+        total_bytes: db.total_bytes(),
+        ..Default::default()
+    };
+
+    // 1- verify the stored files, a file shared by several entries is only
+    // hashed once
+    let mut stored_paths = HashMap::new();
+    for index in 0..report.entries {
+        let path = db.file_path_at(index, &files_db_dir);
+        if !path.is_file() {
+            report.missing.push(index);
+            continue;
+        }
+        let ok = match stored_paths.get(&path) {
+            Some(&ok) => ok,
+            None => {
+                // a compressed file failing to decompress is corrupted
+                let compressed = db.is_compressed_at(index)?;
+                let ok = match stored_file::sha256(&path, compressed) {
+                    Ok(hash) => hash == db.sha256_at(index)?,
+                    Err(_) if compressed => false,
+                    Err(e) => return Err(e.into()),
+                };
+                report.stored_bytes += std::fs::metadata(&path)?.len();
+                stored_paths.insert(path, ok);
+                ok
+            }
+        };
+        if !ok {
+            report.corrupted.push(index);
+        }
+    }
+
+    // 2- look for extra files
+    let stored_paths: HashSet<_> = stored_paths.into_keys().collect();
+    find_extra_files(&files_db_dir, &stored_paths, &mut report.extra)?;
+    report.extra.sort();
+
+    // 3- move the bad files aside
+    let has_bad_files = !report.corrupted.is_empty() || !report.extra.is_empty();
+    if repair == Some(FsckRepair::Quarantine) && has_bad_files {
+        let run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let quarantine_dir = config.files_quarantine_dir().join(run.to_string());
+        for &index in &report.corrupted {
+            let path = db.file_path_at(index, &files_db_dir);
+            // already moved with a previous entry sharing it
+            if path.is_file() {
+                quarantine(&path, &quarantine_dir.join(index.to_string()))?;
+            }
+        }
+        for path in &report.extra {
+            let relative = path.strip_prefix(&files_db_dir).unwrap_or(path);
+            quarantine(path, &quarantine_dir.join("extra").join(relative))?;
+        }
+        report.quarantine_dir = Some(quarantine_dir);
+    }
+
+    Ok(report)
+}
+
+/// Collects the files of `dir` and its subdirectories not in `stored_paths`
+fn find_extra_files(
+    dir: &Path,
+    stored_paths: &HashSet<PathBuf>,
+    extra: &mut Vec<PathBuf>,
+) -> Result<(), ServerError> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_extra_files(&path, stored_paths, extra)?;
+        } else if !stored_paths.contains(&path) {
+            extra.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn quarantine(path: &Path, dst: &Path) -> Result<(), ServerError> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(path, dst)?;
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::future::Future;
+use std::net::SocketAddr;
+
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::Maintenance;
+
+/// Handle to a server started by `start`.
+/// Dropping the handle leaves the server running in the background.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    // starts the graceful shutdown
+    signal: CancellationToken,
+    maintenance: Maintenance,
+    task: JoinHandle<eyre::Result<()>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        local_addrs: Vec<SocketAddr>,
+        signal: CancellationToken,
+        maintenance: Maintenance,
+        task: JoinHandle<eyre::Result<()>>,
+    ) -> Self {
+        ServerHandle {
+            local_addrs,
+            signal,
+            maintenance,
+            task,
+        }
+    }
+
+    /// The first tcp address the server is listening on, `None` when only
+    /// serving a unix domain socket or custom connections.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().copied()
+    }
+
+    /// All the tcp addresses the server is listening on, one per configured
+    /// host. Empty when only serving a unix domain socket or custom connections.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// The maintenance mode of the server, turned on and off at runtime
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.maintenance
+    }
+
+    /// Gracefully shuts the server down and waits for it to exit.
+    /// See `ServerConfig::with_shutdown_grace_period`.
+    pub async fn shutdown(self) -> eyre::Result<()> {
+        self.signal.cancel();
+        self.wait().await
+    }
+
+    /// Waits for the server to exit
+    pub async fn wait(self) -> eyre::Result<()> {
+        self.task.await?
+    }
+
+    /// Runs the server until `signal` resolves, then gracefully shuts it down
+    pub async fn run_until(mut self, signal: impl Future<Output = ()>) -> eyre::Result<()> {
+        tokio::select! {
+            res = &mut self.task => return res?,
+            _ = signal => {}
+        }
+        self.shutdown().await
+    }
+}
@@ -0,0 +1,378 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufReader, Read, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::ServerError;
+
+// Each record is framed as:
+// - the payload length, u32 little endian
+// - the bincode encoded `JournalRecord`
+// - the first 4 bytes of the payload sha256
+// A record not fully written, or not matching its checksum, ends the journal.
+const FRAME_HEADER_LEN: usize = 4;
+const FRAME_CHECKSUM_LEN: usize = 4;
+// records are a few hundred bytes, anything larger is a corrupted length
+const MAX_RECORD_LEN: usize = 64 * 1024;
+
+/// An entry added after the last db snapshot
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct JournalRecord {
+    pub index: u64,
+    pub filename: String,
+    pub sha256: Vec<u8>,
+    pub size: u64,
+    // unix timestamp in seconds
+    pub uploaded_at: u64,
+    // the stored file is zstd compressed
+    pub compressed: bool,
+    // bytes added to the files db directory, 0 if the entry shares the
+    // stored file of a previous entry
+    pub stored_bytes: u64,
+}
+
+// Records written by previous versions, without the file size and upload
+// timestamp
+#[derive(Deserialize)]
+struct JournalRecordV1 {
+    index: u64,
+    filename: String,
+    sha256: Vec<u8>,
+}
+
+// Records written by previous versions, without the compression flag
+#[derive(Deserialize)]
+struct JournalRecordV2 {
+    index: u64,
+    filename: String,
+    sha256: Vec<u8>,
+    size: u64,
+    uploaded_at: u64,
+}
+
+// Records written by previous versions, without the stored bytes
+#[derive(Deserialize)]
+struct JournalRecordV3 {
+    index: u64,
+    filename: String,
+    sha256: Vec<u8>,
+    size: u64,
+    uploaded_at: u64,
+    compressed: bool,
+}
+
+impl From<JournalRecordV1> for JournalRecord {
+    fn from(value: JournalRecordV1) -> Self {
+        JournalRecord {
+            index: value.index,
+            filename: value.filename,
+            sha256: value.sha256,
+            size: 0,
+            uploaded_at: 0,
+            compressed: false,
+            stored_bytes: 0,
+        }
+    }
+}
+
+impl From<JournalRecordV2> for JournalRecord {
+    fn from(value: JournalRecordV2) -> Self {
+        JournalRecord {
+            index: value.index,
+            filename: value.filename,
+            sha256: value.sha256,
+            size: value.size,
+            uploaded_at: value.uploaded_at,
+            compressed: false,
+            stored_bytes: value.size,
+        }
+    }
+}
+
+// the stored size of a compressed file is unknown, the uploaded size is
+// used instead, fsck reports the drift
+impl From<JournalRecordV3> for JournalRecord {
+    fn from(value: JournalRecordV3) -> Self {
+        JournalRecord {
+            index: value.index,
+            filename: value.filename,
+            sha256: value.sha256,
+            size: value.size,
+            uploaded_at: value.uploaded_at,
+            compressed: value.compressed,
+            stored_bytes: value.size,
+        }
+    }
+}
+
+/// Appends `record` to the journal at `path` and syncs it to disk.
+/// On failure, the journal is truncated back to its previous length.
+pub(crate) fn append(path: &Path, record: &JournalRecord) -> Result<(), ServerError> {
+    append_all(path, std::slice::from_ref(record))
+}
+
+/// Same as `append`, the records are synced to disk once
+pub(crate) fn append_all(path: &Path, records: &[JournalRecord]) -> Result<(), ServerError> {
+    let mut frames = vec![];
+    for record in records {
+        let payload = bincode::serialize(record).map_err(|_| ServerError::DbSave)?;
+        frames.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frames.extend_from_slice(&payload);
+        frames.extend_from_slice(&checksum(&payload));
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let len = file.metadata()?.len();
+    let res = file.write_all(&frames).and_then(|_| file.sync_data());
+    if let Err(e) = res {
+        let _ = file.set_len(len);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Reads all the records of the journal at `path`, an empty list if the
+/// journal does not exist. A torn or corrupted final record, left by a crash
+/// during an append, is truncated.
+pub(crate) fn read(path: &Path) -> Result<Vec<JournalRecord>, ServerError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut records = vec![];
+    let mut valid_len = 0u64;
+    while let Some((record, frame_len)) = read_record(&mut reader)? {
+        records.push(record);
+        valid_len += frame_len as u64;
+    }
+
+    if valid_len < len {
+        tracing::warn!(
+            message = "Truncating the torn db journal tail",
+            path = %path.display(),
+            bytes = len - valid_len
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(valid_len)?;
+    }
+    Ok(records)
+}
+
+/// Removes the records with an index lower than `first_index`, already
+/// stored in the db snapshot. Returns the number of remaining records.
+pub(crate) fn retain_from(path: &Path, first_index: u64) -> Result<usize, ServerError> {
+    let records = read(path)?;
+    let remaining: Vec<_> = records
+        .into_iter()
+        .filter(|r| r.index >= first_index)
+        .collect();
+
+    if remaining.is_empty() {
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        return Ok(0);
+    }
+
+    // the retained records are rewritten, then swapped with the journal
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path: &Path = tmp_path.as_ref();
+    let _ = fs::remove_file(tmp_path);
+    append_all(tmp_path, &remaining)?;
+    fs::rename(tmp_path, path)?;
+    Ok(remaining.len())
+}
+
+/// Reads the next record and its frame length, `None` at the end of the
+/// journal or on a torn or corrupted record
+fn read_record(reader: &mut impl Read) -> Result<Option<(JournalRecord, usize)>, ServerError> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    if !read_exact_or_eof(reader, &mut header)? {
+        return Ok(None);
+    }
+    let payload_len = u32::from_le_bytes(header) as usize;
+    if payload_len > MAX_RECORD_LEN {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0u8; payload_len + FRAME_CHECKSUM_LEN];
+    if !read_exact_or_eof(reader, &mut payload)? {
+        return Ok(None);
+    }
+    let (payload, sum) = payload.split_at(payload_len);
+    if sum != checksum(payload) {
+        return Ok(None);
+    }
+    // an older payload is too short to be read as a newer record
+    let record = bincode::deserialize(payload)
+        .or_else(|_| bincode::deserialize::<JournalRecordV3>(payload).map(JournalRecord::from))
+        .or_else(|_| bincode::deserialize::<JournalRecordV2>(payload).map(JournalRecord::from))
+        .or_else(|_| bincode::deserialize::<JournalRecordV1>(payload).map(JournalRecord::from));
+    let Ok(record) = record else {
+        return Ok(None);
+    };
+    Ok(Some((
+        record,
+        FRAME_HEADER_LEN + payload_len + FRAME_CHECKSUM_LEN,
+    )))
+}
+
+/// Same as `read_exact`, returns `false` if the end of the reader is
+/// reached before `buf` is filled
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, ServerError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; FRAME_CHECKSUM_LEN] {
+    let hash = Sha256::digest(payload);
+    let mut sum = [0u8; FRAME_CHECKSUM_LEN];
+    sum.copy_from_slice(&hash[..FRAME_CHECKSUM_LEN]);
+    sum
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::{append, checksum, read, retain_from, JournalRecord};
+
+    fn record(index: u64) -> JournalRecord {
+        JournalRecord {
+            index,
+            filename: format!("file{}", index),
+            sha256: vec![index as u8; 32],
+            size: index * 10,
+            uploaded_at: 1_700_000_000 + index,
+            compressed: index % 2 == 1,
+            stored_bytes: index * 5,
+        }
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db.journal");
+        assert!(read(&path).unwrap().is_empty());
+
+        for i in 0..3 {
+            append(&path, &record(i)).unwrap();
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // a crash in the middle of the 4th append
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[40, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(file);
+
+        let records = read(&path).unwrap();
+        assert_eq!(records, (0..3).map(record).collect::<Vec<_>>());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+        append(&path, &record(3)).unwrap();
+        assert_eq!(read(&path).unwrap().len(), 4);
+
+        // a corrupted record ends the journal
+        let mut bytes = std::fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(read(&path).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_v1_records() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db.journal");
+
+        // a record without size and upload timestamp
+        let payload = bincode::serialize(&(0u64, "file0", vec![0u8; 32])).unwrap();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&checksum(&payload));
+        std::fs::write(&path, frame).unwrap();
+        append(&path, &record(1)).unwrap();
+
+        // a record without compression flag
+        let payload =
+            bincode::serialize(&(2u64, "file2", vec![2u8; 32], 20u64, 1_700_000_002u64)).unwrap();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&checksum(&payload));
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&frame).unwrap();
+
+        // a record without stored bytes
+        let payload =
+            bincode::serialize(&(3u64, "file3", vec![3u8; 32], 30u64, 1_700_000_003u64, true))
+                .unwrap();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&checksum(&payload));
+        file.write_all(&frame).unwrap();
+        drop(file);
+
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].filename, "file0");
+        assert_eq!(records[0].size, 0);
+        assert_eq!(records[0].uploaded_at, 0);
+        assert!(!records[0].compressed);
+        assert_eq!(records[1], record(1));
+        assert_eq!(
+            records[2],
+            JournalRecord {
+                compressed: false,
+                stored_bytes: 20,
+                ..record(2)
+            }
+        );
+        assert_eq!(
+            records[3],
+            JournalRecord {
+                stored_bytes: 30,
+                ..record(3)
+            }
+        );
+    }
+
+    #[test]
+    fn test_retain_from() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("db.journal");
+        for i in 0..5 {
+            append(&path, &record(i)).unwrap();
+        }
+
+        assert_eq!(retain_from(&path, 3).unwrap(), 2);
+        assert_eq!(read(&path).unwrap(), vec![record(3), record(4)]);
+
+        assert_eq!(retain_from(&path, 5).unwrap(), 0);
+        assert!(!path.exists());
+        assert_eq!(retain_from(&path, 5).unwrap(), 0);
+    }
+}
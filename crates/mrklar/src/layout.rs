@@ -0,0 +1,113 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The subdirectory of the files db directory holding the content addressed
+/// files, keeps them apart from the files named by their index
+pub const CONTENT_ADDRESSED_DIR_NAME: &str = "sha256";
+
+/// How the stored files are organized inside the files db directory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum StorageLayout {
+    /// Files are stored in a single directory and named by their index:
+    /// `db/0`, `db/1`, ...
+    #[default]
+    Flat,
+    /// Files are stored in two levels of subdirectories derived from their
+    /// zero-padded index: `db/00/12/001234`
+    Sharded,
+    /// Files are named by the hex sha256 of their content and stored in two
+    /// levels of subdirectories derived from its first bytes:
+    /// `db/sha256/ab/cd/abcd...`. The entries with the same content share a
+    /// single stored file.
+    ContentAddressed,
+}
+
+impl StorageLayout {
+    pub const ALL: [StorageLayout; 3] = [
+        StorageLayout::Flat,
+        StorageLayout::Sharded,
+        StorageLayout::ContentAddressed,
+    ];
+
+    /// Returns the path of the file at `index` with content `sha256` inside
+    /// `files_db_dir`. Only the content addressed layout uses `sha256`.
+    pub fn file_path_at(&self, index: usize, sha256: &[u8], files_db_dir: &Path) -> PathBuf {
+        let mut file_path = PathBuf::new();
+        file_path.push(files_db_dir);
+        match self {
+            StorageLayout::Flat => {
+                file_path.push(format!("{}", index));
+            }
+            StorageLayout::Sharded => {
+                file_path.push(format!("{:02}", index / 10000));
+                file_path.push(format!("{:02}", (index / 100) % 100));
+                file_path.push(format!("{:06}", index));
+            }
+            StorageLayout::ContentAddressed => {
+                let name = hex::encode(sha256);
+                file_path.push(CONTENT_ADDRESSED_DIR_NAME);
+                file_path.push(name.get(..2).unwrap_or_default());
+                file_path.push(name.get(2..4).unwrap_or_default());
+                file_path.push(name);
+            }
+        }
+        file_path
+    }
+
+    /// Returns `true` if the stored files are shared by the entries with the
+    /// same content
+    pub fn is_content_addressed(&self) -> bool {
+        *self == StorageLayout::ContentAddressed
+    }
+
+    /// Returns the layout of the file stored at `index` with content
+    /// `sha256` inside `files_db_dir`, `None` if there is no such file
+    pub fn detect(index: usize, sha256: &[u8], files_db_dir: &Path) -> Option<StorageLayout> {
+        StorageLayout::ALL
+            .into_iter()
+            .find(|l| l.file_path_at(index, sha256, files_db_dir).is_file())
+    }
+}
+
+impl fmt::Display for StorageLayout {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageLayout::Flat => write!(fmt, "flat"),
+            StorageLayout::Sharded => write!(fmt, "sharded"),
+            StorageLayout::ContentAddressed => write!(fmt, "content-addressed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::StorageLayout;
+
+    #[test]
+    fn test_file_path_at() {
+        let dir = Path::new("/files/db");
+        let sha256 = [0xab, 0xcd, 0x01, 0x23];
+        assert_eq!(
+            StorageLayout::Flat.file_path_at(1234, &sha256, dir),
+            Path::new("/files/db/1234")
+        );
+        assert_eq!(
+            StorageLayout::Sharded.file_path_at(1234, &sha256, dir),
+            Path::new("/files/db/00/12/001234")
+        );
+        assert_eq!(
+            StorageLayout::Sharded.file_path_at(12345678, &sha256, dir),
+            Path::new("/files/db/1234/56/12345678")
+        );
+        assert_eq!(
+            StorageLayout::ContentAddressed.file_path_at(1234, &sha256, dir),
+            Path::new("/files/db/sha256/ab/cd/abcd0123")
+        );
+    }
+}
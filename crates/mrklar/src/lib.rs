@@ -1,56 +1,559 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::time::Duration;
+
 use file_service::FileService;
+use lock::DbLock;
 use mem_db::MemDb;
+use mrklar_api::MrklarApi;
+use mrklar_common::config::{GrpcCompression, WRITE_HEALTH_SERVICE};
 use mrklar_common::proto::file_api_server::FileApiServer;
-use node::Node;
+use node::{spawn_blocking, Node};
+use request_id::RequestIdLayer;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_stream::{Stream, StreamExt, StreamMap};
+use tokio_util::sync::CancellationToken;
+use tonic::server::NamedService;
+use tonic::transport::server::{Connected, Router, TcpIncoming};
 use tonic::transport::Server;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tower::layer::util::{Identity, Stack};
+use tracing_appender::non_blocking::WorkerGuard;
 
+pub mod bundle;
 pub mod cmd;
 pub(crate) mod file_service;
+pub mod filename;
+pub mod fsck;
+pub(crate) mod journal;
+pub mod layout;
+pub(crate) mod lock;
+pub(crate) mod logging;
+mod maintenance;
+pub use maintenance::{Maintenance, MAINTENANCE_RETRY_AFTER};
 pub mod mem_db;
+pub mod migrate;
 pub(crate) mod node;
+pub mod rebuild;
+pub(crate) mod replication;
+pub(crate) mod request_id;
+pub mod storage;
+pub(crate) mod stored_file;
+pub(crate) mod throttle;
+pub(crate) mod tree_signer;
+pub(crate) mod upload_session;
 
 mod config;
-pub use config::ServerConfig;
+pub mod config_file;
+pub use config::{
+    DuplicatePolicy, FileMode, LogRotation, PersistencePolicy, ServerConfig, DEFAULT_LOG_FILE,
+    DEFAULT_MAX_FILENAME_LEN, DEFAULT_REPLICATION_INTERVAL, DEFAULT_SHUTDOWN_GRACE_PERIOD,
+    DEFAULT_STREAM_IDLE_TIMEOUT, DEFAULT_UPLOAD_SESSION_TTL,
+};
+mod handle;
+pub use handle::ServerHandle;
 pub mod error;
 
+/// Interval at which the in-memory download statistics are written to disk.
+/// Downloads recorded since the last flush are lost if the server crashes.
+const DB_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Time given to the connections to close once the in-flight transfers have
+/// completed or have been aborted.
+const CONNECTIONS_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum interval at which the expired upload sessions are removed
+const UPLOAD_SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
 pub async fn spawn(config: ServerConfig) {
     try_spawn(config).await.expect("failed to spawn server")
 }
 
-#[tracing::instrument]
+/// The default shutdown signal: ctrl-c
 pub async fn on_shutdown() {
-    // TODO implement graceful shutdown
     tokio::signal::ctrl_c().await.ok();
-    tracing::info!(message = "Shutting down server...");
 }
 
+/// Runs `server` until ctrl-c, each SIGUSR1 toggles its maintenance mode
+pub async fn run_until_shutdown(server: ServerHandle) -> eyre::Result<()> {
+    let toggle = maintenance::toggle_on_sigusr1(server.maintenance().clone());
+    server
+        .run_until(async {
+            tokio::select! {
+                _ = on_shutdown() => {}
+                _ = toggle => {}
+            }
+        })
+        .await
+}
+
+/// Runs the server until ctrl-c, see `run_until_shutdown`
 pub async fn try_spawn(config: ServerConfig) -> eyre::Result<()> {
+    run_until_shutdown(start(config).await?).await
+}
+
+/// Same as `try_spawn`, but serves the connections yielded by `incoming`
+/// (in-memory transports, custom listeners, ...) instead of listening on
+/// the `config` address.
+pub async fn try_spawn_with_incoming<I, IO, IE>(
+    config: ServerConfig,
+    incoming: I,
+) -> eyre::Result<()>
+where
+    I: Stream<Item = Result<IO, IE>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IO::ConnectInfo: Clone + Send + Sync + 'static,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+{
+    run_until_shutdown(start_with_incoming(config, incoming).await?).await
+}
+
+/// Loads the db and starts listening on the `config` addresses and/or unix
+/// domain socket. The server is ready to answer requests once this returns,
+/// it runs in the background until shut down through the returned handle.
+pub async fn start(config: ServerConfig) -> eyre::Result<ServerHandle> {
+    let sock_addrs = config.sock_addrs();
+    let listens_on_tcp = config.listens_on_tcp();
+    let uds_path = config.uds_path().cloned();
+    let uds_mode = config.uds_mode();
+
+    start_with(config, |make_router| {
+        // the tcp listeners are bound first, they are simply released if the
+        // unix domain socket cannot be bound
+        let mut server = None;
+        let mut local_addrs = vec![];
+        if listens_on_tcp {
+            let (router, shutdown) = make_router();
+            let (tcp_server, tcp_addrs) = serve_tcp(router, &sock_addrs, shutdown)?;
+            for local_addr in &tcp_addrs {
+                tracing::info!(message = "Server listening", %local_addr);
+            }
+            server = Some(tcp_server);
+            local_addrs = tcp_addrs;
+        }
+        if let Some(path) = uds_path {
+            let (router, shutdown) = make_router();
+            let uds_server = serve_uds(router, path.clone(), uds_mode, shutdown)?;
+            tracing::info!(message = "Server listening", uds_path = %path.display());
+            server = Some(match server {
+                Some(tcp_server) => {
+                    Box::pin(async move { tokio::try_join!(tcp_server, uds_server).map(|_| ()) })
+                }
+                None => uds_server,
+            });
+        }
+        let server = server.expect("listening on tcp or on a unix domain socket");
+        Ok((server, local_addrs))
+    })
+    .await
+}
+
+/// Same as `start`, but serves the connections yielded by `incoming`
+/// instead of listening on the `config` address.
+pub async fn start_with_incoming<I, IO, IE>(
+    config: ServerConfig,
+    incoming: I,
+) -> eyre::Result<ServerHandle>
+where
+    I: Stream<Item = Result<IO, IE>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IO::ConnectInfo: Clone + Send + Sync + 'static,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>> + Send + 'static,
+{
+    start_with(config, |make_router| {
+        let (router, shutdown) = make_router();
+        tracing::info!(message = "Starting server");
+        Ok((
+            Box::pin(router.serve_with_incoming_shutdown(incoming, shutdown)),
+            vec![],
+        ))
+    })
+    .await
+}
+
+/// Loads the db and spawns the server returned by `listen`
+async fn start_with(
+    config: ServerConfig,
+    listen: impl FnOnce(MakeRouter) -> std::io::Result<(ServeFuture, Vec<SocketAddr>)>,
+) -> eyre::Result<ServerHandle> {
     let config = config.validate()?;
 
-    if config.tracing() {
-        tracing_subscriber::fmt()
-            .with_max_level(config.tracing_level())
-            .init();
+    // flushes the log file when the server task completes
+    let log_guard = logging::init_tracing(&config)?;
+
+    tracing::info!(message = "Config", %config);
+
+    let tls = config.server_tls_config()?;
+    let tree_signer = match config.signing_key() {
+        Some(path) => {
+            let signer = tree_signer::TreeSigner::load_or_generate(path)?;
+            let public_key = hex::encode(signer.public_key());
+            tracing::info!(message = "Signing the tree heads", public_key);
+            Some(signer)
+        }
+        None => None,
+    };
+    // the downloaded files must be verified against the primary root
+    let primary = match config.replicate_from() {
+        Some(url) => {
+            tracing::info!(message = "Replicating the primary server", url);
+            Some(MrklarApi::from_url(url)?.with_strict_verification(true))
+        }
+        None => None,
+    };
+
+    // held as long as the server runs, prevents another server and the
+    // offline maintenance operations from using the same db
+    let db_lock = DbLock::exclusive(&config)?;
+
+    // standard grpc health checks, not serving until the db is loaded
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    set_serving_status(&mut health, ServingStatus::NotServing).await;
+
+    let db = {
+        let config = config.clone();
+        spawn_blocking(move || prepare_db(&config)).await?
+    };
+    let node = Node::new(config, db, tree_signer);
+
+    let service = FileService::new(node.clone());
+    // the size limit applies to the compressed messages, the decompressed
+    // chunks are checked against the max chunk size
+    let max_message_size = node.config().max_message_size();
+    let mut svc = FileApiServer::new(service)
+        .max_decoding_message_size(max_message_size)
+        .max_encoding_message_size(max_message_size);
+    for encoding in GrpcCompression::ACCEPTED {
+        svc = svc.accept_compressed(encoding);
+    }
+    if let Some(encoding) = node.config().net.grpc_compression.encoding() {
+        svc = svc.send_compressed(encoding);
     }
 
-    let sock_addr = config.sock_addr();
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        tracing::info!(message = "TLS enabled");
+        builder = builder.tls_config(tls)?;
+    }
+    let builder = builder
+        // a span per request, see `RequestIdLayer`
+        .layer(RequestIdLayer)
+        // flow control windows adapted to the link latency, a fixed 1 MiB
+        // window caps uploads to 1 MiB per round trip
+        .http2_adaptive_window(Some(true));
+    let make_router = || {
+        let router = builder
+            .clone()
+            .add_service(health_service.clone())
+            .add_service(svc.clone());
+        (router, node.shutdown_requested())
+    };
+    let (server, local_addrs) = listen(&make_router)?;
+    set_serving_status(&mut health, ServingStatus::Serving).await;
+    set_write_status(&mut health, &node).await;
 
-    tracing::info!(message = "Starting server", %sock_addr);
-    tracing::info!(message = "Config", %config);
+    let signal = CancellationToken::new();
+    let maintenance = node.maintenance().clone();
+    let task = tokio::spawn(serve(
+        node,
+        server,
+        primary,
+        health,
+        signal.clone().cancelled_owned(),
+        db_lock,
+        log_guard,
+    ));
+    Ok(ServerHandle::new(local_addrs, signal, maintenance, task))
+}
 
-    let db = MemDb::try_load(&config)?;
-    let node = Node::new(config, db);
+/// Removes the tmp files, the leftovers of uploads interrupted by a server
+/// crash, loads the db and migrates the stored files to the configured
+/// storage layout if needed. The db lock must be held by the caller.
+fn prepare_db(config: &ServerConfig) -> Result<MemDb, error::ServerError> {
+    let (files, bytes) = mrklar_fs::clear_dir(config.files_tmp_dir())?;
+    tracing::info!(message = "Removed orphaned tmp files", files, bytes);
 
-    let service = FileService::new(node);
-    let svc = FileApiServer::new(service);
+    let db = MemDb::try_load(config)?;
+    if db.layout() != config.storage_layout() {
+        let report = migrate::migrate_db(config, &db, config.storage_layout(), false)?;
+        tracing::info!(
+            message = "Migrated the storage layout",
+            from = %report.from,
+            to = %report.to,
+            moved = report.moved
+        );
+    }
+    Ok(db)
+}
+
+/// Runs `server` until `signal` resolves. On shutdown, the server reports
+/// itself as not serving, stops accepting new connections and transfers,
+/// waits for the in-flight ones and persists the db.
+async fn serve(
+    node: Node,
+    mut server: ServeFuture,
+    primary: Option<MrklarApi>,
+    mut health: HealthReporter,
+    signal: impl Future<Output = ()>,
+    _db_lock: DbLock,
+    _log_guard: Option<WorkerGuard>,
+) -> eyre::Result<()> {
+    tokio::select! {
+        res = &mut server => res?,
+        _ = flush_db_periodically(&node) => {}
+        _ = sweep_upload_sessions_periodically(&node) => {}
+        _ = replicate_primary(&node, primary, health.clone()) => {}
+        _ = report_maintenance(&node, health.clone()) => {}
+        _ = signal => {
+            tracing::info!(message = "Shutting down server...");
+            set_serving_status(&mut health, ServingStatus::NotServing).await;
+            let grace_period = node.config().shutdown_grace_period();
+            // the server keeps answering the in-flight requests while draining
+            let (_, res) = tokio::join!(
+                node.shutdown(grace_period),
+                tokio::time::timeout(grace_period + CONNECTIONS_CLOSE_TIMEOUT, &mut server),
+            );
+            match res {
+                Ok(res) => res?,
+                Err(_) => tracing::warn!(message = "Closing the remaining connections"),
+            }
+        }
+    }
 
-    Server::builder()
-        .trace_fn(|_| tracing::info_span!("mrklar_server"))
-        .add_service(svc)
-        .serve_with_shutdown(sock_addr, on_shutdown())
-        .await?;
+    // persist the queued entries and the remaining download statistics
+    node.flush_db_journal().await?;
+    node.save_db_if_dirty().await?;
 
-    tracing::info!(message = "Server shutdown.", %sock_addr);
+    tracing::info!(message = "Server shutdown.");
 
     Ok(())
 }
+
+type ServeFuture = Pin<Box<dyn Future<Output = Result<(), tonic::transport::Error>> + Send>>;
+
+/// Resolves once the server has started shutting down
+type ShutdownFuture = tokio_util::sync::WaitForCancellationFutureOwned;
+
+/// Builds a router serving the server services, one per listener, along with
+/// its shutdown future
+type MakeRouter<'a> = &'a dyn Fn() -> (ServerRouter, ShutdownFuture);
+
+/// Router of the services, each request runs in its own span
+type ServerRouter = Router<Stack<RequestIdLayer, Identity>>;
+
+/// Serves `router` on every tcp address of `sock_addrs`, returns the bound
+/// addresses. With port 0, the addresses following the first one are bound
+/// to the port picked for it. Fails if any address cannot be bound.
+fn serve_tcp(
+    router: ServerRouter,
+    sock_addrs: &[SocketAddr],
+    shutdown: ShutdownFuture,
+) -> std::io::Result<(ServeFuture, Vec<SocketAddr>)> {
+    let mut incoming = StreamMap::new();
+    let mut local_addrs: Vec<SocketAddr> = vec![];
+    for sock_addr in sock_addrs {
+        let mut sock_addr = *sock_addr;
+        if let (0, Some(first)) = (sock_addr.port(), local_addrs.first()) {
+            sock_addr.set_port(first.port());
+        }
+        let listener = bind_tcp(sock_addr).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("unable to bind {}: {}", sock_addr, e))
+        })?;
+        let local_addr = listener.local_addr()?;
+        let listener_incoming =
+            TcpIncoming::from_listener(listener, true, None).map_err(std::io::Error::other)?;
+        incoming.insert(local_addr, listener_incoming);
+        local_addrs.push(local_addr);
+    }
+    // the connections of all the listeners are served by the same router
+    let incoming = incoming.map(|(_, conn)| conn);
+    Ok((
+        Box::pin(router.serve_with_incoming_shutdown(incoming, shutdown)),
+        local_addrs,
+    ))
+}
+
+/// Binds a tcp listener to `sock_addr`. An ipv6 listener only accepts ipv6
+/// connections, `0.0.0.0` and `::` can be bound to the same port.
+fn bind_tcp(sock_addr: SocketAddr) -> std::io::Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(sock_addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    if sock_addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    // same as `std::net::TcpListener::bind`
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?;
+    socket.bind(&sock_addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// Serves `router` on a unix domain socket bound at `path`, replacing the
+/// stale socket file left by a server that did not shut down cleanly.
+/// The socket file gets the `mode` permissions if set, it is removed once the
+/// server has exited or is dropped.
+#[cfg(unix)]
+fn serve_uds(
+    router: ServerRouter,
+    path: PathBuf,
+    mode: Option<FileMode>,
+    shutdown: ShutdownFuture,
+) -> std::io::Result<ServeFuture> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(&path)?;
+        }
+    }
+    let listener = tokio::net::UnixListener::bind(&path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("unable to bind {}: {}", path.display(), e),
+        )
+    })?;
+    let socket_file = SocketFile(path);
+    if let Some(mode) = mode {
+        std::fs::set_permissions(&socket_file.0, std::fs::Permissions::from_mode(mode.0))?;
+    }
+    let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+    Ok(Box::pin(async move {
+        let _socket_file = socket_file;
+        router
+            .serve_with_incoming_shutdown(incoming, shutdown)
+            .await
+    }))
+}
+
+/// Removes the unix domain socket file when dropped
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(not(unix))]
+fn serve_uds(
+    _router: ServerRouter,
+    _path: PathBuf,
+    _mode: Option<FileMode>,
+    _shutdown: ShutdownFuture,
+) -> std::io::Result<ServeFuture> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the `grpc.health.v1.Health` status of the server, of the file api
+/// and of its write path
+async fn set_serving_status(health: &mut HealthReporter, status: ServingStatus) {
+    health.set_service_status("", status).await;
+    health
+        .set_service_status(FileApiServer::<FileService>::NAME, status)
+        .await;
+    health
+        .set_service_status(WRITE_HEALTH_SERVICE, status)
+        .await;
+}
+
+/// Sets the status of the write path health service: not serving on a
+/// replica or in maintenance mode, serving otherwise
+async fn set_write_status(health: &mut HealthReporter, node: &Node) {
+    let status = if node.config().is_replica() || node.maintenance().is_on() {
+        ServingStatus::NotServing
+    } else {
+        ServingStatus::Serving
+    };
+    health
+        .set_service_status(WRITE_HEALTH_SERVICE, status)
+        .await;
+}
+
+/// Reports the maintenance mode in the write path health service. The
+/// queued entries and the download statistics are persisted when the
+/// maintenance mode is turned on, before reporting it, the db files are then
+/// left untouched until it is turned off. Never returns.
+async fn report_maintenance(node: &Node, mut health: HealthReporter) {
+    let mut changes = node.maintenance().subscribe();
+    while changes.changed().await.is_ok() {
+        if *changes.borrow_and_update() {
+            if let Err(e) = node.flush_db_journal().await {
+                tracing::error!(message = "db journal flush failed", %e);
+            }
+            if let Err(e) = node.save_db_if_dirty().await {
+                tracing::error!(message = "db flush failed", %e);
+            }
+        }
+        set_write_status(&mut health, node).await;
+    }
+    std::future::pending().await
+}
+
+/// Periodically persists the db download statistics and, with the
+/// `Interval` persistence policy, the queued entries. Paused in maintenance
+/// mode. Never returns.
+async fn flush_db_periodically(node: &Node) {
+    let (period, flush_journal) = match node.config().persistence() {
+        PersistencePolicy::Interval(period) => (period.max(Duration::from_millis(1)), true),
+        _ => (DB_FLUSH_INTERVAL, false),
+    };
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if node.maintenance().is_on() {
+            continue;
+        }
+        if flush_journal {
+            if let Err(e) = node.flush_db_journal().await {
+                tracing::error!(message = "db journal flush failed", %e);
+            }
+        }
+        if let Err(e) = node.save_db_if_dirty().await {
+            tracing::error!(message = "db flush failed", %e);
+        }
+    }
+}
+
+/// Mirrors `primary` if the server is a replica, see
+/// `replication::replicate_periodically`. A replica diverging from the
+/// primary stops replicating and reports itself as not serving. Never returns.
+async fn replicate_primary(node: &Node, primary: Option<MrklarApi>, mut health: HealthReporter) {
+    if let Some(primary) = primary {
+        let e = replication::replicate_periodically(node, &primary).await;
+        tracing::error!(message = "Replication stopped", %e);
+        set_serving_status(&mut health, ServingStatus::NotServing).await;
+    }
+    std::future::pending().await
+}
+
+/// Periodically removes the upload sessions without any activity for
+/// longer than the configured ttl, along with their files. Never returns.
+async fn sweep_upload_sessions_periodically(node: &Node) {
+    let ttl = node.config().upload_session_ttl();
+    let period = ttl.clamp(Duration::from_millis(1), UPLOAD_SESSION_SWEEP_INTERVAL);
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        for key in node.upload_sessions().remove_expired(ttl) {
+            tracing::info!(message = "Upload session expired", key = %key.display());
+            let storage = node.storage().clone();
+            if let Err(e) = spawn_blocking(move || Ok(storage.delete(&key)?)).await {
+                tracing::error!(message = "Upload session file removal failed", %e);
+            }
+        }
+    }
+}
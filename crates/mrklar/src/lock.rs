@@ -0,0 +1,39 @@
+use std::fs::File;
+
+use fs2::FileExt;
+
+use crate::{config::ServerConfig, error::ServerError};
+
+/// An exclusive advisory lock on the server db directory.
+/// A running server holds it as long as it runs, offline maintenance
+/// operations (layout migration, ...) hold it while they run.
+/// The lock is released when dropped.
+#[derive(Debug)]
+pub struct DbLock {
+    file: File,
+}
+
+impl DbLock {
+    /// Acquires the lock, fails if it is already held.
+    pub fn exclusive(config: &ServerConfig) -> Result<Self, ServerError> {
+        let file = Self::open(config)?;
+        FileExt::try_lock_exclusive(&file)
+            .map_err(|_| ServerError::DbLocked(config.db_lock_file().display().to_string()))?;
+        Ok(DbLock { file })
+    }
+
+    fn open(config: &ServerConfig) -> Result<File, ServerError> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(config.db_lock_file())?;
+        Ok(file)
+    }
+}
+
+impl Drop for DbLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
@@ -0,0 +1,50 @@
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::RollingFileAppender;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+use crate::{config::ServerConfig, error::ServerError};
+
+/// Installs the global tracing subscriber writing to stdout and, if a log
+/// directory is configured, to a rolling log file. Does nothing if tracing
+/// is disabled or a global subscriber is already installed.
+///
+/// The log file is written by a background thread, the returned guard
+/// flushes the pending traces when dropped and must be held as long as
+/// the server runs. Fails if the log file cannot be created.
+pub(crate) fn init_tracing(config: &ServerConfig) -> Result<Option<WorkerGuard>, ServerError> {
+    if !config.tracing() {
+        return Ok(None);
+    }
+
+    let (file_layer, guard) = match config.log_dir() {
+        None => (None, None),
+        Some(log_dir) => {
+            let mut builder = RollingFileAppender::builder()
+                .rotation(config.log_rotation().into())
+                .filename_prefix(config.log_file());
+            if let Some(max_files) = config.log_max_files() {
+                builder = builder.max_log_files(max_files);
+            }
+            let appender = builder
+                .build(log_dir)
+                .map_err(|e| ServerError::LogDir(log_dir.display().to_string(), e.to_string()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let layer = tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(writer);
+            (Some(layer), Some(guard))
+        }
+    };
+    let stdout_layer =
+        (config.log_stdout() || file_layer.is_none()).then(tracing_subscriber::fmt::layer);
+
+    let _ = tracing_subscriber::registry()
+        .with(stdout_layer)
+        .with(file_layer)
+        .with(LevelFilter::from_level(config.tracing_level()))
+        .try_init();
+
+    Ok(guard)
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+
+/// Delay after which the clients are invited to retry the writes rejected
+/// in maintenance mode, sent in the `retry-after` metadata
+pub const MAINTENANCE_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// The maintenance mode of a running server. While on, the uploads and the
+/// replication are paused and the db files on disk are left untouched, the
+/// reads keep being served. Shared by the server and its handle.
+#[derive(Debug, Clone)]
+pub struct Maintenance(Arc<watch::Sender<bool>>);
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl Maintenance {
+    pub fn is_on(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Turns the maintenance mode on or off, returns the previous state
+    pub fn set(&self, on: bool) -> bool {
+        let mut previous = on;
+        // the subscribers are only notified of actual changes
+        self.0.send_if_modified(|state| {
+            previous = std::mem::replace(state, on);
+            previous != on
+        });
+        if previous != on {
+            tracing::info!(message = "Maintenance mode", on);
+        }
+        previous
+    }
+
+    /// Flips the maintenance mode, returns the new state
+    pub fn toggle(&self) -> bool {
+        let mut on = false;
+        self.0.send_modify(|state| {
+            *state = !*state;
+            on = *state;
+        });
+        tracing::info!(message = "Maintenance mode", on);
+        on
+    }
+
+    /// Notified each time the maintenance mode is turned on or off
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.0.subscribe()
+    }
+}
+
+/// Toggles `maintenance` on each SIGUSR1 received by the process. Never
+/// returns, waits forever if the signal cannot be handled.
+pub async fn toggle_on_sigusr1(maintenance: Maintenance) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::user_defined1()) {
+            Ok(mut sigusr1) => {
+                while sigusr1.recv().await.is_some() {
+                    maintenance.toggle();
+                }
+            }
+            Err(e) => tracing::error!(message = "Unable to handle SIGUSR1", %e),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = maintenance;
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::Maintenance;
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let maintenance = Maintenance::default();
+        let mut rx = maintenance.subscribe();
+        assert!(!maintenance.is_on());
+
+        assert!(maintenance.toggle());
+        assert!(maintenance.is_on());
+        rx.changed().await.unwrap();
+        assert!(*rx.borrow_and_update());
+
+        assert!(maintenance.clone().set(false));
+        assert!(!maintenance.is_on());
+        rx.changed().await.unwrap();
+        assert!(!*rx.borrow_and_update());
+        assert!(!maintenance.set(false));
+        assert!(!rx.has_changed().unwrap());
+    }
+}
@@ -1,20 +1,139 @@
 use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{BufWriter, Write},
     os::unix::fs::MetadataExt,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use mrklar_common::merkle_proof::MerkleProof;
+use mrklar_common::{
+    consistency_proof::ConsistencyProof, merkle_proof::MerkleProof, proto::EntryInfo,
+};
 use mrklar_fs::{self, dir_exists, file_exists};
 use mrklar_tree::{error::MerkleTreeError, merkle_tree::MerkleTree};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    config::{DuplicatePolicy, PersistencePolicy, ServerConfig},
+    error::ServerError,
+    journal::{self, JournalRecord},
+    layout::StorageLayout,
+    storage::{Storage, DB_PREFIX},
+    stored_file,
+};
+
+// The db file starts with a header, db files written before
+// the header was introduced start directly with the entries.
+const DB_MAGIC: [u8; 8] = *b"MRKLARDB";
+// - version 1: entries only store the filename
+// - version 2: entries store download statistics
+// - version 3: the db file is a snapshot, the entries added since are
+//   stored in the db journal
+// - version 4: entries store the file size and upload timestamp
+// - version 5: entries store the file sha256, older entries get it from the
+//   merkle tree leaves on load
+// - version 6: entries store whether the stored file is compressed
+// - version 7: the db stores the total size of the stored files, computed
+//   from the files db directory on load for older versions
+const DB_VERSION: u32 = 7;
+
+// number of journal records triggering a new db snapshot
+const JOURNAL_SNAPSHOT_RECORDS: usize = 1024;
+
+/// Number of added entries buffered for the subscribers, see
+/// `MemDb::subscribe`. A subscriber lagging further behind misses entries.
+pub const ADDED_ENTRIES_BUFFER: usize = 1024;
+
+/// The result of `MemDb::audit`
+#[derive(Debug)]
+pub struct AuditSnapshot {
+    // the index and proof of each looked up sha256, `None` if absent
+    pub proofs: Vec<Option<(usize, MerkleProof)>>,
+    pub count: usize,
+    pub merkle_root: Vec<u8>,
+}
+
+/// The result of `MemDb::add_file`
+#[derive(Debug)]
+pub struct AddedFile {
+    pub index: usize,
+    pub merkle_root: Vec<u8>,
+    // number of entries `merkle_root` was computed from
+    pub tree_size: usize,
+    // the proof of the file, leading to `merkle_root`
+    pub proof: MerkleProof,
+}
+
+/// An entry added to the db, see `MemDb::subscribe`
+#[derive(Debug, Clone)]
+pub struct AddedEntry {
+    pub index: usize,
+    pub filename: String,
+    pub sha256: Vec<u8>,
+    // the merkle root once the entry was added, computed from `index + 1`
+    // entries
+    pub merkle_root: Vec<u8>,
+}
 
-use crate::{config::ServerConfig, error::ServerError};
+// the sender of the added entries, shared by the db clones
+#[derive(Debug, Clone)]
+struct AddedEntries(broadcast::Sender<AddedEntry>);
 
+impl Default for AddedEntries {
+    fn default() -> Self {
+        AddedEntries(broadcast::channel(ADDED_ENTRIES_BUFFER).0)
+    }
+}
+
+/// The result of `MemDb::snapshot`
+#[derive(Debug)]
+pub(crate) struct DbSnapshot {
+    // the serialized db, as written in the db file
+    pub bytes: Vec<u8>,
+    pub layout: StorageLayout,
+    pub entries: Vec<MemDbEntry>,
+    // `None` if the db is empty
+    pub merkle_root: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MemDbHeader {
+    magic: [u8; 8],
+    version: u32,
+    layout: StorageLayout,
+}
+
+// The db is persisted as a snapshot, the db file, and an append-only journal
+// of the entries added since the snapshot. Each new entry is appended to the
+// journal, the snapshot is rewritten by `save`.
+//
+// The `inner` lock only guards in-memory reads and mutations, the file moves
+// and the db file writes are performed without holding it.
 #[derive(Debug, Default, Clone)]
 pub struct MemDb {
     inner: Arc<RwLock<MemDbInner>>,
+    // set when download statistics changed since the last save
+    dirty: Arc<AtomicBool>,
+    // number of records in the journal
+    journal_len: Arc<AtomicUsize>,
+    // the records not written to the journal yet, when the persistence
+    // policy is not `Always`. Guarded by the add lock
+    pending_records: Arc<Mutex<Vec<JournalRecord>>>,
+    // serializes the file additions and the journal writes, the index of a
+    // new file is reserved while its file is moved into the files db directory
+    add_lock: Arc<Mutex<()>>,
+    // serializes the db file writes, so that a db snapshot is never
+    // overwritten by an older one
+    save_lock: Arc<Mutex<()>>,
+    // notified of each added entry while holding the add lock, in index order
+    added_entries: AddedEntries,
 }
 
 impl MemDb {
@@ -22,14 +141,122 @@ impl MemDb {
         self.inner.read().num_entries()
     }
 
+    /// Returns a receiver of the entries added from now on, in index order.
+    /// The receiver gets `RecvError::Lagged` once it falls behind by more
+    /// than `ADDED_ENTRIES_BUFFER` entries, the additions never wait for it.
+    pub fn subscribe(&self) -> broadcast::Receiver<AddedEntry> {
+        self.added_entries.0.subscribe()
+    }
+
+    /// The total size in bytes of the stored files, a stored file shared by
+    /// several entries is counted once
+    pub fn total_bytes(&self) -> u64 {
+        self.inner.read().total_bytes
+    }
+
+    /// The number of downloads of all the entries
+    pub fn total_downloads(&self) -> u64 {
+        self.inner.read().total_downloads()
+    }
+
+    /// Returns the number of entries, the total size of the stored files and
+    /// the number of downloads of all the entries, read consistently
+    pub fn totals(&self) -> (usize, u64, u64) {
+        let inner = self.inner.read();
+        (
+            inner.num_entries(),
+            inner.total_bytes,
+            inner.total_downloads(),
+        )
+    }
+
+    /// Sets the total size of the stored files from the files db directory,
+    /// returns `true` if it changed. The stored files are only read after
+    /// the read lock is released, the db must not be modified meanwhile.
+    pub(crate) fn update_total_bytes(&self, files_db_dir: &Path) -> bool {
+        let paths = self.inner.read().stored_paths(files_db_dir);
+        let total_bytes = stored_bytes(&paths);
+        let mut inner = self.inner.write();
+        let changed = inner.total_bytes != total_bytes;
+        inner.total_bytes = total_bytes;
+        changed
+    }
+
     pub fn merkle_root(&self) -> Result<Vec<u8>, MerkleTreeError> {
         self.inner.read().merkle_root()
     }
 
+    /// Returns the number of entries and the merkle root, read consistently
+    pub fn count_and_root(&self) -> Result<(usize, Vec<u8>), MerkleTreeError> {
+        let inner = self.inner.read();
+        Ok((inner.num_entries(), inner.merkle_root()?))
+    }
+
     pub fn compute_proof(&self, file_index: usize) -> Result<MerkleProof, MerkleTreeError> {
         self.inner.read().compute_proof(file_index)
     }
 
+    /// Returns the proof that the archive of the first `new_size` entries
+    /// extends the archive of the first `old_size` entries
+    pub fn consistency_proof(
+        &self,
+        old_size: usize,
+        new_size: usize,
+    ) -> Result<ConsistencyProof, MerkleTreeError> {
+        self.inner.read().tree.consistency_proof(old_size, new_size)
+    }
+
+    /// Returns the db file content along with the entries and the merkle
+    /// root it holds, all read consistently
+    pub(crate) fn snapshot(&self) -> Result<DbSnapshot, ServerError> {
+        let inner = self.inner.read();
+        let merkle_root = if inner.num_entries() == 0 {
+            None
+        } else {
+            Some(inner.merkle_root()?)
+        };
+        Ok(DbSnapshot {
+            bytes: inner.to_bytes()?,
+            layout: inner.layout,
+            entries: inner.entries.clone(),
+            merkle_root,
+        })
+    }
+
+    /// Returns the metadata, download statistics and sha256 of the entry at `file_index`
+    pub fn entry_info_at(&self, file_index: usize) -> Result<EntryInfo, ServerError> {
+        self.inner.read().entry_info_at(file_index)
+    }
+
+    /// Returns the metadata, download statistics and sha256 of at most `limit`
+    /// entries starting at `offset`, along with the total number of entries.
+    /// An out of range `offset` returns an empty page.
+    pub fn entry_infos(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> Result<(Vec<EntryInfo>, usize), ServerError> {
+        let inner = self.inner.read();
+        let total = inner.num_entries();
+        let end = offset.saturating_add(limit).min(total);
+        let infos = (offset.min(end)..end)
+            .map(|i| inner.entry_info_at(i))
+            .collect::<Result<_, _>>()?;
+        Ok((infos, total))
+    }
+
+    /// Increments the download count of the entry at `file_index` and
+    /// updates its last download timestamp.
+    ///
+    /// The change is only kept in memory, it is persisted by the next call
+    /// to `save` or `save_if_dirty`. Increments recorded after the last save
+    /// are lost if the server crashes.
+    pub fn record_download(&self, file_index: usize) -> Result<(), ServerError> {
+        self.inner.write().record_download(file_index)?;
+        self.dirty.store(true, Ordering::Release);
+        Ok(())
+    }
+
     pub(crate) fn compute_proof_and_entry(
         &self,
         file_index: usize,
@@ -37,31 +264,351 @@ impl MemDb {
         self.inner.read().compute_proof_and_entry(file_index)
     }
 
-    pub fn file_path_at(index: usize, files_db_dir: &Path) -> PathBuf {
-        MemDbInner::file_path_at(index, files_db_dir)
+    /// Returns the path of the stored file at `index` using the db storage
+    /// layout. With the content addressed layout, the path of an index out of
+    /// bounds is meaningless.
+    pub fn file_path_at(&self, index: usize, files_db_dir: &Path) -> PathBuf {
+        self.inner.read().file_path_at(index, files_db_dir)
+    }
+
+    /// Returns the storage key of the stored file at `index`, see
+    /// `file_path_at`
+    pub fn file_key_at(&self, index: usize) -> PathBuf {
+        self.file_path_at(index, Path::new(DB_PREFIX))
+    }
+
+    pub fn layout(&self) -> StorageLayout {
+        self.inner.read().layout
+    }
+
+    pub(crate) fn set_layout(&self, layout: StorageLayout) {
+        self.inner.write().layout = layout;
+    }
+
+    /// Returns the sha256 of the file at `index`
+    pub fn sha256_at(&self, index: usize) -> Result<Vec<u8>, ServerError> {
+        Ok(self.inner.read().entry_at(index)?.sha256.clone())
     }
 
+    /// Returns `true` if the stored file at `index` is compressed
+    pub fn is_compressed_at(&self, index: usize) -> Result<bool, ServerError> {
+        Ok(self.inner.read().entry_at(index)?.compressed)
+    }
+
+    pub(crate) fn entry_at(&self, index: usize) -> Result<MemDbEntry, ServerError> {
+        self.inner.read().entry_at(index).cloned()
+    }
+
+    /// Returns the index of the first entry with content `sha256`
+    pub fn index_of_sha256(&self, sha256: &[u8]) -> Option<usize> {
+        self.inner.read().index_by_sha256.get(sha256).copied()
+    }
+
+    /// Returns the index and merkle proof of the first entry of each content
+    /// `sha256`, along with the number of entries and the merkle root of the
+    /// proofs, all read consistently
+    pub fn audit(&self, sha256s: &[Vec<u8>]) -> Result<AuditSnapshot, MerkleTreeError> {
+        let inner = self.inner.read();
+        let proofs = sha256s
+            .iter()
+            .map(|sha256| match inner.index_by_sha256.get(sha256) {
+                Some(&index) => Ok(Some((index, inner.compute_proof(index)?))),
+                None => Ok(None),
+            })
+            .collect::<Result<_, MerkleTreeError>>()?;
+        let count = inner.num_entries();
+        let merkle_root = if count == 0 {
+            vec![]
+        } else {
+            inner.merkle_root()?
+        };
+        Ok(AuditSnapshot {
+            proofs,
+            count,
+            merkle_root,
+        })
+    }
+
+    /// Returns the index of the most recently added entry named `filename`
+    pub fn last_index_of_filename(&self, filename: &str) -> Option<usize> {
+        self.inner
+            .read()
+            .indices_by_filename
+            .get(filename)
+            .and_then(|indices| indices.last().copied())
+    }
+
+    /// Adds the tmp object `tmp_key` of `storage` to the db, unless an entry with the same
+    /// `hash` exists and the config duplicate policy is not `Allow`.
+    /// Returns the file index, the merkle root, the number of entries the
+    /// root was computed from and the merkle proof of the file, all read
+    /// along with the insertion.
+    ///
+    /// With a config compression level, the file is compressed before being
+    /// stored, unless compressing does not make it smaller.
+    ///
+    /// With the content addressed layout, a file whose content is already
+    /// stored is not stored again, the new entry references the existing
+    /// stored file.
+    ///
+    /// The tmp object is committed to the key of the new entry and the entry
+    /// is appended to the db journal before it becomes visible. With a
+    /// persistence policy other than `Always`, the entry is only queued, see
+    /// `flush_journal`. The merkle tree is only updated once both succeeded:
+    /// on failure, the db is left unchanged and the next entry takes the same
+    /// index. The tmp object is deleted once added, it is left in place on
+    /// failure, the caller may add it again.
     pub fn add_file(
         &self,
         config: &ServerConfig,
+        storage: &dyn Storage,
         filename: &str,
         hash: Vec<u8>,
-        tmp_path: &Path,
-    ) -> Result<(usize, Vec<u8>), ServerError> {
-        self.inner
-            .write()
-            .add_file(config, filename, hash, tmp_path)
+        tmp_key: &Path,
+    ) -> Result<AddedFile, ServerError> {
+        let size = storage.size(tmp_key)?;
+        let res = (|| {
+            // the object committed to the entry key: the tmp object itself,
+            // or its compressed copy
+            let compressed = match config.compression_level() {
+                None => false,
+                Some(level) => stored_file::compress(storage, tmp_key, level)?,
+            };
+            let stored_size = match compressed {
+                false => size,
+                true => storage.size(&stored_file::compressed_key(tmp_key))?,
+            };
+            let record = JournalRecord {
+                // set once the index is reserved
+                index: 0,
+                filename: filename.to_string(),
+                sha256: hash,
+                size,
+                uploaded_at: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default(),
+                compressed,
+                stored_bytes: stored_size,
+            };
+            self.add_object(config, storage, record, tmp_key)
+        })();
+        // the tmp object is left once shared with another entry, its
+        // compressed copy whenever it has not been committed
+        if res.is_ok() {
+            let _ = storage.delete(tmp_key);
+        }
+        let _ = storage.delete(&stored_file::compressed_key(tmp_key));
+        res
     }
 
-    pub fn try_load(config: &ServerConfig) -> eyre::Result<Self> {
-        let inner = MemDbInner::try_load(config)?;
+    // `add_file` once the tmp object has been compressed, if at all. The
+    // tmp object is left in place on failure.
+    fn add_object(
+        &self,
+        config: &ServerConfig,
+        storage: &dyn Storage,
+        mut record: JournalRecord,
+        tmp_key: &Path,
+    ) -> Result<AddedFile, ServerError> {
+        let add_guard = self.add_lock.lock();
+
+        let (file_index, duplicate, layout) = {
+            let inner = self.inner.read();
+            (
+                inner.num_entries(),
+                inner.index_by_sha256.get(&record.sha256).copied(),
+                inner.layout,
+            )
+        };
+        if let Some(index) = duplicate {
+            match config.duplicate_policy() {
+                DuplicatePolicy::Allow => {}
+                DuplicatePolicy::Reject => {
+                    return Err(ServerError::DuplicateEntry(index));
+                }
+                DuplicatePolicy::Dedup => {
+                    // no entry can be added while holding the add lock
+                    let inner = self.inner.read();
+                    return Ok(AddedFile {
+                        index,
+                        merkle_root: inner.merkle_root()?,
+                        tree_size: file_index,
+                        proof: inner.compute_proof(index)?,
+                    });
+                }
+            }
+        }
+
+        // commit the object, the index is reserved by the add lock
+        let dst_key = layout.file_path_at(file_index, &record.sha256, Path::new(DB_PREFIX));
+        let shared = match duplicate {
+            // the stored file of an entry with the same content, if not lost
+            Some(index)
+                if layout.is_content_addressed() && storage.exists(&dst_key).unwrap_or(false) =>
+            {
+                Some(self.inner.read().entry_at(index)?.compressed)
+            }
+            _ => None,
+        };
+        let mut src_key = match record.compressed {
+            false => tmp_key.to_path_buf(),
+            true => stored_file::compressed_key(tmp_key),
+        };
+        match shared {
+            Some(shared_compressed) => {
+                record.compressed = shared_compressed;
+                record.stored_bytes = 0;
+            }
+            None => {
+                if let Some(index) = duplicate.filter(|_| layout.is_content_addressed()) {
+                    // a lost content addressed file is committed again as
+                    // read by the entries sharing it, its size is already
+                    // counted
+                    let shared_compressed = self.inner.read().entry_at(index)?.compressed;
+                    if shared_compressed && !record.compressed {
+                        let level = config
+                            .compression_level()
+                            .unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL);
+                        stored_file::compress(storage, tmp_key, level)?;
+                        src_key = stored_file::compressed_key(tmp_key);
+                    } else if !shared_compressed {
+                        src_key = tmp_key.to_path_buf();
+                    }
+                    record.compressed = shared_compressed;
+                    record.stored_bytes = 0;
+                }
+                storage.commit(&src_key, &dst_key)?;
+            }
+        }
+        record.index = file_index as u64;
+        if config.persistence() == PersistencePolicy::Always {
+            if let Err(e) = journal::append(&config.db_journal_file(), &record) {
+                // the committed object is moved back to where it came from
+                if shared.is_none() {
+                    let _ = storage.commit(&dst_key, &src_key);
+                }
+                return Err(e);
+            }
+            self.journal_len.fetch_add(1, Ordering::AcqRel);
+        } else {
+            self.pending_records.lock().push(record.clone());
+        }
+
+        // should never fail, the entry is already in the journal
+        let (filename, sha256) = (record.filename.clone(), record.sha256.clone());
+        let mut inner = self.inner.write();
+        let (index, root_hash) = inner.push_entry(record)?;
+        assert!(index == file_index);
+        let proof = inner.compute_proof(index)?;
+        drop(inner);
+        // fails only if there is no subscriber
+        let _ = self.added_entries.0.send(AddedEntry {
+            index,
+            filename,
+            sha256,
+            merkle_root: root_hash.clone(),
+        });
+        drop(add_guard);
+
+        Ok(AddedFile {
+            index: file_index,
+            merkle_root: root_hash,
+            tree_size: file_index + 1,
+            proof,
+        })
+    }
+
+    /// Builds a db from the stored file hashes, sizes and compression flags,
+    /// in index order. The original filenames are lost, each entry is named
+    /// after its index.
+    pub(crate) fn from_leaves(
+        layout: StorageLayout,
+        leaves: Vec<(Vec<u8>, u64, bool)>,
+    ) -> Result<Self, ServerError> {
+        let inner = MemDbInner::from_leaves(layout, leaves)?;
+        Ok(MemDb {
+            inner: Arc::new(RwLock::new(inner)),
+            ..Default::default()
+        })
+    }
+
+    /// Writes the queued entries to the db journal, synced to disk once.
+    /// The entries are queued again on failure.
+    pub fn flush_journal(&self, config: &ServerConfig) -> Result<(), ServerError> {
+        let _add_guard = self.add_lock.lock();
+        let records = std::mem::take(&mut *self.pending_records.lock());
+        if records.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = journal::append_all(&config.db_journal_file(), &records) {
+            let mut pending = self.pending_records.lock();
+            pending.splice(0..0, records);
+            return Err(e);
+        }
+        self.journal_len.fetch_add(records.len(), Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// Loads the db snapshot then replays the db journal
+    pub fn try_load(config: &ServerConfig) -> Result<Self, ServerError> {
+        let records = journal::read(&config.db_journal_file())?;
+        let mut inner = MemDbInner::try_load(config, records.last())?;
+        let journal_len = records.len();
+        inner.replay(records)?;
         Ok(MemDb {
             inner: Arc::new(RwLock::new(inner)),
+            journal_len: Arc::new(AtomicUsize::new(journal_len)),
+            ..Default::default()
         })
     }
 
+    /// Writes a new db snapshot, then removes the journal records it
+    /// contains. The db is serialized in memory under the read lock, the
+    /// file itself is written once the lock has been released.
     pub fn save(&self, config: &ServerConfig) -> Result<(), ServerError> {
-        self.inner.read().save(config)
+        self.save_with(config, |writer, bytes| Ok(writer.write_all(bytes)?))
+    }
+
+    fn save_with(
+        &self,
+        config: &ServerConfig,
+        write: impl FnOnce(&mut dyn Write, &[u8]) -> Result<(), ServerError>,
+    ) -> Result<(), ServerError> {
+        let _save_guard = self.save_lock.lock();
+        self.dirty.store(false, Ordering::Release);
+        // the read guard is released before writing
+        let (bytes, entries) = {
+            let inner = self.inner.read();
+            (inner.to_bytes(), inner.num_entries())
+        };
+        let res = bytes
+            .and_then(|bytes| persist(config, |writer| write(writer, &bytes)))
+            .and_then(|_| {
+                let _add_guard = self.add_lock.lock();
+                let len = journal::retain_from(&config.db_journal_file(), entries as u64)?;
+                self.journal_len.store(len, Ordering::Release);
+                // the queued entries in the snapshot no longer need to be written
+                self.pending_records
+                    .lock()
+                    .retain(|r| r.index >= entries as u64);
+                Ok(())
+            });
+        res.inspect_err(|_| {
+            self.dirty.store(true, Ordering::Release);
+        })
+    }
+
+    /// Saves the db only if download statistics changed since the last save,
+    /// or if the journal has grown enough to take a new snapshot
+    pub fn save_if_dirty(&self, config: &ServerConfig) -> Result<(), ServerError> {
+        if self.dirty.load(Ordering::Acquire)
+            || self.journal_len.load(Ordering::Acquire) >= JOURNAL_SNAPSHOT_RECORDS
+        {
+            self.save(config)
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -72,17 +619,226 @@ struct MemDbInner {
     entries: Vec<MemDbEntry>,
     // the database merkle tree
     tree: MerkleTree,
+    // total size in bytes of the stored files, a stored file shared by
+    // several entries is counted once
+    total_bytes: u64,
+    // stored in the db file header
+    #[serde(skip)]
+    layout: StorageLayout,
+    // index of the first entry of each sha256, rebuilt from the tree leaves on load
+    #[serde(skip)]
+    index_by_sha256: HashMap<Vec<u8>, usize>,
+    // indices of the entries of each filename in ascending order, rebuilt on load
+    #[serde(skip)]
+    indices_by_filename: HashMap<String, Vec<usize>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub(crate) struct MemDbEntry {
     filename: String,
+    // number of completed downloads
+    download_count: u64,
+    // unix timestamp in milliseconds of the last completed download, 0 if never
+    last_download_ms: u64,
+    // file size in bytes
+    size: u64,
+    // unix timestamp in seconds of the upload, 0 if unknown
+    uploaded_at: u64,
+    // the file sha256, also the merkle tree leaf
+    sha256: Vec<u8>,
+    // the stored file is zstd compressed, the size and sha256 are the ones
+    // of the uploaded content
+    compressed: bool,
 }
 
 impl MemDbEntry {
     pub fn filename(&self) -> &str {
         &self.filename
     }
+
+    pub fn sha256(&self) -> &[u8] {
+        &self.sha256
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn to_entry_info(&self, index: u64) -> EntryInfo {
+        EntryInfo {
+            index,
+            filename: self.filename.clone(),
+            download_count: self.download_count,
+            last_download_ms: self.last_download_ms,
+            sha256: self.sha256.clone(),
+            size: self.size,
+            uploaded_at: self.uploaded_at,
+        }
+    }
+}
+
+// Db formats written by previous versions
+mod legacy {
+    use mrklar_tree::merkle_tree::MerkleTree;
+    use serde::Deserialize;
+
+    use super::{MemDbEntry, MemDbInner};
+
+    #[derive(Deserialize)]
+    pub(super) struct MemDbInnerV1 {
+        entries: Vec<MemDbEntryV1>,
+        tree: MerkleTree,
+    }
+
+    #[derive(Deserialize)]
+    struct MemDbEntryV1 {
+        filename: String,
+    }
+
+    // versions 2 and 3
+    #[derive(Deserialize)]
+    pub(super) struct MemDbInnerV2 {
+        entries: Vec<MemDbEntryV2>,
+        tree: MerkleTree,
+    }
+
+    #[derive(Deserialize)]
+    struct MemDbEntryV2 {
+        filename: String,
+        download_count: u64,
+        last_download_ms: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct MemDbInnerV4 {
+        entries: Vec<MemDbEntryV4>,
+        tree: MerkleTree,
+    }
+
+    #[derive(Deserialize)]
+    struct MemDbEntryV4 {
+        filename: String,
+        download_count: u64,
+        last_download_ms: u64,
+        size: u64,
+        uploaded_at: u64,
+    }
+
+    #[derive(Deserialize)]
+    pub(super) struct MemDbInnerV5 {
+        entries: Vec<MemDbEntryV5>,
+        tree: MerkleTree,
+    }
+
+    #[derive(Deserialize)]
+    struct MemDbEntryV5 {
+        filename: String,
+        download_count: u64,
+        last_download_ms: u64,
+        size: u64,
+        uploaded_at: u64,
+        sha256: Vec<u8>,
+    }
+
+    // version 6, without the total size of the stored files
+    #[derive(Deserialize)]
+    pub(super) struct MemDbInnerV6 {
+        entries: Vec<MemDbEntry>,
+        tree: MerkleTree,
+    }
+
+    impl From<MemDbInnerV1> for MemDbInner {
+        fn from(value: MemDbInnerV1) -> Self {
+            MemDbInner {
+                entries: value
+                    .entries
+                    .into_iter()
+                    .map(|e| MemDbEntry {
+                        filename: e.filename,
+                        ..Default::default()
+                    })
+                    .collect(),
+                tree: value.tree,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<MemDbInnerV2> for MemDbInner {
+        fn from(value: MemDbInnerV2) -> Self {
+            MemDbInner {
+                entries: value
+                    .entries
+                    .into_iter()
+                    .map(|e| MemDbEntry {
+                        filename: e.filename,
+                        download_count: e.download_count,
+                        last_download_ms: e.last_download_ms,
+                        ..Default::default()
+                    })
+                    .collect(),
+                tree: value.tree,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<MemDbInnerV4> for MemDbInner {
+        fn from(value: MemDbInnerV4) -> Self {
+            MemDbInner {
+                entries: value
+                    .entries
+                    .into_iter()
+                    .map(|e| MemDbEntry {
+                        filename: e.filename,
+                        download_count: e.download_count,
+                        last_download_ms: e.last_download_ms,
+                        size: e.size,
+                        uploaded_at: e.uploaded_at,
+                        ..Default::default()
+                    })
+                    .collect(),
+                tree: value.tree,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<MemDbInnerV5> for MemDbInner {
+        fn from(value: MemDbInnerV5) -> Self {
+            MemDbInner {
+                entries: value
+                    .entries
+                    .into_iter()
+                    .map(|e| MemDbEntry {
+                        filename: e.filename,
+                        download_count: e.download_count,
+                        last_download_ms: e.last_download_ms,
+                        size: e.size,
+                        uploaded_at: e.uploaded_at,
+                        sha256: e.sha256,
+                        ..Default::default()
+                    })
+                    .collect(),
+                tree: value.tree,
+                ..Default::default()
+            }
+        }
+    }
+
+    impl From<MemDbInnerV6> for MemDbInner {
+        fn from(value: MemDbInnerV6) -> Self {
+            MemDbInner {
+                entries: value.entries,
+                tree: value.tree,
+                ..Default::default()
+            }
+        }
+    }
 }
 
 impl MemDbInner {
@@ -90,11 +846,13 @@ impl MemDbInner {
         self.entries.len()
     }
 
-    fn file_path_at(index: usize, files_db_dir: &Path) -> PathBuf {
-        let mut file_path = PathBuf::new();
-        file_path.push(files_db_dir);
-        file_path.push(format!("{}", index));
-        file_path
+    fn file_path_at(&self, index: usize, files_db_dir: &Path) -> PathBuf {
+        let sha256 = self
+            .entries
+            .get(index)
+            .map(|e| e.sha256.as_slice())
+            .unwrap_or_default();
+        self.layout.file_path_at(index, sha256, files_db_dir)
     }
 
     pub fn merkle_root(&self) -> Result<Vec<u8>, MerkleTreeError> {
@@ -104,42 +862,103 @@ impl MemDbInner {
         }
     }
 
-    pub fn add_file(
-        &mut self,
-        config: &ServerConfig,
-        filename: &str,
-        hash: Vec<u8>,
-        tmp_path: &Path,
-    ) -> Result<(usize, Vec<u8>), ServerError> {
-        self.tree
-            .add_leaf(hash)
-            .map_err(ServerError::MerkleTree)
-            .and_then(|file_index| {
-                // add file metadata
-                self.entries.push(MemDbEntry {
-                    filename: filename.to_string(),
-                });
-                assert!(file_index == self.entries.len() - 1);
-
-                // compute new root (should never fail)
-                let root_hash = self.tree.root_hash().unwrap().clone();
-
-                // move file into db
-                let dst_path = MemDbInner::file_path_at(file_index, &config.files_db_dir());
-
-                // this should never fail!
-                // TODO rollback if failure
-                std::fs::rename(tmp_path, dst_path)?;
-
-                self.save(config)?;
-
-                Ok((file_index, root_hash))
-            })
-            .map_err(|e| {
-                // in case of failure, remove tmp file
-                let _ = std::fs::remove_file(tmp_path);
-                e
-            })
+    /// Appends an entry, returns its index and the new merkle root
+    fn push_entry(&mut self, record: JournalRecord) -> Result<(usize, Vec<u8>), MerkleTreeError> {
+        let hash = record.sha256;
+        let file_index = self.tree.add_leaf(hash.clone())?;
+        self.indices_by_filename
+            .entry(record.filename.clone())
+            .or_default()
+            .push(file_index);
+        self.entries.push(MemDbEntry {
+            filename: record.filename,
+            size: record.size,
+            uploaded_at: record.uploaded_at,
+            sha256: hash.clone(),
+            compressed: record.compressed,
+            ..Default::default()
+        });
+        assert!(file_index == self.entries.len() - 1);
+        self.total_bytes += record.stored_bytes;
+        self.index_by_sha256.entry(hash).or_insert(file_index);
+
+        // compute new root (should never fail)
+        let root_hash = self.tree.root_hash().unwrap().clone();
+        Ok((file_index, root_hash))
+    }
+
+    /// Appends the journal records not already in the snapshot
+    fn replay(&mut self, records: Vec<JournalRecord>) -> Result<(), ServerError> {
+        for record in records {
+            let index = record.index as usize;
+            if index < self.num_entries() {
+                continue;
+            }
+            // the records are appended in index order
+            if index > self.num_entries() {
+                return Err(ServerError::DbLoad);
+            }
+            self.push_entry(record)?;
+        }
+        Ok(())
+    }
+
+    // the distinct stored file paths of the entries
+    fn stored_paths(&self, files_db_dir: &Path) -> HashSet<PathBuf> {
+        (0..self.num_entries())
+            .map(|index| self.file_path_at(index, files_db_dir))
+            .collect()
+    }
+
+    // rebuilds the sha256 and filename indexes from the entries
+    fn build_indexes(&mut self) {
+        self.index_by_sha256.clear();
+        self.indices_by_filename.clear();
+        for (index, entry) in self.entries.iter().enumerate() {
+            self.index_by_sha256
+                .entry(entry.sha256.clone())
+                .or_insert(index);
+            self.indices_by_filename
+                .entry(entry.filename.clone())
+                .or_default()
+                .push(index);
+        }
+    }
+
+    // sets the sha256 of the entries loaded from a db file written before
+    // version 5, the tree leaves are the file hashes
+    fn set_sha256s_from_leaves(&mut self) -> Result<(), MerkleTreeError> {
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            entry.sha256 = self.tree.leaf_hash_at(index)?.clone();
+        }
+        Ok(())
+    }
+
+    fn entry_at(&self, file_index: usize) -> Result<&MemDbEntry, ServerError> {
+        self.entries
+            .get(file_index)
+            .ok_or(ServerError::FileIndexDoesNotExist(file_index))
+    }
+
+    pub fn entry_info_at(&self, file_index: usize) -> Result<EntryInfo, ServerError> {
+        Ok(self.entry_at(file_index)?.to_entry_info(file_index as u64))
+    }
+
+    fn total_downloads(&self) -> u64 {
+        self.entries.iter().map(|e| e.download_count).sum()
+    }
+
+    pub fn record_download(&mut self, file_index: usize) -> Result<(), ServerError> {
+        let entry = self
+            .entries
+            .get_mut(file_index)
+            .ok_or(ServerError::FileIndexDoesNotExist(file_index))?;
+        entry.download_count += 1;
+        entry.last_download_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        Ok(())
     }
 
     pub fn compute_proof_and_entry(
@@ -161,12 +980,53 @@ impl MemDbInner {
         self.tree.proof_at(file_index)
     }
 
-    pub fn try_load(config: &ServerConfig) -> Result<Self, ServerError> {
-        use std::fs::File;
-        use std::io::BufReader;
+    fn from_leaves(
+        layout: StorageLayout,
+        leaves: Vec<(Vec<u8>, u64, bool)>,
+    ) -> Result<Self, ServerError> {
+        let mut db = MemDbInner {
+            layout,
+            ..Default::default()
+        };
+        for (hash, size, compressed) in leaves {
+            let index = db.tree.add_leaf(hash)?;
+            db.entries.push(MemDbEntry {
+                filename: index.to_string(),
+                size,
+                sha256: db.tree.leaf_hash_at(index)?.clone(),
+                compressed,
+                ..Default::default()
+            });
+        }
+        db.build_indexes();
+        Ok(db)
+    }
+
+    // A new db, or a db whose entries are all in the journal. The journal
+    // does not store the layout, it is found from the last journaled file:
+    // the files are moved in index order, the last one is still at its
+    // original location after an interrupted migration.
+    fn without_snapshot(config: &ServerConfig, last: Option<&JournalRecord>) -> Self {
+        MemDbInner {
+            layout: last
+                .and_then(|r| {
+                    StorageLayout::detect(r.index as usize, &r.sha256, &config.files_db_dir())
+                })
+                .unwrap_or(config.storage_layout()),
+            ..Default::default()
+        }
+    }
+
+    /// Loads the db snapshot, `last` is the last journal record, used to
+    /// find the layout of a db without snapshot
+    pub fn try_load(
+        config: &ServerConfig,
+        last: Option<&JournalRecord>,
+    ) -> Result<Self, ServerError> {
+        use std::io::{BufRead, BufReader};
 
         if !dir_exists(config.db_dir()) {
-            return Ok(MemDbInner::default());
+            return Ok(MemDbInner::without_snapshot(config, last));
         }
 
         let db_file = config.db_file();
@@ -174,14 +1034,56 @@ impl MemDbInner {
 
         if !file_exists(&db_file) {
             tracing::info!("db file does not exist (path={:?})", db_file_str);
-            return Ok(MemDbInner::default());
+            return Ok(MemDbInner::without_snapshot(config, last));
         }
 
         let file = File::open(&db_file)?;
         let db_size_in_bytes = file.metadata().map(|m| m.size()).unwrap_or(0);
-        let reader = BufReader::new(file);
+        let mut reader = BufReader::new(file);
 
-        let db: MemDbInner = bincode::deserialize_from(reader).map_err(|_| ServerError::DbLoad)?;
+        let has_header = reader.fill_buf()?.starts_with(&DB_MAGIC);
+        let (version, layout) = if has_header {
+            let header: MemDbHeader =
+                bincode::deserialize_from(&mut reader).map_err(|_| ServerError::DbLoad)?;
+            if header.version > DB_VERSION {
+                return Err(ServerError::DbLoad);
+            }
+            (header.version, header.layout)
+        } else {
+            (1, StorageLayout::Flat)
+        };
+
+        let mut db: MemDbInner = if version == 1 {
+            bincode::deserialize_from::<_, legacy::MemDbInnerV1>(&mut reader)
+                .map_err(|_| ServerError::DbLoad)?
+                .into()
+        } else if version < 4 {
+            bincode::deserialize_from::<_, legacy::MemDbInnerV2>(&mut reader)
+                .map_err(|_| ServerError::DbLoad)?
+                .into()
+        } else if version == 4 {
+            bincode::deserialize_from::<_, legacy::MemDbInnerV4>(&mut reader)
+                .map_err(|_| ServerError::DbLoad)?
+                .into()
+        } else if version == 5 {
+            bincode::deserialize_from::<_, legacy::MemDbInnerV5>(&mut reader)
+                .map_err(|_| ServerError::DbLoad)?
+                .into()
+        } else if version == 6 {
+            bincode::deserialize_from::<_, legacy::MemDbInnerV6>(&mut reader)
+                .map_err(|_| ServerError::DbLoad)?
+                .into()
+        } else {
+            bincode::deserialize_from(&mut reader).map_err(|_| ServerError::DbLoad)?
+        };
+        if version < 5 {
+            db.set_sha256s_from_leaves()?;
+        }
+        db.layout = layout;
+        db.build_indexes();
+        if version < 7 {
+            db.total_bytes = stored_bytes(&db.stored_paths(&config.files_db_dir()));
+        }
 
         if config.tracing() {
             tracing::info!(
@@ -198,20 +1100,616 @@ impl MemDbInner {
         Ok(db)
     }
 
-    pub fn save(&self, config: &ServerConfig) -> Result<(), ServerError> {
-        use std::fs::{self, File};
-        use std::io::BufWriter;
+    fn to_bytes(&self) -> Result<Vec<u8>, ServerError> {
+        let mut bytes = vec![];
+        self.serialize_into(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn serialize_into(&self, writer: &mut dyn Write) -> Result<(), ServerError> {
+        let header = MemDbHeader {
+            magic: DB_MAGIC,
+            version: DB_VERSION,
+            layout: self.layout,
+        };
+        bincode::serialize_into(&mut *writer, &header).map_err(|_| ServerError::DbSave)?;
+        bincode::serialize_into(&mut *writer, self).map_err(|_| ServerError::DbSave)?;
+        Ok(())
+    }
+}
+
+/// The total size of the files at `paths`, a missing file counts for 0
+fn stored_bytes(paths: &HashSet<PathBuf>) -> u64 {
+    paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Replaces the db file with the content produced by `write`.
+///
+/// The content is written into a temporary file synced to disk, then renamed
+/// over the db file, so that a crash at any point leaves either the previous
+/// or the new db file in place, never a truncated one. The previous db file
+/// is kept as the backup file for one generation.
+fn persist(
+    config: &ServerConfig,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), ServerError>,
+) -> Result<(), ServerError> {
+    let db_dir = config.db_dir();
+    if !dir_exists(db_dir) {
+        fs::create_dir(db_dir)?;
+    }
 
-        let db_dir = config.db_dir();
-        if !dir_exists(db_dir) {
-            fs::create_dir(db_dir)?;
+    let db_file = config.db_file();
+    let db_tmp_file = config.db_tmp_file();
+
+    let res = (|| {
+        let mut writer = BufWriter::new(File::create(&db_tmp_file)?);
+        write(&mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok::<_, ServerError>(())
+    })();
+    if let Err(e) = res {
+        let _ = fs::remove_file(&db_tmp_file);
+        return Err(e);
+    }
+
+    // keep the previous generation, the db file itself is only ever
+    // replaced by the rename below
+    if file_exists(&db_file) {
+        let db_bak_file = config.db_bak_file();
+        let _ = fs::remove_file(&db_bak_file);
+        if fs::hard_link(&db_file, &db_bak_file).is_err() {
+            fs::copy(&db_file, &db_bak_file)?;
         }
+    }
 
-        let db_file = config.db_file();
+    fs::rename(&db_tmp_file, &db_file)?;
+    // persist the rename itself
+    File::open(db_dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use std::io::Write;
+    use std::path::Path;
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use mrklar_tree::merkle_tree::MerkleTree;
+    use serde::Serialize;
+
+    use super::{AddedFile, MemDb, MemDbHeader, DB_MAGIC};
+    use crate::{
+        config::{DuplicatePolicy, ServerConfig},
+        error::ServerError,
+        journal,
+        layout::StorageLayout,
+        storage::LocalStorage,
+        stored_file,
+    };
+
+    // adds the file at `tmp_path`, in the files tmp directory, through the
+    // local storage
+    fn add_file(
+        db: &MemDb,
+        config: &ServerConfig,
+        filename: &str,
+        hash: Vec<u8>,
+        tmp_path: &Path,
+    ) -> Result<AddedFile, ServerError> {
+        let storage = LocalStorage::new(config.files_dir());
+        let tmp_key = tmp_path.strip_prefix(config.files_dir()).unwrap();
+        db.add_file(config, &storage, filename, hash, tmp_key)
+    }
+
+    // fails once `remaining` bytes have been written
+    struct FailingWriter<'a> {
+        inner: &'a mut dyn Write,
+        remaining: usize,
+    }
+
+    impl Write for FailingWriter<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("disk full"));
+            }
+            let n = buf.len().min(self.remaining);
+            self.remaining -= n;
+            self.inner.write(&buf[..n])
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_download_stats_save_load() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+
+        let db = MemDb::try_load(&config).unwrap();
+        for i in 0..2 {
+            let tmp_path = config.files_tmp_dir().join(format!("file{}", i));
+            std::fs::write(&tmp_path, [i as u8]).unwrap();
+            add_file(
+                &db,
+                &config,
+                &format!("file{}", i),
+                vec![i as u8; 32],
+                &tmp_path,
+            )
+            .unwrap();
+        }
+
+        db.record_download(1).unwrap();
+        db.record_download(1).unwrap();
+        assert!(db.record_download(2).is_err());
+        let entry = db.entry_info_at(1).unwrap();
+        assert_eq!(entry.download_count, 2);
+        assert!(entry.last_download_ms > 0);
+        assert_eq!(db.total_downloads(), 2);
+        assert_eq!(db.totals(), (2, 2, 2));
+
+        db.save_if_dirty(&config).unwrap();
+
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.num_entries(), 2);
+        assert_eq!(loaded.entry_info_at(0).unwrap().download_count, 0);
+        assert_eq!(loaded.entry_info_at(0).unwrap().last_download_ms, 0);
+        assert_eq!(loaded.entry_info_at(1).unwrap().download_count, 2);
+        assert_eq!(
+            loaded.entry_info_at(1).unwrap().last_download_ms,
+            entry.last_download_ms
+        );
+    }
+
+    #[test]
+    fn test_duplicate_policy() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+
+        let add = |db: &MemDb, config: &ServerConfig, name: &str, hash: u8| {
+            let tmp_path = config.files_tmp_dir().join(name);
+            std::fs::write(&tmp_path, [hash]).unwrap();
+            let res = add_file(db, config, name, vec![hash; 32], &tmp_path);
+            // left in place on failure
+            assert_eq!(tmp_path.exists(), res.is_err());
+            res
+        };
+
+        let db = MemDb::try_load(&config).unwrap();
+        assert_eq!(add(&db, &config, "a", 1).unwrap().index, 0);
+        assert_eq!(add(&db, &config, "b", 2).unwrap().index, 1);
+        // allowed duplicate, the index of the first entry is kept
+        assert_eq!(add(&db, &config, "c", 1).unwrap().index, 2);
+        assert_eq!(db.index_of_sha256(&[1; 32]), Some(0));
+        assert_eq!(db.index_of_sha256(&[3; 32]), None);
+
+        // the index survives save and load
+        let db = MemDb::try_load(&config).unwrap();
+        assert_eq!(db.index_of_sha256(&[1; 32]), Some(0));
+        assert_eq!(db.index_of_sha256(&[2; 32]), Some(1));
+
+        let reject = config
+            .clone()
+            .with_duplicate_policy(DuplicatePolicy::Reject);
+        assert!(matches!(
+            add(&db, &reject, "d", 2),
+            Err(crate::error::ServerError::DuplicateEntry(1))
+        ));
+
+        let dedup = config.clone().with_duplicate_policy(DuplicatePolicy::Dedup);
+        let root = db.merkle_root().unwrap();
+        let added = add(&db, &dedup, "e", 2).unwrap();
+        assert_eq!((added.index, added.tree_size), (1, 3));
+        assert_eq!(added.merkle_root, root);
+        assert!(added.proof.verify_with_root(&vec![2; 32], &root));
+        assert_eq!(add(&db, &dedup, "f", 3).unwrap().index, 3);
+        assert_eq!(db.num_entries(), 4);
+    }
+
+    #[test]
+    fn test_content_addressed_sharing() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::ContentAddressed);
+        config.create_dirs().unwrap();
+
+        let db = MemDb::try_load(&config).unwrap();
+        for (name, hash) in [("a", 1), ("b", 2), ("c", 1), ("d", 1)] {
+            let tmp_path = config.files_tmp_dir().join(name);
+            std::fs::write(&tmp_path, [hash]).unwrap();
+            add_file(&db, &config, name, vec![hash; 32], &tmp_path).unwrap();
+            assert!(!tmp_path.exists());
+        }
+        assert_eq!(db.total_bytes(), 2);
+
+        // the entries with the same content share their stored file
+        let files_db_dir = config.files_db_dir();
+        assert_eq!(
+            db.file_path_at(0, &files_db_dir),
+            db.file_path_at(3, &files_db_dir)
+        );
+        assert_ne!(
+            db.file_path_at(0, &files_db_dir),
+            db.file_path_at(1, &files_db_dir)
+        );
+        assert_eq!(
+            db.file_path_at(1, &files_db_dir),
+            files_db_dir
+                .join("sha256")
+                .join("02")
+                .join("02")
+                .join(hex::encode([2; 32]))
+        );
+
+        // the sharing is rebuilt on load
+        db.save(&config).unwrap();
+        let db = MemDb::try_load(&config).unwrap();
+        assert_eq!(db.layout(), StorageLayout::ContentAddressed);
+        assert_eq!(
+            db.file_path_at(0, &files_db_dir),
+            db.file_path_at(2, &files_db_dir)
+        );
+
+        // a lost stored file is committed again, without being counted twice
+        std::fs::remove_file(db.file_path_at(0, &files_db_dir)).unwrap();
+        let tmp_path = config.files_tmp_dir().join("e");
+        std::fs::write(&tmp_path, [1]).unwrap();
+        add_file(&db, &config, "e", vec![1; 32], &tmp_path).unwrap();
+        assert!(db.file_path_at(0, &files_db_dir).is_file());
+        assert_eq!(db.total_bytes(), 2);
+    }
+
+    #[test]
+    fn test_content_addressed_lost_file_compression() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::ContentAddressed);
+        config.create_dirs().unwrap();
+        let files_db_dir = config.files_db_dir();
+        let content = "a line of text, compressing well\n"
+            .repeat(100)
+            .into_bytes();
+
+        let add = |db: &MemDb, config: &ServerConfig, name: &str, hash: u8| {
+            let tmp_path = config.files_tmp_dir().join(name);
+            std::fs::write(&tmp_path, &content).unwrap();
+            add_file(db, config, name, vec![hash; 32], &tmp_path).unwrap();
+            assert_eq!(
+                std::fs::read_dir(config.files_tmp_dir()).unwrap().count(),
+                0
+            );
+        };
+        // the stored file is read with the flag of each entry sharing it
+        let assert_readable = |db: &MemDb| {
+            for index in 0..db.num_entries() {
+                let path = db.file_path_at(index, &files_db_dir);
+                let mut read = vec![];
+                stored_file::open(&path, db.is_compressed_at(index).unwrap())
+                    .unwrap()
+                    .read_to_end(&mut read)
+                    .unwrap();
+                assert_eq!(read, content);
+            }
+        };
+
+        let db = MemDb::try_load(&config).unwrap();
+        let compressed_config = config.clone().with_compression_level(Some(3));
+        add(&db, &compressed_config, "a", 1);
+        add(&db, &config, "b", 2);
+        assert!(db.is_compressed_at(0).unwrap());
+        assert!(!db.is_compressed_at(1).unwrap());
+
+        // lost, then uploaded again with another compression level
+        std::fs::remove_file(db.file_path_at(0, &files_db_dir)).unwrap();
+        add(&db, &config, "c", 1);
+        std::fs::remove_file(db.file_path_at(1, &files_db_dir)).unwrap();
+        add(&db, &compressed_config, "d", 2);
+        assert!(db.is_compressed_at(2).unwrap());
+        assert!(!db.is_compressed_at(3).unwrap());
+        assert_readable(&db);
+    }
+
+    #[test]
+    fn test_save_failure_keeps_previous_db() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+
+        let db = MemDb::try_load(&config).unwrap();
+        for i in 0..3 {
+            let tmp_path = config.files_tmp_dir().join(format!("file{}", i));
+            std::fs::write(&tmp_path, [i as u8]).unwrap();
+            add_file(
+                &db,
+                &config,
+                &format!("file{}", i),
+                vec![i as u8; 32],
+                &tmp_path,
+            )
+            .unwrap();
+            db.save(&config).unwrap();
+        }
+        // each save keeps the previous generation
+        let tmp_bak_dir = tempdir().unwrap();
+        let bak_config = config.clone().with_db_dir(tmp_bak_dir.path().to_path_buf());
+        std::fs::copy(config.db_bak_file(), bak_config.db_file()).unwrap();
+        assert_eq!(MemDb::try_load(&bak_config).unwrap().num_entries(), 2);
+        let root = db.merkle_root().unwrap();
+
+        db.record_download(0).unwrap();
+        let res = db.save_with(&config, |writer, bytes| {
+            let mut writer = FailingWriter {
+                inner: writer,
+                remaining: 64,
+            };
+            Ok(writer.write_all(bytes)?)
+        });
+        assert!(res.is_err());
+        assert!(!config.db_tmp_file().exists());
+
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.num_entries(), 3);
+        assert_eq!(loaded.merkle_root().unwrap(), root);
+        assert_eq!(loaded.entry_info_at(0).unwrap().download_count, 0);
+    }
+
+    #[test]
+    fn test_reads_during_save() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+
+        let db = MemDb::try_load(&config).unwrap();
+        let tmp_path = config.files_tmp_dir().join("file");
+        std::fs::write(&tmp_path, [0]).unwrap();
+        add_file(&db, &config, "file", vec![0; 32], &tmp_path).unwrap();
+
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let save = std::thread::spawn({
+            let db = db.clone();
+            let config = config.clone();
+            move || {
+                db.save_with(&config, |writer, bytes| {
+                    // a slow disk
+                    started_tx.send(()).unwrap();
+                    release_rx.recv().unwrap();
+                    Ok(writer.write_all(bytes)?)
+                })
+            }
+        });
+
+        // the db remains readable and writable while the db file is written
+        started_rx.recv().unwrap();
+        assert_eq!(db.num_entries(), 1);
+        assert!(db.compute_proof(0).is_ok());
+        db.record_download(0).unwrap();
+        assert_eq!(db.entry_info_at(0).unwrap().download_count, 1);
+
+        release_tx.send(()).unwrap();
+        save.join().unwrap().unwrap();
+        // the download was recorded after the snapshot
+        db.save_if_dirty(&config).unwrap();
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.entry_info_at(0).unwrap().download_count, 1);
+    }
+
+    #[test]
+    fn test_journal() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+
+        let add = |db: &MemDb, i: u8| {
+            let tmp_path = config.files_tmp_dir().join(format!("file{}", i));
+            std::fs::write(&tmp_path, [i]).unwrap();
+            add_file(db, &config, &format!("file{}", i), vec![i; 32], &tmp_path).unwrap();
+        };
+
+        // the uploads only append to the journal
+        let db = MemDb::try_load(&config).unwrap();
+        add(&db, 0);
+        add(&db, 1);
+        assert!(!config.db_file().exists());
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.num_entries(), 2);
+        assert_eq!(loaded.merkle_root().unwrap(), db.merkle_root().unwrap());
+        assert_eq!(loaded.entry_info_at(1).unwrap().filename, "file1");
+
+        // the snapshot contains the journal records
+        db.save(&config).unwrap();
+        assert!(!config.db_journal_file().exists());
+        add(&db, 2);
+
+        // a crash in the middle of an append
+        let mut journal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(config.db_journal_file())
+            .unwrap();
+        journal.write_all(&[64, 0, 0]).unwrap();
+        drop(journal);
+
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.num_entries(), 3);
+        assert_eq!(loaded.merkle_root().unwrap(), db.merkle_root().unwrap());
+        assert_eq!(loaded.index_of_sha256(&[2; 32]), Some(2));
+        add(&loaded, 3);
+        assert_eq!(MemDb::try_load(&config).unwrap().num_entries(), 4);
+    }
+
+    #[test]
+    fn test_failed_add_keeps_db_consistent() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+        let files_db_dir = config.files_db_dir();
+        let journal_file = config.db_journal_file();
+
+        let add = |db: &MemDb, i: u8| {
+            let tmp_path = config.files_tmp_dir().join(format!("file{}", i));
+            std::fs::write(&tmp_path, [i]).unwrap();
+            let res = add_file(db, &config, &format!("file{}", i), vec![i; 32], &tmp_path);
+            (res, tmp_path)
+        };
+        // the db in memory, the journal and the stored files are unchanged
+        let assert_unchanged = |db: &MemDb, root: &[u8]| {
+            assert_eq!(db.num_entries(), 1);
+            assert_eq!(db.merkle_root().unwrap(), root);
+            assert_eq!(db.index_of_sha256(&[1; 32]), None);
+            assert_eq!(journal::read(&journal_file).unwrap().len(), 1);
+            let dst_path = db.layout().file_path_at(1, &[1; 32], &files_db_dir);
+            assert!(!dst_path.exists());
+        };
+
+        let db = MemDb::try_load(&config).unwrap();
+        add(&db, 0).0.unwrap();
+        let root = db.merkle_root().unwrap();
+
+        // missing tmp file
+        let missing = config.files_tmp_dir().join("missing");
+        assert!(add_file(&db, &config, "missing", vec![1; 32], &missing).is_err());
+        assert_unchanged(&db, &root);
+
+        // the file cannot be moved into the files db directory
+        let moved_db_dir = tmp_files_dir.path().join("moved");
+        std::fs::rename(&files_db_dir, &moved_db_dir).unwrap();
+        std::fs::write(&files_db_dir, []).unwrap();
+        let (res, tmp_path) = add(&db, 1);
+        assert!(res.is_err());
+        assert!(tmp_path.is_file());
+        std::fs::remove_file(&files_db_dir).unwrap();
+        std::fs::rename(&moved_db_dir, &files_db_dir).unwrap();
+        assert_unchanged(&db, &root);
+
+        // the entry cannot be appended to the journal
+        let moved_journal = tmp_db_dir.path().join("moved");
+        std::fs::rename(&journal_file, &moved_journal).unwrap();
+        std::fs::create_dir(&journal_file).unwrap();
+        let (res, tmp_path) = add(&db, 1);
+        assert!(res.is_err());
+        assert!(tmp_path.is_file());
+        std::fs::remove_dir(&journal_file).unwrap();
+        std::fs::rename(&moved_journal, &journal_file).unwrap();
+        assert_unchanged(&db, &root);
+        assert_unchanged(&MemDb::try_load(&config).unwrap(), &root);
+
+        // the next entry takes the index of the failed ones
+        let added = add(&db, 1).0.unwrap();
+        assert_eq!(added.index, 1);
+        assert!(db.file_path_at(1, &files_db_dir).is_file());
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.num_entries(), 2);
+        assert_eq!(loaded.merkle_root().unwrap(), added.merkle_root);
+    }
+
+    #[test]
+    fn test_entry_size_and_upload_time() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        config.create_dirs().unwrap();
+
+        // a version 3 db file, written before the entries stored their size
+        #[derive(Serialize)]
+        struct MemDbEntryV2 {
+            filename: String,
+            download_count: u64,
+            last_download_ms: u64,
+        }
+        let mut tree = MerkleTree::new();
+        tree.add_leaf(vec![0; 32]).unwrap();
+        let entries = vec![MemDbEntryV2 {
+            filename: "file0".to_string(),
+            download_count: 3,
+            last_download_ms: 1234,
+        }];
+        let header = MemDbHeader {
+            magic: DB_MAGIC,
+            version: 3,
+            layout: StorageLayout::Sharded,
+        };
+        let mut bytes = bincode::serialize(&header).unwrap();
+        bytes.extend(bincode::serialize(&(entries, tree)).unwrap());
+        std::fs::write(config.db_file(), bytes).unwrap();
+        let stored_path = StorageLayout::Sharded.file_path_at(0, &[], &config.files_db_dir());
+        std::fs::create_dir_all(stored_path.parent().unwrap()).unwrap();
+        std::fs::write(&stored_path, [0u8; 42]).unwrap();
+
+        let db = MemDb::try_load(&config).unwrap();
+        // the total size of an older db is computed from the stored files
+        assert_eq!(db.total_bytes(), 42);
+        let info = db.entry_info_at(0).unwrap();
+        assert_eq!(info.filename, "file0");
+        assert_eq!(info.download_count, 3);
+        assert_eq!(info.last_download_ms, 1234);
+        assert_eq!(info.size, 0);
+        assert_eq!(info.uploaded_at, 0);
+        // the sha256 of an older entry is its merkle tree leaf
+        assert_eq!(db.sha256_at(0).unwrap(), vec![0; 32]);
+        assert_eq!(db.index_of_sha256(&[0; 32]), Some(0));
 
-        let file = File::create(db_file)?;
-        let mut writer = BufWriter::new(file);
+        let before = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let tmp_path = config.files_tmp_dir().join("file1");
+        std::fs::write(&tmp_path, [1u8; 100]).unwrap();
+        add_file(&db, &config, "file1", vec![1; 32], &tmp_path).unwrap();
+        let info = db.entry_info_at(1).unwrap();
+        assert_eq!(info.size, 100);
+        assert!(info.uploaded_at >= before);
+        assert_eq!(db.total_bytes(), 142);
 
-        bincode::serialize_into(&mut writer, self).map_err(|_| ServerError::DbSave)
+        // from the journal, then from the snapshot
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.entry_info_at(1).unwrap(), info);
+        assert_eq!(loaded.total_bytes(), 142);
+        db.save(&config).unwrap();
+        // no longer computed from the stored files
+        std::fs::remove_file(&stored_path).unwrap();
+        let loaded = MemDb::try_load(&config).unwrap();
+        assert_eq!(loaded.entry_info_at(0).unwrap().download_count, 3);
+        assert_eq!(loaded.entry_info_at(1).unwrap(), info);
+        assert_eq!(loaded.total_bytes(), 142);
     }
 }
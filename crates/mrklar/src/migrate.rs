@@ -0,0 +1,179 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::{
+    config::ServerConfig, error::ServerError, layout::StorageLayout, lock::DbLock, mem_db::MemDb,
+    stored_file,
+};
+
+/// Number of stored files verified when a full verification is not requested
+const VERIFY_SAMPLE_SIZE: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct MigrateReport {
+    pub from: StorageLayout,
+    pub to: StorageLayout,
+    pub entries: usize,
+    pub moved: usize,
+    pub verified: usize,
+}
+
+/// Moves every stored file of the archive into the `to` storage layout,
+/// then rewrites the db header layout field and verifies the stored files
+/// against the merkle tree leaves (all of them if `verify_all` is set,
+/// a sample otherwise).
+///
+/// The server must be stopped, the db lock is held exclusively during the
+/// whole operation. Moves are idempotent: files already located at their
+/// destination are skipped, so an interrupted migration can simply be resumed
+/// by running it again.
+///
+/// Migrating to the content addressed layout keeps a single stored file per
+/// content, the other copies are removed. Migrating from it gives each entry
+/// its own copy of the shared stored files.
+pub fn migrate_layout(
+    config: &ServerConfig,
+    to: StorageLayout,
+    verify_all: bool,
+) -> Result<MigrateReport, ServerError> {
+    let config = config.validate()?;
+    let _lock = DbLock::exclusive(&config)?;
+
+    let db = MemDb::try_load(&config)?;
+    migrate_db(&config, &db, to, verify_all)
+}
+
+/// Same as `migrate_layout` with an already loaded `db`, the db lock must
+/// be held exclusively by the caller
+pub(crate) fn migrate_db(
+    config: &ServerConfig,
+    db: &MemDb,
+    to: StorageLayout,
+    verify_all: bool,
+) -> Result<MigrateReport, ServerError> {
+    let from = db.layout();
+    let entries = db.num_entries();
+    let files_db_dir = config.files_db_dir();
+
+    let sha256s = (0..entries)
+        .map(|index| db.sha256_at(index))
+        .collect::<Result<Vec<_>, _>>()?;
+    // a shared stored file is moved by the last entry referencing it, and
+    // copied by the others
+    let last_refs: HashMap<&[u8], usize> = sha256s
+        .iter()
+        .enumerate()
+        .map(|(index, sha256)| (sha256.as_slice(), index))
+        .collect();
+
+    // 1- move files
+    let mut moved = 0;
+    for (index, sha256) in sha256s.iter().enumerate() {
+        let last_ref = last_refs[sha256.as_slice()] == index;
+        if move_file(index, sha256, last_ref, to, &files_db_dir)? {
+            moved += 1;
+        }
+    }
+
+    // 2- rewrite the db header, and the total size of the stored files
+    // changed by the shared files being copied or merged
+    if from != to {
+        db.set_layout(to);
+    }
+    let total_bytes_changed = db.update_total_bytes(&files_db_dir);
+    if from != to || total_bytes_changed {
+        db.save(config)?;
+    }
+
+    if to != StorageLayout::Sharded {
+        remove_empty_dirs(&files_db_dir);
+    }
+
+    // 3- verify the stored files
+    let indices: Vec<usize> = if verify_all {
+        (0..entries).collect()
+    } else {
+        sample_indices(entries, VERIFY_SAMPLE_SIZE)
+    };
+    for &index in &indices {
+        let path = to.file_path_at(index, &sha256s[index], &files_db_dir);
+        let hash = stored_file::sha256(&path, db.is_compressed_at(index)?)?;
+        if hash != sha256s[index] {
+            return Err(ServerError::StoredFileCorrupted(index));
+        }
+    }
+
+    Ok(MigrateReport {
+        from,
+        to,
+        entries,
+        moved,
+        verified: indices.len(),
+    })
+}
+
+/// Moves the file at `index` with content `sha256` to its `to` layout
+/// location. A content addressed file is copied instead, unless `last_ref`
+/// is set. Returns `false` if the file was already there.
+fn move_file(
+    index: usize,
+    sha256: &[u8],
+    last_ref: bool,
+    to: StorageLayout,
+    files_db_dir: &Path,
+) -> Result<bool, ServerError> {
+    let src = StorageLayout::ALL
+        .into_iter()
+        .filter(|l| *l != to)
+        .map(|l| (l, l.file_path_at(index, sha256, files_db_dir)))
+        .find(|(_, p)| p.is_file());
+
+    let dst = to.file_path_at(index, sha256, files_db_dir);
+    if dst.is_file() {
+        // with the content addressed layout, another entry with the same
+        // content already moved its copy
+        if let Some((from, src)) = src {
+            if to.is_content_addressed() || (from.is_content_addressed() && last_ref) {
+                std::fs::remove_file(src)?;
+            }
+        }
+        return Ok(false);
+    }
+
+    let (from, src) = src.ok_or(ServerError::StoredFileNotFound(index))?;
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if from.is_content_addressed() && !last_ref {
+        // the copy is never left half written at its destination
+        let mut tmp = dst.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::copy(&src, &tmp)?;
+        std::fs::rename(&tmp, &dst)?;
+    } else {
+        std::fs::rename(src, dst)?;
+    }
+    Ok(true)
+}
+
+/// Best effort removal of the empty shard directories
+fn remove_empty_dirs(dir: &Path) {
+    let Ok(read_dir) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in read_dir.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path);
+            // fails if not empty
+            let _ = std::fs::remove_dir(&path);
+        }
+    }
+}
+
+/// Returns at most `max` indices evenly spread over `0..n`
+fn sample_indices(n: usize, max: usize) -> Vec<usize> {
+    if n <= max {
+        return (0..n).collect();
+    }
+    (0..max).map(|i| i * (n - 1) / (max - 1)).collect()
+}
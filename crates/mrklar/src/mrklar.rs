@@ -1,21 +1,78 @@
-use clap::Parser;
-use mrklar::cmd::ServerCmd;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use mrklar::cmd::{ExportCmd, FsckCmd, ImportCmd, MigrateLayoutCmd, RebuildCmd, ServerCmd};
 
 #[derive(Parser)]
-#[command(name = "mrklar", version = env!("CARGO_PKG_VERSION"), next_display_order = None)]
+#[command(
+    name = "mrklar",
+    version = env!("CARGO_PKG_VERSION"),
+    next_display_order = None,
+    subcommand_negates_reqs = true
+)]
 pub struct Mrklar {
     #[command(flatten)]
     pub server: ServerCmd,
+
+    #[command(subcommand)]
+    pub cmd: Option<MrklarSubcommand>,
+}
+
+#[derive(Subcommand)]
+pub enum MrklarSubcommand {
+    /// Convert the storage layout of an existing archive (server must be stopped)
+    #[command(name = "migrate-layout")]
+    MigrateLayout(MigrateLayoutCmd),
+    /// Verify every stored file against the merkle tree (server must be stopped)
+    Fsck(FsckCmd),
+    /// Rebuild the db file from the stored files (server must be stopped)
+    Rebuild(RebuildCmd),
+    /// Write the db and every stored file into a bundle (server must be stopped)
+    Export(ExportCmd),
+    /// Restore a bundle into empty db and files directories, then verify it
+    /// (server must be stopped)
+    Import(ImportCmd),
 }
 
 fn print_env_vars() {
     let env_vars = [
+        "MRKLAR_CONFIG",
         "MRKLAR_IP_ADDR",
         "MRKLAR_PORT",
+        "MRKLAR_UDS",
+        "MRKLAR_UDS_MODE",
+        "MRKLAR_UDS_KEEP_TCP",
         "MRKLAR_DB_DIR",
         "MRKLAR_FILES_DIR",
+        "MRKLAR_CREATE_DIRS",
+        "MRKLAR_GRPC_COMPRESSION",
+        "MRKLAR_MAX_MESSAGE_SIZE",
         "MRKLAR_TRACING",
         "MRKLAR_TRACING_LEVEL",
+        "MRKLAR_LOG_DIR",
+        "MRKLAR_LOG_FILE",
+        "MRKLAR_LOG_ROTATION",
+        "MRKLAR_LOG_MAX_FILES",
+        "MRKLAR_NO_LOG_STDOUT",
+        "MRKLAR_MAX_DOWNLOAD_BYTES_PER_SEC_PER_STREAM",
+        "MRKLAR_MAX_DOWNLOAD_BYTES_PER_SEC",
+        "MRKLAR_SHUTDOWN_GRACE_PERIOD",
+        "MRKLAR_MAX_CONCURRENT_UPLOADS",
+        "MRKLAR_QUEUE_UPLOADS",
+        "MRKLAR_STREAM_IDLE_TIMEOUT",
+        "MRKLAR_UPLOAD_SESSION_TTL",
+        "MRKLAR_MAX_CHUNK_SIZE",
+        "MRKLAR_MAX_FILENAME_LEN",
+        "MRKLAR_DUPLICATE_POLICY",
+        "MRKLAR_PERSISTENCE",
+        "MRKLAR_PERSISTENCE_INTERVAL",
+        "MRKLAR_STORAGE_LAYOUT",
+        "MRKLAR_VERIFY_ON_DOWNLOAD",
+        "MRKLAR_COMPRESSION_LEVEL",
+        "MRKLAR_TLS_CERT",
+        "MRKLAR_TLS_KEY",
+        "MRKLAR_TLS_CLIENT_CA",
+        "MRKLAR_SIGNING_KEY",
+        "MRKLAR_REPLICATE_FROM",
+        "MRKLAR_REPLICATION_INTERVAL",
     ];
     env_vars.iter().for_each(|e| {
         let value = std::env::var(*e).unwrap_or_default();
@@ -25,7 +82,19 @@ fn print_env_vars() {
 
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
-    let app = Mrklar::parse();
-    print_env_vars();
-    app.server.run().await
+    let matches = Mrklar::command().get_matches();
+    let app = Mrklar::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match app.cmd {
+        Some(MrklarSubcommand::MigrateLayout(cmd)) => cmd.run(),
+        Some(MrklarSubcommand::Fsck(cmd)) => cmd.run(),
+        Some(MrklarSubcommand::Rebuild(cmd)) => cmd.run(),
+        Some(MrklarSubcommand::Export(cmd)) => cmd.run(),
+        Some(MrklarSubcommand::Import(cmd)) => cmd.run(),
+        None => {
+            if !app.server.print_config {
+                print_env_vars();
+            }
+            app.server.run(&matches).await
+        }
+    }
 }
@@ -1,14 +1,69 @@
-use crate::{config::ServerConfig, mem_db::MemDb};
+use std::future::Future;
+use std::path::Path;
+use std::sync::Arc;
+
+use mrklar_common::proto::SignedTreeHead;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+use tokio_util::task::TaskTracker;
+
+use crate::{
+    config::{DuplicatePolicy, ServerConfig},
+    error::ServerError,
+    maintenance::{Maintenance, MAINTENANCE_RETRY_AFTER},
+    mem_db::{AddedFile, MemDb},
+    storage::{LocalStorage, Storage},
+    throttle::{DownloadThrottle, RateLimiter},
+    tree_signer::TreeSigner,
+    upload_session::UploadSessions,
+};
 
 #[derive(Debug, Clone)]
 pub struct Node {
     config: ServerConfig,
     db: MemDb,
+    // holds the content of the stored and tmp files
+    storage: Arc<dyn Storage>,
+    // server-wide download limiter, shared by all download streams
+    download_limiter: Option<Arc<RateLimiter>>,
+    // one permit per upload allowed to run concurrently
+    upload_slots: Option<Arc<Semaphore>>,
+    // the resumable uploads in progress
+    upload_sessions: UploadSessions,
+    // pauses the writes while on
+    maintenance: Maintenance,
+    // cancelled when the server stops accepting new transfers
+    shutdown: CancellationToken,
+    // cancelled when the shutdown grace period has expired
+    abort: CancellationToken,
+    // the in-flight upload and download tasks
+    transfers: TaskTracker,
+    // signs the merkle roots returned to the clients, if the server has a key
+    tree_signer: Option<Arc<TreeSigner>>,
 }
 
 impl Node {
-    pub fn new(config: ServerConfig, db: MemDb) -> Self {
-        Node { config, db }
+    pub fn new(config: ServerConfig, db: MemDb, tree_signer: Option<TreeSigner>) -> Self {
+        let download_limiter = config
+            .max_download_bytes_per_sec()
+            .map(|l| Arc::new(RateLimiter::new(l)));
+        let upload_slots = config
+            .max_concurrent_uploads()
+            .map(|n| Arc::new(Semaphore::new(n)));
+        let storage = Arc::new(LocalStorage::new(config.files_dir()));
+        Node {
+            config,
+            db,
+            storage,
+            download_limiter,
+            upload_slots,
+            upload_sessions: UploadSessions::default(),
+            maintenance: Maintenance::default(),
+            shutdown: CancellationToken::new(),
+            abort: CancellationToken::new(),
+            transfers: TaskTracker::new(),
+            tree_signer: tree_signer.map(Arc::new),
+        }
     }
 
     pub fn config(&self) -> &ServerConfig {
@@ -19,7 +74,219 @@ impl Node {
         &self.db
     }
 
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    /// Signs `merkle_root`, computed from `tree_size` entries, `None` if
+    /// the server has no signing key
+    pub fn sign_tree_head(&self, merkle_root: &[u8], tree_size: usize) -> Option<SignedTreeHead> {
+        self.tree_signer
+            .as_ref()
+            .map(|signer| signer.sign(merkle_root.to_vec(), tree_size as u64))
+    }
+
+    pub fn upload_sessions(&self) -> &UploadSessions {
+        &self.upload_sessions
+    }
+
+    pub fn maintenance(&self) -> &Maintenance {
+        &self.maintenance
+    }
+
+    /// Adds an uploaded file to the db, see `MemDb::add_file`. The commit of
+    /// the tmp object and the journal append run on the blocking thread pool.
+    /// The tmp object is left in place on failure, `tmp_key` may be a
+    /// `TmpObject` deleting it on drop.
+    pub async fn add_file(
+        &self,
+        filename: &str,
+        hash: Vec<u8>,
+        tmp_key: impl AsRef<Path> + Send + 'static,
+    ) -> Result<AddedFile, ServerError> {
+        // the uploads completing after the maintenance mode was turned on
+        self.check_not_in_maintenance()?;
+        let db = self.db.clone();
+        let config = self.config.clone();
+        let storage = self.storage.clone();
+        let filename = filename.to_string();
+        spawn_blocking(move || db.add_file(&config, &*storage, &filename, hash, tmp_key.as_ref()))
+            .await
+    }
+
+    /// Appends a file downloaded from the primary by the replication, see
+    /// `MemDb::add_file`. Every entry of the primary is added, whatever the
+    /// duplicate policy, fails if the new entry does not take `index`.
+    pub async fn add_replicated_file(
+        &self,
+        index: usize,
+        filename: &str,
+        hash: Vec<u8>,
+        tmp_key: impl AsRef<Path> + Send + 'static,
+    ) -> Result<AddedFile, ServerError> {
+        self.check_not_in_maintenance()?;
+        let db = self.db.clone();
+        let config = self
+            .config
+            .clone()
+            .with_duplicate_policy(DuplicatePolicy::Allow);
+        let storage = self.storage.clone();
+        let filename = filename.to_string();
+        let added = spawn_blocking(move || {
+            db.add_file(&config, &*storage, &filename, hash, tmp_key.as_ref())
+        })
+        .await?;
+        if added.index != index {
+            return Err(ServerError::ReplicaDiverged(format!(
+                "entry {} of the primary added at index {}",
+                index, added.index
+            )));
+        }
+        Ok(added)
+    }
+
+    /// Saves the db on the blocking thread pool if the download statistics
+    /// changed since the last save
+    pub async fn save_db_if_dirty(&self) -> Result<(), ServerError> {
+        let db = self.db.clone();
+        let config = self.config.clone();
+        spawn_blocking(move || db.save_if_dirty(&config)).await
+    }
+
+    /// Writes the entries queued by the persistence policy to the db
+    /// journal, on the blocking thread pool
+    pub async fn flush_db_journal(&self) -> Result<(), ServerError> {
+        let db = self.db.clone();
+        let config = self.config.clone();
+        spawn_blocking(move || db.flush_journal(&config)).await
+    }
+
     pub fn file_count(&self) -> usize {
         self.db.num_entries()
     }
+
+    /// Fails with `ServerError::FileIndexDoesNotExist` if `file_index` is out of bounds
+    pub fn check_file_index(&self, file_index: u64) -> Result<(), ServerError> {
+        if file_index >= self.file_count() as u64 {
+            return Err(ServerError::FileIndexDoesNotExist(file_index as usize));
+        }
+        Ok(())
+    }
+
+    /// Returns a new throttle to apply to a single download stream
+    pub fn download_throttle(&self) -> DownloadThrottle {
+        DownloadThrottle::new(
+            self.config.max_download_bytes_per_sec_per_stream(),
+            self.download_limiter.clone(),
+        )
+    }
+
+    /// Acquires the slot of a new upload, held until the upload completes.
+    /// Waits for a slot when uploads are queued, fails with
+    /// `ServerError::TooManyUploads` otherwise.
+    pub async fn acquire_upload_slot(&self) -> Result<Option<OwnedSemaphorePermit>, ServerError> {
+        let Some(slots) = &self.upload_slots else {
+            return Ok(None);
+        };
+        let permit = if self.config.queue_uploads() {
+            tokio::select! {
+                permit = slots.clone().acquire_owned() => permit.ok(),
+                _ = self.shutdown.cancelled() => return Err(ServerError::ShuttingDown),
+            }
+        } else {
+            slots.clone().try_acquire_owned().ok()
+        };
+        let permit = permit.ok_or(ServerError::TooManyUploads)?;
+        tracing::debug!(
+            message = "upload slot acquired",
+            uploads = self.uploads_in_flight()
+        );
+        Ok(Some(permit))
+    }
+
+    /// The number of uploads holding a slot, 0 if the uploads are not limited
+    pub fn uploads_in_flight(&self) -> usize {
+        match (&self.upload_slots, self.config.max_concurrent_uploads()) {
+            (Some(slots), Some(max)) => max - slots.available_permits(),
+            _ => 0,
+        }
+    }
+
+    /// Spawns an upload or download task, the task is given the shutdown
+    /// grace period to complete and is dropped once the period has expired,
+    /// in which case `on_abort` is awaited instead.
+    pub fn spawn_transfer<T, F, A>(&self, task: F, on_abort: A) -> tokio::task::JoinHandle<T>
+    where
+        T: Send + 'static,
+        F: Future<Output = T> + Send + 'static,
+        A: Future<Output = T> + Send + 'static,
+    {
+        let abort = self.abort.clone();
+        self.transfers.spawn(async move {
+            tokio::select! {
+                res = task => res,
+                _ = abort.cancelled() => on_abort.await,
+            }
+        })
+    }
+
+    /// Fails with `ServerError::ReadOnlyReplica` if the server is a replica,
+    /// its entries only come from the primary
+    pub fn check_not_replica(&self) -> Result<(), ServerError> {
+        if self.config.is_replica() {
+            return Err(ServerError::ReadOnlyReplica);
+        }
+        Ok(())
+    }
+
+    /// Fails with `ServerError::Maintenance` while the maintenance mode is on,
+    /// the db is not modified until it is turned off
+    pub fn check_not_in_maintenance(&self) -> Result<(), ServerError> {
+        if self.maintenance.is_on() {
+            return Err(ServerError::Maintenance(MAINTENANCE_RETRY_AFTER));
+        }
+        Ok(())
+    }
+
+    /// Fails with `ServerError::ShuttingDown` once the shutdown has started
+    pub fn check_not_shutting_down(&self) -> Result<(), ServerError> {
+        if self.shutdown.is_cancelled() {
+            return Err(ServerError::ShuttingDown);
+        }
+        Ok(())
+    }
+
+    /// Resolves once `shutdown` has been called
+    pub fn shutdown_requested(&self) -> WaitForCancellationFutureOwned {
+        self.shutdown.clone().cancelled_owned()
+    }
+
+    /// Stops accepting new transfers and waits for the in-flight ones to
+    /// complete. Transfers still running after `grace_period` are aborted.
+    pub async fn shutdown(&self, grace_period: std::time::Duration) {
+        self.shutdown.cancel();
+        self.transfers.close();
+
+        if tokio::time::timeout(grace_period, self.transfers.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                message = "Shutdown grace period expired, aborting transfers",
+                transfers = self.transfers.len()
+            );
+            self.abort.cancel();
+            self.transfers.wait().await;
+        }
+    }
+}
+
+/// Runs the blocking file system or serialization work `f` on the tokio
+/// blocking thread pool, keeping the async workers available
+pub(crate) async fn spawn_blocking<T, F>(f: F) -> Result<T, ServerError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ServerError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
 }
@@ -0,0 +1,75 @@
+use crate::{
+    config::ServerConfig, error::ServerError, layout::StorageLayout, lock::DbLock, mem_db::MemDb,
+    stored_file,
+};
+
+#[derive(Debug, Clone)]
+pub struct RebuildReport {
+    pub layout: StorageLayout,
+    pub entries: usize,
+    pub root: Option<Vec<u8>>,
+}
+
+/// Rebuilds the db file from the stored files: the files `0..N` found in
+/// the files db directory are hashed and added to a new merkle tree in
+/// index order, stopping at the first missing index. The storage layout is
+/// detected from the location of the first file. A file starting with a
+/// valid zstd frame is considered compressed, its entry gets the sha256 and
+/// size of the decompressed content.
+///
+/// The original filenames are not stored along with the files, each
+/// rebuilt entry is named after its index and has no upload timestamp nor
+/// download statistics. The files of the content addressed layout are named
+/// by their content, their order is lost, such a db cannot be rebuilt.
+///
+/// Refuses to overwrite a non-empty db file or db journal unless `force`
+/// is set. The server must be stopped, the db lock is held exclusively
+/// during the whole operation.
+pub fn rebuild(config: &ServerConfig, force: bool) -> Result<RebuildReport, ServerError> {
+    let config = config.validate()?;
+    let _lock = DbLock::exclusive(&config)?;
+
+    for db_file in [config.db_file(), config.db_journal_file()] {
+        let len = std::fs::metadata(&db_file).map(|m| m.len()).unwrap_or(0);
+        if len > 0 && !force {
+            return Err(ServerError::DbFileNotEmpty(db_file.display().to_string()));
+        }
+    }
+
+    let files_db_dir = config.files_db_dir();
+    let layout = StorageLayout::detect(0, &[], &files_db_dir).unwrap_or(config.storage_layout());
+    if layout.is_content_addressed() {
+        return Err(ServerError::RebuildUnsupportedLayout(layout));
+    }
+
+    let mut leaves = vec![];
+    loop {
+        let path = layout.file_path_at(leaves.len(), &[], &files_db_dir);
+        if !path.is_file() {
+            break;
+        }
+        leaves.push(stored_file::inspect(&path)?);
+    }
+
+    let db = MemDb::from_leaves(layout, leaves)?;
+    db.update_total_bytes(&files_db_dir);
+    // the journal entries refer to the previous db
+    if let Err(e) = std::fs::remove_file(config.db_journal_file()) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    db.save(&config)?;
+
+    let entries = db.num_entries();
+    let root = if entries > 0 {
+        Some(db.merkle_root()?)
+    } else {
+        None
+    };
+    Ok(RebuildReport {
+        layout,
+        entries,
+        root,
+    })
+}
@@ -0,0 +1,177 @@
+use mrklar_api::{error::ApiError, ArchiveStatus, MrklarApi};
+use mrklar_fs::gen_tmp_filename;
+use tokio::io::AsyncWriteExt;
+use tokio_stream::StreamExt;
+
+use crate::{
+    error::ServerError,
+    node::Node,
+    storage::{self, TmpObject},
+};
+
+/// Periodically pulls the new entries of `primary` into the replica, see
+/// `replicate_once`. The failed rounds are retried at the next interval,
+/// the rounds are skipped in maintenance mode. Only returns once the
+/// replica diverged from the primary.
+pub(crate) async fn replicate_periodically(node: &Node, primary: &MrklarApi) -> ServerError {
+    let period = node
+        .config()
+        .replication_interval()
+        .max(std::time::Duration::from_millis(1));
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if node.maintenance().is_on() {
+            continue;
+        }
+        match replicate_once(node, primary).await {
+            Ok(0) => {}
+            Ok(added) => {
+                tracing::info!(
+                    message = "Replicated entries",
+                    added,
+                    count = node.file_count()
+                );
+            }
+            Err(e @ ServerError::ReplicaDiverged(_)) => return e,
+            Err(e) => tracing::warn!(message = "Replication failed", %e),
+        }
+    }
+}
+
+/// Appends the entries of `primary` missing in the replica, in order, and
+/// returns their number. Before pulling anything, the primary root must
+/// extend the replica root: same root at the same size, a valid consistency
+/// proof otherwise. Each downloaded file must be verified against the
+/// primary root. The entries added by the uploads to the primary during the
+/// round are pulled against its new root.
+///
+/// Fails with `ServerError::ReplicaDiverged` if the replica entries are not
+/// a prefix of the primary ones.
+pub(crate) async fn replicate_once(node: &Node, primary: &MrklarApi) -> Result<usize, ServerError> {
+    let mut added = 0;
+    loop {
+        // the root of an empty archive is undefined
+        let status = match primary.count().await? {
+            0 => ArchiveStatus {
+                count: 0,
+                root: vec![],
+            },
+            _ => primary.status().await?,
+        };
+        check_consistency(node, primary, &status).await?;
+
+        let pulled = pull_entries(node, primary, &status).await?;
+        added += pulled;
+        // up to date, or the primary root keeps changing without any progress
+        if pulled == 0 || node.file_count() as u64 == status.count {
+            return Ok(added);
+        }
+    }
+}
+
+/// Fails with `ServerError::ReplicaDiverged` unless the primary archive
+/// described by `status` starts with the replica entries
+async fn check_consistency(
+    node: &Node,
+    primary: &MrklarApi,
+    status: &ArchiveStatus,
+) -> Result<(), ServerError> {
+    let count = node.file_count() as u64;
+    if count == 0 {
+        return Ok(());
+    }
+    if count > status.count {
+        return Err(ServerError::ReplicaDiverged(format!(
+            "the replica has {} entries, the primary {}",
+            count, status.count
+        )));
+    }
+
+    let root = node.db().merkle_root()?;
+    if count == status.count {
+        if root != status.root {
+            return Err(ServerError::ReplicaDiverged(format!(
+                "the roots of the {} entries differ, replica {}, primary {}",
+                count,
+                hex::encode(&root),
+                hex::encode(&status.root)
+            )));
+        }
+        return Ok(());
+    }
+
+    let proof = primary.consistency_proof(count, status.count).await?;
+    if proof.old_size() != count
+        || proof.new_size() != status.count
+        || !proof.verify_with_roots(&root, &status.root)
+    {
+        return Err(ServerError::ReplicaDiverged(format!(
+            "the primary root {} of {} entries does not extend the replica root {} of {} entries",
+            hex::encode(&status.root),
+            status.count,
+            hex::encode(&root),
+            count
+        )));
+    }
+    Ok(())
+}
+
+/// Downloads the entries of the primary following the replica ones, up to
+/// `status.count`, and appends them. Each file is streamed into a tmp object
+/// of the replica storage. Stops early once the primary root is no longer
+/// `status.root`. Returns the number of appended entries.
+async fn pull_entries(
+    node: &Node,
+    primary: &MrklarApi,
+    status: &ArchiveStatus,
+) -> Result<usize, ServerError> {
+    let mut added = 0;
+    for index in node.file_count() as u64..status.count {
+        node.check_not_shutting_down()?;
+
+        let mut stream = primary.download_stream(index).await?;
+        // the primary got new entries since `status`
+        if stream.proof().root() != &status.root {
+            break;
+        }
+
+        // deleted on failure, committed by `add_replicated_file` otherwise
+        let tmp_object = TmpObject::new(
+            node.storage().clone(),
+            storage::tmp_key(&gen_tmp_filename()),
+        );
+        let mut writer = node.storage().put_stream(tmp_object.as_ref(), 0).await?;
+        while let Some(chunk) = stream.next().await {
+            writer.write_all(&chunk?).await?;
+        }
+        writer.shutdown().await?;
+        let summary = stream.finish().await?;
+        if !summary
+            .proof
+            .verify_with_root(&summary.sha256, &status.root)
+        {
+            return Err(ApiError::VerificationFailed {
+                index,
+                expected_root: status.root.clone(),
+            }
+            .into());
+        }
+
+        tracing::debug!(
+            message = "Replicating entry",
+            index,
+            filename = summary.filename
+        );
+        node.add_replicated_file(
+            index as usize,
+            &summary.filename,
+            summary.sha256,
+            tmp_object,
+        )
+        .await?;
+        added += 1;
+    }
+    Ok(added)
+}
@@ -0,0 +1,121 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use mrklar_common::config::REQUEST_ID_METADATA_KEY;
+use tonic::codegen::http::{HeaderValue, Request, Response};
+use tonic::transport::server::TcpConnectInfo;
+use tower::{Layer, Service};
+use tracing::Instrument;
+
+/// Longest request id accepted from the clients
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+/// Runs every request in a `mrklar_server` span holding the request id, the
+/// address of the peer and the called method. The request id is the one
+/// sent by the client in the `x-request-id` metadata if valid, a random one
+/// otherwise. It is returned to the client in the response metadata.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_string)
+            .unwrap_or_else(gen_request_id);
+
+        let span = tracing::info_span!(
+            "mrklar_server",
+            request_id,
+            remote_addr = tracing::field::Empty,
+            method = request.uri().path(),
+        );
+        // unknown for the unix domain sockets
+        if let Some(remote_addr) = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(TcpConnectInfo::remote_addr)
+        {
+            span.record("remote_addr", tracing::field::display(remote_addr));
+        }
+
+        let response = span.in_scope(|| self.inner.call(request));
+        Box::pin(
+            async move {
+                let mut response = response.await?;
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    response
+                        .headers_mut()
+                        .insert(REQUEST_ID_METADATA_KEY, value);
+                }
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// A client request id is logged as is, it is limited to a short run of
+/// alphanumeric characters, dashes and underscores
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+fn gen_request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gen_request_id, is_valid_request_id};
+
+    #[test]
+    fn test_request_id() {
+        let id = gen_request_id();
+        assert_eq!(id.len(), 16);
+        assert!(is_valid_request_id(&id));
+        assert_ne!(id, gen_request_id());
+
+        for id in ["abc", "0123-4567_89ab", &"a".repeat(64)] {
+            assert!(is_valid_request_id(id), "{id}");
+        }
+        for id in ["", "a b", "a\nb", "{id}", "été", &"a".repeat(65)] {
+            assert!(!is_valid_request_id(id), "{id:?}");
+        }
+    }
+}
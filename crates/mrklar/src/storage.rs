@@ -0,0 +1,254 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
+
+/// The prefix of the keys of the stored files, see `MemDb::file_key_at`
+pub const DB_PREFIX: &str = "db";
+/// The prefix of the keys of the files being uploaded or replicated
+pub const TMP_PREFIX: &str = "tmp";
+
+/// The key of the tmp object `name`
+pub fn tmp_key(name: &str) -> PathBuf {
+    Path::new(TMP_PREFIX).join(name)
+}
+
+/// The backend holding the content of the uploaded files, addressed by
+/// relative keys. A file is first written to a tmp key, then committed to
+/// its final key once complete.
+///
+/// The streams are plain `AsyncRead` and `AsyncWrite` objects, a slow peer
+/// applies backpressure to the backend. The blocking methods must run on the
+/// blocking thread pool, the `MemDb` calls them while adding a file.
+#[tonic::async_trait]
+pub trait Storage: Debug + Send + Sync {
+    /// Opens the object `key` for writing at `offset`, the content past
+    /// `offset` is dropped. The object is created if needed, it must then
+    /// be written from offset 0.
+    async fn put_stream(
+        &self,
+        key: &Path,
+        offset: u64,
+    ) -> io::Result<Box<dyn AsyncWrite + Send + Unpin>>;
+
+    /// Opens the object `key` for reading from `offset`
+    async fn get_stream(
+        &self,
+        key: &Path,
+        offset: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>>;
+
+    /// Blocking version of `get_stream`
+    fn get(&self, key: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Creates or truncates the object `key` for blocking writes
+    fn put(&self, key: &Path) -> io::Result<Box<dyn Write + Send>>;
+
+    /// Durably moves the complete object `tmp_key` to `key`, replacing
+    /// the object `key` if any
+    fn commit(&self, tmp_key: &Path, key: &Path) -> io::Result<()>;
+
+    fn delete(&self, key: &Path) -> io::Result<()>;
+
+    fn exists(&self, key: &Path) -> io::Result<bool>;
+
+    /// The number of bytes of the object `key`
+    fn size(&self, key: &Path) -> io::Result<u64>;
+}
+
+/// Stores the objects as files under the `root` directory, a key is the
+/// path of the file relative to `root`. The tmp objects are committed with
+/// a rename, the tmp and db directories must be on the same file system.
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, key: &Path) -> PathBuf {
+        self.root.join(key)
+    }
+}
+
+#[tonic::async_trait]
+impl Storage for LocalStorage {
+    async fn put_stream(
+        &self,
+        key: &Path,
+        offset: u64,
+    ) -> io::Result<Box<dyn AsyncWrite + Send + Unpin>> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    async fn get_stream(
+        &self,
+        key: &Path,
+        offset: u64,
+    ) -> io::Result<Box<dyn AsyncRead + Send + Unpin>> {
+        let mut file = tokio::fs::File::open(self.path(key)).await?;
+        file.seek(io::SeekFrom::Start(offset)).await?;
+        Ok(Box::new(file))
+    }
+
+    fn get(&self, key: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(self.path(key))?;
+        file.seek(io::SeekFrom::Start(offset))?;
+        Ok(Box::new(file))
+    }
+
+    fn put(&self, key: &Path) -> io::Result<Box<dyn Write + Send>> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn commit(&self, tmp_key: &Path, key: &Path) -> io::Result<()> {
+        let tmp_path = self.path(tmp_key);
+        File::open(&tmp_path)?.sync_all()?;
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(tmp_path, path)
+    }
+
+    fn delete(&self, key: &Path) -> io::Result<()> {
+        fs::remove_file(self.path(key))
+    }
+
+    fn exists(&self, key: &Path) -> io::Result<bool> {
+        match fs::metadata(self.path(key)) {
+            Ok(metadata) => Ok(metadata.is_file()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn size(&self, key: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(self.path(key))?.len())
+    }
+}
+
+/// A tmp object deleted on drop, unless it has been committed before
+#[derive(Debug)]
+pub struct TmpObject {
+    storage: Arc<dyn Storage>,
+    key: PathBuf,
+}
+
+impl TmpObject {
+    pub fn new(storage: Arc<dyn Storage>, key: PathBuf) -> Self {
+        TmpObject { storage, key }
+    }
+}
+
+impl AsRef<Path> for TmpObject {
+    fn as_ref(&self) -> &Path {
+        &self.key
+    }
+}
+
+impl Drop for TmpObject {
+    fn drop(&mut self) {
+        let _ = self.storage.delete(&self.key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        path::Path,
+        sync::Arc,
+    };
+
+    use tempfile::tempdir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{tmp_key, LocalStorage, Storage, TmpObject};
+
+    #[tokio::test]
+    async fn test_local_storage() {
+        let dir = tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path());
+        let key = tmp_key("upload");
+        assert!(!storage.exists(&key).unwrap());
+
+        let mut writer = storage.put_stream(&key, 0).await.unwrap();
+        writer.write_all(b"hello world").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert!(dir.path().join("tmp/upload").is_file());
+        assert_eq!(storage.size(&key).unwrap(), 11);
+
+        // a resumed write drops the bytes past the offset
+        let mut writer = storage.put_stream(&key, 5).await.unwrap();
+        writer.write_all(b"!").await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut content = String::new();
+        let mut reader = storage.get_stream(&key, 1).await.unwrap();
+        reader.read_to_string(&mut content).await.unwrap();
+        assert_eq!(content, "ello!");
+
+        let db_key = Path::new("db/00/1");
+        storage.commit(&key, db_key).unwrap();
+        assert!(!storage.exists(&key).unwrap());
+        let mut content = String::new();
+        storage
+            .get(db_key, 4)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "o!");
+
+        storage.delete(db_key).unwrap();
+        assert!(!storage.exists(db_key).unwrap());
+        assert!(storage.size(db_key).is_err());
+    }
+
+    #[test]
+    fn test_tmp_object() {
+        let dir = tempdir().unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir.path()));
+        let key = tmp_key("tmp_object");
+        storage.put(&key).unwrap().write_all(b"content").unwrap();
+
+        let tmp = TmpObject::new(storage.clone(), key.clone());
+        assert!(storage.exists(tmp.as_ref()).unwrap());
+        drop(tmp);
+        assert!(!storage.exists(&key).unwrap());
+
+        // a committed object is kept
+        storage.put(&key).unwrap().write_all(b"content").unwrap();
+        let tmp = TmpObject::new(storage.clone(), key);
+        storage.commit(tmp.as_ref(), Path::new("db/0")).unwrap();
+        drop(tmp);
+        assert!(storage.exists(Path::new("db/0")).unwrap());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::SyncIoBridge;
+
+use crate::{error::ServerError, node::spawn_blocking, storage::Storage};
+
+// A stored file is either the uploaded content itself or its zstd
+// compressed frame, as recorded by the entry `compressed` flag. The entry
+// sha256 is always the one of the uploaded content.
+
+// the first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// size of the buffer between the decompressing thread and a download stream
+const DECOMPRESS_BUFFER_SIZE: usize = 256 * 1024;
+
+/// The key of the compressed copy of the tmp object `key`, see `compress`
+pub(crate) fn compressed_key(key: &Path) -> PathBuf {
+    let mut zst_key = key.as_os_str().to_owned();
+    zst_key.push(".zst");
+    zst_key.into()
+}
+
+/// Compresses the tmp object `key` of `storage` with zstd at `level` into
+/// the tmp object `compressed_key(key)`, `key` is left untouched. Returns
+/// `true` if the compressed copy is smaller, incompressible content is
+/// stored as is.
+pub(crate) fn compress(storage: &dyn Storage, key: &Path, level: i32) -> Result<bool, ServerError> {
+    let zst_key = compressed_key(key);
+    let res = (|| -> Result<bool, ServerError> {
+        let mut src = BufReader::new(storage.get(key, 0)?);
+        let len = storage.size(key)?;
+        let mut encoder = zstd::Encoder::new(storage.put(&zst_key)?, level)?;
+        io::copy(&mut src, &mut encoder)?;
+        encoder.finish()?.flush()?;
+        Ok(storage.size(&zst_key)? < len)
+    })();
+    if res.is_err() {
+        let _ = storage.delete(&zst_key);
+    }
+    res
+}
+
+/// Opens the stored file at `path` for blocking reads of its uploaded content
+pub(crate) fn open(path: &Path, compressed: bool) -> io::Result<Box<dyn Read + Send>> {
+    let file = File::open(path)?;
+    if compressed {
+        Ok(Box::new(zstd::Decoder::new(file)?))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Returns the sha256 of the uploaded content of the stored file at `path`
+pub(crate) fn sha256(path: &Path, compressed: bool) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut open(path, compressed)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Returns the sha256 and the size of the uploaded content of the stored
+/// file at `path`, and whether it is compressed. Used when the entry flag
+/// is unknown: a file starting with a zstd frame that decompresses is
+/// considered compressed.
+pub(crate) fn inspect(path: &Path) -> io::Result<(Vec<u8>, u64, bool)> {
+    let mut magic = [0u8; ZSTD_MAGIC.len()];
+    let n = File::open(path)?.read(&mut magic)?;
+    if n == magic.len() && magic == ZSTD_MAGIC {
+        let mut hasher = Sha256::new();
+        if let Ok(size) = open(path, true).and_then(|mut r| io::copy(&mut r, &mut hasher)) {
+            return Ok((hasher.finalize().to_vec(), size, true));
+        }
+    }
+    let size = fs::metadata(path)?.len();
+    Ok((sha256(path, false)?, size, false))
+}
+
+/// Feeds `hasher` with `len` bytes of the uploaded content of the stored file
+/// `key` starting at `offset`. A compressed file is decompressed from its
+/// beginning.
+pub(crate) async fn hash_range(
+    storage: &Arc<dyn Storage>,
+    key: &Path,
+    compressed: bool,
+    mut hasher: Sha256,
+    offset: u64,
+    len: u64,
+) -> Result<Sha256, ServerError> {
+    let storage = storage.clone();
+    let key = key.to_path_buf();
+    spawn_blocking(move || {
+        let reader = open_at(&*storage, &key, compressed, offset)?;
+        io::copy(&mut reader.take(len), &mut hasher)?;
+        Ok(hasher)
+    })
+    .await
+}
+
+/// Returns a reader of `len` bytes of the uploaded content of the stored
+/// file `key` starting at `offset`. A compressed file is decompressed on the
+/// blocking thread pool, the reader ends early if the decompression fails.
+pub(crate) async fn open_range(
+    storage: &Arc<dyn Storage>,
+    key: &Path,
+    compressed: bool,
+    offset: u64,
+    len: u64,
+) -> Result<Box<dyn AsyncRead + Send + Unpin>, ServerError> {
+    if !compressed {
+        let reader = storage.get_stream(key, offset).await?;
+        return Ok(Box::new(reader.take(len)));
+    }
+
+    let (reader, writer) = tokio::io::duplex(DECOMPRESS_BUFFER_SIZE);
+    let mut writer = SyncIoBridge::new(writer);
+    let storage = storage.clone();
+    let key = key.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let res = open_at(&*storage, &key, true, offset)
+            .and_then(|r| io::copy(&mut r.take(len), &mut writer));
+        // a download dropped by the client closes the reader
+        if let Err(e) = res {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                tracing::error!(message = "Stored file decompression failed", key = %key.display(), %e);
+            }
+        }
+    });
+    Ok(Box::new(reader))
+}
+
+/// Opens the stored file `key` for blocking reads of its uploaded content
+/// starting at `offset`
+fn open_at(
+    storage: &dyn Storage,
+    key: &Path,
+    compressed: bool,
+    offset: u64,
+) -> io::Result<Box<dyn Read + Send>> {
+    if compressed {
+        let mut reader: Box<dyn Read + Send> = Box::new(zstd::Decoder::new(storage.get(key, 0)?)?);
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        Ok(reader)
+    } else {
+        storage.get(key, offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Read, path::Path, sync::Arc};
+
+    use sha2::{Digest, Sha256};
+    use tempfile::tempdir;
+    use tokio::io::AsyncReadExt;
+
+    use super::{compress, compressed_key, inspect, open, open_range};
+    use crate::storage::{LocalStorage, Storage};
+
+    #[tokio::test]
+    async fn test_compress() {
+        let dir = tempdir().unwrap();
+        let storage: Arc<dyn Storage> = Arc::new(LocalStorage::new(dir.path()));
+        let text = "a line of text, compressing well\n"
+            .repeat(1000)
+            .into_bytes();
+        let (key, path) = (Path::new("text"), dir.path().join("text"));
+        std::fs::write(&path, &text).unwrap();
+
+        assert!(compress(&*storage, key, 3).unwrap());
+        // the uncompressed object is left untouched
+        assert_eq!(std::fs::read(&path).unwrap(), text);
+        storage.commit(&compressed_key(key), key).unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < text.len() as u64 / 5);
+        let mut content = vec![];
+        open(&path, true)
+            .unwrap()
+            .read_to_end(&mut content)
+            .unwrap();
+        assert_eq!(content, text);
+        let (_, size, compressed) = inspect(&path).unwrap();
+        assert_eq!(size, text.len() as u64);
+        assert!(compressed);
+
+        let mut range = vec![];
+        open_range(&storage, key, true, 100, 1000)
+            .await
+            .unwrap()
+            .read_to_end(&mut range)
+            .await
+            .unwrap();
+        assert_eq!(range, &text[100..1100]);
+
+        // incompressible content is kept as is
+        let random: Vec<u8> = (0..300u32)
+            .flat_map(|i| Sha256::digest(i.to_le_bytes()))
+            .collect();
+        let (key, path) = (Path::new("random"), dir.path().join("random"));
+        std::fs::write(&path, &random).unwrap();
+        assert!(!compress(&*storage, key, 3).unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), random);
+        storage.delete(&compressed_key(key)).unwrap();
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert!(!inspect(&path).unwrap().2);
+
+        let mut range = vec![];
+        open_range(&storage, key, false, 10, 20)
+            .await
+            .unwrap()
+            .read_to_end(&mut range)
+            .await
+            .unwrap();
+        assert_eq!(range, &random[10..30]);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+/// A token bucket limiting the number of bytes sent per second.
+/// The bucket starts empty and holds at most one second worth of tokens.
+/// A reservation larger than the available tokens puts the bucket into debt,
+/// the caller is then expected to wait for the returned delay before sending.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    state: Mutex<RateLimiterState>,
+}
+
+#[derive(Debug)]
+struct RateLimiterState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0);
+        RateLimiter {
+            bytes_per_sec,
+            state: Mutex::new(RateLimiterState {
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Consumes `n` tokens, returns how long the caller must wait
+    /// before sending the corresponding `n` bytes.
+    pub fn reserve(&self, n: usize) -> Duration {
+        let rate = self.bytes_per_sec as f64;
+        let mut state = self.state.lock();
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = f64::min(rate, state.tokens + elapsed * rate);
+        state.last_refill = now;
+
+        state.tokens -= n as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+}
+
+/// The set of limiters applied to a single download stream:
+/// an optional per-stream limiter and an optional server-wide limiter
+/// shared by all the download streams.
+#[derive(Debug, Default)]
+pub struct DownloadThrottle {
+    stream: Option<RateLimiter>,
+    global: Option<Arc<RateLimiter>>,
+}
+
+impl DownloadThrottle {
+    pub fn new(per_stream_bytes_per_sec: Option<u64>, global: Option<Arc<RateLimiter>>) -> Self {
+        DownloadThrottle {
+            stream: per_stream_bytes_per_sec.map(RateLimiter::new),
+            global,
+        }
+    }
+
+    /// Returns the largest chunk size that should be read and sent at once
+    /// so that sends are spread evenly over time.
+    pub fn chunk_size(&self, chunk_size: usize) -> usize {
+        let mut size = chunk_size as u64;
+        if let Some(l) = &self.stream {
+            size = size.min(l.bytes_per_sec());
+        }
+        if let Some(l) = &self.global {
+            size = size.min(l.bytes_per_sec());
+        }
+        size.max(1) as usize
+    }
+
+    /// Consumes `n` bytes from every limiter, returns the longest delay.
+    pub fn reserve(&self, n: usize) -> Duration {
+        let stream_delay = self
+            .stream
+            .as_ref()
+            .map(|l| l.reserve(n))
+            .unwrap_or_default();
+        let global_delay = self
+            .global
+            .as_ref()
+            .map(|l| l.reserve(n))
+            .unwrap_or_default();
+        stream_delay.max(global_delay)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{DownloadThrottle, RateLimiter};
+
+    #[test]
+    fn test_reserve() {
+        let l = RateLimiter::new(1000);
+
+        // bucket starts empty
+        let d = l.reserve(500);
+        assert!(d > Duration::from_millis(400) && d <= Duration::from_millis(500));
+
+        // debt accumulates
+        let d = l.reserve(1000);
+        assert!(d > Duration::from_millis(1400) && d <= Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        let t = DownloadThrottle::default();
+        assert_eq!(t.chunk_size(1024), 1024);
+        assert_eq!(t.reserve(1024), Duration::ZERO);
+
+        let t = DownloadThrottle::new(Some(100), None);
+        assert_eq!(t.chunk_size(1024), 100);
+    }
+}
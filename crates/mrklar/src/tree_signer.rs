@@ -0,0 +1,136 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::Engine;
+use mrklar_common::proto::SignedTreeHead;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+
+use crate::error::ServerError;
+
+/// PEM label of the PKCS#8 key files
+const PEM_LABEL: &str = "PRIVATE KEY";
+
+/// Signs the merkle roots returned to the clients with the ed25519 key of
+/// the server, see `SignedTreeHead`
+#[derive(Debug)]
+pub(crate) struct TreeSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl TreeSigner {
+    /// Loads the PEM encoded PKCS#8 ed25519 key at `path`. If the file does
+    /// not exist, a new key is generated and saved there, only readable by
+    /// the server user.
+    pub fn load_or_generate(path: &Path) -> Result<Self, ServerError> {
+        let invalid = |message: String| ServerError::SigningKey {
+            path: path.display().to_string(),
+            message,
+        };
+        if !path.exists() {
+            let signer = Self::generate(path).map_err(invalid)?;
+            tracing::info!(message = "Generated a new signing key", path = %path.display());
+            return Ok(signer);
+        }
+
+        let content = fs::read(path).map_err(|e| invalid(e.to_string()))?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&content)
+            .map_err(|_| invalid("not a PEM file".to_string()))?;
+        if pem.label != PEM_LABEL {
+            return Err(invalid(format!(
+                "expecting a '{}' PEM block, got '{}'",
+                PEM_LABEL, pem.label
+            )));
+        }
+        // also accepts the PKCS#8 v1 keys without public key, as generated
+        // by `openssl genpkey -algorithm ed25519`
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&pem.contents)
+            .map_err(|e| invalid(format!("not a PKCS#8 ed25519 key: {}", e)))?;
+        Ok(TreeSigner { key_pair })
+    }
+
+    fn generate(path: &Path) -> Result<Self, String> {
+        let pkcs8 =
+            Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|e| e.to_string())?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|e| e.to_string())?;
+
+        let encoded = base64::engine::general_purpose::STANDARD.encode(pkcs8.as_ref());
+        let mut pem = format!("-----BEGIN {}-----\n", PEM_LABEL);
+        for line in encoded.as_bytes().chunks(64) {
+            pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+            pem.push('\n');
+        }
+        pem.push_str(&format!("-----END {}-----\n", PEM_LABEL));
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path).map_err(|e| e.to_string())?;
+        file.write_all(pem.as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| e.to_string())?;
+
+        Ok(TreeSigner { key_pair })
+    }
+
+    /// The ed25519 public key verifying the signed tree heads
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    /// Signs `merkle_root`, computed from `tree_size` entries, at the
+    /// current time
+    pub fn sign(&self, merkle_root: Vec<u8>, tree_size: u64) -> SignedTreeHead {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        let message = SignedTreeHead::message(&merkle_root, tree_size, timestamp_ms);
+        SignedTreeHead {
+            signature: self.key_pair.sign(&message).as_ref().to_vec(),
+            merkle_root,
+            tree_size,
+            timestamp_ms,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempfile::tempdir;
+
+    use super::TreeSigner;
+    use crate::error::ServerError;
+
+    #[test]
+    fn test_load_or_generate() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("signing_key.pem");
+
+        let signer = TreeSigner::load_or_generate(&path).unwrap();
+        let tree_head = signer.sign(vec![1; 32], 3);
+        tree_head.verify(signer.public_key()).unwrap();
+
+        // the generated key is loaded on the next startup
+        let loaded = TreeSigner::load_or_generate(&path).unwrap();
+        assert_eq!(loaded.public_key(), signer.public_key());
+        tree_head.verify(loaded.public_key()).unwrap();
+
+        let mut tampered = tree_head.clone();
+        tampered.tree_size = 2;
+        assert!(tampered.verify(signer.public_key()).is_err());
+
+        std::fs::write(&path, "not a key").unwrap();
+        assert!(matches!(
+            TreeSigner::load_or_generate(&path),
+            Err(ServerError::SigningKey { .. })
+        ));
+    }
+}
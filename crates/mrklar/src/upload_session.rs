@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use sha2::Sha256;
+use tokio::sync::OwnedMutexGuard;
+
+use crate::error::ServerError;
+
+/// The state of a resumable upload: the chunks received so far are written
+/// to the tmp object `tmp_key` and hashed in order. The file is added to the
+/// db once the client finishes the session.
+#[derive(Debug)]
+pub struct PendingUpload {
+    pub filename: String,
+    pub tmp_key: PathBuf,
+    /// number of bytes written to `tmp_key`
+    pub offset: u64,
+    /// hash state of the first `offset` bytes
+    pub hasher: Sha256,
+    last_activity: Instant,
+    // set once the session has been finished or has expired
+    closed: bool,
+}
+
+impl PendingUpload {
+    pub fn new(filename: String, tmp_key: PathBuf) -> Self {
+        PendingUpload {
+            filename,
+            tmp_key,
+            offset: 0,
+            hasher: Sha256::default(),
+            last_activity: Instant::now(),
+            closed: false,
+        }
+    }
+
+    /// Postpones the expiry of the session
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+}
+
+/// The open upload sessions, keyed by session id. A session is locked by the
+/// request using it, a chunk stream holds the lock until it completes.
+/// Sessions live in memory only, the server clears their files on restart.
+#[derive(Debug, Clone, Default)]
+pub struct UploadSessions {
+    sessions: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<PendingUpload>>>>>,
+}
+
+impl UploadSessions {
+    pub fn insert(&self, session_id: String, upload: PendingUpload) {
+        self.sessions
+            .lock()
+            .insert(session_id, Arc::new(tokio::sync::Mutex::new(upload)));
+    }
+
+    /// Waits until the session is not used by another request, then locks
+    /// it. Fails with `ServerError::UploadSessionDoesNotExist` if the session
+    /// does not exist, or has been closed in the meantime.
+    pub async fn lock(
+        &self,
+        session_id: &str,
+    ) -> Result<OwnedMutexGuard<PendingUpload>, ServerError> {
+        let not_found = || ServerError::UploadSessionDoesNotExist(session_id.to_string());
+        let session = self
+            .sessions
+            .lock()
+            .get(session_id)
+            .cloned()
+            .ok_or_else(not_found)?;
+
+        let mut upload = session.lock_owned().await;
+        if upload.closed {
+            return Err(not_found());
+        }
+        upload.touch();
+        Ok(upload)
+    }
+
+    /// Removes the locked session `upload`, the requests waiting for it fail
+    /// with `ServerError::UploadSessionDoesNotExist`
+    pub fn close(&self, session_id: &str, upload: &mut PendingUpload) {
+        upload.closed = true;
+        self.sessions.lock().remove(session_id);
+    }
+
+    /// Removes the sessions not used by any request for at least `ttl`,
+    /// returns the keys of their tmp objects
+    pub fn remove_expired(&self, ttl: Duration) -> Vec<PathBuf> {
+        let mut expired = vec![];
+        self.sessions.lock().retain(|_, session| {
+            let Ok(mut upload) = session.try_lock() else {
+                return true;
+            };
+            if upload.last_activity.elapsed() < ttl {
+                return true;
+            }
+            upload.closed = true;
+            expired.push(upload.tmp_key.clone());
+            false
+        });
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, time::Duration};
+
+    use super::{PendingUpload, UploadSessions};
+
+    #[tokio::test]
+    async fn test_remove_expired() {
+        let sessions = UploadSessions::default();
+        for id in ["a", "b", "c"] {
+            sessions.insert(
+                id.to_string(),
+                PendingUpload::new(id.to_string(), id.into()),
+            );
+        }
+
+        let mut closed = sessions.lock("c").await.unwrap();
+        sessions.close("c", &mut closed);
+        drop(closed);
+        assert!(sessions.lock("c").await.is_err());
+
+        assert!(sessions.remove_expired(Duration::from_secs(60)).is_empty());
+
+        // a session in use never expires
+        let locked = sessions.lock("a").await.unwrap();
+        assert_eq!(
+            sessions.remove_expired(Duration::ZERO),
+            vec![PathBuf::from("b")]
+        );
+        drop(locked);
+        assert!(sessions.lock("b").await.is_err());
+        assert_eq!(
+            sessions.remove_expired(Duration::ZERO),
+            vec![PathBuf::from("a")]
+        );
+    }
+}
@@ -0,0 +1,39 @@
+// The tracing subscriber is global, this test runs in its own process
+use mrklar::{LogRotation, ServerConfig};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_log_file() {
+    let tmp_db_dir = tempfile::tempdir().unwrap();
+    let tmp_files_dir = tempfile::tempdir().unwrap();
+    let tmp_log_dir = tempfile::tempdir().unwrap();
+
+    let config = ServerConfig::default()
+        .with_port(0)
+        .with_tracing(true)
+        .with_db_dir(tmp_db_dir.path().to_path_buf())
+        .with_files_dir(tmp_files_dir.path().to_path_buf())
+        .with_log_rotation(LogRotation::Never)
+        .with_log_stdout(false);
+
+    // the log directory cannot be created below a regular file
+    let not_a_dir = tmp_log_dir.path().join("file");
+    std::fs::write(&not_a_dir, b"").unwrap();
+    let err = mrklar::start(config.clone().with_log_dir(Some(not_a_dir.join("logs"))))
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("Unable to write the server log"),
+        "{err}"
+    );
+
+    let log_dir = tmp_log_dir.path().join("logs");
+    let server = mrklar::start(config.with_log_dir(Some(log_dir.clone())))
+        .await
+        .unwrap();
+    server.shutdown().await.unwrap();
+
+    // the pending traces are flushed once the server has shut down
+    let log = std::fs::read_to_string(log_dir.join(mrklar::DEFAULT_LOG_FILE)).unwrap();
+    assert!(log.contains("Server listening"), "{log}");
+    assert!(log.contains("Server shutdown."), "{log}");
+}
@@ -1,14 +1,21 @@
 use mrklar::ServerConfig;
 
-pub async fn start_server(config: ServerConfig) {
-    tokio::spawn(async move { mrklar::spawn(config).await });
-}
-
 #[tokio::test(flavor = "multi_thread")]
 async fn test_spawn() {
-    let config = ServerConfig::test_default().with_tracing(false);
+    let tmp_db_dir = tempfile::tempdir().unwrap();
+    let tmp_files_dir = tempfile::tempdir().unwrap();
+
+    let config = ServerConfig::default()
+        .with_port(0)
+        .with_tracing(false)
+        .with_db_dir(tmp_db_dir.path().to_path_buf())
+        .with_files_dir(tmp_files_dir.path().to_path_buf());
 
-    start_server(config.clone()).await;
+    // listens on any free port
+    let server = mrklar::start(config.clone()).await.unwrap();
+    let local_addr = server.local_addr().unwrap();
+    assert_eq!(local_addr.ip(), config.sock_addr().ip());
+    assert_ne!(local_addr.port(), 0);
 
-    tokio::time::sleep(std::time::Duration::from_millis(700)).await;
+    server.shutdown().await.unwrap();
 }
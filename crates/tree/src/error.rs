@@ -8,6 +8,12 @@ pub enum MerkleTreeError {
     TreeEmpty,
     #[error("Node index {1} does not exist at level {0}")]
     NodeDoesNotExist(u8, usize),
+    #[error("Invalid tree sizes {old_size} and {new_size}, expecting 0 < old_size <= new_size <= {leaf_count}")]
+    InvalidTreeSizes {
+        old_size: usize,
+        new_size: usize,
+        leaf_count: usize,
+    },
     #[error("Too many levels in the tree")]
     TooManyLevels,
     #[error("Tree level {0} is full")]
@@ -1,4 +1,4 @@
-pub mod merkle_tree;
 pub mod error;
+pub mod merkle_tree;
 
-mod pow2;
\ No newline at end of file
+mod pow2;
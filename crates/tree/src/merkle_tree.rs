@@ -1,5 +1,6 @@
 use crate::error::MerkleTreeError;
 use crate::pow2::two_pow_n;
+use mrklar_common::consistency_proof::{tree_depth, ConsistencyProof};
 use mrklar_common::merkle_proof::{MerkleProof, MerkleProofHash};
 use serde::{Deserialize, Serialize};
 
@@ -197,7 +198,7 @@ impl MerkleTree {
         self.level(self.level_count() - 1)
     }
 
-    /// Returns the merkle root 
+    /// Returns the merkle root
     pub fn root_hash(&self) -> Result<&Vec<u8>, MerkleTreeError> {
         self.root().get_hash_at(0)
     }
@@ -230,6 +231,11 @@ impl MerkleTree {
         Ok(())
     }
 
+    /// Returns the leaf hash at `index`
+    pub fn leaf_hash_at(&self, index: usize) -> Result<&Vec<u8>, MerkleTreeError> {
+        self.leaves().get_hash_at(index)
+    }
+
     /// Add a new leaf to the merkle tree
     pub fn add_leaf(&mut self, hash: Vec<u8>) -> Result<usize, MerkleTreeError> {
         if self.leaves().is_full() || self.is_empty() {
@@ -289,7 +295,70 @@ impl MerkleTree {
             assert!(level.try_parent_index(sibling_index)? == pos);
         }
 
-        Ok(MerkleProof::from_raw_parts(self.root_hash()?.clone(), proof))
+        Ok(MerkleProof::from_raw_parts(
+            self.root_hash()?.clone(),
+            proof,
+        ))
+    }
+
+    /// Computes the proof that the tree made of the first `new_size` leaves
+    /// extends the tree made of the first `old_size` leaves, see
+    /// `ConsistencyProof`
+    pub fn consistency_proof(
+        &self,
+        old_size: usize,
+        new_size: usize,
+    ) -> Result<ConsistencyProof, MerkleTreeError> {
+        if old_size == 0 || old_size > new_size || new_size > self.leaf_count() {
+            return Err(MerkleTreeError::InvalidTreeSizes {
+                old_size,
+                new_size,
+                leaf_count: self.leaf_count(),
+            });
+        }
+
+        let mut pos = old_size - 1;
+        let mut hashes = vec![self.leaf_hash_at(pos)?.clone()];
+        for level in 0..tree_depth(new_size as u64) {
+            let sibling = match pos % 2 {
+                1 => self.hash_at_size(level, pos - 1, new_size)?,
+                _ => self.hash_at_size(level, pos + 1, new_size)?,
+            };
+            hashes.push(sibling);
+            pos /= 2;
+        }
+
+        let old_root = self.hash_at_size(tree_depth(old_size as u64), 0, old_size)?;
+        let new_root = self.hash_at_size(tree_depth(new_size as u64), 0, new_size)?;
+        Ok(ConsistencyProof::from_raw_parts(
+            old_size as u64,
+            new_size as u64,
+            old_root,
+            new_root,
+            hashes,
+        ))
+    }
+
+    /// Returns the hash of the node at `index` and `level` (0 for the leaves)
+    /// in the tree made of the first `size` leaves: a null hash if the node
+    /// has no leaf, the current hash if all its leaves are in the first
+    /// `size` leaves, recomputed otherwise
+    fn hash_at_size(
+        &self,
+        level: usize,
+        index: usize,
+        size: usize,
+    ) -> Result<Vec<u8>, MerkleTreeError> {
+        let width = 1usize << level;
+        if index * width >= size {
+            return Ok(MerkleProof::null_hash());
+        }
+        if (index + 1) * width <= size {
+            return Ok(self.level(level as u8).get_hash_at(index)?.clone());
+        }
+        let left = self.hash_at_size(level - 1, 2 * index, size)?;
+        let right = self.hash_at_size(level - 1, 2 * index + 1, size)?;
+        Ok(MerkleProof::sha256_pair(&left, &right))
     }
 }
 
@@ -517,6 +586,36 @@ mod test {
         assert!(verified);
     }
 
+    #[test]
+    fn test_consistency_proof() {
+        let mut t = MerkleTree::new();
+        let mut roots = vec![];
+        for _ in 0..40 {
+            t.add_leaf(rand_hash()).unwrap();
+            roots.push(t.root_hash().unwrap().clone());
+        }
+
+        for new_size in 1..=40 {
+            for old_size in 1..=new_size {
+                let proof = t.consistency_proof(old_size, new_size).unwrap();
+                assert_eq!(proof.old_root(), &roots[old_size - 1]);
+                assert_eq!(proof.new_root(), &roots[new_size - 1]);
+                assert!(proof.verify());
+                // the roots of other sizes are rejected
+                if old_size > 1 {
+                    assert!(!proof.verify_with_roots(&roots[old_size - 2], proof.new_root()));
+                }
+                if new_size < 40 {
+                    assert!(!proof.verify_with_roots(proof.old_root(), &roots[new_size]));
+                }
+            }
+        }
+
+        assert!(t.consistency_proof(0, 1).is_err());
+        assert!(t.consistency_proof(2, 1).is_err());
+        assert!(t.consistency_proof(1, 41).is_err());
+    }
+
     fn rand_hash() -> Vec<u8> {
         let mut v = vec![];
         for _ in 0..32 {
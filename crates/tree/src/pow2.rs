@@ -1,4 +1,3 @@
-
 #[inline]
 pub fn two_pow_n(power: u8) -> u64 {
     assert!(power <= 63);
@@ -57,4 +56,4 @@ mod test {
         assert_eq!(next_power_of_two(256), 8);
         assert_eq!(next_power_of_two(257), 9);
     }
-}
\ No newline at end of file
+}
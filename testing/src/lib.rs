@@ -1,17 +1,210 @@
 #[cfg(test)]
 mod test {
     use std::io::Write;
+    use std::path::{Path, PathBuf};
 
-    use mrklar::ServerConfig;
-    use mrklar_api::MrklarApi;
-    use mrklar_common::config::DEFAULT_SERVER_PORT;
+    use mrklar::{
+        bundle::{export_bundle, import_bundle},
+        error::ServerError,
+        fsck::{fsck, FsckRepair},
+        layout::StorageLayout,
+        migrate::migrate_layout,
+        rebuild::rebuild,
+        storage::{tmp_key, LocalStorage},
+        DuplicatePolicy, FileMode, PersistencePolicy, ServerConfig, ServerHandle,
+        MAINTENANCE_RETRY_AFTER,
+    };
+    use mrklar_api::{
+        error::ApiError,
+        mirror::{MirrorManifest, VerifyStatus},
+        retry::RetryPolicy,
+        tls::ClientTls,
+        CancellationToken, MrklarApi,
+    };
+    use mrklar_common::config::{
+        GrpcCompression, NetConfig, MAX_AUDIT_HASHES, MAX_CHUNK_SIZE, MAX_LIST_LIMIT,
+        MESSAGE_OVERHEAD, REQUEST_ID_METADATA_KEY, WRITE_HEALTH_SERVICE,
+    };
+    use mrklar_common::merkle_proof::{MerkleProof, MerkleProofHash};
+    use mrklar_common::proto::{
+        download_response,
+        file_api_server::{FileApi, FileApiServer},
+        upload_request, AuditRequest, AuditResponse, ConsistencyProofRequest,
+        ConsistencyProofResponse, DownloadRequest, DownloadResponse, Empty, EntryInfo, FileIndex,
+        FileName, FileSha256, FinishUploadRequest, ListRequest, ListResponse, ProofResponse,
+        RootResponse, ServerInfo, StartUploadRequest, StatsResponse, UploadChunk, UploadRequest,
+        UploadResponse, UploadSession, UploadSessionId, WatchEvent, U64,
+    };
     use mrklar_fs::{gen_tmp_filename, get_test_files_dir, sha256};
+    use sha2::{Digest, Sha256};
     use tempfile::tempdir;
+    use tokio::task::JoinHandle;
+    use tokio_stream::wrappers::ReceiverStream;
+    use tonic::{Request, Response, Status, Streaming};
 
+    /// Maximum time given to a test server to start
+    const SERVER_READY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+    /// Starts a server, ready to answer once this returns. The test configs
+    /// use port 0, the server listens on any free port.
     async fn start_server(config: ServerConfig) -> MrklarApi {
-        let api = MrklarApi::new(config.net.clone());
-        tokio::spawn(async move { mrklar::spawn(config).await });
-        api
+        start_server_task(config).await.0
+    }
+
+    /// Same as `start_server`, the returned handle can be used to stop the server
+    async fn start_server_task(config: ServerConfig) -> (MrklarApi, ServerHandle) {
+        let server = mrklar::start(config.clone()).await.unwrap();
+        let api = MrklarApi::new(client_net(&config, &server)).unwrap();
+        (api, server)
+    }
+
+    /// The client config of a `server` started with `config`
+    fn client_net(config: &ServerConfig, server: &ServerHandle) -> NetConfig {
+        match server.local_addr() {
+            Some(addr) => config.net.clone().with_port(addr.port()),
+            // unix domain socket
+            None => config.net.clone(),
+        }
+    }
+
+    /// Returns a free local port, for the servers started by the test itself
+    fn free_port() -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Spawns a TLS terminating proxy forwarding to the plain text server at
+    /// `target`, using a self-signed certificate issued for `localhost`.
+    /// Returns the proxy address and the PEM encoded certificate.
+    async fn start_tls_proxy(target: std::net::SocketAddr) -> (std::net::SocketAddr, String) {
+        use tokio_rustls::rustls::{self, pki_types::PrivatePkcs8KeyDer};
+
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(signing_key.serialize_der());
+        let mut tls_config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.der().clone()], key.into())
+            .unwrap();
+        tls_config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(tls_config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let Ok((socket, _)) = listener.accept().await else {
+                    break;
+                };
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(mut tls) = acceptor.accept(socket).await else {
+                        return;
+                    };
+                    let Ok(mut upstream) = tokio::net::TcpStream::connect(target).await else {
+                        return;
+                    };
+                    let _ = tokio::io::copy_bidirectional(&mut tls, &mut upstream).await;
+                });
+            }
+        });
+        (addr, cert.pem())
+    }
+
+    /// Spawns a plain tcp proxy listening on `port`, any free port if 0, and
+    /// forwarding to `target`. Returns the proxy address, aborting the returned
+    /// handle closes the listener and every proxied connection, as a server
+    /// restart would.
+    async fn start_tcp_proxy(
+        port: u16,
+        target: std::net::SocketAddr,
+    ) -> (std::net::SocketAddr, JoinHandle<()>) {
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            // aborted with the accept loop
+            let mut connections = tokio::task::JoinSet::new();
+            while let Ok((mut socket, _)) = listener.accept().await {
+                connections.spawn(async move {
+                    let Ok(mut upstream) = tokio::net::TcpStream::connect(target).await else {
+                        return;
+                    };
+                    let _ = tokio::io::copy_bidirectional(&mut socket, &mut upstream).await;
+                });
+            }
+        });
+        (addr, handle)
+    }
+
+    /// Same as `start_tcp_proxy`, delaying the data by `delay` in each
+    /// direction, without limiting the bandwidth
+    async fn start_latency_proxy(
+        target: std::net::SocketAddr,
+        delay: std::time::Duration,
+    ) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            // aborted with the accept loop
+            let mut connections = tokio::task::JoinSet::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                socket.set_nodelay(true).unwrap();
+                let Ok(upstream) = tokio::net::TcpStream::connect(target).await else {
+                    continue;
+                };
+                upstream.set_nodelay(true).unwrap();
+                let (socket_r, socket_w) = socket.into_split();
+                let (upstream_r, upstream_w) = upstream.into_split();
+                connections.spawn(delayed_copy(socket_r, upstream_w, delay));
+                connections.spawn(delayed_copy(upstream_r, socket_w, delay));
+            }
+        });
+        addr
+    }
+
+    async fn delayed_copy(
+        mut r: tokio::net::tcp::OwnedReadHalf,
+        mut w: tokio::net::tcp::OwnedWriteHalf,
+        delay: std::time::Duration,
+    ) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (tx, mut rx) =
+            tokio::sync::mpsc::unbounded_channel::<(tokio::time::Instant, Vec<u8>)>();
+        let writer = tokio::spawn(async move {
+            while let Some((received_at, data)) = rx.recv().await {
+                tokio::time::sleep_until(received_at + delay).await;
+                if w.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+            let _ = w.shutdown().await;
+        });
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok(n @ 1..) = r.read(&mut buf).await {
+            if tx
+                .send((tokio::time::Instant::now(), buf[..n].to_vec()))
+                .is_err()
+            {
+                break;
+            }
+        }
+        drop(tx);
+        let _ = writer.await;
+    }
+
+    /// Generates `n` files with random content in `dir`
+    fn gen_files(dir: &Path, n: usize) -> Vec<PathBuf> {
+        (0..n)
+            .map(|_| {
+                let s = gen_tmp_filename();
+                let p = dir.join(&s);
+                std::fs::write(&p, s.as_bytes()).unwrap();
+                p
+            })
+            .collect()
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -20,19 +213,15 @@ mod test {
         println!("test db dir='{:?}'", tmp_empty_db_dir.path());
 
         let config = ServerConfig::test_default()
-            .with_port(DEFAULT_SERVER_PORT)
+            .with_port(0)
             .with_tracing(false)
             .with_db_dir(tmp_empty_db_dir.path().to_path_buf());
 
         let api = start_server(config.clone()).await;
 
-        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-
         let a = api.count().await.unwrap();
         assert_eq!(a, 0);
 
-        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-
         let a = api.count().await.unwrap();
         assert_eq!(a, 0);
 
@@ -50,35 +239,31 @@ mod test {
 
         // inc the port to avoid port conflict
         let config = ServerConfig::test_default()
-            .with_port(DEFAULT_SERVER_PORT + 1)
+            .with_port(0)
             .with_tracing(false)
             .with_db_dir(tmp_empty_db_dir.path().to_path_buf())
             .with_files_dir(tmp_empty_files_dir.path().to_path_buf());
 
         let api = start_server(config.clone()).await;
 
-        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-
         let a = api.count().await.unwrap();
         assert_eq!(a, 0);
 
         let p = get_test_files_dir().unwrap().join("0");
-        let (file_index, merkle_root) = api.upload(&p).await.unwrap();
-        assert_eq!(file_index, 0);
+        let outcome = api.upload(&p).await.unwrap();
+        assert_eq!(outcome.index, 0);
         let p_sha256 = sha256(p).unwrap();
 
-        let zero = config.files_db_dir().join("0");
+        let zero = StorageLayout::Sharded.file_path_at(0, &[], &config.files_db_dir());
         assert!(zero.is_file());
         assert_eq!(sha256(zero).unwrap(), p_sha256);
 
-        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
-
         let count_files = api.count().await.unwrap();
         assert_eq!(count_files, 1);
 
-        let merkle_proof = api.proof(file_index).await.unwrap();
+        let merkle_proof = api.proof(outcome.index).await.unwrap();
         assert!(merkle_proof.verify(&p_sha256));
-        assert_eq!(merkle_proof.root(), &merkle_root);
+        assert_eq!(merkle_proof.root(), &outcome.root);
 
         tmp_empty_db_dir.close().unwrap();
         tmp_empty_files_dir.close().unwrap();
@@ -86,6 +271,7 @@ mod test {
 
     /// Upload + Download + Verify 300 randomly generated files
     #[tokio::test(flavor = "multi_thread")]
+    #[allow(clippy::needless_range_loop)]
     async fn test_all_sequential() {
         const N_FILES: usize = 300;
 
@@ -105,7 +291,7 @@ mod test {
 
         // inc the port to avoid port conflict
         let config = ServerConfig::default()
-            .with_port(DEFAULT_SERVER_PORT + 2)
+            .with_port(0)
             .with_tracing(false)
             .with_db_dir(tmp_db_dir.path().to_path_buf())
             .with_files_dir(tmp_files_dir.path().to_path_buf());
@@ -138,7 +324,7 @@ mod test {
         for i in 0..N_FILES {
             // index, merkle_root
             let info = api.upload(&file_names[i]).await.unwrap();
-            assert_eq!(info.0, i as u64);
+            assert_eq!(info.index, i as u64);
             file_infos.push(info);
         }
 
@@ -148,7 +334,7 @@ mod test {
 
         // 6- verify the merkle root
         let root = api.root().await.unwrap();
-        assert_eq!(root, file_infos.last().unwrap().1);
+        assert_eq!(root, file_infos.last().unwrap().root);
 
         // 7- compute and verify each proof
         for i in 0..N_FILES {
@@ -167,23 +353,41 @@ mod test {
                     Some(tmp_dl_path.clone()),
                     None,
                     false,
+                    Some(root.clone()),
                 )
                 .await
                 .unwrap();
-            assert!(dl_result.0.is_file());
+            assert!(dl_result.path.is_file());
             let expected_path = tmp_dl_path.join(file_names[i].file_name().unwrap());
             assert!(expected_path.is_file());
 
-            let dl_sha256 = sha256(&dl_result.0).unwrap();
+            let dl_sha256 = sha256(&dl_result.path).unwrap();
             let expected_sha256 = sha256(&expected_path).unwrap();
 
             assert_eq!(&dl_sha256, &expected_sha256);
 
-            let ok = dl_result.1.verify(&dl_sha256);
-            assert_eq!(dl_result.1.root(), &root);
+            let ok = dl_result.proof.verify(&dl_sha256);
+            assert_eq!(dl_result.proof.root(), &root);
             assert!(ok);
+            assert!(dl_result.verified);
         }
 
+        // 9- a stale pinned root is rejected before anything is written
+        let stale_root = file_infos[0].root.clone();
+        let res = api
+            .download(
+                0,
+                Some(tmp_dl_path.clone()),
+                Some("pinned".to_string()),
+                false,
+                Some(stale_root.clone()),
+            )
+            .await;
+        assert!(
+            matches!(res, Err(ApiError::RootMismatch { expected, actual }) if expected == stale_root && actual == root)
+        );
+        assert!(!tmp_dl_path.join("pinned").exists());
+
         tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
         tmp_dl_dir.close().unwrap();
@@ -191,4 +395,5197 @@ mod test {
         tmp_db_dir.close().unwrap();
         tmp_files_dir.close().unwrap();
     }
+
+    /// Download the same file from a capped server and from an uncapped server
+    /// successively serving the same archive directories
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_bandwidth_cap() {
+        const FILE_SIZE: usize = 60_000;
+        const BYTES_PER_SEC: u64 = 40_000;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let src_path = tmp_src_dir.path().join("payload");
+        std::fs::write(&src_path, vec![7u8; FILE_SIZE]).unwrap();
+
+        let capped_config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_max_download_bytes_per_sec_per_stream(Some(BYTES_PER_SEC));
+
+        let (capped_api, capped_server) = start_server_task(capped_config.clone()).await;
+
+        let file_index = capped_api.upload(&src_path).await.unwrap().index;
+
+        // 1- capped transfer
+        let start = std::time::Instant::now();
+        let dl_result = capped_api
+            .download(
+                file_index,
+                Some(tmp_dl_path.clone()),
+                Some("capped".to_string()),
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        let capped_elapsed = start.elapsed();
+        assert!(dl_result.verified);
+        let min_elapsed =
+            std::time::Duration::from_secs_f64(FILE_SIZE as f64 / BYTES_PER_SEC as f64);
+        assert!(
+            capped_elapsed >= min_elapsed,
+            "{capped_elapsed:?} < {min_elapsed:?}"
+        );
+
+        // 2- uncapped control transfer
+        capped_server.shutdown().await.unwrap();
+        let uncapped_config = capped_config.with_max_download_bytes_per_sec_per_stream(None);
+        let uncapped_api = start_server(uncapped_config).await;
+
+        let start = std::time::Instant::now();
+        let dl_result = uncapped_api
+            .download(
+                file_index,
+                Some(tmp_dl_path.clone()),
+                Some("uncapped".to_string()),
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        let uncapped_elapsed = start.elapsed();
+        assert!(dl_result.verified);
+        assert!(uncapped_elapsed < min_elapsed / 2, "{uncapped_elapsed:?}");
+
+        tmp_dl_dir.close().unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The tmp files left by a killed server are removed at startup,
+    /// the stored files are kept
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tmp_dir_sweep() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+        api.upload_bytes("a.txt", b"a".to_vec().into())
+            .await
+            .unwrap();
+        api.upload_bytes("b.txt", b"b".to_vec().into())
+            .await
+            .unwrap();
+        let root = api.root().await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let tmp_dir = config.files_tmp_dir();
+        std::fs::write(tmp_dir.join(gen_tmp_filename()), vec![0u8; 1000]).unwrap();
+        std::fs::write(tmp_dir.join(gen_tmp_filename()), b"partial").unwrap();
+        std::fs::create_dir(tmp_dir.join("junk")).unwrap();
+        std::fs::write(tmp_dir.join("junk").join("file"), b"junk").unwrap();
+        let stored_files: Vec<_> = (0..2)
+            .map(|i| StorageLayout::Sharded.file_path_at(i, &[], &config.files_db_dir()))
+            .collect();
+        assert!(stored_files.iter().all(|p| p.is_file()));
+
+        let api = start_server(config.clone()).await;
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        assert!(stored_files.iter().all(|p| p.is_file()));
+        assert_eq!(api.root().await.unwrap(), root);
+        let mut buf = vec![];
+        let (_, _, _, verified) = api.download_to_writer(1, &mut buf).await.unwrap();
+        assert!(verified);
+        assert_eq!(buf, b"b");
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Corrupt, remove and add stored files, fsck reports exactly those,
+    /// then moves the bad files aside
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fsck() {
+        const N_FILES: usize = 8;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+        for i in 0..N_FILES {
+            api.upload_bytes(
+                &format!("{i}.txt"),
+                format!("content {i}").into_bytes().into(),
+            )
+            .await
+            .unwrap();
+        }
+
+        // refused while the server is running
+        assert!(fsck(&config, None).is_err());
+        server.shutdown().await.unwrap();
+
+        let report = fsck(&config, None).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.entries, N_FILES);
+        assert!(!report.has_total_bytes_drift());
+        assert_eq!(report.stored_bytes, 9 * N_FILES as u64);
+
+        // same size, different content
+        let files_db_dir = config.files_db_dir();
+        let path_3 = StorageLayout::Sharded.file_path_at(3, &[], &files_db_dir);
+        std::fs::write(&path_3, b"content X").unwrap();
+        std::fs::remove_file(StorageLayout::Sharded.file_path_at(5, &[], &files_db_dir)).unwrap();
+        let extra = files_db_dir.join("junk");
+        std::fs::write(&extra, b"junk").unwrap();
+
+        let report = fsck(&config, None).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupted, vec![3]);
+        assert_eq!(report.missing, vec![5]);
+        assert_eq!(report.extra, vec![extra.clone()]);
+        assert!(report.quarantine_dir.is_none());
+        // the removed file is missing from the recomputed total
+        assert!(report.has_total_bytes_drift());
+        assert_eq!(report.total_bytes, 9 * N_FILES as u64);
+        assert_eq!(report.stored_bytes, 9 * (N_FILES as u64 - 1));
+        assert!(path_3.is_file());
+
+        let report = fsck(&config, Some(FsckRepair::Quarantine)).unwrap();
+        assert_eq!(report.corrupted, vec![3]);
+        let quarantine_dir = report.quarantine_dir.unwrap();
+        assert!(quarantine_dir.starts_with(config.files_quarantine_dir()));
+        assert_eq!(
+            std::fs::read(quarantine_dir.join("3")).unwrap(),
+            b"content X"
+        );
+        assert!(quarantine_dir.join("extra").join("junk").is_file());
+        assert!(!extra.exists());
+
+        // the quarantined index is now missing
+        let report = fsck(&config, None).unwrap();
+        assert!(report.corrupted.is_empty());
+        assert_eq!(report.missing, vec![3, 5]);
+        assert!(report.extra.is_empty());
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Lose the db file, rebuild it from the stored files and restart
+    /// the server with the same merkle root
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rebuild() {
+        const N_FILES: usize = 12;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+        for i in 0..N_FILES {
+            api.upload_bytes(
+                &format!("{i}.txt"),
+                format!("content {i}").into_bytes().into(),
+            )
+            .await
+            .unwrap();
+        }
+        let root = api.root().await.unwrap();
+
+        // refused while the server is running
+        assert!(rebuild(&config, true).is_err());
+        server.shutdown().await.unwrap();
+
+        // the existing db file is kept without force
+        assert!(matches!(
+            rebuild(&config, false),
+            Err(ServerError::DbFileNotEmpty(_))
+        ));
+
+        std::fs::write(config.db_file(), b"corrupted").unwrap();
+        let report = rebuild(&config, true).unwrap();
+        assert_eq!(report.layout, StorageLayout::Sharded);
+        assert_eq!(report.entries, N_FILES);
+        assert_eq!(report.root, Some(root.clone()));
+
+        let api = start_server(config.clone()).await;
+        assert_eq!(api.count().await.unwrap(), N_FILES as u64);
+        assert_eq!(api.root().await.unwrap(), root);
+        for i in 0..N_FILES as u64 {
+            let (filename, proof) = api.entry(i).await.unwrap();
+            assert_eq!(filename, i.to_string());
+            let bytes = format!("content {i}").into_bytes();
+            assert!(proof.verify(&Sha256::digest(&bytes).to_vec()));
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Export an archive into a bundle, restore it into new directories and
+    /// restart the server on them with the same merkle root
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_import() {
+        const N_FILES: usize = 12;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_bundle_dir = tempdir().unwrap();
+        let tmp_restore_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let bundle = tmp_bundle_dir.path().join("backup.tar.zst");
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::ContentAddressed)
+            .with_compression_level(Some(3));
+
+        let (api, server) = start_server_task(config.clone()).await;
+        for i in 0..N_FILES {
+            // a few entries share their content, all are compressible
+            let content = format!("content {}", i % 8).repeat(100);
+            api.upload_bytes(&format!("{i}.txt"), content.into_bytes().into())
+                .await
+                .unwrap();
+        }
+        let status = api.status().await.unwrap();
+
+        // refused while the server is running
+        assert!(matches!(
+            export_bundle(&config, &bundle),
+            Err(ServerError::DbLocked(_))
+        ));
+        server.shutdown().await.unwrap();
+
+        let report = export_bundle(&config, &bundle).unwrap();
+        assert_eq!(report.entries, N_FILES);
+        assert_eq!(report.files, 8);
+        assert_eq!(report.root, Some(status.root.clone()));
+
+        // restored on another machine
+        let restored = config
+            .clone()
+            .with_db_dir(tmp_restore_dir.path().join("db"))
+            .with_files_dir(tmp_restore_dir.path().join("files"))
+            .with_create_missing_dirs(true);
+        let report = import_bundle(&restored, &bundle).unwrap();
+        assert_eq!(report.layout, StorageLayout::ContentAddressed);
+        assert_eq!(report.entries, N_FILES);
+        assert_eq!(report.root, Some(status.root.clone()));
+        assert!(matches!(
+            import_bundle(&restored, &bundle),
+            Err(ServerError::DbFileNotEmpty(_))
+        ));
+
+        let (api, server) = start_server_task(restored.clone()).await;
+        assert_eq!(api.status().await.unwrap(), status);
+        for i in 0..N_FILES as u64 {
+            let outcome = api
+                .download(i, Some(tmp_dl_dir.path().to_path_buf()), None, false, None)
+                .await
+                .unwrap();
+            assert!(outcome.verified);
+            assert_eq!(outcome.filename, format!("{i}.txt"));
+        }
+        server.shutdown().await.unwrap();
+
+        // a bundle with a tampered stored file is rejected, nothing is left
+        // in the directories
+        let tampered = tmp_bundle_dir.path().join("tampered.tar.zst");
+        {
+            let decoder = zstd::Decoder::new(std::fs::File::open(&bundle).unwrap()).unwrap();
+            let mut archive = tar::Archive::new(decoder);
+            let encoder = zstd::Encoder::new(std::fs::File::create(&tampered).unwrap(), 0).unwrap();
+            let mut builder = tar::Builder::new(encoder);
+            for entry in archive.entries().unwrap() {
+                let entry = entry.unwrap();
+                let mut header = entry.header().clone();
+                if entry.path().unwrap().starts_with("files") {
+                    let data = b"tampered";
+                    header.set_size(data.len() as u64);
+                    header.set_cksum();
+                    builder.append(&header, &data[..]).unwrap();
+                } else {
+                    builder.append(&header, entry).unwrap();
+                }
+            }
+            builder.into_inner().unwrap().finish().unwrap();
+        }
+        let empty = restored
+            .clone()
+            .with_db_dir(tmp_restore_dir.path().join("db2"))
+            .with_files_dir(tmp_restore_dir.path().join("files2"));
+        match import_bundle(&empty, &tampered) {
+            Err(ServerError::InvalidBundle { message, .. }) => {
+                assert!(message.contains("stored file of entry 0"), "{message}")
+            }
+            r => panic!("unexpected result {r:?}"),
+        }
+        assert!(!empty.db_file().exists());
+        assert!(!empty.files_db_dir().exists());
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+        tmp_bundle_dir.close().unwrap();
+        tmp_restore_dir.close().unwrap();
+        tmp_dl_dir.close().unwrap();
+    }
+
+    /// Migrate a populated flat archive to the sharded layout,
+    /// restart the server and download + verify every file
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_migrate_layout() {
+        const N_FILES: usize = 30;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::Flat);
+
+        let file_names = gen_files(tmp_src_dir.path(), N_FILES);
+
+        // 1- populate a flat archive
+        let (api, server) = start_server_task(config.clone()).await;
+        for file_name in &file_names {
+            api.upload(file_name).await.unwrap();
+        }
+        let root = api.root().await.unwrap();
+        assert!(config.files_db_dir().join("0").is_file());
+
+        // 2- migration and a second server are refused while the server is
+        // running
+        assert!(migrate_layout(&config, StorageLayout::Sharded, true).is_err());
+        let err = mrklar::start(config.clone()).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ServerError>(),
+            Some(ServerError::DbLocked(_))
+        ));
+
+        server.shutdown().await.unwrap();
+
+        // 3- simulate an interrupted migration
+        let sharded_0 = StorageLayout::Sharded.file_path_at(0, &[], &config.files_db_dir());
+        std::fs::create_dir_all(sharded_0.parent().unwrap()).unwrap();
+        std::fs::rename(config.files_db_dir().join("0"), &sharded_0).unwrap();
+
+        // 4- resume the migration
+        let report = migrate_layout(&config, StorageLayout::Sharded, true).unwrap();
+        assert_eq!(report.from, StorageLayout::Flat);
+        assert_eq!(report.entries, N_FILES);
+        assert_eq!(report.moved, N_FILES - 1);
+        assert_eq!(report.verified, N_FILES);
+        assert!(!config.files_db_dir().join("0").exists());
+        assert!(sharded_0.is_file());
+
+        // idempotent
+        let report = migrate_layout(&config, StorageLayout::Sharded, false).unwrap();
+        assert_eq!(report.moved, 0);
+
+        // 5- restart the server
+        let config = config.with_storage_layout(StorageLayout::Sharded);
+        let api = start_server(config.clone()).await;
+        assert_eq!(api.count().await.unwrap(), N_FILES as u64);
+        assert_eq!(api.root().await.unwrap(), root);
+
+        for (i, file_name) in file_names.iter().enumerate() {
+            let sha = sha256(file_name).unwrap();
+            let proof = api.proof(i as u64).await.unwrap();
+            assert!(proof.verify(&sha));
+
+            let dl_result = api
+                .download(i as u64, Some(tmp_dl_path.clone()), None, false, None)
+                .await
+                .unwrap();
+            assert!(dl_result.verified);
+            assert_eq!(sha256(&dl_result.path).unwrap(), sha);
+        }
+
+        tmp_dl_dir.close().unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A flat archive is migrated to the configured sharded layout when
+    /// the server starts
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_startup_layout_migration() {
+        const N_FILES: usize = 5;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        assert_eq!(config.storage_layout(), StorageLayout::Sharded);
+
+        let file_names = gen_files(tmp_src_dir.path(), N_FILES);
+
+        let flat_config = config.clone().with_storage_layout(StorageLayout::Flat);
+        let (api, server) = start_server_task(flat_config).await;
+        for file_name in &file_names {
+            api.upload(file_name).await.unwrap();
+        }
+        let root = api.root().await.unwrap();
+        server.shutdown().await.unwrap();
+        assert!(config.files_db_dir().join("0").is_file());
+
+        let api = start_server(config.clone()).await;
+        assert_eq!(api.root().await.unwrap(), root);
+        for (i, file_name) in file_names.iter().enumerate() {
+            assert!(!config.files_db_dir().join(i.to_string()).exists());
+            assert!(StorageLayout::Sharded
+                .file_path_at(i, &[], &config.files_db_dir())
+                .is_file());
+
+            let dl_result = api
+                .download(i as u64, Some(tmp_dl_path.clone()), None, false, None)
+                .await
+                .unwrap();
+            assert!(dl_result.verified);
+            assert_eq!(sha256(&dl_result.path).unwrap(), sha256(file_name).unwrap());
+        }
+
+        tmp_dl_dir.close().unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Identical files share a single stored file with the content addressed
+    /// layout, an archive is migrated to and from it without changing its
+    /// entries
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_content_addressed_layout() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let ca_config = config
+            .clone()
+            .with_storage_layout(StorageLayout::ContentAddressed);
+        let files_db_dir = config.files_db_dir();
+        // number of stored files, checked by fsck
+        let count_files = || {
+            let report = fsck(&config, None).unwrap();
+            assert!(report.is_ok());
+            let mut n = 0;
+            let mut dirs = vec![files_db_dir.clone()];
+            while let Some(dir) = dirs.pop() {
+                for entry in std::fs::read_dir(dir).unwrap() {
+                    let path = entry.unwrap().path();
+                    if path.is_dir() {
+                        dirs.push(path);
+                    } else {
+                        n += 1;
+                    }
+                }
+            }
+            n
+        };
+
+        let mut contents = vec![b"content A", b"content B", b"content A", b"content A"];
+        let (api, server) = start_server_task(ca_config.clone()).await;
+        for (i, content) in contents.iter().enumerate() {
+            let outcome = api
+                .upload_bytes(&format!("file{}", i), content.to_vec().into())
+                .await
+                .unwrap();
+            assert_eq!(outcome.index, i as u64);
+        }
+        let root = api.root().await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let sha_a = Sha256::digest(b"content A").to_vec();
+        let blob_a = StorageLayout::ContentAddressed.file_path_at(0, &sha_a, &files_db_dir);
+        assert_eq!(std::fs::read(&blob_a).unwrap(), b"content A");
+        assert_eq!(count_files(), 2);
+        assert!(matches!(
+            rebuild(&ca_config, true),
+            Err(ServerError::RebuildUnsupportedLayout(
+                StorageLayout::ContentAddressed
+            ))
+        ));
+
+        // each entry gets its own copy
+        let (api, server) = start_server_task(config.clone()).await;
+        assert_eq!(api.root().await.unwrap(), root);
+        assert!(!blob_a.exists());
+        for (i, content) in contents.iter().enumerate() {
+            let path = StorageLayout::Sharded.file_path_at(i, &[], &files_db_dir);
+            assert_eq!(&std::fs::read(path).unwrap(), content);
+        }
+        api.upload_bytes("file4", b"content B".to_vec().into())
+            .await
+            .unwrap();
+        contents.push(b"content B");
+        server.shutdown().await.unwrap();
+        assert_eq!(count_files(), 5);
+
+        // the copies are merged back
+        let report = migrate_layout(&config, StorageLayout::ContentAddressed, true).unwrap();
+        assert_eq!(report.moved, 2);
+        assert_eq!(report.verified, 5);
+        assert_eq!(count_files(), 2);
+
+        let api = start_server(ca_config).await;
+        for (i, content) in contents.iter().enumerate() {
+            let mut downloaded = vec![];
+            let (filename, proof, _, verified) = api
+                .download_to_writer(i as u64, &mut downloaded)
+                .await
+                .unwrap();
+            assert!(verified);
+            assert_eq!(filename, format!("file{}", i));
+            assert!(proof.verify(&Sha256::digest(content).to_vec()));
+            assert_eq!(&downloaded, content);
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The flat files named `10` to `99` do not collide with the content
+    /// addressed directories, an archive of more than 100 entries is migrated
+    /// both ways
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_content_addressed_migration_many_entries() {
+        const N_FILES: usize = 120;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::Flat);
+        let files_db_dir = config.files_db_dir();
+
+        let (api, server) = start_server_task(config.clone()).await;
+        for i in 0..N_FILES {
+            api.upload_bytes(&format!("file{}", i), format!("content {}", i).into())
+                .await
+                .unwrap();
+        }
+        let root = api.root().await.unwrap();
+        server.shutdown().await.unwrap();
+        assert!(files_db_dir.join("99").is_file());
+
+        let report = migrate_layout(&config, StorageLayout::ContentAddressed, true).unwrap();
+        assert_eq!(report.moved, N_FILES);
+        assert_eq!(report.verified, N_FILES);
+        assert!(!files_db_dir.join("99").exists());
+        assert!(fsck(&config, None).unwrap().is_ok());
+
+        let report = migrate_layout(&config, StorageLayout::Flat, true).unwrap();
+        assert_eq!(report.moved, N_FILES);
+        assert_eq!(report.verified, N_FILES);
+        assert!(files_db_dir.join("99").is_file());
+        assert!(fsck(&config, None).unwrap().is_ok());
+
+        let api = start_server(config).await;
+        assert_eq!(api.count().await.unwrap(), N_FILES as u64);
+        assert_eq!(api.root().await.unwrap(), root);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// File size and upload timestamp of the entries, kept across restarts
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_entry_size_and_upload_time() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let before = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let (api, server) = start_server_task(config.clone()).await;
+        api.upload_bytes("a.txt", vec![1u8; 1000].into())
+            .await
+            .unwrap();
+        api.upload_bytes("empty.txt", vec![].into()).await.unwrap();
+
+        let info = api.metadata(0).await.unwrap();
+        assert_eq!(info.filename, "a.txt");
+        assert_eq!(info.size, 1000);
+        assert!(info.uploaded_at >= before);
+        assert_eq!(api.metadata(1).await.unwrap().size, 0);
+        server.shutdown().await.unwrap();
+
+        let api = start_server(config).await;
+        assert_eq!(api.metadata(0).await.unwrap(), info);
+        let list = api.list_all().await.unwrap();
+        assert_eq!(list[0].size, 1000);
+        assert_eq!(list[0].uploaded_at, info.uploaded_at);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The stats report the size of the stored files, counted once when
+    /// shared, and compressed when the server compresses them
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stats_total_bytes() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+        let stats = api.stats().await.unwrap();
+        assert_eq!(stats.count, 0);
+        assert_eq!(stats.total_bytes, 0);
+        assert_eq!(stats.tree_depth, 0);
+        assert_eq!(stats.tmp_files, 0);
+        assert_eq!(stats.tmp_bytes, 0);
+
+        for (i, size) in [1000usize, 0, 2500, 1].into_iter().enumerate() {
+            let content: Vec<u8> = (0..size).map(|j| (i + j) as u8).collect();
+            api.upload_bytes(&format!("{i}.bin"), content.into())
+                .await
+                .unwrap();
+        }
+        let stats = api.stats().await.unwrap();
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.total_bytes, 3501);
+        assert_eq!(stats.tree_depth, 2);
+        assert_eq!(stats.tmp_files, 0);
+        assert_eq!(stats.db_file_bytes, 0);
+        server.shutdown().await.unwrap();
+
+        // persisted in the db file, written by the migration
+        migrate_layout(&config, StorageLayout::Flat, false).unwrap();
+        let (api, server) = start_server_task(config.clone()).await;
+        let stats = api.stats().await.unwrap();
+        assert_eq!(stats.total_bytes, 3501);
+        let db_file_len = std::fs::metadata(config.db_file()).unwrap().len();
+        assert_eq!(stats.db_file_bytes, db_file_len);
+        server.shutdown().await.unwrap();
+
+        // compressed stored files, shared once migrated to the content
+        // addressed layout
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::Flat)
+            .with_compression_level(Some(3));
+        let (api, server) = start_server_task(config.clone()).await;
+        for i in 0..6 {
+            let content = format!("content {}", i % 3).repeat(1000);
+            api.upload_bytes(&format!("{i}.txt"), content.into_bytes().into())
+                .await
+                .unwrap();
+        }
+        let stats = api.stats().await.unwrap();
+        assert_eq!(stats.count, 6);
+        let (files, bytes) = mrklar_fs::dir_size(config.files_db_dir()).unwrap();
+        assert_eq!(files, 6);
+        assert_eq!(stats.total_bytes, bytes);
+        assert!(stats.total_bytes < 6 * 9000);
+        server.shutdown().await.unwrap();
+
+        migrate_layout(&config, StorageLayout::ContentAddressed, false).unwrap();
+        let config = config.with_storage_layout(StorageLayout::ContentAddressed);
+        let (api, server) = start_server_task(config.clone()).await;
+        let (files, shared_bytes) = mrklar_fs::dir_size(config.files_db_dir()).unwrap();
+        assert_eq!(files, 3);
+        assert_eq!(api.stats().await.unwrap().total_bytes, shared_bytes);
+        assert!(shared_bytes < bytes);
+        server.shutdown().await.unwrap();
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Download counters and last access timestamps
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_stats() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        for file_name in gen_files(tmp_src_dir.path(), 2) {
+            api.upload(&file_name).await.unwrap();
+        }
+
+        let info = api.metadata(0).await.unwrap();
+        assert_eq!(info.download_count, 0);
+        assert_eq!(info.last_download_ms, 0);
+
+        let mut last_download_ms = 0;
+        for i in 1..=3 {
+            api.download(0, Some(tmp_dl_path.clone()), None, true, None)
+                .await
+                .unwrap();
+            let info = api.metadata(0).await.unwrap();
+            assert_eq!(info.download_count, i);
+            assert!(info.last_download_ms > last_download_ms);
+            last_download_ms = info.last_download_ms;
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let list = api.list_all().await.unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].download_count, 3);
+        assert_eq!(list[1].download_count, 0);
+
+        let stats = api.stats().await.unwrap();
+        assert_eq!(stats.count, 2);
+        assert_eq!(stats.total_downloads, 3);
+
+        let info = api.metadata(1).await.unwrap();
+        assert_eq!(info.index, 1);
+        assert_eq!(info.sha256.len(), 32);
+
+        assert!(matches!(
+            api.metadata(2).await,
+            Err(ApiError::IndexNotFound(2))
+        ));
+        assert!(matches!(
+            api.proof(2).await,
+            Err(ApiError::IndexNotFound(2))
+        ));
+        assert!(matches!(
+            api.download(2, Some(tmp_dl_path.clone()), None, true, None)
+                .await,
+            Err(ApiError::IndexNotFound(2))
+        ));
+
+        tmp_dl_dir.close().unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Mirror a small archive, tamper with one file, delete another one
+    /// and verify the mirror against the remote root
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_mirror() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+        let tmp_mirror_dir = tempdir().unwrap();
+        let mirror_dir = tmp_mirror_dir.path();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let mut file_names = gen_files(tmp_src_dir.path(), 4);
+        // same filename, different content
+        let dup_dir = tmp_src_dir.path().join("dup");
+        std::fs::create_dir(&dup_dir).unwrap();
+        let dup = dup_dir.join(file_names[0].file_name().unwrap());
+        std::fs::write(&dup, b"duplicate").unwrap();
+        file_names.push(dup);
+
+        for file_name in &file_names {
+            api.upload(file_name).await.unwrap();
+        }
+        let root = api.root().await.unwrap();
+
+        let report = api.download_all(mirror_dir, 2).await.unwrap();
+        assert!(report.is_ok());
+        let manifest = MirrorManifest::load(mirror_dir).unwrap().unwrap();
+        assert_eq!(manifest.entries.len(), 5);
+        assert_ne!(manifest.entries[0].path, manifest.entries[4].path);
+
+        let report = api.verify_mirror(mirror_dir, None, 2).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.root, root);
+        assert_eq!(report.count(VerifyStatus::Ok), 5);
+
+        // tamper with index 1, delete index 2
+        std::fs::write(mirror_dir.join(&manifest.entries[1].path), b"tampered").unwrap();
+        std::fs::remove_file(mirror_dir.join(&manifest.entries[2].path)).unwrap();
+
+        let report = api
+            .verify_mirror(mirror_dir, Some(root.clone()), 2)
+            .await
+            .unwrap();
+        assert!(!report.is_ok());
+        let statuses: Vec<VerifyStatus> = report.results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                VerifyStatus::Ok,
+                VerifyStatus::Mismatch,
+                VerifyStatus::Missing,
+                VerifyStatus::Ok,
+                VerifyStatus::Ok
+            ]
+        );
+
+        // pinned root does not match the remote archive
+        let report = api
+            .verify_mirror(mirror_dir, Some(vec![0; 32]), 2)
+            .await
+            .unwrap();
+        assert_eq!(report.count(VerifyStatus::Ok), 0);
+
+        // the archive grows after the mirroring, the mirror root is checked
+        // through a consistency proof and only the mirrored entries are verified
+        assert!(api.download_all(mirror_dir, 2).await.unwrap().is_ok());
+        for file_name in gen_files(tmp_src_dir.path(), 3) {
+            api.upload(&file_name).await.unwrap();
+        }
+        assert_ne!(api.root().await.unwrap(), root);
+
+        let report = api.verify_mirror(mirror_dir, None, 2).await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.root, root);
+        assert_eq!(report.results.len(), 5);
+
+        // manifest written without the tree size
+        let mut manifest = MirrorManifest::load(mirror_dir).unwrap().unwrap();
+        assert_eq!(manifest.size, 5);
+        manifest.size = 0;
+        manifest.save(mirror_dir).unwrap();
+        let report = api
+            .verify_mirror(mirror_dir, Some(root.clone()), 2)
+            .await
+            .unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.count(VerifyStatus::Ok), 5);
+
+        tmp_mirror_dir.close().unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// `MrklarApi::connect` fails if the server is unreachable,
+    /// then a single channel is shared by all the calls
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_connect() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            // the client connects before the server is started
+            .with_port(free_port())
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        assert!(MrklarApi::connect(config.net.clone()).await.is_err());
+
+        start_server(config.clone()).await;
+
+        let api = MrklarApi::connect(config.net.clone()).await.unwrap();
+        let file_names = gen_files(tmp_src_dir.path(), 20);
+        for (i, file_name) in file_names.iter().enumerate() {
+            let outcome = api.upload(file_name).await.unwrap();
+            assert_eq!(outcome.index, i as u64);
+            assert_eq!(api.root().await.unwrap(), outcome.root);
+            assert_eq!(api.count().await.unwrap(), i as u64 + 1);
+        }
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Upload in-memory data and an arbitrary reader
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_bytes_and_reader() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_chunk_size(1000)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        // several chunks, last one is partial
+        let data: Vec<u8> = (0..4500u32).map(|i| (i % 251) as u8).collect();
+        let outcome = api
+            .upload_bytes("bytes.bin", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+
+        let reader = std::io::Cursor::new(b"hello reader".to_vec());
+        let outcome = api.upload_reader("reader.txt", reader, None).await.unwrap();
+        assert_eq!(outcome.index, 1);
+
+        // empty content
+        let outcome = api
+            .upload_bytes("empty.bin", Vec::new().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 2);
+
+        assert!(matches!(
+            api.upload_bytes("", data.clone().into()).await,
+            Err(ApiError::MissingFilename)
+        ));
+
+        let outcome = api
+            .download(0, Some(tmp_dl_path.clone()), None, false, None)
+            .await
+            .unwrap();
+        assert!(outcome.verified);
+        // the digest computed while streaming matches the file on disk
+        assert!(outcome.proof.verify(&sha256(&outcome.path).unwrap()));
+        assert_eq!(std::fs::read(outcome.path).unwrap(), data);
+
+        let outcome = api
+            .download(1, Some(tmp_dl_path.clone()), None, false, None)
+            .await
+            .unwrap();
+        assert!(outcome.verified);
+        assert_eq!(std::fs::read(outcome.path).unwrap(), b"hello reader");
+
+        // digest sent ahead of the content
+        let src_path = tmp_dl_path.join("known.bin");
+        std::fs::write(&src_path, &data).unwrap();
+        let known_sha256 = sha256(&src_path).unwrap();
+        let outcome = api
+            .upload_with_known_sha256(&src_path, known_sha256.clone())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 3);
+        assert!(api.proof(3).await.unwrap().verify(&known_sha256));
+        // the server rejection is surfaced as is
+        let err = api
+            .upload_with_known_sha256(&src_path, vec![0u8; 32])
+            .await
+            .unwrap_err();
+        let status = err.status().expect("server status");
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Upload failed, invalid hash value");
+
+        // a local read failure cancels the upload
+        struct FailingReader;
+        impl tokio::io::AsyncRead for FailingReader {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _cx: &mut std::task::Context<'_>,
+                _buf: &mut tokio::io::ReadBuf<'_>,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                std::task::Poll::Ready(Err(std::io::Error::other("disk on fire")))
+            }
+        }
+        let err = api
+            .upload_reader("failing", FailingReader, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ApiError::Io(_)), "{err:?}");
+        assert_eq!(api.count().await.unwrap(), 4);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Download into an in-memory buffer
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_to_writer() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_chunk_size(1000)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let data: Vec<u8> = (0..3500u32).map(|i| (i % 253) as u8).collect();
+        api.upload_bytes("a.bin", data.clone().into())
+            .await
+            .unwrap();
+        api.upload_bytes("b.bin", b"b".to_vec().into())
+            .await
+            .unwrap();
+
+        let mut buf: Vec<u8> = vec![];
+        let (filename, proof, len, verified) = api.download_to_writer(0, &mut buf).await.unwrap();
+        assert_eq!(filename, "a.bin");
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(buf, data);
+        assert!(verified);
+        assert_eq!(proof.root(), &api.root().await.unwrap());
+
+        assert!(api.download_to_writer(2, &mut buf).await.is_err());
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A download stalled by the server bandwidth cap is interrupted
+    /// by the api deadline and its output file removed
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deadline_exceeded() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_chunk_size(1000)
+            .with_tracing(false)
+            .with_max_download_bytes_per_sec_per_stream(Some(10_000))
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config)
+            .await
+            .with_timeout(std::time::Duration::from_millis(500));
+        api.upload_bytes("slow.bin", vec![7u8; 50_000].into())
+            .await
+            .unwrap();
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        let res = api
+            .download(0, Some(tmp_dl_path.clone()), None, false, None)
+            .await;
+        assert!(matches!(res, Err(ApiError::DeadlineExceeded)));
+        assert!(!tmp_dl_path.join("slow.bin").exists());
+
+        let mut buf: Vec<u8> = vec![];
+        let res = api.download_to_writer(0, &mut buf).await;
+        assert!(matches!(res, Err(ApiError::DeadlineExceeded)));
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Invalid server urls are reported when creating the api, never panic
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invalid_endpoint() {
+        for url in [
+            "",
+            "127.0.0.1:3000",
+            "http://",
+            "ftp://127.0.0.1",
+            "unix:/tmp/mrklar.sock",
+        ] {
+            assert!(
+                matches!(MrklarApi::from_url(url), Err(ApiError::InvalidEndpoint(u)) if u == url),
+                "{url}"
+            );
+        }
+        assert!(MrklarApi::new(mrklar_common::config::NetConfig::default()).is_ok());
+        assert!(MrklarApi::from_url("http://[::1]:3000").is_ok());
+    }
+
+    /// Read-only calls are retried until the server is up
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_retry() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            // the client is created before the server is started
+            .with_port(free_port())
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        // no retry, fails immediately
+        let api = MrklarApi::new(config.net.clone()).unwrap();
+        assert!(matches!(api.count().await, Err(e) if e.is_connection_error()));
+        let res = api
+            .wait_until_ready(std::time::Duration::from_millis(100))
+            .await;
+        assert!(matches!(res, Err(ApiError::ServerNotReady(_))));
+
+        // exhausted attempts, returns the last error
+        let policy = RetryPolicy::default()
+            .with_max_attempts(2)
+            .with_base_delay(std::time::Duration::from_millis(10));
+        let api = MrklarApi::new(config.net.clone())
+            .unwrap()
+            .with_retry(policy);
+        assert!(matches!(api.root().await, Err(e) if e.is_connection_error()));
+
+        // server starts after a short delay
+        let policy = RetryPolicy::default()
+            .with_max_attempts(20)
+            .with_base_delay(std::time::Duration::from_millis(50))
+            .with_max_delay(std::time::Duration::from_millis(200));
+        let api = MrklarApi::new(config.net.clone())
+            .unwrap()
+            .with_retry(policy);
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+            mrklar::spawn(config).await
+        });
+        assert_eq!(api.count().await.unwrap(), 0);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Verify local files against the remote archive without downloading them
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_local_file() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let file_names = gen_files(tmp_src_dir.path(), 3);
+        for file_name in &file_names {
+            api.upload(file_name).await.unwrap();
+        }
+        let root = api.root().await.unwrap();
+
+        let outcome = api.verify(1, &file_names[1]).await.unwrap();
+        assert!(outcome.verified);
+        assert_eq!(outcome.root, root);
+        assert_eq!(outcome.sha256, sha256(&file_names[1]).unwrap());
+
+        // wrong index
+        let outcome = api.verify(0, &file_names[1]).await.unwrap();
+        assert!(!outcome.verified);
+
+        // modified file
+        std::fs::write(&file_names[2], b"modified").unwrap();
+        let outcome = api.verify_current(2, &file_names[2]).await.unwrap();
+        assert!(!outcome.verified);
+        assert_eq!(outcome.root, root);
+
+        // missing file
+        assert!(api
+            .verify(0, tmp_src_dir.path().join("missing"))
+            .await
+            .is_err());
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Upload ~100 files with bounded concurrency, a bad path does not
+    /// abort the batch
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_many() {
+        const N_FILES: usize = 100;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let mut paths = gen_files(tmp_src_dir.path(), N_FILES);
+        paths.insert(50, tmp_src_dir.path().join("does_not_exist"));
+
+        let (results, root) = api.upload_many(paths.clone(), 8).await.unwrap();
+        assert_eq!(results.len(), N_FILES + 1);
+        assert!(matches!(results[50], Err(ApiError::UploadFileNotFound(_))));
+        assert_eq!(root, api.root().await.unwrap());
+        assert_eq!(api.count().await.unwrap(), N_FILES as u64);
+
+        let mut indices: Vec<u64> = vec![];
+        for (path, result) in paths.iter().zip(&results) {
+            let Ok(outcome) = result else {
+                continue;
+            };
+            indices.push(outcome.index);
+            let proof = api.proof(outcome.index).await.unwrap();
+            assert!(proof.verify(&sha256(path).unwrap()));
+        }
+        indices.sort();
+        assert_eq!(indices, (0..N_FILES as u64).collect::<Vec<u64>>());
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Download a 50 files archive with bounded parallelism
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_all() {
+        const N_FILES: usize = 50;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let dl_dir = tmp_dl_dir.path();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let mut paths = gen_files(tmp_src_dir.path(), N_FILES - 1);
+        // filename collision
+        let dup_dir = tmp_src_dir.path().join("dup");
+        std::fs::create_dir(&dup_dir).unwrap();
+        let dup = dup_dir.join(paths[3].file_name().unwrap());
+        std::fs::write(&dup, b"duplicate").unwrap();
+        paths.push(dup);
+
+        let (results, root) = api.upload_many(paths.clone(), 8).await.unwrap();
+
+        let report = api.download_all(dl_dir, 8).await.unwrap();
+        assert_eq!(report.root, root);
+        assert_eq!(report.entries.len(), N_FILES);
+        assert!(report.is_ok());
+
+        for (src, result) in paths.iter().zip(results) {
+            let index = result.unwrap().index;
+            let entry = &report.entries[index as usize];
+            assert_eq!(entry.index, index);
+            assert_eq!(sha256(&entry.path).unwrap(), sha256(src).unwrap());
+        }
+        let names: std::collections::HashSet<_> =
+            report.entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(names.len(), N_FILES);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Download of the most recently uploaded entry with a given filename
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_by_name() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let dl_dir = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+        for (name, content) in [("a.txt", "a0"), ("b.txt", "b"), ("a.txt", "a1")] {
+            api.upload_bytes(name, content.as_bytes().to_vec().into())
+                .await
+                .unwrap();
+        }
+
+        let outcome = api
+            .download_by_name("a.txt", Some(dl_dir.clone()), None, true, None)
+            .await
+            .unwrap();
+        assert!(outcome.verified);
+        assert_eq!(outcome.filename, "a.txt");
+        assert_eq!(std::fs::read(&outcome.path).unwrap(), b"a1");
+        assert_eq!(outcome.proof.root(), &api.root().await.unwrap());
+
+        assert!(matches!(
+            api.download_by_name("c.txt", Some(dl_dir.clone()), None, true, None)
+                .await,
+            Err(ApiError::FileNameNotFound(name)) if name == "c.txt"
+        ));
+        server.shutdown().await.unwrap();
+
+        // the filenames are indexed again on load
+        let api = start_server(config).await;
+        let outcome = api
+            .download_by_name("b.txt", Some(dl_dir.clone()), None, true, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&outcome.path).unwrap(), b"b");
+        let outcome = api
+            .download_by_name("a.txt", Some(dl_dir.clone()), None, true, None)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&outcome.path).unwrap(), b"a1");
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Page through 300 entries in chunks of 64
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_pages() {
+        const N_FILES: u64 = 300;
+        const PAGE_SIZE: u64 = 64;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        for i in 0..N_FILES {
+            let name = format!("file{}", i);
+            api.upload_bytes(&name, name.clone().into_bytes().into())
+                .await
+                .unwrap();
+        }
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        loop {
+            let page = api.list(offset, PAGE_SIZE).await.unwrap();
+            assert_eq!(page.total, N_FILES);
+            if page.entries.is_empty() {
+                break;
+            }
+            assert!(page.entries.len() as u64 <= PAGE_SIZE);
+            offset += page.entries.len() as u64;
+            entries.extend(page.entries);
+        }
+        assert_eq!(entries.len() as u64, N_FILES);
+        for (i, e) in entries.iter().enumerate() {
+            assert_eq!(e.index, i as u64);
+            assert_eq!(e.filename, format!("file{}", i));
+        }
+
+        // out of range offset
+        let page = api.list(N_FILES + 10, PAGE_SIZE).await.unwrap();
+        assert!(page.entries.is_empty());
+        assert_eq!(page.total, N_FILES);
+
+        assert_eq!(api.list_all().await.unwrap().len() as u64, N_FILES);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The server caps the number of entries of a page
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_list_limit_cap() {
+        const N_FILES: u64 = MAX_LIST_LIMIT + 10;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        // faster than uploading the files one by one
+        config.create_dirs().unwrap();
+        for i in 0..N_FILES as usize {
+            let path = StorageLayout::Sharded.file_path_at(i, &[], &config.files_db_dir());
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, i.to_le_bytes()).unwrap();
+        }
+        rebuild(&config, false).unwrap();
+
+        let api = start_server(config).await;
+
+        for limit in [0, MAX_LIST_LIMIT + 1, u64::MAX] {
+            let page = api.list(0, limit).await.unwrap();
+            assert_eq!(page.entries.len() as u64, MAX_LIST_LIMIT);
+            assert_eq!(page.total, N_FILES);
+        }
+        let page = api.list(MAX_LIST_LIMIT, 0).await.unwrap();
+        assert_eq!(page.entries.len(), 10);
+        assert_eq!(page.entries[0].index, MAX_LIST_LIMIT);
+
+        let entries = api.list_all().await.unwrap();
+        assert_eq!(entries.len() as u64, N_FILES);
+        assert!(entries.iter().enumerate().all(|(i, e)| e.index == i as u64));
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Upload + Download through a TLS terminating proxy
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tls() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (_, server) = start_server_task(config).await;
+        let (proxy_addr, ca_cert) = start_tls_proxy(server.local_addr().unwrap()).await;
+
+        let api = MrklarApi::from_url(&format!("https://{}", proxy_addr))
+            .unwrap()
+            .with_tls(
+                ClientTls::new()
+                    .with_ca_cert(ca_cert)
+                    .with_domain_name("localhost"),
+            );
+
+        let uploaded = api
+            .upload_bytes("hello.txt", b"hello".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(uploaded.index, 0);
+        assert_eq!(api.root().await.unwrap(), uploaded.root);
+
+        let downloaded = api
+            .download(
+                uploaded.index,
+                Some(tmp_dl_dir.path().to_path_buf()),
+                None,
+                false,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(downloaded.verified);
+        assert_eq!(downloaded.filename, "hello.txt");
+        assert_eq!(std::fs::read(downloaded.path).unwrap(), b"hello");
+
+        // the self-signed certificate is not trusted by default
+        let api = MrklarApi::from_url(&format!("https://localhost:{}", proxy_addr.port())).unwrap();
+        assert!(api.count().await.is_err());
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// TLS terminated by the server itself
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_tls() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_tls_dir = tempdir().unwrap();
+
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = tmp_tls_dir.path().join("server.pem");
+        let key_path = tmp_tls_dir.path().join("server.key");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        // both the certificate and the key are required
+        let err = mrklar::start(config.clone().with_tls_cert(Some(cert_path.clone())))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("missing TLS key"), "{err}");
+        let err = mrklar::start(
+            config
+                .clone()
+                .with_tls_cert(Some(tmp_tls_dir.path().join("missing.pem")))
+                .with_tls_key(Some(key_path.clone())),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("missing.pem"), "{err}");
+
+        let config = config
+            .with_tls_cert(Some(cert_path))
+            .with_tls_key(Some(key_path));
+        let (plain_api, server) = start_server_task(config).await;
+        let port = server.local_addr().unwrap().port();
+
+        let api = MrklarApi::from_url(&format!("https://127.0.0.1:{}", port))
+            .unwrap()
+            .with_tls(
+                ClientTls::new()
+                    .with_ca_cert(cert.pem())
+                    .with_domain_name("localhost"),
+            );
+        let uploaded = api
+            .upload_bytes("hello.txt", b"hello".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(uploaded.index, 0);
+        let mut buf = vec![];
+        let (_, _, _, verified) = api
+            .download_to_writer(uploaded.index, &mut buf)
+            .await
+            .unwrap();
+        assert!(verified);
+        assert_eq!(buf, b"hello");
+
+        // plain text connections are refused
+        assert!(plain_api.count().await.is_err());
+
+        tmp_tls_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_server_mtls() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_tls_dir = tempdir().unwrap();
+
+        let rcgen::CertifiedKey { cert, signing_key } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_path = tmp_tls_dir.path().join("server.pem");
+        let key_path = tmp_tls_dir.path().join("server.key");
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, signing_key.serialize_pem()).unwrap();
+
+        // the client certificate is signed by a dedicated CA
+        let mut ca_params = rcgen::CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "mrklar test CA");
+        let ca =
+            rcgen::CertifiedIssuer::self_signed(ca_params, rcgen::KeyPair::generate().unwrap())
+                .unwrap();
+        let ca_path = tmp_tls_dir.path().join("client-ca.pem");
+        std::fs::write(&ca_path, ca.pem()).unwrap();
+
+        let mut client_params = rcgen::CertificateParams::new(vec![]).unwrap();
+        client_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "uploader");
+        client_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let client_key = rcgen::KeyPair::generate().unwrap();
+        let client_cert = client_params.signed_by(&client_key, &ca).unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_tls_client_ca(Some(ca_path));
+
+        // client authentication requires TLS
+        let err = mrklar::start(config.clone()).await.unwrap_err();
+        assert!(err.to_string().contains("requires TLS"), "{err}");
+
+        let config = config
+            .with_tls_cert(Some(cert_path))
+            .with_tls_key(Some(key_path));
+        let (_, server) = start_server_task(config).await;
+        let url = format!("https://127.0.0.1:{}", server.local_addr().unwrap().port());
+        let server_tls = ClientTls::new()
+            .with_ca_cert(cert.pem())
+            .with_domain_name("localhost");
+
+        // accepted client
+        let api = MrklarApi::from_url(&url).unwrap().with_tls(
+            server_tls
+                .clone()
+                .with_identity(client_cert.pem(), client_key.serialize_pem()),
+        );
+        let uploaded = api
+            .upload_bytes("hello.txt", b"hello".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(uploaded.index, 0);
+
+        // clients without a certificate are rejected
+        let anonymous_api = MrklarApi::from_url(&url).unwrap().with_tls(server_tls);
+        assert!(anonymous_api.count().await.is_err());
+        assert!(anonymous_api
+            .upload_bytes("hello.txt", b"hello".to_vec().into())
+            .await
+            .is_err());
+
+        // clients with a certificate signed by an unknown CA are rejected
+        let rcgen::CertifiedKey {
+            cert: rogue_cert,
+            signing_key: rogue_key,
+        } = rcgen::generate_simple_self_signed(vec!["uploader".to_string()]).unwrap();
+        let rogue_api = MrklarApi::from_url(&url).unwrap().with_tls(
+            ClientTls::new()
+                .with_ca_cert(cert.pem())
+                .with_domain_name("localhost")
+                .with_identity(rogue_cert.pem(), rogue_key.serialize_pem()),
+        );
+        assert!(rogue_api.count().await.is_err());
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        server.shutdown().await.unwrap();
+        tmp_tls_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Cancel a throttled multi-chunk download halfway
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancel_download() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+
+        // 64 KiB sent in 4 KiB chunks at 16 KiB/s: about 4s
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_chunk_size(4 * 1024)
+            .with_max_download_bytes_per_sec_per_stream(Some(16 * 1024))
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let index = api
+            .upload_bytes("big.bin", vec![7u8; 64 * 1024].into())
+            .await
+            .unwrap()
+            .index;
+
+        let cancel = CancellationToken::new();
+        let out_dir = tmp_dl_dir.path().to_path_buf();
+        let download = {
+            let api = api.clone();
+            let cancel = cancel.clone();
+            tokio::spawn(async move {
+                api.download_with_cancel(index, Some(out_dir), None, false, None, cancel)
+                    .await
+            })
+        };
+
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        // the content is streamed into a temporary file
+        let path = tmp_dl_dir.path().join("big.bin");
+        assert!(!path.exists());
+        let tmp_files: Vec<_> = std::fs::read_dir(tmp_dl_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(tmp_files.len(), 1);
+        let partial_len = std::fs::metadata(&tmp_files[0]).unwrap().len();
+        assert!(partial_len > 0 && partial_len < 64 * 1024);
+
+        cancel.cancel();
+        let res = download.await.unwrap();
+        assert!(matches!(res, Err(ApiError::Cancelled)));
+        assert!(!path.exists());
+        assert_eq!(std::fs::read_dir(tmp_dl_dir.path()).unwrap().count(), 0);
+
+        // the api remains usable
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Hostile server filenames never escape the output directory
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_hostile_filenames() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let out_dir = tmp_dl_dir.path().join("a").join("b");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let out_dir = out_dir.canonicalize().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        // (server filename, expected local filename)
+        let names = [
+            ("../../evil.txt", "evil.txt"),
+            ("/etc/cron.d/evil", "evil"),
+            ("..\\..\\evil.bat", "evil.bat"),
+            ("..", "3"),
+            ("dir/", "4"),
+            ("nul\0byte", "5"),
+            (".", "6"),
+        ];
+        // the server rejects these uploads, the entries are added to the db
+        // as if stored by a server not validating the filenames
+        let validated = config.validate().unwrap();
+        validated.create_dirs().unwrap();
+        let db = mrklar::mem_db::MemDb::try_load(&validated).unwrap();
+        let storage = LocalStorage::new(validated.files_dir());
+        for (index, (name, _)) in names.iter().enumerate() {
+            let tmp_key = tmp_key(&index.to_string());
+            std::fs::write(validated.files_dir().join(&tmp_key), name.as_bytes()).unwrap();
+            let sha256 = Sha256::digest(name.as_bytes()).to_vec();
+            db.add_file(&validated, &storage, name, sha256, &tmp_key)
+                .unwrap();
+        }
+        drop(db);
+
+        let api = start_server(config).await;
+
+        for (index, (name, expected)) in names.iter().enumerate() {
+            let outcome = api
+                .download(index as u64, Some(out_dir.clone()), None, false, None)
+                .await
+                .unwrap();
+            assert!(outcome.verified);
+            assert_eq!(outcome.filename, *name);
+            assert_eq!(outcome.path, out_dir.join(expected));
+            assert_eq!(std::fs::read(&outcome.path).unwrap(), name.as_bytes());
+        }
+        assert_eq!(std::fs::read_dir(tmp_dl_dir.path()).unwrap().count(), 1);
+
+        // same for a mirror
+        let mirror_dir = tmp_dl_dir.path().join("mirror");
+        std::fs::create_dir(&mirror_dir).unwrap();
+        let report = api.download_all(&mirror_dir, 4).await.unwrap();
+        assert!(report.is_ok());
+        for entry in &report.entries {
+            assert_eq!(entry.path.parent().unwrap(), mirror_dir);
+        }
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A server answering uploads with a root that does not include the
+    /// uploaded file, its proofs lead to another root
+    #[derive(Default)]
+    struct LyingFileService {
+        uploaded_sha256: std::sync::Mutex<Vec<u8>>,
+    }
+
+    #[tonic::async_trait]
+    impl FileApi for LyingFileService {
+        async fn count(&self, _: Request<Empty>) -> Result<Response<U64>, Status> {
+            Err(Status::unimplemented("count"))
+        }
+
+        async fn root(&self, _: Request<Empty>) -> Result<Response<RootResponse>, Status> {
+            Ok(Response::new(RootResponse {
+                merkle_root: vec![7u8; 32],
+                count: 1,
+                signed_tree_head: None,
+            }))
+        }
+
+        async fn upload(
+            &self,
+            request: Request<Streaming<UploadRequest>>,
+        ) -> Result<Response<UploadResponse>, Status> {
+            let mut stream = request.into_inner();
+            while let Some(request) = stream.message().await? {
+                if let Some(upload_request::Type::Sha256(h)) = request.r#type {
+                    *self.uploaded_sha256.lock().unwrap() = h;
+                }
+            }
+            Ok(Response::new(UploadResponse {
+                index: Some(FileIndex { index: 0 }),
+                merkle_root: vec![7u8; 32],
+                sha256: self.uploaded_sha256.lock().unwrap().clone(),
+                signed_tree_head: None,
+                merkle_proof: vec![],
+            }))
+        }
+
+        type ProofStream = ReceiverStream<Result<ProofResponse, Status>>;
+
+        async fn proof(
+            &self,
+            _: Request<FileIndex>,
+        ) -> Result<Response<Self::ProofStream>, Status> {
+            let sibling = vec![1u8; 32];
+            let sha256 = self.uploaded_sha256.lock().unwrap().clone();
+            let root = MerkleProof::sha256_pair(&sibling, &sha256);
+            let proof = MerkleProof::from_raw_parts(root, vec![MerkleProofHash::new_left(sibling)]);
+            let (tx, rx) = tokio::sync::mpsc::channel(1);
+            tx.send(Ok(ProofResponse::new_proof(proof).unwrap()))
+                .await
+                .unwrap();
+            Ok(Response::new(ReceiverStream::new(rx)))
+        }
+
+        type DownloadStream = ReceiverStream<Result<DownloadResponse, Status>>;
+
+        async fn download(
+            &self,
+            _: Request<DownloadRequest>,
+        ) -> Result<Response<Self::DownloadStream>, Status> {
+            Err(Status::unimplemented("download"))
+        }
+
+        async fn find(&self, _: Request<FileSha256>) -> Result<Response<FileIndex>, Status> {
+            Err(Status::unimplemented("find"))
+        }
+
+        type DownloadByNameStream = ReceiverStream<Result<DownloadResponse, Status>>;
+
+        async fn download_by_name(
+            &self,
+            _: Request<FileName>,
+        ) -> Result<Response<Self::DownloadByNameStream>, Status> {
+            Err(Status::unimplemented("download_by_name"))
+        }
+
+        async fn metadata(&self, _: Request<FileIndex>) -> Result<Response<EntryInfo>, Status> {
+            Err(Status::unimplemented("metadata"))
+        }
+
+        async fn list(&self, _: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+            Err(Status::unimplemented("list"))
+        }
+
+        async fn stats(&self, _: Request<Empty>) -> Result<Response<StatsResponse>, Status> {
+            Err(Status::unimplemented("stats"))
+        }
+
+        async fn info(&self, _: Request<Empty>) -> Result<Response<ServerInfo>, Status> {
+            Err(Status::unimplemented("info"))
+        }
+
+        type WatchStream = ReceiverStream<Result<WatchEvent, Status>>;
+
+        async fn watch(&self, _: Request<Empty>) -> Result<Response<Self::WatchStream>, Status> {
+            Err(Status::unimplemented("watch"))
+        }
+
+        async fn start_upload(
+            &self,
+            _: Request<StartUploadRequest>,
+        ) -> Result<Response<UploadSession>, Status> {
+            Err(Status::unimplemented("start_upload"))
+        }
+
+        async fn upload_chunks(
+            &self,
+            _: Request<tonic::Streaming<UploadChunk>>,
+        ) -> Result<Response<UploadSession>, Status> {
+            Err(Status::unimplemented("upload_chunks"))
+        }
+
+        async fn get_upload_session(
+            &self,
+            _: Request<UploadSessionId>,
+        ) -> Result<Response<UploadSession>, Status> {
+            Err(Status::unimplemented("get_upload_session"))
+        }
+
+        async fn finish_upload(
+            &self,
+            _: Request<FinishUploadRequest>,
+        ) -> Result<Response<UploadResponse>, Status> {
+            Err(Status::unimplemented("finish_upload"))
+        }
+
+        async fn consistency_proof(
+            &self,
+            _: Request<ConsistencyProofRequest>,
+        ) -> Result<Response<ConsistencyProofResponse>, Status> {
+            Err(Status::unimplemented("consistency_proof"))
+        }
+
+        async fn audit(&self, _: Request<AuditRequest>) -> Result<Response<AuditResponse>, Status> {
+            Err(Status::unimplemented("audit"))
+        }
+    }
+
+    /// Upload with inclusion verification, against a real and a lying server
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_verified() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config.clone()).await;
+
+        let file_names = gen_files(tmp_src_dir.path(), 3);
+        for (i, file_name) in file_names.iter().enumerate() {
+            let outcome = api.upload_verified(file_name).await.unwrap();
+            assert_eq!(outcome.index, i as u64);
+            assert_eq!(outcome.root, api.root().await.unwrap());
+        }
+
+        // simulated mismatch
+        let lying_net = config.net.clone().with_port(free_port());
+        let sock_addr = lying_net.sock_addr();
+        tokio::spawn(async move {
+            tonic::transport::Server::builder()
+                .add_service(FileApiServer::new(LyingFileService::default()))
+                .serve(sock_addr)
+                .await
+        });
+
+        let lying_api = MrklarApi::new(lying_net).unwrap();
+        lying_api
+            .wait_until_ready(SERVER_READY_TIMEOUT)
+            .await
+            .unwrap();
+        // a plain upload cannot tell, without a proof returned along
+        let outcome = lying_api.upload(&file_names[0]).await.unwrap();
+        assert_eq!(outcome.root, vec![7u8; 32]);
+        assert!(outcome.proof.is_none());
+        let res = lying_api.upload_verified(&file_names[0]).await;
+        assert!(
+            matches!(res, Err(ApiError::UploadNotVerified { index: 0, root }) if root == vec![7u8; 32])
+        );
+        // nor can a proof leading to another root than the archive root
+        assert!(lying_api.proof(0).await.is_ok());
+        let res = lying_api.proof_checked(0).await;
+        assert!(
+            matches!(res, Err(ApiError::RootMismatch { expected, .. }) if expected == vec![7u8; 32])
+        );
+        let proof = api.proof_checked(1).await.unwrap();
+        assert_eq!(proof.root(), &api.root().await.unwrap());
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The merkle proof returned along with an upload leads to the returned
+    /// root, without any other call
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_proof() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_duplicate_policy(DuplicatePolicy::Dedup);
+        let api = start_server(config).await;
+
+        let file_names = gen_files(tmp_src_dir.path(), 5);
+        for (i, file_name) in file_names.iter().enumerate() {
+            let outcome = if i % 2 == 0 {
+                api.upload(file_name).await.unwrap()
+            } else {
+                api.upload_resumable(file_name).await.unwrap()
+            };
+            assert_eq!(outcome.index, i as u64);
+            let proof = outcome.proof.unwrap();
+            assert_eq!(proof.root(), &outcome.root);
+            assert!(proof.verify_with_root(&outcome.sha256, &outcome.root));
+        }
+
+        // the proof of the existing entry for a deduplicated upload
+        let outcome = api.upload(&file_names[1]).await.unwrap();
+        assert_eq!(outcome.index, 1);
+        assert!(outcome
+            .proof
+            .unwrap()
+            .verify_with_root(&outcome.sha256, &outcome.root));
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A metadata only download sends the entry, then ends the stream
+    /// without reading the stored file
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_metadata_only() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::Flat);
+        let (api, server) = start_server_task(config.clone()).await;
+
+        let data = vec![3u8; 3 * 1024 * 1024];
+        let outcome = api
+            .upload_bytes("a.bin", data.clone().into())
+            .await
+            .unwrap();
+        // the stored file is never opened
+        std::fs::remove_file(config.files_db_dir().join("0")).unwrap();
+
+        let mut client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let mut stream = client
+            .download(DownloadRequest {
+                index: 0,
+                offset: 0,
+                chunk_size: 1024,
+                length: 0,
+                metadata_only: true,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut entries = vec![];
+        while let Some(response) = stream.message().await.unwrap() {
+            match response.r#type {
+                Some(download_response::Type::Entry(entry)) => entries.push(entry),
+                r => panic!("unexpected response {r:?}"),
+            }
+        }
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].metadata.as_ref().unwrap().filename, "a.bin");
+        assert_eq!(entries[0].size, data.len() as u64);
+
+        let (filename, proof) = api.entry(0).await.unwrap();
+        assert_eq!(filename, "a.bin");
+        assert!(proof.verify_with_root(&outcome.sha256, &outcome.root));
+        // not counted as downloads
+        assert_eq!(api.metadata(0).await.unwrap().download_count, 0);
+        assert!(api.entry(1).await.is_err());
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The errors of the download and proof streams reach the client as a
+    /// status, no output file is left behind
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_errors() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let dl_dir = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_storage_layout(StorageLayout::Flat);
+        let (api, server) = start_server_task(config.clone()).await;
+        api.upload_bytes("a.bin", vec![1u8; 1000].into())
+            .await
+            .unwrap();
+
+        // nonexistent index
+        assert!(matches!(
+            api.download(5, Some(dl_dir.clone()), None, false, None)
+                .await,
+            Err(ApiError::IndexNotFound(5))
+        ));
+        assert!(matches!(
+            api.proof(5).await,
+            Err(ApiError::IndexNotFound(5))
+        ));
+        assert_eq!(std::fs::read_dir(&dl_dir).unwrap().count(), 0);
+
+        // the stored file is lost, the index exists
+        let stored_path = config.files_db_dir().join("0");
+        std::fs::remove_file(&stored_path).unwrap();
+        let err = api
+            .download(0, Some(dl_dir.clone()), None, false, None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().code(), tonic::Code::DataLoss);
+        assert_eq!(std::fs::read_dir(&dl_dir).unwrap().count(), 0);
+
+        // the stored file cannot be read once the stream has started
+        std::fs::create_dir(&stored_path).unwrap();
+        let err = api
+            .download(0, Some(dl_dir.clone()), None, false, None)
+            .await
+            .unwrap_err();
+        assert!(err.status().is_some(), "{err:?}");
+        assert_eq!(std::fs::read_dir(&dl_dir).unwrap().count(), 0);
+
+        // the proof does not depend on the stored file
+        assert!(api.proof(0).await.is_ok());
+
+        server.shutdown().await.unwrap();
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Interrupt a throttled download, then resume it from the part file
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_resumable() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let dl_dir = tmp_dl_dir.path().to_path_buf();
+
+        // 64 KiB sent in 4 KiB chunks at 16 KiB/s: about 4s
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_chunk_size(4 * 1024)
+            .with_max_download_bytes_per_sec_per_stream(Some(16 * 1024))
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let data: Vec<u8> = (0..64 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let index = api
+            .upload_bytes("big.bin", data.clone().into())
+            .await
+            .unwrap()
+            .index;
+
+        // 1- interrupted download
+        let download = {
+            let api = api.clone();
+            let dl_dir = dl_dir.clone();
+            tokio::spawn(async move {
+                api.download_resumable(index, Some(dl_dir), None, false, None)
+                    .await
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+        download.abort();
+        let _ = download.await;
+
+        let path = dl_dir.join("big.bin");
+        let part_path = dl_dir.join(".big.bin.part");
+        assert!(!path.exists());
+        let partial_len = std::fs::metadata(&part_path).unwrap().len();
+        assert!(partial_len > 0 && partial_len < data.len() as u64);
+
+        // 2- resumed download
+        let outcome = api
+            .download_resumable(index, Some(dl_dir.clone()), None, false, None)
+            .await
+            .unwrap();
+        assert!(outcome.verified);
+        assert_eq!(outcome.path, path);
+        assert_eq!(outcome.size, data.len() as u64);
+        assert_eq!(outcome.stats.bytes, data.len() as u64 - partial_len);
+        assert!(!part_path.exists());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert_eq!(outcome.sha256, sha256(&path).unwrap());
+        assert!(outcome.proof.verify(&outcome.sha256));
+
+        // 3- a part file longer than the remote file is discarded
+        std::fs::write(&part_path, vec![0u8; 2 * data.len()]).unwrap();
+        let outcome = api
+            .download_resumable(index, Some(dl_dir.clone()), None, true, None)
+            .await
+            .unwrap();
+        assert!(outcome.verified);
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_chunk_size_override() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        assert!(api.with_chunk_size(0).is_err());
+        assert!(api.with_chunk_size(MAX_CHUNK_SIZE + 1).is_err());
+
+        // chunks larger than the default grpc message size limit
+        let data: Vec<u8> = (0..7 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let outcome = api
+            .with_chunk_size(6 * 1024 * 1024)
+            .unwrap()
+            .upload_bytes("big.bin", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+        assert_eq!(outcome.stats.bytes, data.len() as u64);
+        assert_eq!(outcome.stats.chunks, 2);
+
+        for chunk_size in [64 * 1024, MAX_CHUNK_SIZE] {
+            let outcome = api
+                .with_chunk_size(chunk_size)
+                .unwrap()
+                .download(0, Some(tmp_dl_path.clone()), None, true, None)
+                .await
+                .unwrap();
+            assert!(outcome.verified);
+            assert_eq!(std::fs::read(&outcome.path).unwrap(), data);
+            assert_eq!(outcome.size, data.len() as u64);
+            assert_eq!(outcome.stats.bytes, data.len() as u64);
+            assert_eq!(outcome.stats.chunks, data.len().div_ceil(chunk_size) as u64);
+        }
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_clones() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let mut hashes = vec![];
+        for i in 0..4u8 {
+            let data = vec![i; 100];
+            hashes.push(Sha256::digest(&data).to_vec());
+            api.upload_bytes(&format!("file{}", i), data.into())
+                .await
+                .unwrap();
+        }
+        let root = api.root().await.unwrap();
+
+        let tasks: Vec<_> = (0..16u64)
+            .map(|i| {
+                let api = api.clone();
+                let hash = hashes[(i % 4) as usize].clone();
+                let root = root.clone();
+                tokio::spawn(async move {
+                    for _ in 0..10 {
+                        if i % 2 == 0 {
+                            assert_eq!(api.count().await.unwrap(), 4);
+                        } else {
+                            let proof = api.proof(i % 4).await.unwrap();
+                            assert!(proof.verify_with_root(&hash, &root));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_blocking_api() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_dl_path = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let (_, server) = start_server_task(config.clone()).await;
+
+        let api = mrklar_api::blocking::MrklarApi::new(client_net(&config, &server)).unwrap();
+        assert!(matches!(api.count(), Err(ApiError::InsideAsyncRuntime)));
+
+        // the blocking api is used from a thread outside of the test runtime
+        let test_files_dir = get_test_files_dir().unwrap();
+        let thread = std::thread::spawn(move || {
+            let outcome = api.upload(&test_files_dir.join("0")).unwrap();
+            assert_eq!(outcome.index, 0);
+            assert_eq!(api.count().unwrap(), 1);
+            assert_eq!(api.root().unwrap(), outcome.root);
+            let proof = api.proof(0).unwrap();
+            assert_eq!(proof.root(), &outcome.root);
+            let outcome = api
+                .download(0, Some(tmp_dl_path), None, false, None)
+                .unwrap();
+            assert!(outcome.verified);
+        });
+        tokio::task::spawn_blocking(move || thread.join())
+            .await
+            .unwrap()
+            .unwrap();
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Download of a corrupted stored file, with and without strict verification
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_strict_verification() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let dl_dir = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let stored_path = StorageLayout::Sharded.file_path_at(0, &[], &config.files_db_dir());
+
+        let api = start_server(config).await;
+
+        let outcome = api
+            .upload_bytes("file.bin", b"original content".to_vec().into())
+            .await
+            .unwrap();
+        std::fs::write(&stored_path, b"forged content").unwrap();
+
+        let outcome_dl = api
+            .download(0, Some(dl_dir.clone()), None, false, None)
+            .await
+            .unwrap();
+        assert!(!outcome_dl.verified);
+        assert!(outcome_dl.path.is_file());
+        std::fs::remove_file(&outcome_dl.path).unwrap();
+
+        let strict_api = api.clone().with_strict_verification(true);
+        let res = strict_api
+            .download(0, Some(dl_dir.clone()), None, false, None)
+            .await;
+        assert!(matches!(
+            res,
+            Err(ApiError::VerificationFailed { index: 0, expected_root }) if expected_root == outcome.root
+        ));
+        let res = strict_api
+            .download_resumable(0, Some(dl_dir.clone()), None, false, None)
+            .await;
+        assert!(matches!(res, Err(ApiError::VerificationFailed { .. })));
+        // nothing left in the download directory, temporary files included
+        assert_eq!(std::fs::read_dir(&dl_dir).unwrap().count(), 0);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Server side verification of the stored files on download
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_on_download() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let dl_dir = tmp_dl_dir.path().to_path_buf();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_chunk_size(1024)
+            .with_verify_on_download(true);
+        let stored_path = StorageLayout::Sharded.file_path_at(0, &[], &config.files_db_dir());
+
+        let api = start_server(config).await;
+
+        let content: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        api.upload_bytes("file.bin", content.clone().into())
+            .await
+            .unwrap();
+
+        let outcome_dl = api
+            .download(0, Some(dl_dir.clone()), None, false, None)
+            .await
+            .unwrap();
+        assert!(outcome_dl.verified);
+        std::fs::remove_file(&outcome_dl.path).unwrap();
+
+        // same size, one flipped byte in the last chunk
+        let mut forged = content;
+        forged[9_999] ^= 0xff;
+        std::fs::write(&stored_path, forged).unwrap();
+
+        let res = api
+            .download(0, Some(dl_dir.clone()), None, false, None)
+            .await;
+        assert!(matches!(
+            res,
+            Err(ApiError::Status(s)) if s.code() == tonic::Code::DataLoss
+        ));
+        assert_eq!(api.metadata(0).await.unwrap().download_count, 1);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_range() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        // the bytes around the range are hashed to check the stored file
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_verify_on_download(true);
+
+        let api = start_server(config).await;
+
+        const MIB: u64 = 1024 * 1024;
+        let content: Vec<u8> = (0..5 * MIB as u32).map(|i| (i % 251) as u8).collect();
+        api.upload_bytes("file.bin", content.clone().into())
+            .await
+            .unwrap();
+
+        // the middle 1 MiB
+        let mut range = vec![];
+        let (filename, proof, n) = api
+            .download_range(0, 2 * MIB, MIB, &mut range)
+            .await
+            .unwrap();
+        assert_eq!(filename, "file.bin");
+        assert_eq!(proof.root(), api.proof(0).await.unwrap().root());
+        assert_eq!(n, MIB);
+        assert_eq!(range, &content[2 * MIB as usize..3 * MIB as usize]);
+        assert_eq!(api.metadata(0).await.unwrap().download_count, 0);
+
+        // a length of 0 streams up to the end of the file
+        let mut tail = vec![];
+        let (_, _, n) = api.download_range(0, 4 * MIB, 0, &mut tail).await.unwrap();
+        assert_eq!(n, MIB);
+        assert_eq!(tail, &content[4 * MIB as usize..]);
+        assert_eq!(api.metadata(0).await.unwrap().download_count, 1);
+
+        for (offset, length) in [(4 * MIB, MIB + 1), (6 * MIB, 0), (1, u64::MAX)] {
+            let res = api.download_range(0, offset, length, &mut vec![]).await;
+            assert!(matches!(
+                res,
+                Err(ApiError::Status(s)) if s.code() == tonic::Code::OutOfRange
+            ));
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Uploads and downloads of full size chunks succeed whatever the server
+    /// and client gRPC compression
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_grpc_compression() {
+        let text: Vec<u8> = (0..250_000)
+            .flat_map(|i| format!("line {} of a compressible text file\n", i).into_bytes())
+            .collect();
+        assert!(text.len() > MAX_CHUNK_SIZE);
+        let all = [
+            GrpcCompression::None,
+            GrpcCompression::Gzip,
+            GrpcCompression::Zstd,
+        ];
+
+        for server_compression in all {
+            let tmp_db_dir = tempdir().unwrap();
+            let tmp_files_dir = tempdir().unwrap();
+
+            let config = ServerConfig::default()
+                .with_port(0)
+                .with_tracing(false)
+                .with_db_dir(tmp_db_dir.path().to_path_buf())
+                .with_files_dir(tmp_files_dir.path().to_path_buf())
+                .with_chunk_size(MAX_CHUNK_SIZE)
+                .with_grpc_compression(server_compression);
+            let (api, server) = start_server_task(config).await;
+
+            for (i, client_compression) in all.into_iter().enumerate() {
+                // records the encoding of every outgoing request
+                let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+                let record = {
+                    let seen = seen.clone();
+                    tower::util::MapRequestLayer::new(
+                        move |request: tonic::codegen::http::Request<tonic::body::BoxBody>| {
+                            seen.lock()
+                                .unwrap()
+                                .push(request.headers().get("grpc-encoding").cloned());
+                            request
+                        },
+                    )
+                };
+                let api = api
+                    .clone()
+                    .with_grpc_compression(client_compression)
+                    .with_chunk_size(MAX_CHUNK_SIZE)
+                    .unwrap()
+                    .with_layer(record);
+
+                let outcome = api
+                    .upload_bytes("text.txt", text.clone().into())
+                    .await
+                    .unwrap();
+                assert_eq!(outcome.index, i as u64);
+                assert_eq!(outcome.sha256, Sha256::digest(&text).to_vec());
+
+                let mut downloaded = vec![];
+                let (_, _, len, verified) = api
+                    .download_to_writer(i as u64, &mut downloaded)
+                    .await
+                    .unwrap();
+                assert!(verified);
+                assert_eq!(len, text.len() as u64);
+                assert_eq!(downloaded, text);
+
+                let expected = client_compression.to_string();
+                for encoding in seen.lock().unwrap().iter() {
+                    match client_compression {
+                        GrpcCompression::None => assert!(encoding.is_none()),
+                        _ => assert_eq!(encoding.as_ref().unwrap(), expected.as_str()),
+                    }
+                }
+            }
+
+            server.shutdown().await.unwrap();
+            tmp_db_dir.close().unwrap();
+            tmp_files_dir.close().unwrap();
+        }
+    }
+
+    /// Compressed and uncompressed stored files coexist, the downloads and
+    /// proofs are the ones of the uploaded content
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_compression() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_chunk_size(64 * 1024)
+            .with_verify_on_download(true);
+        let stored_path =
+            |index| StorageLayout::Sharded.file_path_at(index, &[], &config.files_db_dir());
+
+        let (api, server) = start_server_task(config.clone().with_compression_level(Some(3))).await;
+
+        let text: Vec<u8> = (0..20_000)
+            .flat_map(|i| format!("line {} of a compressible text file\n", i).into_bytes())
+            .collect();
+        let random: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| Sha256::digest(i.to_le_bytes()))
+            .collect();
+
+        let outcome = api
+            .upload_bytes("text.txt", text.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.sha256, Sha256::digest(&text).to_vec());
+        api.upload_bytes("random.bin", random.clone().into())
+            .await
+            .unwrap();
+
+        assert!(std::fs::metadata(stored_path(0)).unwrap().len() < text.len() as u64 / 5);
+        // the incompressible file is stored as is
+        assert_eq!(std::fs::read(stored_path(1)).unwrap(), random);
+        assert_eq!(api.metadata(0).await.unwrap().size, text.len() as u64);
+
+        // new uploads are no longer compressed, the compressed files are still served
+        server.shutdown().await.unwrap();
+        let api = start_server(config.clone()).await;
+        api.upload_bytes("text2.txt", text.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(stored_path(2)).unwrap(), text);
+
+        for (index, content) in [(0, &text), (1, &random), (2, &text)] {
+            let mut downloaded = vec![];
+            let (_, proof, n, verified) = api
+                .download_to_writer(index, &mut downloaded)
+                .await
+                .unwrap();
+            assert!(verified);
+            assert!(proof.verify(&Sha256::digest(content).to_vec()));
+            assert_eq!(n, content.len() as u64);
+            assert_eq!(&downloaded, content);
+        }
+
+        let mut range = vec![];
+        api.download_range(0, 100_000, 200_000, &mut range)
+            .await
+            .unwrap();
+        assert_eq!(range, &text[100_000..300_000]);
+
+        assert_eq!(api.count().await.unwrap(), 3);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The merkle roots returned by a server with a signing key are signed,
+    /// the api verifies them with the server public key
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_signed_tree_head() {
+        use rcgen::{KeyPair, PKCS_ED25519};
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_key_dir = tempdir().unwrap();
+        let key_path = tmp_key_dir.path().join("signing_key.pem");
+        let key_pair = KeyPair::generate_for(&PKCS_ED25519).unwrap();
+        std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        let public_key = key_pair.public_key_raw().to_vec();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let (api, server) =
+            start_server_task(config.clone().with_signing_key(Some(key_path.clone()))).await;
+        let api = api.with_tree_head_key(Some(public_key.clone()));
+
+        let outcome = api.upload_bytes("a", vec![1u8; 10].into()).await.unwrap();
+        let tree_head = outcome.tree_head.unwrap();
+        assert_eq!(tree_head.merkle_root, outcome.root);
+        assert_eq!(tree_head.tree_size, 1);
+        tree_head.verify(&public_key).unwrap();
+
+        api.upload_bytes("b", vec![2u8; 10].into()).await.unwrap();
+        let tree_head = api.tree_head().await.unwrap();
+        assert_eq!(tree_head.tree_size, 2);
+        assert_eq!(tree_head.merkle_root, api.root().await.unwrap());
+        tree_head.verify(&public_key).unwrap();
+
+        // a tampered root or tree size fails the verification
+        let mut tampered = tree_head.clone();
+        tampered.merkle_root[0] ^= 1;
+        assert!(tampered.verify(&public_key).is_err());
+        let mut tampered = tree_head.clone();
+        tampered.tree_size = 1;
+        assert!(tampered.verify(&public_key).is_err());
+
+        // signed by another key
+        let other_key = KeyPair::generate_for(&PKCS_ED25519).unwrap();
+        let other_api = api
+            .clone()
+            .with_tree_head_key(Some(other_key.public_key_raw().to_vec()));
+        assert!(matches!(
+            other_api.root().await,
+            Err(ApiError::Common(
+                mrklar_common::error::Error::InvalidTreeHeadSignature(_)
+            ))
+        ));
+        server.shutdown().await.unwrap();
+
+        // the roots of a server without signing key are rejected
+        let (unsigned_api, server) = start_server_task(config.clone()).await;
+        assert!(unsigned_api.tree_head().await.is_err());
+        let unsigned_api = unsigned_api.with_tree_head_key(Some(public_key));
+        assert!(matches!(
+            unsigned_api.status().await,
+            Err(ApiError::MissingTreeHead)
+        ));
+        assert!(matches!(
+            unsigned_api.upload_bytes("c", vec![3u8; 10].into()).await,
+            Err(ApiError::MissingTreeHead)
+        ));
+        server.shutdown().await.unwrap();
+
+        // an invalid key fails the startup
+        std::fs::write(&key_path, "not a key").unwrap();
+        let e = mrklar::start(config.clone().with_signing_key(Some(key_path.clone())))
+            .await
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(e.contains("Invalid signing key"), "{e}");
+
+        // entries lost on a crash could be covered by a signed root
+        let e = mrklar::start(
+            config
+                .with_signing_key(Some(key_path))
+                .with_persistence(PersistencePolicy::OnShutdown),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(
+            matches!(
+                e.downcast_ref::<ServerError>(),
+                Some(ServerError::SigningKeyPersistence(
+                    PersistencePolicy::OnShutdown
+                ))
+            ),
+            "{e}"
+        );
+
+        tmp_key_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The archive after more uploads extends the archive before them
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_consistency_proof() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let api = start_server(config).await;
+
+        let mut statuses = vec![];
+        for i in 0..30u8 {
+            api.upload_bytes(&format!("f{i}"), vec![i; 10].into())
+                .await
+                .unwrap();
+            statuses.push(api.status().await.unwrap());
+        }
+
+        for (old_size, new_size) in [(1, 1), (1, 2), (3, 4), (10, 30), (16, 17), (30, 30)] {
+            let old_root = &statuses[old_size - 1].root;
+            let new_root = &statuses[new_size - 1].root;
+            let proof = api
+                .consistency_proof(old_size as u64, new_size as u64)
+                .await
+                .unwrap();
+            assert_eq!(proof.old_root(), old_root);
+            assert_eq!(proof.new_root(), new_root);
+            assert!(proof.verify_with_roots(old_root, new_root));
+            // not the root of another size
+            let other_root = &statuses[old_size % 30].root;
+            assert!(!proof.verify_with_roots(other_root, new_root));
+        }
+
+        for (old_size, new_size) in [(0, 5), (11, 10), (10, 31)] {
+            match api.consistency_proof(old_size, new_size).await {
+                Err(ApiError::Status(s)) => assert_eq!(s.code(), tonic::Code::InvalidArgument),
+                r => panic!("unexpected result {r:?}"),
+            }
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Audit a mix of present and absent hashes in a single call
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_audit() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let api = start_server(config).await;
+
+        let report = api.audit(&[vec![1u8; 32]]).await.unwrap();
+        assert!(report.entries[0].found.is_none());
+        assert_eq!(report.status.count, 0);
+
+        let mut sha256s = vec![];
+        for i in 0..5u8 {
+            let outcome = api
+                .upload_bytes(&format!("f{i}"), vec![i; 10].into())
+                .await
+                .unwrap();
+            sha256s.push(outcome.sha256);
+        }
+        // the first entry of a duplicated content
+        api.upload_bytes("f1-copy", vec![1u8; 10].into())
+            .await
+            .unwrap();
+
+        let absent = vec![0xffu8; 32];
+        let audited = vec![
+            sha256s[3].clone(),
+            absent.clone(),
+            sha256s[1].clone(),
+            sha256s[0].clone(),
+            absent.clone(),
+        ];
+        let report = api.audit(&audited).await.unwrap();
+        assert_eq!(report.status, api.status().await.unwrap());
+        assert_eq!(report.status.count, 6);
+        assert_eq!(report.entries.len(), audited.len());
+        for (entry, (sha256, expected_index)) in
+            report
+                .entries
+                .iter()
+                .zip(audited.iter().zip([Some(3), None, Some(1), Some(0), None]))
+        {
+            assert_eq!(&entry.sha256, sha256);
+            match (&entry.found, expected_index) {
+                (Some((index, proof)), Some(expected_index)) => {
+                    assert_eq!(*index, expected_index);
+                    assert!(proof.verify_with_root(sha256, &report.status.root));
+                }
+                (None, None) => {}
+                (found, _) => panic!("unexpected result {found:?} for index {expected_index:?}"),
+            }
+        }
+
+        let too_many = vec![absent.clone(); MAX_AUDIT_HASHES + 1];
+        match api.audit(&too_many).await {
+            Err(ApiError::Status(s)) => assert_eq!(s.code(), tonic::Code::InvalidArgument),
+            r => panic!("unexpected result {r:?}"),
+        }
+        assert!(api.audit(&vec![absent; MAX_AUDIT_HASHES]).await.is_ok());
+        match api.audit(&[sha256s[0].clone(), vec![1u8; 31]]).await {
+            Err(ApiError::Status(s)) => assert_eq!(s.code(), tonic::Code::InvalidArgument),
+            r => panic!("unexpected result {r:?}"),
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Upload and download over a unix domain socket
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_uds() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+        let tmp_sock_dir = tempdir().unwrap();
+        let uds_path = tmp_sock_dir.path().join("mrklar.sock");
+
+        let config = ServerConfig::default()
+            .with_uds_path(Some(uds_path.clone()))
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+        assert!(uds_path.exists());
+
+        let data = b"over a unix domain socket".to_vec();
+        let outcome = api
+            .upload_bytes("uds.txt", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+
+        let outcome = api
+            .download(0, Some(tmp_dl_dir.path().to_path_buf()), None, false, None)
+            .await
+            .unwrap();
+        assert!(outcome.verified);
+        assert_eq!(std::fs::read(&outcome.path).unwrap(), data);
+
+        tmp_sock_dir.close().unwrap();
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A server listening on both a unix domain socket, with restricted
+    /// permissions, and tcp. The stale socket file is replaced at startup and
+    /// the socket file is removed on shutdown.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_uds_keep_tcp() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_sock_dir = tempdir().unwrap();
+        let uds_path = tmp_sock_dir.path().join("mrklar.sock");
+        // left by a server that did not shut down cleanly
+        drop(std::os::unix::net::UnixListener::bind(&uds_path).unwrap());
+        assert!(uds_path.exists());
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_uds_path(Some(uds_path.clone()))
+            .with_uds_mode(Some(FileMode(0o600)))
+            .with_uds_keep_tcp(true)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let server = mrklar::start(config.clone()).await.unwrap();
+        let mode = std::fs::metadata(&uds_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
+
+        let uds_api = MrklarApi::new(config.net.clone()).unwrap();
+        let tcp_api = MrklarApi::new(client_net(&config, &server).with_uds_path(None)).unwrap();
+        uds_api
+            .upload_bytes("uds", vec![1u8; 10].into())
+            .await
+            .unwrap();
+        tcp_api
+            .upload_bytes("tcp", vec![2u8; 10].into())
+            .await
+            .unwrap();
+        assert_eq!(uds_api.count().await.unwrap(), 2);
+        assert_eq!(uds_api.root().await.unwrap(), tcp_api.root().await.unwrap());
+
+        server.shutdown().await.unwrap();
+        assert!(!uds_path.exists());
+
+        tmp_sock_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Client and server connected through in-memory duplex streams,
+    /// without binding any port
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_with_channel_duplex() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let net_config = config.net.clone();
+
+        let (conn_tx, conn_rx) = tokio::sync::mpsc::channel(4);
+        mrklar::start_with_incoming(config, ReceiverStream::new(conn_rx))
+            .await
+            .unwrap();
+
+        let connector = tower::service_fn(move |_: tonic::transport::Uri| {
+            let conn_tx = conn_tx.clone();
+            async move {
+                let (client, server) = tokio::io::duplex(64 * 1024);
+                conn_tx
+                    .send(Ok::<_, std::io::Error>(server))
+                    .await
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+                Ok::<_, std::io::Error>(hyper_util::rt::TokioIo::new(client))
+            }
+        });
+        let channel = tonic::transport::Endpoint::from_static("http://in-memory")
+            .connect_with_connector(connector)
+            .await
+            .unwrap();
+        let api = MrklarApi::with_channel(channel, net_config);
+
+        let data = b"in-memory transport".to_vec();
+        let outcome = api
+            .upload_bytes("duplex.txt", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        let mut content = vec![];
+        let (filename, proof, _, verified) = api.download_to_writer(0, &mut content).await.unwrap();
+        assert_eq!(filename, "duplex.txt");
+        assert_eq!(content, data);
+        assert!(verified);
+        assert_eq!(proof.root(), &outcome.root);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Calls keep working after the connection to the server breaks
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_reconnect() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let (_, server) = start_server_task(config.clone()).await;
+        let server_addr = server.local_addr().unwrap();
+        let (proxy_addr, proxy) = start_tcp_proxy(0, server_addr).await;
+        let proxy_net = config.net.clone().with_port(proxy_addr.port());
+
+        let api = MrklarApi::new(proxy_net.clone()).unwrap();
+        api.upload_bytes("a.txt", b"a".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        // break the established connection
+        proxy.abort();
+        let _ = proxy.await;
+        start_tcp_proxy(proxy_net.port, server_addr).await;
+
+        // no retry policy, the single reconnect attempt is enough
+        assert_eq!(api.count().await.unwrap(), 1);
+        let outcome = api
+            .upload_bytes("b.txt", b"b".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 1);
+        assert_eq!(api.count().await.unwrap(), 2);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// `status` never reports a root computed from another number of entries
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_status_consistency() {
+        const N_FILES: usize = 30;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+        // the root of an empty archive is undefined
+        let first = api
+            .upload_bytes("file0", vec![0u8; 10].into())
+            .await
+            .unwrap();
+
+        let uploads = {
+            let api = api.clone();
+            tokio::spawn(async move {
+                let mut roots = vec![first.root];
+                for i in 1..N_FILES {
+                    let outcome = api
+                        .upload_bytes(&format!("file{}", i), vec![i as u8; 10].into())
+                        .await
+                        .unwrap();
+                    roots.push(outcome.root);
+                }
+                roots
+            })
+        };
+
+        let mut statuses = vec![];
+        while !uploads.is_finished() {
+            statuses.push(api.status().await.unwrap());
+        }
+        let roots = uploads.await.unwrap();
+        statuses.push(api.status().await.unwrap());
+
+        for status in &statuses {
+            assert_eq!(status.root, roots[status.count as usize - 1]);
+        }
+        assert_eq!(statuses.last().unwrap().count, N_FILES as u64);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Custom interceptors and layers wrap every call
+    #[tokio::test(flavor = "multi_thread")]
+    // tonic interceptors return a `Status` error
+    #[allow(clippy::result_large_err)]
+    async fn test_client_layers() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        // records the metadata of every outgoing request
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let record = {
+            let seen = seen.clone();
+            tower::util::MapRequestLayer::new(
+                move |request: tonic::codegen::http::Request<tonic::body::BoxBody>| {
+                    let headers = request.headers();
+                    seen.lock().unwrap().push((
+                        headers.get("authorization").cloned(),
+                        headers.get("x-trace-id").cloned(),
+                    ));
+                    request
+                },
+            )
+        };
+        let traced = api
+            .clone()
+            .with_auth_token("abc".to_string())
+            .unwrap()
+            .with_interceptor(|mut request: Request<()>| {
+                request
+                    .metadata_mut()
+                    .insert("x-trace-id", "42".parse().unwrap());
+                Ok(request)
+            })
+            .with_layer(record);
+
+        traced
+            .upload_bytes("a", vec![1u8; 10].into())
+            .await
+            .unwrap();
+        assert_eq!(traced.count().await.unwrap(), 1);
+        {
+            let seen = seen.lock().unwrap();
+            // the first upload also gets the server limits
+            assert_eq!(seen.len(), 3);
+            for (auth, trace) in seen.iter() {
+                assert_eq!(auth.as_ref().unwrap(), "Bearer abc");
+                assert_eq!(trace.as_ref().unwrap(), "42");
+            }
+        }
+
+        // layers are not shared with the api they have been added to
+        assert_eq!(api.count().await.unwrap(), 1);
+        assert_eq!(seen.lock().unwrap().len(), 3);
+
+        // a rejecting interceptor fails the call with its status
+        let rejected = api
+            .clone()
+            .with_interceptor(|_| Err(Status::permission_denied("no tracing context")));
+        match rejected.count().await {
+            Err(ApiError::Status(s)) => {
+                assert_eq!(s.code(), tonic::Code::PermissionDenied);
+                assert_eq!(s.message(), "no tracing context");
+            }
+            r => panic!("unexpected result {r:?}"),
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// `entry` only transfers the file metadata
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_entry() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        // a full download would take about 40s
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_max_download_bytes_per_sec_per_stream(Some(100_000))
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let data: Vec<u8> = (0..4_000_000u32).map(|i| (i % 251) as u8).collect();
+        let sha256 = Sha256::digest(&data).to_vec();
+        api.upload_bytes("large.bin", data.into()).await.unwrap();
+
+        let start = std::time::Instant::now();
+        let (filename, proof) = api.entry(0).await.unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+        assert_eq!(filename, "large.bin");
+        assert!(proof.verify(&sha256));
+        assert_eq!(proof.root(), &api.root().await.unwrap());
+
+        // the download has not been completed
+        assert_eq!(api.metadata(0).await.unwrap().download_count, 0);
+        assert!(matches!(
+            api.entry(1).await,
+            Err(ApiError::IndexNotFound(1))
+        ));
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Lookup of the entry index by content sha256
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_find() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+        let mut hashes = vec![];
+        for content in ["a", "b", "a"] {
+            let outcome = api
+                .upload_bytes("file.txt", content.as_bytes().to_vec().into())
+                .await
+                .unwrap();
+            hashes.push(outcome.sha256);
+        }
+
+        assert_eq!(api.find(&hashes[0]).await.unwrap(), Some(0));
+        assert_eq!(api.find(&hashes[1]).await.unwrap(), Some(1));
+        assert_eq!(api.find(&[0u8; 32]).await.unwrap(), None);
+        assert!(matches!(
+            api.find(&[0u8; 31]).await,
+            Err(ApiError::Status(s)) if s.code() == tonic::Code::InvalidArgument
+        ));
+        server.shutdown().await.unwrap();
+
+        // the hashes are indexed again on load
+        let api = start_server(config).await;
+        assert_eq!(api.find(&hashes[1]).await.unwrap(), Some(1));
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// `upload_dedup` does not transfer the content already in the archive
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_dedup() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+        let files = gen_files(tmp_src_dir.path(), 2);
+
+        let first = api.upload(&files[0]).await.unwrap();
+        assert!(!first.deduplicated);
+
+        let again = api.upload_dedup(&files[0]).await.unwrap();
+        assert!(again.deduplicated);
+        assert_eq!(again.index, first.index);
+        assert_eq!(again.root, first.root);
+        assert_eq!(again.stats.bytes, 0);
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        let other = api.upload_dedup(&files[1]).await.unwrap();
+        assert!(!other.deduplicated);
+        assert_eq!(other.index, 1);
+        assert!(other.stats.bytes > 0);
+        assert_eq!(api.count().await.unwrap(), 2);
+
+        assert!(matches!(
+            api.upload_dedup(&tmp_src_dir.path().join("missing")).await,
+            Err(ApiError::UploadFileNotFound(_))
+        ));
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// With the interval persistence policy, the uploads are written in
+    /// batches and the pending ones are written on shutdown
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_persistence_interval() {
+        const N_FILES: usize = 4;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let journal_len = || {
+            std::fs::metadata(config.db_journal_file())
+                .map(|m| m.len())
+                .unwrap_or(0)
+        };
+
+        // 1- written in the background
+        let short = config.clone().with_persistence(PersistencePolicy::Interval(
+            std::time::Duration::from_millis(50),
+        ));
+        let (api, server) = start_server_task(short).await;
+        api.upload_bytes("0.txt", b"content 0".to_vec().into())
+            .await
+            .unwrap();
+        let start = std::time::Instant::now();
+        while journal_len() == 0 {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        server.shutdown().await.unwrap();
+
+        // 2- not written before the shutdown
+        let long = config.clone().with_persistence(PersistencePolicy::Interval(
+            std::time::Duration::from_secs(3600),
+        ));
+        let (api, server) = start_server_task(long).await;
+        let written = journal_len();
+        for i in 1..N_FILES {
+            api.upload_bytes(
+                &format!("{i}.txt"),
+                format!("content {i}").into_bytes().into(),
+            )
+            .await
+            .unwrap();
+        }
+        // the uploaded entries are in the tree
+        assert_eq!(api.count().await.unwrap(), N_FILES as u64);
+        let root = api.root().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(journal_len(), written);
+
+        server.shutdown().await.unwrap();
+        assert!(journal_len() > written);
+
+        // 3- nothing lost
+        let api = start_server(config.clone()).await;
+        assert_eq!(api.count().await.unwrap(), N_FILES as u64);
+        assert_eq!(api.root().await.unwrap(), root);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Identical content uploaded under different filenames with each
+    /// server duplicate policy
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_duplicate_policy() {
+        for policy in [
+            DuplicatePolicy::Allow,
+            DuplicatePolicy::Reject,
+            DuplicatePolicy::Dedup,
+        ] {
+            let tmp_db_dir = tempdir().unwrap();
+            let tmp_files_dir = tempdir().unwrap();
+
+            let config = ServerConfig::default()
+                .with_port(0)
+                .with_tracing(false)
+                .with_duplicate_policy(policy)
+                .with_db_dir(tmp_db_dir.path().to_path_buf())
+                .with_files_dir(tmp_files_dir.path().to_path_buf());
+            let tmp_dir = config.validate().unwrap().files_tmp_dir();
+
+            let api = start_server(config).await;
+            let first = api
+                .upload_bytes("a.txt", b"same".to_vec().into())
+                .await
+                .unwrap();
+            assert_eq!(first.index, 0);
+            api.upload_bytes("other.txt", b"other".to_vec().into())
+                .await
+                .unwrap();
+            let root = api.root().await.unwrap();
+
+            let again = api.upload_bytes("b.txt", b"same".to_vec().into()).await;
+            match policy {
+                DuplicatePolicy::Allow => {
+                    let again = again.unwrap();
+                    assert_eq!(again.index, 2);
+                    assert_ne!(again.root, root);
+                    assert_eq!(api.count().await.unwrap(), 3);
+                    assert_eq!(api.metadata(2).await.unwrap().filename, "b.txt");
+                }
+                DuplicatePolicy::Reject => {
+                    assert!(
+                        matches!(again, Err(ApiError::AlreadyExists(0))),
+                        "{again:?}"
+                    );
+                    assert_eq!(api.count().await.unwrap(), 2);
+                    assert_eq!(api.root().await.unwrap(), root);
+                }
+                DuplicatePolicy::Dedup => {
+                    let again = again.unwrap();
+                    assert_eq!(again.index, 0);
+                    assert_eq!(again.root, root);
+                    assert_eq!(api.count().await.unwrap(), 2);
+                    assert_eq!(api.metadata(0).await.unwrap().filename, "a.txt");
+                }
+            }
+            assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+            tmp_db_dir.close().unwrap();
+            tmp_files_dir.close().unwrap();
+        }
+    }
+
+    /// Download into a caller provided pipeline, chunk by chunk
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_download_stream() {
+        use tokio_stream::StreamExt;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_chunk_size(1000)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let data: Vec<u8> = (0..3500u32).map(|i| (i % 253) as u8).collect();
+        let outcome = api
+            .upload_bytes("stream.bin", data.clone().into())
+            .await
+            .unwrap();
+
+        let mut stream = api.download_stream(0).await.unwrap();
+        assert_eq!(stream.filename(), "stream.bin");
+        assert_eq!(stream.size(), data.len() as u64);
+        assert_eq!(stream.proof().root(), &outcome.root);
+
+        let mut content = vec![];
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() <= 1000);
+            content.extend_from_slice(&chunk);
+        }
+        assert_eq!(content, data);
+
+        let summary = stream.finish().await.unwrap();
+        assert!(summary.verified);
+        assert_eq!(summary.sha256, Sha256::digest(&data).to_vec());
+        assert_eq!(summary.stats.bytes, data.len() as u64);
+        assert_eq!(summary.stats.chunks, 4);
+
+        // `finish` receives the chunks not consumed by the caller
+        let mut stream = api.download_stream(0).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().len(), 1000);
+        let summary = stream.finish().await.unwrap();
+        assert!(summary.verified);
+        assert_eq!(summary.stats.bytes, data.len() as u64);
+
+        assert!(matches!(
+            api.download_stream(1).await,
+            Err(ApiError::IndexNotFound(1))
+        ));
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Audit of local copies listed in a caller manifest
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_archive() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        let files = gen_files(tmp_src_dir.path(), 20);
+        let mut entries = vec![];
+        for path in &files {
+            let outcome = api.upload(path).await.unwrap();
+            entries.push((outcome.index, path.clone()));
+        }
+
+        std::fs::write(&files[3], b"corrupted").unwrap();
+        std::fs::write(&files[17], b"").unwrap();
+
+        let report = api.verify_archive(&entries, 4).await.unwrap();
+        assert_eq!(report.root, api.root().await.unwrap());
+        assert_eq!(report.results.len(), 20);
+        let failed: Vec<u64> = report
+            .results
+            .iter()
+            .filter(|r| r.status != VerifyStatus::Ok)
+            .map(|r| r.index)
+            .collect();
+        assert_eq!(failed, vec![3, 17]);
+        assert_eq!(report.count(VerifyStatus::Mismatch), 2);
+
+        // unknown indices and missing local copies do not stop the audit
+        let entries = vec![
+            (99, files[0].clone()),
+            (1, tmp_src_dir.path().join("missing")),
+            (2, files[2].clone()),
+        ];
+        let report = api.verify_archive(&entries, 4).await.unwrap();
+        let statuses: Vec<VerifyStatus> = report.results.iter().map(|r| r.status).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                VerifyStatus::IndexNotFound,
+                VerifyStatus::Missing,
+                VerifyStatus::Ok
+            ]
+        );
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Uploads in progress on shutdown either complete within the grace
+    /// period or are aborted without leaving anything behind
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_graceful_shutdown() {
+        use tokio::io::AsyncWriteExt;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        for (grace_period, completes) in [(5000, true), (200, false)] {
+            let config = ServerConfig::default()
+                .with_port(0)
+                .with_tracing(false)
+                .with_shutdown_grace_period(std::time::Duration::from_millis(grace_period))
+                .with_db_dir(tmp_db_dir.path().to_path_buf())
+                .with_files_dir(tmp_files_dir.path().to_path_buf());
+            let tmp_dir = config.validate().unwrap().files_tmp_dir();
+
+            let (api, server) = start_server_task(config.clone()).await;
+            let count = api.count().await.unwrap();
+
+            let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+            let upload = tokio::spawn(async move { api.upload_reader("slow", reader, None).await });
+            writer.write_all(&[1u8; 100_000]).await.unwrap();
+
+            // wait for the upload to reach the server
+            while std::fs::read_dir(&tmp_dir).map_or(true, |mut d| d.next().is_none()) {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            let shutdown = tokio::spawn(server.shutdown());
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            if completes {
+                writer.write_all(&[2u8; 100_000]).await.unwrap();
+                drop(writer);
+                let outcome = upload.await.unwrap().unwrap();
+                assert_eq!(outcome.index, count);
+            } else {
+                // the upload call returns once the reader is exhausted
+                tokio::time::sleep(std::time::Duration::from_millis(grace_period)).await;
+                drop(writer);
+                let err = upload.await.unwrap().unwrap_err();
+                assert_eq!(err.status().unwrap().code(), tonic::Code::Unavailable);
+            }
+            shutdown.await.unwrap().unwrap();
+
+            // the uploaded file fully landed in the db, or nothing remains
+            let db = mrklar::mem_db::MemDb::try_load(&config.validate().unwrap()).unwrap();
+            let expected = if completes { count + 1 } else { count };
+            assert_eq!(db.num_entries() as u64, expected);
+            assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Standard grpc health checks
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_health() {
+        use tonic_health::pb::{health_client::HealthClient, HealthCheckRequest};
+        use tonic_health::ServingStatus;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (_, server) = start_server_task(config).await;
+        let channel = tonic::transport::Endpoint::from_shared(format!(
+            "http://{}",
+            server.local_addr().unwrap()
+        ))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+        let mut client = HealthClient::new(channel);
+
+        for service in ["", "mrklar.v1.FileApi"] {
+            let request = HealthCheckRequest {
+                service: service.to_string(),
+            };
+            let response = client.check(request).await.unwrap().into_inner();
+            assert_eq!(response.status(), ServingStatus::Serving.into());
+        }
+        let request = HealthCheckRequest {
+            service: "unknown".to_string(),
+        };
+        let status = client.check(request).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        // not serving as soon as the shutdown starts
+        let request = HealthCheckRequest {
+            service: String::new(),
+        };
+        let mut watch = client.watch(request).await.unwrap().into_inner();
+        let response = watch.message().await.unwrap().unwrap();
+        assert_eq!(response.status(), ServingStatus::Serving.into());
+
+        let shutdown = tokio::spawn(server.shutdown());
+        // the watch stream may repeat the current status first
+        let mut response = watch.message().await.unwrap().unwrap();
+        while response.status() == ServingStatus::Serving.into() {
+            response = watch.message().await.unwrap().unwrap();
+        }
+        assert_eq!(response.status(), ServingStatus::NotServing.into());
+        drop(watch);
+        shutdown.await.unwrap().unwrap();
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Uploads exceeding the concurrency limit are queued or rejected
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_concurrent_uploads() {
+        use tokio::io::AsyncWriteExt;
+
+        const N_UPLOADS: usize = 100;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_max_concurrent_uploads(Some(8))
+            .with_queue_uploads(true)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        // 1- queued
+        let (api, server) = start_server_task(config.clone()).await;
+        let mut uploads = tokio::task::JoinSet::new();
+        for i in 0..N_UPLOADS {
+            let api = api.clone();
+            uploads.spawn(async move {
+                let name = format!("{i}.txt");
+                let outcome = api.upload_bytes(&name, name.clone().into()).await.unwrap();
+                (name, outcome.index)
+            });
+        }
+        let mut indices = vec![];
+        while let Some(res) = uploads.join_next().await {
+            let (name, index) = res.unwrap();
+            assert_eq!(api.metadata(index).await.unwrap().filename, name);
+            indices.push(index);
+        }
+        indices.sort();
+        assert_eq!(indices, (0..N_UPLOADS as u64).collect::<Vec<_>>());
+
+        // 2- rejected
+        server.shutdown().await.unwrap();
+        let config = config
+            .with_max_concurrent_uploads(Some(1))
+            .with_queue_uploads(false);
+        let tmp_dir = config.validate().unwrap().files_tmp_dir();
+        let api = start_server(config).await;
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let stalled = {
+            let api = api.clone();
+            tokio::spawn(async move { api.upload_reader("stalled", reader, None).await })
+        };
+        writer.write_all(b"stalled").await.unwrap();
+        // wait for the stalled upload to reach the server
+        while std::fs::read_dir(&tmp_dir).map_or(true, |mut d| d.next().is_none()) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let err = api
+            .upload_bytes("excess.txt", b"excess".to_vec().into())
+            .await
+            .unwrap_err();
+        assert_eq!(err.status().unwrap().code(), tonic::Code::ResourceExhausted);
+
+        // the slot is released once the upload completes
+        drop(writer);
+        stalled.await.unwrap().unwrap();
+        api.upload_bytes("excess.txt", b"excess".to_vec().into())
+            .await
+            .unwrap();
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Silent upload streams are aborted after the idle timeout
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_stream_idle_timeout() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_stream_idle_timeout(std::time::Duration::from_millis(200))
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let tmp_dir = config.validate().unwrap().files_tmp_dir();
+
+        let (api, server) = start_server_task(config).await;
+        let mut client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+
+        // nothing sent
+        let (_tx, rx) = tokio::sync::mpsc::channel::<UploadRequest>(4);
+        let status = client.upload(ReceiverStream::new(rx)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+
+        // stalled after the first chunk
+        let (tx, rx) = tokio::sync::mpsc::channel::<UploadRequest>(4);
+        tx.send(UploadRequest::new_metadata("stalled"))
+            .await
+            .unwrap();
+        tx.send(UploadRequest::new_chunk(vec![1u8; 1000]))
+            .await
+            .unwrap();
+        let status = client.upload(ReceiverStream::new(rx)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+        drop(tx);
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+
+        // active streams are not affected
+        let outcome = api
+            .upload_bytes("ok.txt", b"ok".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// An upload aborted by the client mid-stream leaves no tmp file behind.
+    /// The server sees the end of the stream, the received content does not
+    /// match the announced sha256.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_aborted_upload() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let tmp_dir = config.validate().unwrap().files_tmp_dir();
+
+        let (api, server) = start_server_task(config).await;
+        let mut client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+
+        let sha256 = Sha256::digest([1u8; 2000]).to_vec();
+        let (tx, rx) = tokio::sync::mpsc::channel::<UploadRequest>(4);
+        tx.send(UploadRequest::new_metadata("aborted"))
+            .await
+            .unwrap();
+        tx.send(UploadRequest::new_sha256(sha256)).await.unwrap();
+        tx.send(UploadRequest::new_chunk(vec![1u8; 1000]))
+            .await
+            .unwrap();
+        let upload = tokio::spawn(async move { client.upload(ReceiverStream::new(rx)).await });
+
+        // wait for the upload to reach the server, then drop the request
+        while std::fs::read_dir(&tmp_dir).map_or(true, |mut d| d.next().is_none()) {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        upload.abort();
+        assert!(upload.await.unwrap_err().is_cancelled());
+
+        let removed = async {
+            while std::fs::read_dir(&tmp_dir).unwrap().count() > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), removed)
+            .await
+            .unwrap();
+        assert_eq!(api.count().await.unwrap(), 0);
+        drop(tx);
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A server listening on several addresses serves the same archive on
+    /// each of them, on the same port
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_multiple_hosts() {
+        use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let v4 = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_hosts(vec![v4, v6]);
+        let server = mrklar::start(config.clone()).await.unwrap();
+        let local_addrs = server.local_addrs().to_vec();
+        assert_eq!(local_addrs.len(), 2);
+        assert_eq!(local_addrs[0].ip(), v4);
+        assert_eq!(local_addrs[1].ip(), v6);
+        assert_eq!(local_addrs[0].port(), local_addrs[1].port());
+        assert_eq!(server.local_addr(), Some(local_addrs[0]));
+
+        let port = local_addrs[0].port();
+        let api_v4 = MrklarApi::new(config.net.clone().with_host(v4).with_port(port)).unwrap();
+        let api_v6 = MrklarApi::new(config.net.clone().with_host(v6).with_port(port)).unwrap();
+        api_v4
+            .upload_bytes("v4", vec![4u8; 10].into())
+            .await
+            .unwrap();
+        assert_eq!(api_v6.count().await.unwrap(), 1);
+        api_v6
+            .upload_bytes("v6", vec![6u8; 10].into())
+            .await
+            .unwrap();
+        assert_eq!(api_v4.root().await.unwrap(), api_v6.root().await.unwrap());
+        let mut downloaded = vec![];
+        let (filename, _, _, verified) =
+            api_v6.download_to_writer(0, &mut downloaded).await.unwrap();
+        assert_eq!(filename, "v4");
+        assert!(verified);
+        server.shutdown().await.unwrap();
+
+        // the startup fails if any address cannot be bound
+        let taken = std::net::TcpListener::bind((v6, 0)).unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let res = mrklar::start(config.with_port(taken_port)).await;
+        let e = res.err().unwrap().to_string();
+        assert!(e.contains("unable to bind [::1]"), "{e}");
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The missing db and files directories are only created on demand
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_create_dirs() {
+        let tmp_dir = tempdir().unwrap();
+        let db_dir = tmp_dir.path().join("state").join("db");
+        let files_dir = tmp_dir.path().join("state").join("files").join("store");
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(db_dir.clone())
+            .with_files_dir(files_dir.clone());
+        assert!(matches!(
+            config.validate(),
+            Err(ServerError::DbDirDoesNotExist(_))
+        ));
+        assert!(!db_dir.exists());
+
+        let config = config.with_create_missing_dirs(true);
+        let (api, server) = start_server_task(config.clone()).await;
+        assert!(db_dir.is_dir());
+        assert!(files_dir.is_dir());
+        api.upload_bytes("a", vec![1u8; 10].into()).await.unwrap();
+        server.shutdown().await.unwrap();
+
+        // existing directories are reused
+        let (api, server) = start_server_task(config).await;
+        assert_eq!(api.count().await.unwrap(), 1);
+        server.shutdown().await.unwrap();
+
+        // a parent is a regular file
+        let blocker = tmp_dir.path().join("blocker");
+        std::fs::write(&blocker, b"").unwrap();
+        let config = ServerConfig::default()
+            .with_db_dir(blocker.join("db"))
+            .with_files_dir(files_dir)
+            .with_create_missing_dirs(true);
+        match config.validate() {
+            Err(ServerError::CreateDir(path, _)) => {
+                assert_eq!(path, blocker.join("db").display().to_string())
+            }
+            r => panic!("unexpected result {r:?}"),
+        }
+
+        tmp_dir.close().unwrap();
+    }
+
+    /// 8 MiB chunks go through with the message size limits raised above
+    /// them, too small limits are rejected before anything is sent
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_message_size() {
+        const TONIC_DEFAULT: usize = 4 * 1024 * 1024;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_chunk_size(MAX_CHUNK_SIZE);
+        // the server fails to start if its max chunk does not fit
+        for max_message_size in [TONIC_DEFAULT, MAX_CHUNK_SIZE] {
+            let too_small = config.clone().with_max_message_size(max_message_size);
+            assert!(mrklar::start(too_small.clone()).await.is_err());
+            assert!(matches!(
+                too_small.validate(),
+                Err(ServerError::Common(
+                    mrklar_common::error::Error::MessageSizeTooSmall { chunk_size, .. }
+                )) if chunk_size == MAX_CHUNK_SIZE
+            ));
+        }
+        let config = config.with_max_message_size(MAX_CHUNK_SIZE + MESSAGE_OVERHEAD);
+        let (api, server) = start_server_task(config.clone()).await;
+
+        // incompressible content, 2 full chunks and a partial one
+        let data: Vec<u8> = (0..(2 * MAX_CHUNK_SIZE + 1000) as u32 / 32)
+            .flat_map(|i| Sha256::digest(i.to_le_bytes()))
+            .collect();
+        let outcome = api
+            .upload_bytes("data.bin", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.stats.chunks, 3);
+        let mut downloaded = vec![];
+        let (_, _, _, verified) = api.download_to_writer(0, &mut downloaded).await.unwrap();
+        assert!(verified);
+        assert_eq!(downloaded, data);
+
+        // a client with the tonic default limit cannot use 8 MiB chunks
+        let net = client_net(&config, &server).with_max_message_size(TONIC_DEFAULT);
+        assert!(matches!(
+            MrklarApi::new(net.clone()),
+            Err(ApiError::Common(
+                mrklar_common::error::Error::MessageSizeTooSmall { .. }
+            ))
+        ));
+        assert!(matches!(
+            api.clone().with_max_message_size(TONIC_DEFAULT),
+            Err(ApiError::Common(
+                mrklar_common::error::Error::MessageSizeTooSmall { .. }
+            ))
+        ));
+        let small = MrklarApi::new(NetConfig {
+            chunk_size: 1024 * 1024,
+            ..net
+        })
+        .unwrap();
+        assert!(small.with_chunk_size(MAX_CHUNK_SIZE).is_err());
+
+        // the largest chunks fitting in its limit
+        let outcome = small
+            .with_chunk_size(TONIC_DEFAULT - MESSAGE_OVERHEAD)
+            .unwrap()
+            .upload_bytes("data.bin", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.stats.chunks, 5);
+        let mut downloaded = vec![];
+        small.download_to_writer(1, &mut downloaded).await.unwrap();
+        assert_eq!(downloaded, data);
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A client configured with chunks larger than the server limit adapts
+    /// to the advertised limit, oversized chunks are rejected
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_max_chunk_size() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        const MAX_CHUNK: usize = 64 * 1024;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        // the default chunk size is above the limit
+        assert!(matches!(
+            config.clone().with_max_chunk_size(MAX_CHUNK).validate(),
+            Err(ServerError::ChunkTooLarge { .. })
+        ));
+        let config = config
+            .with_chunk_size(MAX_CHUNK)
+            .with_max_chunk_size(MAX_CHUNK);
+
+        let (api, server) = start_server_task(config.clone()).await;
+        assert_eq!(
+            api.server_info().await.unwrap().max_chunk_size,
+            MAX_CHUNK as u64
+        );
+
+        let data: Vec<u8> = (0..5 * MAX_CHUNK as u32 + 7)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let path = tmp_src_dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        // the client chunk size is lowered to the server limit
+        let big_chunks = api.with_chunk_size(1024 * 1024).unwrap();
+        let outcome = big_chunks.upload(&path).await.unwrap();
+        assert_eq!(outcome.stats.chunks, 6);
+        let outcome = big_chunks.upload_resumable(&path).await.unwrap();
+        assert_eq!(outcome.index, 1);
+
+        let mut downloaded = vec![];
+        big_chunks
+            .download_to_writer(0, &mut downloaded)
+            .await
+            .unwrap();
+        assert_eq!(downloaded, data);
+
+        // a raw client ignoring the limit
+        let mut client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let status = client
+            .upload(tokio_stream::iter([
+                UploadRequest::new_metadata("oversized"),
+                UploadRequest::new_chunk(vec![1u8; MAX_CHUNK + 1]),
+            ]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(status.message().contains(&MAX_CHUNK.to_string()));
+
+        // downloads are streamed in chunks of at most the limit
+        let mut stream = client
+            .download(DownloadRequest {
+                index: 0,
+                offset: 0,
+                chunk_size: 1024 * 1024,
+                length: 0,
+                metadata_only: false,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let mut chunks = 0;
+        while let Some(response) = stream.message().await.unwrap() {
+            if let Some(download_response::Type::Chunk(chunk)) = response.r#type {
+                assert!(chunk.len() <= MAX_CHUNK);
+                chunks += 1;
+            }
+        }
+        assert_eq!(chunks, 6);
+        assert_eq!(api.count().await.unwrap(), 2);
+
+        server.shutdown().await.unwrap();
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// An upload session interrupted by a disconnection is resumed from the
+    /// acknowledged offset by another client
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_session() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_dl_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config.clone()).await;
+
+        const CHUNK: usize = 64 * 1024;
+        let data: Vec<u8> = (0..20 * CHUNK as u32).map(|i| (i % 251) as u8).collect();
+        let data_sha256 = Sha256::digest(&data).to_vec();
+        let path = tmp_dl_dir.path().join("big.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let session_id = api.start_upload_session("big.bin").await.unwrap();
+        assert_eq!(api.upload_session_offset(&session_id).await.unwrap(), 0);
+
+        // the connection breaks after 3 chunks
+        let client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for (i, chunk) in data.chunks(CHUNK).take(3).enumerate() {
+            tx.send(UploadChunk {
+                session_id: session_id.clone(),
+                offset: (i * CHUNK) as u64,
+                chunk: chunk.to_vec().into(),
+            })
+            .await
+            .unwrap();
+        }
+        let mut raw_client = client.clone();
+        let call = tokio::spawn(async move {
+            raw_client
+                .upload_chunks(tokio_stream::wrappers::ReceiverStream::new(rx))
+                .await
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        call.abort();
+        drop(client);
+
+        // resumed by another client
+        let api = MrklarApi::new(client_net(&config, &server)).unwrap();
+        assert_eq!(
+            api.upload_session_offset(&session_id).await.unwrap(),
+            3 * CHUNK as u64
+        );
+
+        // a chunk not at the session offset is rejected
+        let status = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap()
+            .upload_chunks(tokio_stream::iter([UploadChunk {
+                session_id: session_id.clone(),
+                offset: 0,
+                chunk: data[..CHUNK].to_vec().into(),
+            }]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+        let offset = api.resume_upload_session(&session_id, &path).await.unwrap();
+        assert_eq!(offset, data.len() as u64);
+
+        let outcome = api
+            .finish_upload_session(&session_id, Some(data_sha256.clone()))
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+        assert_eq!(outcome.sha256, data_sha256);
+        assert!(matches!(
+            api.upload_session_offset(&session_id).await,
+            Err(ApiError::UploadSessionNotFound(id)) if id == session_id
+        ));
+
+        let mut downloaded = vec![];
+        let (filename, _, _, verified) = api.download_to_writer(0, &mut downloaded).await.unwrap();
+        assert_eq!(filename, "big.bin");
+        assert!(verified);
+        assert_eq!(downloaded, data);
+
+        // a sha256 mismatch closes the session
+        let session_id = api.start_upload_session("bad.bin").await.unwrap();
+        api.resume_upload_session(&session_id, &path).await.unwrap();
+        let res = api
+            .finish_upload_session(&session_id, Some(vec![0u8; 32]))
+            .await;
+        assert!(matches!(
+            res,
+            Err(ApiError::Status(s)) if s.code() == tonic::Code::InvalidArgument
+        ));
+        assert!(matches!(
+            api.upload_session_offset(&session_id).await,
+            Err(ApiError::UploadSessionNotFound(_))
+        ));
+
+        let outcome = api.upload_resumable(&path).await.unwrap();
+        assert_eq!(outcome.index, 1);
+        assert_eq!(outcome.stats.bytes, data.len() as u64);
+        assert_eq!(api.count().await.unwrap(), 2);
+        let tmp_dir = config.validate().unwrap().files_tmp_dir();
+        assert_eq!(std::fs::read_dir(tmp_dir).unwrap().count(), 0);
+
+        tmp_dl_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// A session whose file cannot be added is kept along with its file, it
+    /// can be finished again
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_session_failed_finish() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let tmp_src_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_duplicate_policy(DuplicatePolicy::Reject)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let tmp_dir = config.clone().validate().unwrap().files_tmp_dir();
+
+        let api = start_server(config).await;
+        api.upload_bytes("a.bin", b"content".to_vec().into())
+            .await
+            .unwrap();
+
+        let path = tmp_src_dir.path().join("b.bin");
+        std::fs::write(&path, b"content").unwrap();
+        let session_id = api.start_upload_session("b.bin").await.unwrap();
+        api.resume_upload_session(&session_id, &path).await.unwrap();
+
+        for _ in 0..2 {
+            assert!(matches!(
+                api.finish_upload_session(&session_id, None).await,
+                Err(ApiError::AlreadyExists(0))
+            ));
+            assert_eq!(api.upload_session_offset(&session_id).await.unwrap(), 7);
+            assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 1);
+        }
+        assert_eq!(api.count().await.unwrap(), 1);
+
+        tmp_src_dir.close().unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Abandoned upload sessions expire, their file is removed
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_session_expiry() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf())
+            .with_upload_session_ttl(std::time::Duration::from_millis(100));
+        let tmp_dir = config.clone().validate().unwrap().files_tmp_dir();
+
+        let api = start_server(config).await;
+
+        let session_id = api.start_upload_session("file.bin").await.unwrap();
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 1);
+
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(matches!(
+            api.upload_session_offset(&session_id).await,
+            Err(ApiError::UploadSessionNotFound(_))
+        ));
+        assert!(matches!(
+            api.finish_upload_session(&session_id, None).await,
+            Err(ApiError::UploadSessionNotFound(_))
+        ));
+        assert_eq!(std::fs::read_dir(&tmp_dir).unwrap().count(), 0);
+        assert_eq!(api.count().await.unwrap(), 0);
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The server rejects the filenames which cannot be written to disk or
+    /// logged as is, with the broken rule
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_invalid_filenames() {
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_max_filename_len(16)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let api = start_server(config).await;
+
+        // the empty filenames are rejected by the client
+        let rejected = [
+            ("a_long_filename.txt", "19 bytes long, the maximum is 16"),
+            ("dir/file", "path separator"),
+            ("../evil", "path separator"),
+            ("/etc/passwd", "path separator"),
+            ("..\\evil.bat", "path separator"),
+            (".", "'.' or '..'"),
+            ("..", "'.' or '..'"),
+            ("nul\0byte", "control character"),
+            ("new\nline", "control character"),
+            ("esc\x1b[2J", "control character"),
+        ];
+        for (name, rule) in rejected {
+            let err = api
+                .upload_bytes(name, b"content".to_vec().into())
+                .await
+                .unwrap_err();
+            let status = err.status().unwrap();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{name:?}");
+            assert!(
+                status.message().contains(rule),
+                "{name:?}: {}",
+                status.message()
+            );
+
+            // same rules for the resumable uploads and the lookups by name
+            let err = api.start_upload_session(name).await.unwrap_err();
+            assert_eq!(err.status().unwrap().code(), tonic::Code::InvalidArgument);
+            let err = api
+                .download_by_name(name, None, None, false, None)
+                .await
+                .unwrap_err();
+            assert_eq!(err.status().unwrap().code(), tonic::Code::InvalidArgument);
+        }
+        assert_eq!(api.count().await.unwrap(), 0);
+
+        let outcome = api
+            .upload_bytes("été.txt", b"content".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+        assert!(matches!(
+            api.download_by_name("missing.txt", None, None, false, None)
+                .await,
+            Err(ApiError::FileNameNotFound(name)) if name == "missing.txt"
+        ));
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// The client errors are reported with a specific status code, the
+    /// server side failures with `INTERNAL`
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_status_codes() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let tmp_dir = config.validate().unwrap().files_tmp_dir();
+
+        let (_, server) = start_server_task(config).await;
+        let mut client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let upload_client = client.clone();
+        let upload = |filename: &str, sha256: Option<Vec<u8>>| {
+            let mut requests = vec![UploadRequest::new_metadata(filename)];
+            requests.extend(sha256.map(UploadRequest::new_sha256));
+            requests.push(UploadRequest::new_chunk(b"content".to_vec()));
+            let mut client = upload_client.clone();
+            async move { client.upload(tokio_stream::iter(requests)).await }
+        };
+
+        upload("a.txt", None).await.unwrap();
+
+        // invalid hash, invalid filename
+        let status = upload("b.txt", Some(vec![0u8; 32])).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let status = upload("../b.txt", None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // empty upload stream, first message without a type
+        let status = client
+            .upload(tokio_stream::iter(Vec::<UploadRequest>::new()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let status = client
+            .upload(tokio_stream::iter(vec![UploadRequest::default()]))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // out of range index
+        let status = client.proof(FileIndex { index: 1 }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = client.metadata(FileIndex { index: 1 }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let request = |index, offset| DownloadRequest {
+            index,
+            offset,
+            chunk_size: 0,
+            length: 0,
+            metadata_only: false,
+        };
+        let status = client.download(request(1, 0)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        let status = client.download(request(0, 100)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::OutOfRange);
+
+        // the server cannot create its tmp directory
+        std::fs::remove_dir(&tmp_dir).unwrap();
+        std::fs::write(&tmp_dir, b"").unwrap();
+        let status = upload("c.txt", None).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+        let status = client
+            .start_upload(StartUploadRequest {
+                metadata: Some(mrklar_common::proto::FileMetadata {
+                    filename: "c.txt".to_string(),
+                }),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Internal);
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Every response, including the failed ones, carries the request id,
+    /// either generated by the server or sent by the client
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_request_id() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (_, server) = start_server_task(config).await;
+        let mut client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let request_id = |metadata: &tonic::metadata::MetadataMap| {
+            metadata
+                .get(REQUEST_ID_METADATA_KEY)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        let with_request_id = |id: &str| {
+            let mut request = Request::new(Empty {});
+            request
+                .metadata_mut()
+                .insert(REQUEST_ID_METADATA_KEY, id.parse().unwrap());
+            request
+        };
+
+        // generated by the server, unique per request
+        let first = request_id(client.count(Empty {}).await.unwrap().metadata());
+        let second = request_id(client.count(Empty {}).await.unwrap().metadata());
+        assert!(!first.is_empty());
+        assert_ne!(first, second);
+
+        // sent by the client
+        let response = client.count(with_request_id("client-id_42")).await.unwrap();
+        assert_eq!(request_id(response.metadata()), "client-id_42");
+
+        // an invalid client id is replaced
+        let response = client.count(with_request_id("a.b")).await.unwrap();
+        assert_ne!(request_id(response.metadata()), "a.b");
+
+        // failed requests
+        let status = client.proof(FileIndex { index: 0 }).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+        assert!(!request_id(status.metadata()).is_empty());
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Uploads with and without the client sha256, the server returns the
+    /// sha256 of the stored content in both cases
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upload_optional_sha256() {
+        use mrklar_common::proto::file_api_client::FileApiClient;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_chunk_size(1000)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config).await;
+        let data: Vec<u8> = (0..4500u32).map(|i| (i % 251) as u8).collect();
+        let data_sha256 = Sha256::digest(&data).to_vec();
+
+        // sha256 sent after the last chunk
+        let outcome = api
+            .upload_bytes("with.bin", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 0);
+        assert_eq!(outcome.sha256, data_sha256);
+
+        // sha256 computed by the server
+        let outcome = api
+            .clone()
+            .with_upload_sha256(false)
+            .upload_bytes("without.bin", data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(outcome.index, 1);
+        assert_eq!(outcome.sha256, data_sha256);
+        assert!(api.proof(1).await.unwrap().verify(&data_sha256));
+
+        let client = FileApiClient::connect(format!("http://{}", server.local_addr().unwrap()))
+            .await
+            .unwrap();
+        let upload = |messages: Vec<UploadRequest>| {
+            let mut client = client.clone();
+            async move {
+                client
+                    .upload(tokio_stream::iter(messages))
+                    .await
+                    .map(|r| r.into_inner())
+            }
+        };
+        let chunks = || {
+            data.chunks(1000)
+                .map(|c| UploadRequest::new_chunk(c.to_vec()))
+        };
+
+        let response = upload(
+            std::iter::once(UploadRequest::new_metadata("raw.bin"))
+                .chain(chunks())
+                .collect(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.index.unwrap().index, 2);
+        assert_eq!(response.sha256, data_sha256);
+
+        // a sent sha256 is still verified, before or after the chunks
+        let status = upload(
+            std::iter::once(UploadRequest::new_metadata("bad.bin"))
+                .chain(std::iter::once(UploadRequest::new_sha256(vec![0u8; 32])))
+                .chain(chunks())
+                .collect(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let status = upload(
+            std::iter::once(UploadRequest::new_metadata("bad.bin"))
+                .chain(chunks())
+                .chain(std::iter::once(UploadRequest::new_sha256(vec![0u8; 32])))
+                .collect(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        // empty content
+        let response = upload(vec![UploadRequest::new_metadata("empty.bin")])
+            .await
+            .unwrap();
+        assert_eq!(response.index.unwrap().index, 3);
+        assert_eq!(response.sha256, Sha256::digest(b"").to_vec());
+
+        assert_eq!(api.count().await.unwrap(), 4);
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Upload throughput over a simulated 50ms RTT link, with the default
+    /// upload window and deeper ones. Run with:
+    /// `cargo test -p mrklar-testing --release -- --ignored bench_upload_window --nocapture`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_upload_window() {
+        const SIZE: usize = 64 * 1024 * 1024;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let (_, server) = start_server_task(config.clone()).await;
+        let proxy_addr = start_latency_proxy(
+            server.local_addr().unwrap(),
+            std::time::Duration::from_millis(25),
+        )
+        .await;
+        let proxy_net = config.net.clone().with_port(proxy_addr.port());
+
+        let data = vec![7u8; SIZE];
+
+        for window in [None, Some(8), Some(16), Some(64)] {
+            // a new connection for each window, warmed up by a first upload:
+            // the flow control windows adapt to the link over the lifetime
+            // of a connection
+            let api = MrklarApi::new(proxy_net.clone()).unwrap();
+            let api = match window {
+                Some(window) => api.with_upload_window(window),
+                None => api,
+            };
+            api.upload_bytes("warmup.bin", data[..SIZE / 4].to_vec().into())
+                .await
+                .unwrap();
+            let outcome = api
+                .upload_bytes("bench.bin", data.clone().into())
+                .await
+                .unwrap();
+            let secs = outcome.stats.elapsed.as_secs_f64();
+            println!(
+                "window {:>7}: {:>6.1} MiB/s ({:.2}s)",
+                window.map_or("default".to_string(), |w| w.to_string()),
+                SIZE as f64 / (1024.0 * 1024.0) / secs,
+                secs
+            );
+        }
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+    /// Latency of `count` requests while many small files are uploaded
+    /// concurrently into a large db, each upload saving the whole db. Run with:
+    /// `cargo test -p mrklar-testing --release -- --ignored bench_concurrent_uploads --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore]
+    async fn bench_concurrent_uploads() {
+        const DB_ENTRIES: usize = 100_000;
+        const UPLOADS: usize = 256;
+        const CONCURRENT_UPLOADS: usize = 32;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        // a large db
+        let files_db_dir = config.files_db_dir();
+        std::fs::create_dir_all(&files_db_dir).unwrap();
+        for i in 0..DB_ENTRIES {
+            std::fs::write(files_db_dir.join(i.to_string()), i.to_le_bytes()).unwrap();
+        }
+        rebuild(&config, false).unwrap();
+
+        let (api, server) = start_server_task(config.clone()).await;
+
+        let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let probe = tokio::spawn({
+            let api = api.clone();
+            let done = done.clone();
+            async move {
+                let mut latencies = vec![];
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    let start = std::time::Instant::now();
+                    api.count().await.unwrap();
+                    latencies.push(start.elapsed());
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                }
+                latencies
+            }
+        });
+
+        let start = std::time::Instant::now();
+        let mut uploads = tokio::task::JoinSet::<mrklar_api::UploadResult>::new();
+        for i in 0..UPLOADS {
+            if uploads.len() == CONCURRENT_UPLOADS {
+                uploads.join_next().await.unwrap().unwrap().unwrap();
+            }
+            let api = api.clone();
+            uploads.spawn(async move {
+                api.upload_bytes(
+                    &format!("{i}.txt"),
+                    format!("bench {i}").into_bytes().into(),
+                )
+                .await
+            });
+        }
+        while let Some(res) = uploads.join_next().await {
+            res.unwrap().unwrap();
+        }
+        let elapsed = start.elapsed();
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        let mut latencies = probe.await.unwrap();
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{} uploads in {:.2}s, count latency: p50 {:?}, p99 {:?}, max {:?}",
+            UPLOADS,
+            elapsed.as_secs_f64(),
+            percentile(50),
+            percentile(99),
+            latencies.last().unwrap()
+        );
+
+        server.shutdown().await.unwrap();
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
+
+    /// Waits until the archive of `api` has `count` entries
+    async fn wait_for_count(api: &MrklarApi, count: u64) {
+        tokio::time::timeout(std::time::Duration::from_secs(30), async {
+            while api.count().await.unwrap() != count {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// A replica pulls the entries of the primary and reproduces its root,
+    /// whatever its own duplicate policy, layout and compression
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replication() {
+        let primary_db_dir = tempdir().unwrap();
+        let primary_files_dir = tempdir().unwrap();
+        let primary_config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(primary_db_dir.path().to_path_buf())
+            .with_files_dir(primary_files_dir.path().to_path_buf());
+        let (primary, primary_server) = start_server_task(primary_config).await;
+        let url = format!("http://{}", primary_server.local_addr().unwrap());
+
+        // 40 distinct contents, shared by several entries
+        let upload = |i: usize| {
+            let primary = primary.clone();
+            async move {
+                let content = format!("content {}", i % 40).repeat(50);
+                primary
+                    .upload_bytes(&format!("{i}.txt"), content.into_bytes().into())
+                    .await
+                    .unwrap();
+            }
+        };
+        for i in 0..60 {
+            upload(i).await;
+        }
+
+        let replica_db_dir = tempdir().unwrap();
+        let replica_files_dir = tempdir().unwrap();
+        let replica_config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(replica_db_dir.path().to_path_buf())
+            .with_files_dir(replica_files_dir.path().to_path_buf())
+            .with_duplicate_policy(DuplicatePolicy::Dedup)
+            .with_storage_layout(StorageLayout::ContentAddressed)
+            .with_compression_level(Some(3))
+            .with_replicate_from(Some(url))
+            .with_replication_interval(std::time::Duration::from_millis(100));
+        let (replica, replica_server) = start_server_task(replica_config.clone()).await;
+        wait_for_count(&replica, 60).await;
+        assert_eq!(
+            replica.status().await.unwrap(),
+            primary.status().await.unwrap()
+        );
+
+        // the uploads to the primary keep being replicated
+        for i in 60..100 {
+            upload(i).await;
+        }
+        wait_for_count(&replica, 100).await;
+        let status = primary.status().await.unwrap();
+        assert_eq!(status.count, 100);
+        assert_eq!(replica.status().await.unwrap(), status);
+        // every duplicate is replicated, sharing the stored file of its content
+        let mut stored_files = 0;
+        let mut dirs = vec![replica_config.files_db_dir()];
+        while let Some(dir) = dirs.pop() {
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else {
+                    stored_files += 1;
+                }
+            }
+        }
+        assert_eq!(stored_files, 40);
+
+        let dir = tempdir().unwrap();
+        let out = || Some(dir.path().to_path_buf());
+        for index in [0, 42, 99] {
+            let expected = primary
+                .download(index, out(), Some(format!("p{index}")), true, None)
+                .await
+                .unwrap();
+            let root = Some(status.root.clone());
+            let outcome = replica
+                .download(index, out(), Some(format!("r{index}")), true, root)
+                .await
+                .unwrap();
+            assert!(outcome.verified);
+            assert_eq!(outcome.filename, expected.filename);
+            assert_eq!(outcome.sha256, expected.sha256);
+        }
+
+        // a replica is read only
+        match replica.upload_bytes("a.txt", b"a".to_vec().into()).await {
+            Err(e) => assert_eq!(e.status().unwrap().code(), tonic::Code::FailedPrecondition),
+            Ok(_) => panic!("upload to a replica accepted"),
+        }
+
+        // a restarted replica resumes from its own entries
+        replica_server.shutdown().await.unwrap();
+        upload(100).await;
+        let (replica, replica_server) = start_server_task(replica_config).await;
+        wait_for_count(&replica, 101).await;
+        assert_eq!(
+            replica.status().await.unwrap(),
+            primary.status().await.unwrap()
+        );
+
+        replica_server.shutdown().await.unwrap();
+        primary_server.shutdown().await.unwrap();
+    }
+
+    /// A replica with entries the primary does not have stops replicating
+    /// and reports itself as not serving
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_replication_diverged() {
+        use tonic_health::pb::{health_client::HealthClient, HealthCheckRequest};
+        use tonic_health::ServingStatus;
+
+        let primary_db_dir = tempdir().unwrap();
+        let primary_files_dir = tempdir().unwrap();
+        let primary_config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(primary_db_dir.path().to_path_buf())
+            .with_files_dir(primary_files_dir.path().to_path_buf());
+        let (primary, primary_server) = start_server_task(primary_config).await;
+        for i in 0..10 {
+            primary
+                .upload_bytes(
+                    &format!("{i}.txt"),
+                    format!("primary {i}").into_bytes().into(),
+                )
+                .await
+                .unwrap();
+        }
+
+        // same size, different entries
+        let replica_db_dir = tempdir().unwrap();
+        let replica_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(replica_db_dir.path().to_path_buf())
+            .with_files_dir(replica_files_dir.path().to_path_buf());
+        let (api, server) = start_server_task(config.clone()).await;
+        for i in 0..10 {
+            api.upload_bytes(
+                &format!("{i}.txt"),
+                format!("replica {i}").into_bytes().into(),
+            )
+            .await
+            .unwrap();
+        }
+        let root = api.status().await.unwrap().root;
+        server.shutdown().await.unwrap();
+
+        let config = config
+            .with_replicate_from(Some(format!(
+                "http://{}",
+                primary_server.local_addr().unwrap()
+            )))
+            .with_replication_interval(std::time::Duration::from_millis(100));
+        let (replica, replica_server) = start_server_task(config).await;
+        let channel = tonic::transport::Endpoint::from_shared(format!(
+            "http://{}",
+            replica_server.local_addr().unwrap()
+        ))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+        let mut client = HealthClient::new(channel);
+        let request = HealthCheckRequest {
+            service: String::new(),
+        };
+        let mut watch = client.watch(request).await.unwrap().into_inner();
+        tokio::time::timeout(std::time::Duration::from_secs(30), async {
+            while watch.message().await.unwrap().unwrap().status()
+                != ServingStatus::NotServing.into()
+            {}
+        })
+        .await
+        .unwrap();
+        drop(watch);
+
+        // nothing has been pulled, the primary entries are still served
+        primary
+            .upload_bytes("10.txt", b"primary 10".to_vec().into())
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let status = replica.status().await.unwrap();
+        assert_eq!(status.count, 10);
+        assert_eq!(status.root, root);
+
+        replica_server.shutdown().await.unwrap();
+        primary_server.shutdown().await.unwrap();
+    }
+
+    /// In maintenance mode, the uploads are rejected with a retry hint while
+    /// the reads keep working, the mode is reported by the write health
+    /// service and the stats
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_maintenance() {
+        use tonic_health::pb::{
+            health_client::HealthClient, HealthCheckRequest, HealthCheckResponse,
+        };
+        use tonic_health::ServingStatus;
+
+        async fn wait_for(watch: &mut Streaming<HealthCheckResponse>, status: ServingStatus) {
+            tokio::time::timeout(std::time::Duration::from_secs(10), async {
+                while watch.message().await.unwrap().unwrap().status() != status.into() {}
+            })
+            .await
+            .unwrap()
+        }
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+        let (api, server) = start_server_task(config).await;
+        api.upload_bytes("0.txt", b"before".to_vec().into())
+            .await
+            .unwrap();
+        let session_id = api.start_upload_session("1.txt").await.unwrap();
+
+        let channel = tonic::transport::Endpoint::from_shared(format!(
+            "http://{}",
+            server.local_addr().unwrap()
+        ))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+        let mut client = HealthClient::new(channel);
+        let request = HealthCheckRequest {
+            service: WRITE_HEALTH_SERVICE.to_string(),
+        };
+        let mut watch = client.watch(request).await.unwrap().into_inner();
+        wait_for(&mut watch, ServingStatus::Serving).await;
+
+        assert!(!server.maintenance().set(true));
+        wait_for(&mut watch, ServingStatus::NotServing).await;
+
+        let e = api
+            .upload_bytes("1.txt", b"during".to_vec().into())
+            .await
+            .unwrap_err();
+        assert_eq!(e.status().unwrap().code(), tonic::Code::Unavailable);
+        assert_eq!(e.retry_after(), Some(MAINTENANCE_RETRY_AFTER));
+        let e = api.start_upload_session("1.txt").await.unwrap_err();
+        assert_eq!(e.retry_after(), Some(MAINTENANCE_RETRY_AFTER));
+        // the session survives a rejected finish
+        let e = api
+            .finish_upload_session(&session_id, None)
+            .await
+            .unwrap_err();
+        assert_eq!(e.status().unwrap().code(), tonic::Code::Unavailable);
+
+        let mut content = vec![];
+        api.download_to_writer(0, &mut content).await.unwrap();
+        assert_eq!(content, b"before");
+        let stats = api.stats().await.unwrap();
+        assert!(stats.maintenance);
+        assert_eq!(stats.count, 1);
+
+        assert!(server.maintenance().set(false));
+        wait_for(&mut watch, ServingStatus::Serving).await;
+        assert!(!api.stats().await.unwrap().maintenance);
+
+        let uploaded = api
+            .upload_bytes("1.txt", b"after".to_vec().into())
+            .await
+            .unwrap();
+        assert_eq!(uploaded.index, 1);
+        let finished = api.finish_upload_session(&session_id, None).await.unwrap();
+        assert_eq!(finished.index, 2);
+
+        server.shutdown().await.unwrap();
+    }
+
+    /// The subscribers get an event per upload, in order, from the moment
+    /// they subscribed
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_watch() {
+        use tokio_stream::StreamExt;
+
+        let tmp_db_dir = tempdir().unwrap();
+        let tmp_files_dir = tempdir().unwrap();
+
+        let config = ServerConfig::default()
+            .with_port(0)
+            .with_tracing(false)
+            .with_db_dir(tmp_db_dir.path().to_path_buf())
+            .with_files_dir(tmp_files_dir.path().to_path_buf());
+
+        let (api, server) = start_server_task(config).await;
+        api.upload_bytes("before.txt", b"before".to_vec().into())
+            .await
+            .unwrap();
+
+        let mut events = api.subscribe().await.unwrap();
+        let mut uploaded = vec![];
+        for i in 0..3 {
+            let filename = format!("{i}.txt");
+            let outcome = api
+                .upload_bytes(&filename, format!("content {i}").into_bytes().into())
+                .await
+                .unwrap();
+            uploaded.push((filename, outcome));
+        }
+        for (filename, outcome) in uploaded {
+            let event = events.next().await.unwrap().unwrap();
+            assert_eq!(event.index, outcome.index);
+            assert_eq!(event.filename, filename);
+            assert_eq!(event.sha256, outcome.sha256);
+            assert_eq!(event.merkle_root, outcome.root);
+            assert!(event.signed_tree_head.is_none());
+        }
+        assert_eq!(api.count().await.unwrap(), 4);
+
+        // the stream ends with the server
+        let shutdown = tokio::spawn(server.shutdown());
+        match events.next().await {
+            Some(Err(e)) => assert_eq!(e.status().unwrap().code(), tonic::Code::Unavailable),
+            other => panic!("unexpected watch event {other:?}"),
+        }
+        shutdown.await.unwrap().unwrap();
+
+        tmp_db_dir.close().unwrap();
+        tmp_files_dir.close().unwrap();
+    }
 }